@@ -1,27 +1,85 @@
-use cpu::bus::Bus;
-use cpu::lookup_table::LookUpTable;
-use cpu::{Cpu, FLAGS};
-use find_folder;
+use cpu::interrupt::IrqSource;
+use cpu::system::{Console, System};
+use cpu::FLAGS;
 use piston_window::{Context, Event, EventLoop, G2d, PistonWindow, WindowSettings, *};
-use std::cell::RefCell;
-use std::rc::Rc;
+use script::{Script, Text};
 
 const WHITE: [f32; 4] = [255.0, 255.0, 255.0, 1.0];
 const RED: [f32; 4] = [255.0, 0.0, 0.0, 1.0];
 const GREEN: [f32; 4] = [0.0, 255.0, 0.0, 1.0];
 const BLUE: [f32; 4] = [0.0, 0.0, 255.0, 1.0];
-const CYAN: [f32; 4] = [50.0, 100.0, 255.0, 1.0];
 
 struct Game<'a> {
     font: Glyphs,
-    cpu: &'a mut Cpu,
-    map_asm: Vec<String>,
+    system: &'a mut System,
+    script: Option<Script>,
+    // What the script last put on screen, kept once it is done
+    script_text: Vec<Text>,
 }
 
 impl<'a> Game<'a> {
-    fn new(font: Glyphs, cpu: &'a mut Cpu, map_asm: Vec<String>) -> Self {
-        Game { font, cpu, map_asm }
+    fn new(font: Glyphs, system: &'a mut System, script: Option<Script>) -> Self {
+        Game {
+            font,
+            system,
+            script_text: script.as_ref().map_or_else(Vec::new, Script::text),
+            script,
+        }
+    }
+
+    // Step an instruction and run the script hooks it set off, as headless does
+    fn step(&mut self) {
+        let system = &mut *self.system;
+        let script = match self.script.as_mut() {
+            Some(script) => script,
+            None => return system.step_instruction(),
+        };
+        let hits = system.cpu.check_execute_breakpoints();
+        let frame = system.frame;
+        let result = script.dispatch(system, hits).and_then(|_| {
+            // stop() in the top level or a hook before the instruction
+            if script.stopped() {
+                return Ok(());
+            }
+            system.step_instruction();
+            let hits = system.cpu.take_break_hits();
+            script.dispatch(system, hits)?;
+            if system.frame != frame {
+                script.frame_end(system)?;
+            }
+            Ok(())
+        });
+        script
+            .take_output()
+            .iter()
+            .for_each(|line| println!("{}", line));
+        self.script_text = script.text();
+        let done = match result {
+            Err(e) => Some(e.to_string()),
+            Ok(()) if script.stopped() => Some("stopped".to_string()),
+            Ok(()) => None,
+        };
+        if let Some(reason) = done {
+            println!("script: {}", reason);
+            script.finish(system);
+            self.script = None;
+        }
     }
+
+    fn run_frame(&mut self) {
+        let frame = self.system.frame;
+        while self.system.frame == frame {
+            self.step();
+        }
+    }
+
+    fn draw_script_text(&mut self, c: Context, g: &mut G2d, d: &mut GfxDevice) {
+        for t in self.script_text.clone() {
+            self.draw_string(c, g, d, t.x as f64, t.y as f64, &t.message, WHITE);
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn draw_string(
         &mut self,
         c: Context,
@@ -40,20 +98,7 @@ impl<'a> Game<'a> {
         self.font.factory.encoder.flush(d);
     }
 
-    fn render(&mut self, c: Context, g: &mut G2d, d: &mut GfxDevice) {
-        self.draw_string(
-            c,
-            g,
-            d,
-            10.0,
-            25.0,
-            "Hello world",
-            [255.0, 255.0, 255.0, 1.0],
-        )
-    }
-
-    fn update(&mut self, args: UpdateArgs) {}
-
+    #[allow(clippy::too_many_arguments)]
     fn draw_ram(
         &mut self,
         c: Context,
@@ -71,8 +116,11 @@ impl<'a> Game<'a> {
         for _ in 0..rows {
             let mut s_offset = format!("${:04x}:", addr);
             for _ in 0..cols {
-                s_offset.insert_str(s_offset.len(), &format!(" {:04x}", self.cpu.read(addr))[..]);
-                addr += 1
+                s_offset.insert_str(
+                    s_offset.len(),
+                    &format!(" {:04x}", self.system.cpu.peek(addr))[..],
+                );
+                addr = addr.wrapping_add(1)
             }
 
             self.draw_string(c, g, d, ram_x, ram_y, &s_offset, [255.0, 255.0, 255.0, 1.0]);
@@ -89,7 +137,7 @@ impl<'a> Game<'a> {
             x + 72.0,
             y,
             "N",
-            if (self.cpu.get_flag(FLAGS::n())) == 1 {
+            if (self.system.cpu.get_flag(FLAGS::n())) == 1 {
                 GREEN
             } else {
                 RED
@@ -102,7 +150,7 @@ impl<'a> Game<'a> {
             x + 88.0,
             y,
             "V",
-            if (self.cpu.get_flag(FLAGS::v())) == 1 {
+            if (self.system.cpu.get_flag(FLAGS::v())) == 1 {
                 GREEN
             } else {
                 RED
@@ -115,7 +163,7 @@ impl<'a> Game<'a> {
             x + 104.0,
             y,
             "-",
-            if (self.cpu.get_flag(FLAGS::u())) == 1 {
+            if (self.system.cpu.get_flag(FLAGS::u())) == 1 {
                 GREEN
             } else {
                 RED
@@ -128,7 +176,7 @@ impl<'a> Game<'a> {
             x + 120.0,
             y,
             "B",
-            if (self.cpu.get_flag(FLAGS::b())) == 1 {
+            if (self.system.cpu.get_flag(FLAGS::b())) == 1 {
                 GREEN
             } else {
                 RED
@@ -141,7 +189,7 @@ impl<'a> Game<'a> {
             x + 136.0,
             y,
             "D",
-            if (self.cpu.get_flag(FLAGS::d())) == 1 {
+            if (self.system.cpu.get_flag(FLAGS::d())) == 1 {
                 GREEN
             } else {
                 RED
@@ -154,7 +202,7 @@ impl<'a> Game<'a> {
             x + 152.0,
             y,
             "I",
-            if (self.cpu.get_flag(FLAGS::i())) == 1 {
+            if (self.system.cpu.get_flag(FLAGS::i())) == 1 {
                 GREEN
             } else {
                 RED
@@ -167,7 +215,7 @@ impl<'a> Game<'a> {
             x + 168.0,
             y,
             "Z",
-            if (self.cpu.get_flag(FLAGS::z())) == 1 {
+            if (self.system.cpu.get_flag(FLAGS::z())) == 1 {
                 GREEN
             } else {
                 RED
@@ -180,7 +228,7 @@ impl<'a> Game<'a> {
             x + 184.0,
             y,
             "C",
-            if (self.cpu.get_flag(FLAGS::c())) == 1 {
+            if (self.system.cpu.get_flag(FLAGS::c())) == 1 {
                 GREEN
             } else {
                 RED
@@ -192,7 +240,7 @@ impl<'a> Game<'a> {
             d,
             x,
             y + 16.0,
-            &format!("PC: ${:04x}", self.cpu.pc)[..],
+            &format!("PC: ${:04x}", self.system.cpu.pc)[..],
             WHITE,
         );
         self.draw_string(
@@ -201,7 +249,7 @@ impl<'a> Game<'a> {
             d,
             x,
             y + 32.0,
-            &format!("A: ${:04x} [{}]", self.cpu.acc, self.cpu.acc)[..],
+            &format!("A: ${:04x} [{}]", self.system.cpu.acc, self.system.cpu.acc)[..],
             WHITE,
         );
         self.draw_string(
@@ -210,7 +258,7 @@ impl<'a> Game<'a> {
             d,
             x,
             y + 48.0,
-            &format!("X: ${:04x} [{}]", self.cpu.x, self.cpu.x)[..],
+            &format!("X: ${:04x} [{}]", self.system.cpu.x, self.system.cpu.x)[..],
             WHITE,
         );
         self.draw_string(
@@ -219,7 +267,7 @@ impl<'a> Game<'a> {
             d,
             x,
             y + 64.0,
-            &format!("Y: ${:04x} [{}]", self.cpu.y, self.cpu.y)[..],
+            &format!("Y: ${:04x} [{}]", self.system.cpu.y, self.system.cpu.y)[..],
             WHITE,
         );
         self.draw_string(
@@ -228,148 +276,139 @@ impl<'a> Game<'a> {
             d,
             x,
             y + 80.0,
-            &format!("Stack: ${:04x}", self.cpu.sp)[..],
+            &format!("Stack: ${:04x}", self.system.cpu.sp)[..],
             WHITE,
         );
     }
-
-    fn draw_code(
-        &mut self,
-        c: Context,
-        g: &mut G2d,
-        d: &mut GfxDevice,
-        x: f64,
-        y: f64,
-        n_lines: u32,
-    ) {
-        let it_a = &self.map_asm.get(self.cpu.pc as usize).unwrap().clone();
-        let mut n_line_y = (n_lines >> 1) * 10 + y as u32;
-        let end = &self.map_asm[self.map_asm.len() - 1].clone();
-        if it_a != end {
-            self.draw_string(c, g, d, x, n_line_y as f64, it_a, CYAN);
-            while n_line_y < (n_lines * 10) + y as u32 {
-                n_line_y += 32;
-                let it_a = &self
-                    .map_asm
-                    .get((self.cpu.pc + 1) as usize)
-                    .unwrap()
-                    .clone();
-                if it_a != end {
-                    self.draw_string(c, g, d, x, n_line_y as f64, it_a, WHITE);
-                }
-            }
-        }
-
-        let it_a = &self.map_asm.get(self.cpu.pc as usize).unwrap().clone();
-        let mut n_line_y = (n_lines >> 1) * 10 + y as u32;
-        if it_a != end {
-            self.draw_string(c, g, d, x, n_line_y as f64, it_a, CYAN);
-            while n_line_y > y as u32 {
-                n_line_y -= 32;
-                let it_a = &self
-                    .map_asm
-                    .get((self.cpu.pc - 1) as usize)
-                    .unwrap()
-                    .clone();
-                if it_a != end {
-                    self.draw_string(c, g, d, x, n_line_y as f64, it_a, WHITE);
-                }
-            }
-        }
-    }
 }
 
 fn main() {
-    let bus = Rc::new(RefCell::new(Bus::new()));
+    let mut system = System::new(Console::Nes);
 
-    let mut cpu = Cpu::new(Rc::clone(&bus));
+    // Multiplies 10 by 3 into $0002
+    let program = cpu::asm::assemble(
+        "
+        .org $8000
+        ldx #10
+        stx $00
+        ldx #3
+        stx $01
+        ldy $00
+        lda #0
+        clc
+loop:   adc $01
+        dey
+        bne loop
+        sta $02
+        nop
+        nop
+        nop
 
-    let mut look_up = LookUpTable::new();
-    let data = [
-        0xA2, 0x0A, 0x8E, 0x00, 0x00, 0xA2, 0x03, 0x8E, 0x01, 0x00, 0xAC, 0x00, 0x00, 0xA9, 0x00,
-        0x18, 0x6D, 0x01, 0x00, 0x88, 0xD0, 0xFA, 0x8D, 0x02, 0x00, 0xEA, 0xEA, 0xEA,
-    ];
+        .org $FFFC
+        .word $8000
+        ",
+    )
+    .unwrap();
+    program.load(&mut system.bus.borrow_mut().ram);
 
-    let mut n_offset = 0x8000;
-    for i in 0..data.len() {
-        cpu.bus.borrow_mut().ram[n_offset] = data[i] as u8;
-        n_offset += 1;
-    }
-
-    cpu.bus.borrow_mut().ram[0xFFFC] = 0x00;
-	cpu.bus.borrow_mut().ram[0xFFFD] = 0x80;
-
-
-    cpu.reset();
-
-    let map_asm = cpu.disassemble(0x0000, 0xFFFF, &look_up);
+    system.reset();
 
     let mut window: PistonWindow = WindowSettings::new("NES 6502 TEST", (1024, 768))
         .exit_on_esc(true)
         .build()
         .unwrap();
     window.set_ups(60);
-    
 
     let assets = find_folder::Search::Kids(1).for_folder("assets").unwrap();
     let glyphs = window.load_font(assets.join("Roboto-Regular.ttf")).unwrap();
 
-    let mut app = Game::new(glyphs, &mut cpu, map_asm);
+    // cpu-test [--script <file>], the script runs along as the program is stepped
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let script = match args.iter().position(|a| a == "--script") {
+        Some(i) => {
+            let file = args.get(i + 1).expect("--script needs a file");
+            let started = Script::load(file, &system).and_then(|mut script| {
+                script.start(&mut system)?;
+                Ok(script)
+            });
+            match started {
+                Ok(mut script) => {
+                    script
+                        .take_output()
+                        .iter()
+                        .for_each(|line| println!("{}", line));
+                    Some(script)
+                }
+                Err(e) => {
+                    eprintln!("error: {}: {}", file, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mut app = Game::new(glyphs, &mut system, script);
+    app.system.cpu.enable_history(100_000);
+    app.system.enable_rewind(16 * 1024 * 1024);
+    app.system.bus.borrow_mut().enable_stats();
     while let Some(e) = window.next() {
         match e {
-            Event::Loop(l) => match l {
-                Loop::Render(args) => {
-                    window.draw_2d(&e, |c, g, d| {
-                        clear(BLUE, g);
-                        app.draw_ram(c,g,d, 2.0, 48.0, 0x0000, 16, 16);
-		                app.draw_ram(c,g,d, 2.0, 332.0, 0x8000, 16, 16);
-		                app.draw_cpu(c,g,d,800.0, 24.0);
-		                //app.draw_code(c,g,d,800.0, 160.0, 26);
-
+            Event::Loop(Loop::Render(_)) => {
+                window.draw_2d(&e, |c, g, d| {
+                    clear(BLUE, g);
+                    app.draw_ram(c, g, d, 2.0, 48.0, 0x0000, 16, 16);
+                    app.draw_ram(c, g, d, 2.0, 332.0, 0x8000, 16, 16);
+                    app.draw_cpu(c, g, d, 800.0, 24.0);
+                    app.draw_script_text(c, g, d);
 
-		                app.draw_string(c,g,d, 10.0, 600.0, "SPACE = Step Instruction    R = RESET    I = IRQ    N = NMI", WHITE);
-                    });
-                }
-                _ => {}
-            },
-            Event::Custom(_, _, _) => {}
-            Event::Input(input, dt) => {
-                match input {
-                    Input::Button(args) => {
-                        match args.state {
-                            ButtonState::Press => {
-                                match args.button {
-                                    Button::Keyboard(Key::Space) => {
-                                        loop {
-                                            app.cpu.clock(&mut look_up);
-                                            if app.cpu.complete()  {
-                                                break;
-                                            }
-                                        }
-                                    },
-                                    Button::Keyboard(Key::R) => {
-                                        
-                                        app.cpu.reset();
-                                    },
-                                    Button::Keyboard(Key::I) => {
-                                        
-                                        app.cpu.irq();
-                                    },
-                                    Button::Keyboard(Key::N) => {
-                                        
-                                        app.cpu.nmi()
-                                    },
-                                    _ => {}
-                                }
+                    app.draw_string(
+                        c,
+                        g,
+                        d,
+                        10.0,
+                        600.0,
+                        "SPACE = Step Instruction    B = Step Back    F = Run Frame    V = Back Frame    R = RESET    I = Toggle IRQ    N = NMI    H = Save heatmap.png",
+                        WHITE,
+                    );
+                });
+            }
+            Event::Input(Input::Button(args), _) if args.state == ButtonState::Press => {
+                match args.button {
+                    Button::Keyboard(Key::Space) => {
+                        app.step();
+                    }
+                    Button::Keyboard(Key::B) => {
+                        app.system.step_back(1);
+                    }
+                    Button::Keyboard(Key::F) => app.run_frame(),
+                    Button::Keyboard(Key::V) => {
+                        if let Err(e) = app.system.rewind_frames(1) {
+                            println!("rewind: {}", e);
+                        }
+                    }
+                    Button::Keyboard(Key::R) => {
+                        app.system.reset();
+                    }
+                    Button::Keyboard(Key::I) => {
+                        let irq = &mut app.system.cpu.interrupts;
+                        let asserted = irq.irq_asserted(IrqSource::External);
+                        irq.set_irq(IrqSource::External, !asserted);
+                    }
+                    Button::Keyboard(Key::N) => app.system.cpu.interrupts.pulse_nmi(),
+                    Button::Keyboard(Key::H) => {
+                        let bus = app.system.bus.borrow();
+                        if let Some(stats) = bus.stats.as_ref() {
+                            let file = std::fs::File::create("heatmap.png");
+                            if let Err(e) = file.and_then(|f| stats.write_png(f, 2)) {
+                                println!("heatmap.png: {}", e);
                             }
-                            _ => {}
                         }
-
                     }
                     _ => {}
-                    
                 }
             }
+            _ => {}
         }
     }
 }
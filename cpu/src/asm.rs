@@ -0,0 +1,767 @@
+// Two pass 6502 assembler for test programs and tools.
+//
+//   instructions  LDA #$05, STA $10,X, LDA ($20),Y, JMP ($FFFC), ASL A, BNE loop
+//   labels        `name:`, and `@name:` local to the last label without an @
+//   equates       name = expr
+//   directives    .org expr, .byte/.db expr|"text",..., .word/.dw expr,...,
+//                 .include "file" (relative to the including file), .setcpu
+//   expressions   $1F %1010 31 'c' numbers, labels, * for the current address,
+//                 unary - ~ < (low byte) > (high byte), * / % + - << >> & ^ |
+//   comments      ; to the end of the line
+//
+// Opcodes and addressing modes come from LookUpTable, the table the cpu runs
+// from. An operand whose value is known in the first pass and fits in a byte
+// uses zero page addressing when the instruction has it; `a:` before the
+// operand forces absolute addressing, as in ca65.
+use crate::disasm::AddrMode;
+use crate::lookup_table::LookUpTable;
+use crate::symbols::SymbolTable;
+use std::collections::HashMap;
+use std::path::Path;
+use std::{fmt, fs};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    // 1 based, 0 when the error is not about a line
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Assembly {
+    // Each .org starts a chunk
+    pub chunks: Vec<Chunk>,
+    // Labels and equates, with the source line of every byte emitting line
+    pub symbols: SymbolTable,
+}
+
+impl Assembly {
+    // Everything from the lowest address assembled to the highest, gaps as 0
+    pub fn image(&self) -> (u16, Vec<u8>) {
+        let chunks = self.chunks.iter().filter(|c| !c.bytes.is_empty());
+        let start = chunks.clone().map(|c| c.addr as usize).min().unwrap_or(0);
+        let end = chunks
+            .clone()
+            .map(|c| c.addr as usize + c.bytes.len())
+            .max()
+            .unwrap_or(0);
+        let mut image = vec![0; end.saturating_sub(start)];
+        for chunk in chunks {
+            let at = chunk.addr as usize - start;
+            image[at..at + chunk.bytes.len()].copy_from_slice(&chunk.bytes);
+        }
+        (start as u16, image)
+    }
+
+    // Copy every chunk into a 64K memory
+    pub fn load(&self, ram: &mut [u8]) {
+        for chunk in &self.chunks {
+            let at = chunk.addr as usize;
+            ram[at..at + chunk.bytes.len()].copy_from_slice(&chunk.bytes);
+        }
+    }
+}
+
+pub struct Assembler {
+    // Where assembly starts before any .org
+    pub origin: u16,
+    opcodes: HashMap<(&'static str, AddrMode), u8>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// A line of source after includes are expanded
+struct Line {
+    file: String,
+    number: usize,
+    text: String,
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        let mut opcodes = HashMap::new();
+        for (opcode, ins) in LookUpTable::new().table.iter().enumerate() {
+            let mode = AddrMode::from_name(ins.addr_name);
+            if mode != AddrMode::Unknown {
+                opcodes.entry((ins.name, mode)).or_insert(opcode as u8);
+            }
+        }
+        Assembler { origin: 0, opcodes }
+    }
+
+    // name is used in error messages and the source lines of the symbols;
+    // includes are looked up from the current directory
+    pub fn assemble(&self, source: &str, name: &str) -> Result<Assembly, AsmError> {
+        let mut lines = Vec::new();
+        expand(source, name, Path::new("."), 0, &mut lines)?;
+        self.assemble_lines(&lines)
+    }
+
+    pub fn assemble_file(&self, path: impl AsRef<Path>) -> Result<Assembly, AsmError> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        let source = fs::read_to_string(path).map_err(|e| AsmError {
+            file: name.clone(),
+            line: 0,
+            message: e.to_string(),
+        })?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut lines = Vec::new();
+        expand(&source, &name, dir, 0, &mut lines)?;
+        self.assemble_lines(&lines)
+    }
+
+    fn assemble_lines(&self, lines: &[Line]) -> Result<Assembly, AsmError> {
+        let mut pass = Pass {
+            asm: self,
+            labels: HashMap::new(),
+            pending: Vec::new(),
+            modes: Vec::new(),
+            last: false,
+            pc: self.origin,
+            scope: String::new(),
+            instruction: 0,
+            assembly: Assembly::default(),
+        };
+        for line in lines {
+            pass.line(line).map_err(|message| error(line, message))?;
+        }
+        pass.resolve_equates()?;
+
+        pass.last = true;
+        pass.pc = self.origin;
+        pass.scope.clear();
+        pass.instruction = 0;
+        for line in lines {
+            pass.line(line).map_err(|message| error(line, message))?;
+        }
+
+        let mut assembly = pass.assembly;
+        assembly.chunks.retain(|c| !c.bytes.is_empty());
+        for (name, value) in pass.labels {
+            assembly.symbols.add_label(&name, value as u16);
+        }
+        Ok(assembly)
+    }
+
+    fn has(&self, mnemonic: &str, mode: AddrMode) -> bool {
+        self.opcode(mnemonic, mode).is_some()
+    }
+
+    fn opcode(&self, mnemonic: &str, mode: AddrMode) -> Option<u8> {
+        self.opcodes
+            .iter()
+            .find(|((name, m), _)| *m == mode && name.eq_ignore_ascii_case(mnemonic))
+            .map(|(_, opcode)| *opcode)
+    }
+}
+
+// Assemble source, looking includes up from the current directory
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    Assembler::new().assemble(source, "<source>")
+}
+
+fn error(line: &Line, message: String) -> AsmError {
+    AsmError {
+        file: line.file.clone(),
+        line: line.number,
+        message,
+    }
+}
+
+fn expand(
+    source: &str,
+    name: &str,
+    dir: &Path,
+    depth: usize,
+    lines: &mut Vec<Line>,
+) -> Result<(), AsmError> {
+    for (n, text) in source.lines().enumerate() {
+        let line = Line {
+            file: name.to_string(),
+            number: n + 1,
+            text: strip_comment(text).trim().to_string(),
+        };
+        let (directive, rest) = split_word(&line.text);
+        if !directive.eq_ignore_ascii_case(".include") {
+            lines.push(line);
+            continue;
+        }
+        if depth >= 16 {
+            return Err(error(&line, "includes nested too deeply".to_string()));
+        }
+        let file = string_literal(rest.trim())
+            .ok_or_else(|| error(&line, "expected .include \"file\"".to_string()))?;
+        let path = dir.join(file);
+        let text = fs::read_to_string(&path)
+            .map_err(|e| error(&line, format!("{}: {}", path.display(), e)))?;
+        let dir = path.parent().unwrap_or(dir);
+        expand(&text, &path.display().to_string(), dir, depth + 1, lines)?;
+    }
+    Ok(())
+}
+
+struct Equate {
+    name: String,
+    value: String,
+    // The last label without an @ where it was defined
+    scope: String,
+    file: String,
+    line: usize,
+}
+
+struct Pass<'a> {
+    asm: &'a Assembler,
+    labels: HashMap<String, i64>,
+    // Equates that use symbols defined later
+    pending: Vec<Equate>,
+    // The addressing mode each instruction got in the first pass, so sizes
+    // cannot change in the second
+    modes: Vec<AddrMode>,
+    last: bool,
+    pc: u16,
+    // The last label without an @
+    scope: String,
+    instruction: usize,
+    assembly: Assembly,
+}
+
+impl<'a> Pass<'a> {
+    fn line(&mut self, line: &Line) -> Result<(), String> {
+        let mut text = line.text.as_str();
+
+        // Label definitions
+        let name_len = text
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '@'))
+            .unwrap_or(text.len());
+        if name_len > 0 && text[name_len..].starts_with(':') {
+            let name = &text[..name_len];
+            if !name.starts_with('@') {
+                self.scope = name.to_string();
+            }
+            self.define(name, self.pc as i64)?;
+            text = text[name_len + 1..].trim();
+        }
+        if text.is_empty() {
+            return Ok(());
+        }
+
+        // Equates
+        if let Some((name, value)) = text.split_once('=') {
+            let name = name.trim();
+            if is_name(name) {
+                if self.last {
+                    return Ok(());
+                }
+                match self.eval(value)? {
+                    Some(v) => self.define(name, v)?,
+                    None => self.pending.push(Equate {
+                        name: name.to_string(),
+                        value: value.to_string(),
+                        scope: self.scope.clone(),
+                        file: line.file.clone(),
+                        line: line.number,
+                    }),
+                }
+                return Ok(());
+            }
+        }
+
+        let start = self.pc;
+        let (word, rest) = split_word(text);
+        let rest = rest.trim();
+        if word.starts_with('.') {
+            self.directive(&word.to_ascii_lowercase(), rest)?;
+        } else {
+            self.instruction(word, rest)?;
+        }
+        if self.last && self.pc != start {
+            self.assembly
+                .symbols
+                .add_line(&line.file, line.number as u32, start);
+        }
+        Ok(())
+    }
+
+    fn directive(&mut self, directive: &str, args: &str) -> Result<(), String> {
+        match directive {
+            ".org" => {
+                let addr = self
+                    .eval(args)?
+                    .ok_or(".org needs a value known in the first pass")?;
+                self.pc = check(addr, 0, 0xFFFF, ".org address")? as u16;
+                if self.last {
+                    self.assembly.chunks.push(Chunk {
+                        addr: self.pc,
+                        bytes: Vec::new(),
+                    });
+                }
+            }
+            ".byte" | ".db" => {
+                for item in split_args(args) {
+                    if let Some(text) = string_literal(item) {
+                        self.emit(text.as_bytes())?;
+                        continue;
+                    }
+                    let value = self.value(item)?;
+                    self.emit(&[check(value, -128, 0xFF, "byte")? as u8])?;
+                }
+            }
+            ".word" | ".dw" => {
+                for item in split_args(args) {
+                    let value = self.value(item)?;
+                    let word = check(value, -0x8000, 0xFFFF, "word")? as u16;
+                    self.emit(&word.to_le_bytes())?;
+                }
+            }
+            ".setcpu" => {}
+            _ => return Err(format!("unknown directive {}", directive)),
+        }
+        Ok(())
+    }
+
+    fn instruction(&mut self, mnemonic: &str, operand: &str) -> Result<(), String> {
+        if !self
+            .asm
+            .opcodes
+            .keys()
+            .any(|(name, _)| name.eq_ignore_ascii_case(mnemonic))
+        {
+            return Err(format!("unknown instruction {}", mnemonic));
+        }
+        let has = |mode| self.asm.has(mnemonic, mode);
+        let operand = squeeze(operand);
+        let upper = operand.to_ascii_uppercase();
+
+        // The mode, the expression and whether absolute addressing is forced
+        let (mode, expr) = if operand.is_empty() {
+            let mode = if has(AddrMode::Implied) {
+                AddrMode::Implied
+            } else {
+                AddrMode::Accumulator
+            };
+            (mode, "")
+        } else if upper == "A" && has(AddrMode::Accumulator) {
+            (AddrMode::Accumulator, "")
+        } else if let Some(expr) = operand.strip_prefix('#') {
+            (AddrMode::Immediate, expr)
+        } else if upper.starts_with('(') && upper.ends_with(",X)") {
+            (AddrMode::IndirectX, &operand[1..operand.len() - 3])
+        } else if upper.starts_with('(') && upper.ends_with("),Y") {
+            (AddrMode::IndirectY, &operand[1..operand.len() - 3])
+        } else if upper.starts_with('(') && upper.ends_with(')') && has(AddrMode::Indirect) {
+            (AddrMode::Indirect, &operand[1..operand.len() - 1])
+        } else if has(AddrMode::Relative) {
+            (AddrMode::Relative, operand.as_str())
+        } else if upper.ends_with(",X") {
+            let expr = &operand[..operand.len() - 2];
+            (self.size(mnemonic, expr, AddrMode::ZeroPageX)?, expr)
+        } else if upper.ends_with(",Y") {
+            let expr = &operand[..operand.len() - 2];
+            (self.size(mnemonic, expr, AddrMode::ZeroPageY)?, expr)
+        } else {
+            (
+                self.size(mnemonic, &operand, AddrMode::ZeroPage)?,
+                operand.as_str(),
+            )
+        };
+        let expr = expr.strip_prefix("a:").unwrap_or(expr);
+
+        let opcode = self
+            .asm
+            .opcode(mnemonic, mode)
+            .ok_or_else(|| format!("{} has no {} addressing mode", mnemonic, mode.name()))?;
+        let next = self.pc.wrapping_add(1 + mode.operand_size());
+        let mut bytes = vec![opcode];
+        match mode.operand_size() {
+            0 => {}
+            1 => {
+                let value = self.value(expr)?;
+                let byte = if mode == AddrMode::Relative {
+                    let offset = value - next as i64;
+                    if self.last && !(-128..=127).contains(&offset) {
+                        return Err(format!("branch out of range by {} bytes", offset));
+                    }
+                    offset as u8
+                } else if mode == AddrMode::Immediate {
+                    check(value, -128, 0xFF, "immediate value")? as u8
+                } else {
+                    check(value, 0, 0xFF, "zero page address")? as u8
+                };
+                bytes.push(byte);
+            }
+            _ => {
+                let value = check(self.value(expr)?, 0, 0xFFFF, "address")? as u16;
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        self.emit(&bytes)
+    }
+
+    // Zero page or absolute form of a memory operand. Decided in the first
+    // pass, where operands defined later count as absolute.
+    fn size(&mut self, mnemonic: &str, expr: &str, zp: AddrMode) -> Result<AddrMode, String> {
+        let abs = match zp {
+            AddrMode::ZeroPageX => AddrMode::AbsoluteX,
+            AddrMode::ZeroPageY => AddrMode::AbsoluteY,
+            _ => AddrMode::Absolute,
+        };
+        let index = self.instruction;
+        self.instruction += 1;
+        if self.last {
+            return Ok(self.modes[index]);
+        }
+
+        let (has_zp, has_abs) = (self.asm.has(mnemonic, zp), self.asm.has(mnemonic, abs));
+        let fits = match expr.strip_prefix("a:") {
+            Some(_) => false,
+            None => matches!(self.eval(expr)?, Some(v) if (0..0x100).contains(&v)),
+        };
+        let mode = if has_zp && (fits || !has_abs) {
+            zp
+        } else {
+            abs
+        };
+        self.modes.push(mode);
+        Ok(mode)
+    }
+
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.pc as usize + bytes.len() > 0x10000 {
+            return Err("past the end of memory".to_string());
+        }
+        if self.last {
+            if self.assembly.chunks.is_empty() {
+                self.assembly.chunks.push(Chunk {
+                    addr: self.pc,
+                    bytes: Vec::new(),
+                });
+            }
+            let chunk = self.assembly.chunks.last_mut().unwrap();
+            chunk.bytes.extend_from_slice(bytes);
+        }
+        self.pc = self.pc.wrapping_add(bytes.len() as u16);
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, value: i64) -> Result<(), String> {
+        // The second pass sees the same definitions again
+        if self.last {
+            return Ok(());
+        }
+        let name = self.qualify(name);
+        if self.labels.contains_key(&name) {
+            return Err(format!("{} is already defined", name));
+        }
+        self.labels.insert(name, value);
+        Ok(())
+    }
+
+    // Local labels are stored as scope@name
+    fn qualify(&self, name: &str) -> String {
+        if name.starts_with('@') {
+            format!("{}{}", self.scope, name)
+        } else {
+            name.to_string()
+        }
+    }
+
+    // Equates waiting on later definitions, until none of them makes progress
+    fn resolve_equates(&mut self) -> Result<(), AsmError> {
+        while !self.pending.is_empty() {
+            let before = self.pending.len();
+            for equate in std::mem::take(&mut self.pending) {
+                self.scope = equate.scope.clone();
+                let error = |message| AsmError {
+                    file: equate.file.clone(),
+                    line: equate.line,
+                    message,
+                };
+                match self.eval(&equate.value).map_err(error)? {
+                    Some(v) => {
+                        let name = self.qualify(&equate.name);
+                        self.labels.insert(name, v);
+                    }
+                    None => self.pending.push(equate),
+                }
+            }
+            if self.pending.len() == before {
+                let equate = &self.pending[0];
+                return Err(AsmError {
+                    file: equate.file.clone(),
+                    line: equate.line,
+                    message: format!("{} depends on an undefined symbol", equate.name),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // A value that must be known in the second pass, taken as 0 in the first
+    fn value(&self, expr: &str) -> Result<i64, String> {
+        match self.eval(expr)? {
+            Some(value) => Ok(value),
+            None if !self.last => Ok(0),
+            None => Err(format!("undefined symbol in '{}'", expr)),
+        }
+    }
+
+    // None when a symbol is not defined yet
+    fn eval(&self, expr: &str) -> Result<Option<i64>, String> {
+        let tokens = tokenize(expr.trim())?;
+        if tokens.is_empty() {
+            return Err("expected a value".to_string());
+        }
+        let mut parser = Parser {
+            tokens: &tokens,
+            pos: 0,
+            pass: self,
+        };
+        let value = parser.expr(0)?;
+        if parser.pos < tokens.len() {
+            return Err(format!("unexpected {:?} in '{}'", tokens[parser.pos], expr));
+        }
+        Ok(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+const OPERATORS: [&str; 15] = [
+    "<<", ">>", "(", ")", "+", "-", "*", "/", "%", "&", "|", "^", "~", "<", ">",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if c.is_whitespace() {
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let end = |rest: &str, radix: u32| {
+            rest.find(|c: char| !c.is_digit(radix) && c != '_')
+                .unwrap_or(rest.len())
+        };
+        // % starts a binary number when binary digits follow, else it is modulo
+        let binary = c == '%' && rest[1..].starts_with(['0', '1']);
+        let (token, len) = if c == '$' || binary {
+            let radix = if c == '$' { 16 } else { 2 };
+            let len = end(&rest[1..], radix);
+            let digits = rest[1..1 + len].replace('_', "");
+            let value = i64::from_str_radix(&digits, radix)
+                .map_err(|_| format!("bad number '{}'", &rest[..1 + len]))?;
+            (Token::Number(value), 1 + len)
+        } else if c.is_ascii_digit() {
+            let len = end(rest, 10);
+            let value = rest[..len]
+                .replace('_', "")
+                .parse()
+                .map_err(|_| format!("bad number '{}'", &rest[..len]))?;
+            (Token::Number(value), len)
+        } else if c == '\'' {
+            let mut chars = rest[1..].chars();
+            match (chars.next(), chars.next()) {
+                (Some(ch), Some('\'')) => (Token::Number(ch as i64), 2 + ch.len_utf8()),
+                _ => return Err("bad character literal".to_string()),
+            }
+        } else if c.is_ascii_alphabetic() || c == '_' || c == '@' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '@'))
+                .unwrap_or(rest.len());
+            (Token::Name(rest[..len].to_string()), len)
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(**op))
+                .ok_or_else(|| format!("unexpected '{}'", c))?;
+            (Token::Op(op), op.len())
+        };
+        tokens.push(token);
+        rest = &rest[len..];
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a, 'b> {
+    tokens: &'a [Token],
+    pos: usize,
+    pass: &'a Pass<'b>,
+}
+
+impl<'a, 'b> Parser<'a, 'b> {
+    fn binary(&self) -> Option<(&'static str, u8)> {
+        let op = match self.tokens.get(self.pos)? {
+            Token::Op(op) => *op,
+            _ => return None,
+        };
+        let precedence = match op {
+            "|" => 1,
+            "^" => 2,
+            "&" => 3,
+            "<<" | ">>" => 4,
+            "+" | "-" => 5,
+            "*" | "/" | "%" => 6,
+            _ => return None,
+        };
+        Some((op, precedence))
+    }
+
+    fn expr(&mut self, min: u8) -> Result<Option<i64>, String> {
+        let mut left = self.unary()?;
+        while let Some((op, precedence)) = self.binary() {
+            if precedence <= min {
+                break;
+            }
+            self.pos += 1;
+            let right = self.expr(precedence)?;
+            left = match (left, right) {
+                (Some(l), Some(r)) => Some(match op {
+                    "|" => l | r,
+                    "^" => l ^ r,
+                    "&" => l & r,
+                    "<<" => l.checked_shl(r as u32).unwrap_or(0),
+                    ">>" => l.checked_shr(r as u32).unwrap_or(0),
+                    "+" => l.wrapping_add(r),
+                    "-" => l.wrapping_sub(r),
+                    "*" => l.wrapping_mul(r),
+                    _ if r == 0 => return Err("division by zero".to_string()),
+                    "/" => l.wrapping_div(r),
+                    _ => l.wrapping_rem(r),
+                }),
+                _ => None,
+            };
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Option<i64>, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .ok_or("expression ends early")?
+            .clone();
+        self.pos += 1;
+        let value = match token {
+            Token::Number(n) => Some(n),
+            Token::Name(name) => self.pass.labels.get(&self.pass.qualify(&name)).copied(),
+            Token::Op("*") => Some(self.pass.pc as i64),
+            Token::Op("(") => {
+                let value = self.expr(0)?;
+                if self.tokens.get(self.pos) != Some(&Token::Op(")")) {
+                    return Err("missing )".to_string());
+                }
+                self.pos += 1;
+                value
+            }
+            Token::Op(op @ "-")
+            | Token::Op(op @ "~")
+            | Token::Op(op @ "<")
+            | Token::Op(op @ ">") => self.unary()?.map(|v| match op {
+                "-" => v.wrapping_neg(),
+                "~" => !v,
+                "<" => v & 0xFF,
+                _ => (v >> 8) & 0xFF,
+            }),
+            Token::Op(op) => return Err(format!("unexpected '{}'", op)),
+        };
+        Ok(value)
+    }
+}
+
+fn check(value: i64, min: i64, max: i64, what: &str) -> Result<i64, String> {
+    if (min..=max).contains(&value) {
+        Ok(value & 0xFFFF)
+    } else {
+        Err(format!("{} ${:X} out of range", what, value))
+    }
+}
+
+fn is_name(text: &str) -> bool {
+    !text.is_empty()
+        && !text.starts_with(|c: char| c.is_ascii_digit())
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '@')
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    text.split_once(char::is_whitespace).unwrap_or((text, ""))
+}
+
+// Text before a ; that is not inside quotes
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, ';') => return &text[..i],
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            _ => {}
+        }
+    }
+    text
+}
+
+// Operands without whitespace, except inside character literals
+fn squeeze(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut quoted = false;
+    for c in text.chars() {
+        if c == '\'' {
+            quoted = !quoted;
+        }
+        if quoted || !c.is_whitespace() {
+            out.push(c);
+        }
+    }
+    out
+}
+
+// Comma separated arguments, commas inside quotes included in the argument
+fn split_args(text: &str) -> Vec<&str> {
+    let mut args = Vec::new();
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (None, ',') => {
+                args.push(text[start..i].trim());
+                start = i + 1;
+            }
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            _ => {}
+        }
+    }
+    args.push(text[start..].trim());
+    args
+}
+
+fn string_literal(text: &str) -> Option<&str> {
+    text.strip_prefix('"')?.strip_suffix('"')
+}
@@ -0,0 +1,254 @@
+// Breakpoints and watchpoints.
+//
+// Execute breakpoints (pc, address range, opcode) are checked before an
+// instruction runs, so the debugger stops with that instruction next. Read and
+// write watchpoints fire from Cpu::read/Cpu::write and interrupt breakpoints on
+// dispatch of the sequence; those stop once the instruction or sequence is done.
+use crate::bus::Access;
+use crate::expr::Expr;
+use crate::interrupt::Interrupt;
+use crate::Cpu;
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddrRange {
+    pub start: u16,
+    // Inclusive
+    pub end: u16,
+}
+
+impl AddrRange {
+    pub fn new(start: u16, end: u16) -> Self {
+        AddrRange { start, end }
+    }
+
+    pub fn single(addr: u16) -> Self {
+        AddrRange::new(addr, addr)
+    }
+
+    pub fn contains(&self, addr: u16) -> bool {
+        addr >= self.start && addr <= self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakKind {
+    Pc(u16),
+    Execute(AddrRange),
+    Read(AddrRange),
+    Write(AddrRange),
+    // Any access, read or write
+    Access(AddrRange),
+    Opcode(u8),
+    // None matches both NMI and IRQ
+    Interrupt(Option<Interrupt>),
+}
+
+impl fmt::Display for BreakKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let range = |f: &mut fmt::Formatter, name: &str, r: &AddrRange| {
+            if r.start == r.end {
+                write!(f, "{} ${:04X}", name, r.start)
+            } else {
+                write!(f, "{} ${:04X}-${:04X}", name, r.start, r.end)
+            }
+        };
+        match self {
+            BreakKind::Pc(pc) => write!(f, "pc ${:04X}", pc),
+            BreakKind::Execute(r) => range(f, "exec", r),
+            BreakKind::Read(r) => range(f, "read", r),
+            BreakKind::Write(r) => range(f, "write", r),
+            BreakKind::Access(r) => range(f, "access", r),
+            BreakKind::Opcode(op) => write!(f, "opcode ${:02X}", op),
+            BreakKind::Interrupt(None) => write!(f, "interrupt"),
+            BreakKind::Interrupt(Some(Interrupt::Nmi)) => write!(f, "nmi"),
+            BreakKind::Interrupt(Some(Interrupt::Irq)) => write!(f, "irq"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub id: u32,
+    pub kind: BreakKind,
+    // Expression that must be non zero for the breakpoint to match
+    pub condition: Option<Expr>,
+    pub enabled: bool,
+    // Times the breakpoint matched with its condition true
+    pub hit_count: u32,
+    // Matches to let through before stopping
+    pub ignore_count: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakHit {
+    pub id: u32,
+    pub kind: BreakKind,
+    pub pc: u16,
+    // Address accessed, or the pc/vector for execute and interrupt breaks
+    pub addr: u16,
+    pub value: u8,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Breakpoints {
+    pub list: Vec<Breakpoint>,
+    // Hits raised by the accesses of the instruction in flight
+    pub hits: Vec<BreakHit>,
+    next_id: u32,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Breakpoints::default()
+    }
+
+    pub fn add(&mut self, kind: BreakKind) -> u32 {
+        self.next_id += 1;
+        self.list.push(Breakpoint {
+            id: self.next_id,
+            kind,
+            condition: None,
+            enabled: true,
+            hit_count: 0,
+            ignore_count: 0,
+        });
+        self.next_id
+    }
+
+    pub fn add_conditional(&mut self, kind: BreakKind, condition: Expr) -> u32 {
+        let id = self.add(kind);
+        self.get_mut(id).unwrap().condition = Some(condition);
+        id
+    }
+
+    pub fn remove(&mut self, id: u32) -> bool {
+        let len = self.list.len();
+        self.list.retain(|b| b.id != id);
+        self.list.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+        self.hits.clear();
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Breakpoint> {
+        self.list.iter_mut().find(|b| b.id == id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    fn watches(&self, access: Access) -> bool {
+        self.list.iter().any(|b| {
+            b.enabled
+                && matches!(
+                    (b.kind, access),
+                    (BreakKind::Read(_), Access::Read)
+                        | (BreakKind::Write(_), Access::Write)
+                        | (BreakKind::Access(_), Access::Read)
+                        | (BreakKind::Access(_), Access::Write)
+                )
+        })
+    }
+
+    fn matches(kind: &BreakKind, access: Access, addr: u16, opcode: u8) -> bool {
+        match (kind, access) {
+            (BreakKind::Pc(pc), Access::Execute) => *pc == addr,
+            (BreakKind::Execute(r), Access::Execute) => r.contains(addr),
+            (BreakKind::Opcode(op), Access::Execute) => *op == opcode,
+            (BreakKind::Read(r), Access::Read) => r.contains(addr),
+            (BreakKind::Write(r), Access::Write) => r.contains(addr),
+            (BreakKind::Access(r), Access::Read) | (BreakKind::Access(r), Access::Write) => {
+                r.contains(addr)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Cpu {
+    // Evaluate every breakpoint of the given access kind, counting hits and
+    // returning those that should stop execution
+    fn collect_hits(
+        &mut self,
+        pred: impl Fn(&BreakKind) -> bool,
+        addr: u16,
+        value: u8,
+    ) -> Vec<BreakHit> {
+        let mut breakpoints = std::mem::take(&mut self.breakpoints.list);
+        let mut hits = Vec::new();
+        for b in breakpoints.iter_mut() {
+            if !b.enabled || !pred(&b.kind) {
+                continue;
+            }
+            if let Some(condition) = b.condition.as_ref() {
+                // A condition that fails to evaluate stops, so the user sees why
+                if condition.eval(self) == Ok(0) {
+                    continue;
+                }
+            }
+            b.hit_count += 1;
+            if b.hit_count > b.ignore_count {
+                hits.push(BreakHit {
+                    id: b.id,
+                    kind: b.kind,
+                    pc: self.pc,
+                    addr,
+                    value,
+                });
+            }
+        }
+        self.breakpoints.list = breakpoints;
+        hits
+    }
+
+    // Called from Cpu::read/Cpu::write for data accesses
+    pub(crate) fn check_watchpoints(&mut self, access: Access, addr: u16, value: u8) {
+        if !self.breakpoints.watches(access) {
+            return;
+        }
+        let hits = self.collect_hits(
+            |kind| Breakpoints::matches(kind, access, addr, 0),
+            addr,
+            value,
+        );
+        self.breakpoints.hits.extend(hits);
+    }
+
+    pub(crate) fn check_interrupt_breakpoints(&mut self, interrupt: Interrupt) {
+        if self.breakpoints.is_empty() {
+            return;
+        }
+        let hits = self.collect_hits(
+            |kind| match kind {
+                BreakKind::Interrupt(None) => true,
+                BreakKind::Interrupt(Some(i)) => *i == interrupt,
+                _ => false,
+            },
+            interrupt.vector(),
+            0,
+        );
+        self.breakpoints.hits.extend(hits);
+    }
+
+    // Execute breakpoints for the instruction about to run at pc
+    pub fn check_execute_breakpoints(&mut self) -> Vec<BreakHit> {
+        if self.breakpoints.is_empty() || self.pending_interrupt.is_some() {
+            return Vec::new();
+        }
+        let pc = self.pc;
+        let opcode = self.peek(pc);
+        self.collect_hits(
+            |kind| Breakpoints::matches(kind, Access::Execute, pc, opcode),
+            pc,
+            opcode,
+        )
+    }
+
+    pub fn take_break_hits(&mut self) -> Vec<BreakHit> {
+        std::mem::take(&mut self.breakpoints.hits)
+    }
+}
@@ -1,13 +1,32 @@
+use crate::heatmap::AccessStats;
+
 pub const RAM_SIZE: usize = 64 * 1024;
 
+// What the cpu is doing with an address. Opcode and operand fetches are Execute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
 #[derive(Debug, Clone)]
 pub struct Bus {
-    pub ram: [u8; RAM_SIZE]
+    pub ram: [u8; RAM_SIZE],
+    // Access counts per address, None while not counting
+    pub stats: Option<AccessStats>,
+}
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
+
 impl Bus {
     pub fn new() -> Self {
         Bus {
-            ram: [0; RAM_SIZE]
+            ram: [0; RAM_SIZE],
+            stats: None,
         }
     }
 
@@ -18,9 +37,8 @@ impl Bus {
     }
 }
 
-
 pub trait BusWrite {
-    fn write(&mut self, addr: u16, data: u8) -> ();
+    fn write(&mut self, addr: u16, data: u8);
 }
 
 pub trait BusRead {
@@ -28,13 +46,19 @@ pub trait BusRead {
 }
 
 impl BusWrite for Bus {
-    fn write(&mut self, addr: u16, data: u8) -> () {
-            self.ram[addr as usize] = data;
+    fn write(&mut self, addr: u16, data: u8) {
+        if let Some(stats) = self.stats.as_mut() {
+            stats.record(Access::Write, addr);
+        }
+        self.ram[addr as usize] = data;
     }
 }
 
 impl BusRead for Bus {
-    fn read(&mut self, addr: u16, _read_only: bool) -> u8 {
+    fn read(&mut self, addr: u16, read_only: bool) -> u8 {
+        if let Some(stats) = self.stats.as_mut().filter(|_| !read_only) {
+            stats.record(Access::Read, addr);
+        }
         self.ram[addr as usize]
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,200 @@
+// Code/data logging.
+//
+// While a CodeDataLog is attached, every byte of the rom the cpu touches is
+// flagged with how it was used, in the FCEUX layout that Mesen reads too:
+//
+//   bit 0     executed as an opcode or operand
+//   bit 1     read as data
+//   bits 2-3  which 8K window of $8000-$FFFF the byte was last used through
+//   bit 4     jumped to through JMP (ind)
+//   bit 5     read as data through a (zp,X) or (zp),Y pointer
+//
+// The rom is prg bytes at base and up, mirrored every prg length, so a 16K
+// NROM image logged at $8000 covers $C000-$FFFF too. Files are the prg flags
+// followed by one byte per chr byte; the cpu never sees chr, so those are
+// only carried over from a loaded file. Bytes left at zero were never used
+// and are the ones safe to change.
+use crate::Cpu;
+use std::fmt;
+
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const INDIRECT_CODE: u8 = 0x10;
+pub const INDIRECT_DATA: u8 = 0x20;
+
+// Header Mesen 2 puts in front of the flags, followed by a crc32 of the rom
+const MESEN_MAGIC: &[u8; 5] = b"CDLv2";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CdlError {
+    // The file does not hold prg flags for a rom of this size
+    SizeMismatch { prg: usize, file: usize },
+}
+
+impl fmt::Display for CdlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CdlError::SizeMismatch { prg, file } => write!(
+                f,
+                "code/data log of {} bytes is too short for {} bytes of prg",
+                file, prg
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CdlError {}
+
+// How many prg bytes were used in each way. A byte both executed and read
+// counts as code and as data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CdlStats {
+    pub code: usize,
+    pub data: usize,
+    pub indirect_data: usize,
+    pub unused: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeDataLog {
+    // Cpu address of the first prg byte
+    pub base: u16,
+    pub prg: Vec<u8>,
+    pub chr: Vec<u8>,
+}
+
+impl CodeDataLog {
+    pub fn new(base: u16, prg_size: usize, chr_size: usize) -> Self {
+        CodeDataLog {
+            base,
+            prg: vec![0; prg_size],
+            chr: vec![0; chr_size],
+        }
+    }
+
+    // Prg offset of a cpu address, None below the rom
+    pub fn offset(&self, addr: u16) -> Option<usize> {
+        if addr < self.base || self.prg.is_empty() {
+            return None;
+        }
+        Some((addr - self.base) as usize % self.prg.len())
+    }
+
+    pub fn flags(&self, addr: u16) -> u8 {
+        self.offset(addr).map_or(0, |offset| self.prg[offset])
+    }
+
+    pub fn mark(&mut self, addr: u16, flags: u8) {
+        if let Some(offset) = self.offset(addr) {
+            let byte = &mut self.prg[offset];
+            if addr >= 0x8000 {
+                *byte = (*byte & !0x0C) | ((addr >> 11) as u8 & 0x0C);
+            }
+            *byte |= flags;
+        }
+    }
+
+    pub fn stats(&self) -> CdlStats {
+        let mut stats = CdlStats::default();
+        for byte in &self.prg {
+            stats.code += (byte & CODE != 0) as usize;
+            stats.data += (byte & DATA != 0) as usize;
+            stats.indirect_data += (byte & INDIRECT_DATA != 0) as usize;
+            stats.unused += (byte & (CODE | DATA) == 0) as usize;
+        }
+        stats
+    }
+
+    // Runs of prg offsets never executed or read, as inclusive ranges
+    pub fn unused(&self) -> Vec<(usize, usize)> {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        for (offset, byte) in self.prg.iter().enumerate() {
+            if byte & (CODE | DATA) != 0 {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == offset => *end = offset,
+                _ => ranges.push((offset, offset)),
+            }
+        }
+        ranges
+    }
+
+    // Flags from another log of the same rom, such as an earlier session
+    pub fn merge(&mut self, other: &CodeDataLog) {
+        for (a, b) in self.prg.iter_mut().zip(&other.prg) {
+            *a |= b;
+        }
+        for (a, b) in self.chr.iter_mut().zip(&other.chr) {
+            *a |= b;
+        }
+    }
+
+    // The FCEUX file: prg flags then chr flags
+    pub fn save(&self) -> Vec<u8> {
+        let mut data = self.prg.clone();
+        data.extend_from_slice(&self.chr);
+        data
+    }
+
+    // An FCEUX file, or a Mesen 2 one without its header checked. Whatever
+    // follows the prg flags is taken as chr flags.
+    pub fn load(data: &[u8], base: u16, prg_size: usize) -> Result<Self, CdlError> {
+        let data = if data.starts_with(MESEN_MAGIC) && data.len() >= 9 {
+            &data[9..]
+        } else {
+            data
+        };
+        if data.len() < prg_size {
+            return Err(CdlError::SizeMismatch {
+                prg: prg_size,
+                file: data.len(),
+            });
+        }
+        Ok(CodeDataLog {
+            base,
+            prg: data[..prg_size].to_vec(),
+            chr: data[prg_size..].to_vec(),
+        })
+    }
+}
+
+impl Cpu {
+    pub fn enable_cdl(&mut self, log: CodeDataLog) {
+        self.cdl = Some(log);
+    }
+
+    pub fn disable_cdl(&mut self) -> Option<CodeDataLog> {
+        self.cdl.take()
+    }
+
+    pub(crate) fn log_code(&mut self, addr: u16) {
+        if let Some(cdl) = self.cdl.as_mut() {
+            cdl.mark(addr, CODE);
+        }
+    }
+
+    pub(crate) fn log_data(&mut self, addr: u16) {
+        if let Some(cdl) = self.cdl.as_mut() {
+            // Immediate operands are fetched as data but belong to the code
+            let immediate = self.addr_mode_name == "IMM" && addr == self.addr_abs;
+            cdl.mark(addr, if immediate { CODE } else { DATA });
+        }
+    }
+
+    // Called once an instruction has run, for the ways of reaching a byte
+    // only the addressing mode tells apart
+    pub(crate) fn log_indirect(&mut self) {
+        let cdl = match self.cdl.as_mut() {
+            Some(cdl) => cdl,
+            None => return,
+        };
+        match self.addr_mode_name.as_str() {
+            "ABSIND" => cdl.mark(self.pc, INDIRECT_CODE),
+            "INDX" | "INDY" if !matches!(self.opcode, 0x81 | 0x91) => {
+                cdl.mark(self.addr_abs, INDIRECT_DATA)
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,358 @@
+// Instruction decoding apart from execution, for the debuggers and tools.
+//
+// A Disassembler turns a byte slice, or memory read through a function, into
+// DecodedInstruction values. Text comes from a Formatter: standard 6502
+// syntax, ca65 source that assembles back to the same bytes, or JSON.
+use crate::lookup_table::LookUpTable;
+use crate::symbols::SymbolTable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddrMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Relative,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    IndirectX,
+    IndirectY,
+    // Opcodes the cpu does not implement, decoded as a single byte
+    Unknown,
+}
+
+impl AddrMode {
+    // From the addr_name of a lookup table entry
+    pub fn from_name(name: &str) -> AddrMode {
+        match name {
+            "IMP" => AddrMode::Implied,
+            "ACC" => AddrMode::Accumulator,
+            "IMM" => AddrMode::Immediate,
+            "ZP" => AddrMode::ZeroPage,
+            "ZPX" => AddrMode::ZeroPageX,
+            "ZPY" => AddrMode::ZeroPageY,
+            "REL" => AddrMode::Relative,
+            "ABS" => AddrMode::Absolute,
+            "ABSX" => AddrMode::AbsoluteX,
+            "ABSY" => AddrMode::AbsoluteY,
+            "ABSIND" => AddrMode::Indirect,
+            "INDX" => AddrMode::IndirectX,
+            "INDY" => AddrMode::IndirectY,
+            _ => AddrMode::Unknown,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AddrMode::Implied => "IMP",
+            AddrMode::Accumulator => "ACC",
+            AddrMode::Immediate => "IMM",
+            AddrMode::ZeroPage => "ZP",
+            AddrMode::ZeroPageX => "ZPX",
+            AddrMode::ZeroPageY => "ZPY",
+            AddrMode::Relative => "REL",
+            AddrMode::Absolute => "ABS",
+            AddrMode::AbsoluteX => "ABSX",
+            AddrMode::AbsoluteY => "ABSY",
+            AddrMode::Indirect => "ABSIND",
+            AddrMode::IndirectX => "INDX",
+            AddrMode::IndirectY => "INDY",
+            AddrMode::Unknown => "XXX",
+        }
+    }
+
+    // Bytes following the opcode
+    pub fn operand_size(self) -> u16 {
+        match self {
+            AddrMode::Implied | AddrMode::Accumulator | AddrMode::Unknown => 0,
+            AddrMode::Absolute | AddrMode::AbsoluteX | AddrMode::AbsoluteY | AddrMode::Indirect => {
+                2
+            }
+            _ => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedInstruction {
+    pub addr: u16,
+    // Opcode followed by the operand bytes
+    pub bytes: Vec<u8>,
+    // "???" for unknown opcodes
+    pub mnemonic: &'static str,
+    pub mode: AddrMode,
+    // The operand byte or little endian word, None for implied modes
+    pub operand: Option<u16>,
+    // Where a branch, JMP or JSR goes when it is known without running
+    pub target: Option<u16>,
+}
+
+impl DecodedInstruction {
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    // Address of the following instruction
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.bytes.len() as u16)
+    }
+
+    pub fn hex_bytes(&self) -> String {
+        let hex: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        hex.join(" ")
+    }
+}
+
+pub struct Disassembler {
+    lookup: LookUpTable<'static>,
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Disassembler {
+    pub fn new() -> Self {
+        Disassembler {
+            lookup: LookUpTable::new(),
+        }
+    }
+
+    // The instruction at the start of bytes, which sit at addr. None when the
+    // slice ends inside the instruction.
+    pub fn decode(&self, bytes: &[u8], addr: u16) -> Option<DecodedInstruction> {
+        let opcode = *bytes.first()?;
+        let size = self.mode(opcode).operand_size() as usize;
+        if bytes.len() <= size {
+            return None;
+        }
+        Some(self.decode_at(|a| bytes[a.wrapping_sub(addr) as usize], addr))
+    }
+
+    // Every instruction in a slice loaded at origin, stopping at a truncated one
+    pub fn decode_slice(&self, bytes: &[u8], origin: u16) -> Vec<DecodedInstruction> {
+        let mut instructions = Vec::new();
+        let mut offset = 0;
+        while let Some(ins) = bytes
+            .get(offset..)
+            .and_then(|rest| self.decode(rest, origin.wrapping_add(offset as u16)))
+        {
+            offset += ins.bytes.len();
+            instructions.push(ins);
+        }
+        instructions
+    }
+
+    // The instruction at addr, reading memory through read. Operands wrap
+    // around the top of the address space like the cpu's fetches.
+    pub fn decode_at(&self, read: impl Fn(u16) -> u8, addr: u16) -> DecodedInstruction {
+        let opcode = read(addr);
+        let mode = self.mode(opcode);
+        let bytes: Vec<u8> = (0..=mode.operand_size())
+            .map(|i| read(addr.wrapping_add(i)))
+            .collect();
+        let operand = match bytes.len() {
+            2 => Some(bytes[1] as u16),
+            3 => Some(u16::from_le_bytes([bytes[1], bytes[2]])),
+            _ => None,
+        };
+        let mnemonic = self.lookup.table[opcode as usize].name;
+        let next = addr.wrapping_add(bytes.len() as u16);
+        let target = match (mode, operand) {
+            (AddrMode::Relative, Some(offset)) => {
+                Some(next.wrapping_add(offset as u8 as i8 as u16))
+            }
+            (AddrMode::Absolute, Some(addr)) if mnemonic == "JMP" || mnemonic == "JSR" => {
+                Some(addr)
+            }
+            _ => None,
+        };
+        DecodedInstruction {
+            addr,
+            bytes,
+            mnemonic,
+            mode,
+            operand,
+            target,
+        }
+    }
+
+    // Instructions starting from start up to, not including, stop
+    pub fn decode_range(
+        &self,
+        read: impl Fn(u16) -> u8,
+        start: u16,
+        stop: u16,
+    ) -> Vec<DecodedInstruction> {
+        let mut instructions = Vec::new();
+        let mut addr = start;
+        while addr < stop {
+            let ins = self.decode_at(&read, addr);
+            // Ran off the end of the address space
+            let wrapped = ins.next_addr() <= addr;
+            addr = ins.next_addr();
+            instructions.push(ins);
+            if wrapped {
+                break;
+            }
+        }
+        instructions
+    }
+
+    // count instructions from start
+    pub fn decode_count(
+        &self,
+        read: impl Fn(u16) -> u8,
+        start: u16,
+        count: usize,
+    ) -> Vec<DecodedInstruction> {
+        let mut instructions: Vec<DecodedInstruction> = Vec::with_capacity(count);
+        let mut addr = start;
+        for _ in 0..count {
+            let ins = self.decode_at(&read, addr);
+            addr = ins.next_addr();
+            instructions.push(ins);
+        }
+        instructions
+    }
+
+    fn mode(&self, opcode: u8) -> AddrMode {
+        AddrMode::from_name(self.lookup.table[opcode as usize].addr_name)
+    }
+}
+
+pub trait Formatter {
+    // The instruction alone, without its address or bytes. Addresses that
+    // have a label in symbols are shown by name.
+    fn format(&self, ins: &DecodedInstruction, symbols: Option<&SymbolTable>) -> String;
+}
+
+// Conventional 6502 syntax: `LDA ($10),Y`, `BNE $8000`, `ASL A`
+pub struct Standard;
+
+impl Formatter for Standard {
+    fn format(&self, ins: &DecodedInstruction, symbols: Option<&SymbolTable>) -> String {
+        let operand = ins.operand.unwrap_or(0);
+        let zp = || name(operand, symbols).unwrap_or_else(|| format!("${:02X}", operand));
+        let abs = || name(operand, symbols).unwrap_or_else(|| format!("${:04X}", operand));
+        let text = match ins.mode {
+            AddrMode::Implied => String::new(),
+            AddrMode::Accumulator => "A".to_string(),
+            AddrMode::Immediate => format!("#${:02X}", operand),
+            AddrMode::ZeroPage => zp(),
+            AddrMode::ZeroPageX => format!("{},X", zp()),
+            AddrMode::ZeroPageY => format!("{},Y", zp()),
+            AddrMode::Relative => {
+                let target = ins.target.unwrap_or(0);
+                name(target, symbols).unwrap_or_else(|| format!("${:04X}", target))
+            }
+            AddrMode::Absolute => abs(),
+            AddrMode::AbsoluteX => format!("{},X", abs()),
+            AddrMode::AbsoluteY => format!("{},Y", abs()),
+            AddrMode::Indirect => format!("({})", abs()),
+            AddrMode::IndirectX => format!("({},X)", zp()),
+            AddrMode::IndirectY => format!("({}),Y", zp()),
+            AddrMode::Unknown => return format!(".BYTE ${:02X}", ins.opcode()),
+        };
+        if text.is_empty() {
+            ins.mnemonic.to_string()
+        } else {
+            format!("{} {}", ins.mnemonic, text)
+        }
+    }
+}
+
+// ca65 source. Absolute operands below $100 get an `a:` prefix, as ca65
+// would otherwise pick the zero page form and change the bytes.
+pub struct Ca65;
+
+impl Formatter for Ca65 {
+    fn format(&self, ins: &DecodedInstruction, symbols: Option<&SymbolTable>) -> String {
+        let operand = ins.operand.unwrap_or(0);
+        let zp = || name(operand, symbols).unwrap_or_else(|| format!("${:02x}", operand));
+        let abs = || {
+            let text = name(operand, symbols).unwrap_or_else(|| format!("${:04x}", operand));
+            if operand < 0x100 {
+                format!("a:{}", text)
+            } else {
+                text
+            }
+        };
+        let text = match ins.mode {
+            AddrMode::Implied => String::new(),
+            AddrMode::Accumulator => "a".to_string(),
+            AddrMode::Immediate => format!("#${:02x}", operand),
+            AddrMode::ZeroPage => zp(),
+            AddrMode::ZeroPageX => format!("{},x", zp()),
+            AddrMode::ZeroPageY => format!("{},y", zp()),
+            AddrMode::Relative => {
+                let target = ins.target.unwrap_or(0);
+                name(target, symbols).unwrap_or_else(|| format!("${:04x}", target))
+            }
+            AddrMode::Absolute => abs(),
+            AddrMode::AbsoluteX => format!("{},x", abs()),
+            AddrMode::AbsoluteY => format!("{},y", abs()),
+            // JMP (ind) has no zero page form to be confused with
+            AddrMode::Indirect => format!(
+                "({})",
+                name(operand, symbols).unwrap_or_else(|| format!("${:04x}", operand))
+            ),
+            AddrMode::IndirectX => format!("({},x)", zp()),
+            AddrMode::IndirectY => format!("({}),y", zp()),
+            AddrMode::Unknown => return format!(".byte ${:02x}", ins.opcode()),
+        };
+        let mnemonic = ins.mnemonic.to_ascii_lowercase();
+        if text.is_empty() {
+            mnemonic
+        } else {
+            format!("{} {}", mnemonic, text)
+        }
+    }
+}
+
+// One JSON object per instruction, with the standard text included:
+// {"address":32768,"bytes":[169,5],"mnemonic":"LDA","mode":"IMM",
+//  "operand":5,"target":null,"text":"LDA #$05"}
+pub struct Json;
+
+impl Formatter for Json {
+    fn format(&self, ins: &DecodedInstruction, symbols: Option<&SymbolTable>) -> String {
+        let bytes: Vec<String> = ins.bytes.iter().map(u8::to_string).collect();
+        let number = |n: Option<u16>| n.map_or("null".to_string(), |n| n.to_string());
+        format!(
+            "{{\"address\":{},\"bytes\":[{}],\"mnemonic\":{},\"mode\":{},\"operand\":{},\"target\":{},\"text\":{}}}",
+            ins.addr,
+            bytes.join(","),
+            json_string(ins.mnemonic),
+            json_string(ins.mode.name()),
+            number(ins.operand),
+            number(ins.target),
+            json_string(&Standard.format(ins, symbols)),
+        )
+    }
+}
+
+fn name(addr: u16, symbols: Option<&SymbolTable>) -> Option<String> {
+    symbols.and_then(|s| s.label_at(addr)).map(str::to_string)
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
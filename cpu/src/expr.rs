@@ -0,0 +1,510 @@
+// Expression language for debugger conditions and watch windows.
+//
+//   registers    A X Y SP PC P, flags C Z I D B U V N (0 or 1)
+//   memory       [expr] byte, {expr} little endian word
+//   literals     $1F 0x1F hex, %1010 binary, 31 decimal
+//   symbols      any other name, resolved through a Symbols table
+//   grouping     ( )
+//   operators    C precedence: unary - ! ~, * / %, + -, << >>, < <= > >=,
+//                == !=, &, ^, |, &&, ||
+//
+// Comparisons and logical operators yield 1 or 0. Memory is read with
+// Cpu::peek, so evaluating an expression never has side effects.
+use crate::{Cpu, FLAGS};
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    // 1 based column of the offending token
+    pub column: usize,
+    pub message: String,
+}
+
+impl ExprError {
+    fn new(column: usize, message: impl Into<String>) -> Self {
+        ExprError {
+            column,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+// Resolves symbol names to addresses
+pub trait Symbols {
+    fn resolve(&self, name: &str) -> Option<u16>;
+}
+
+pub struct NoSymbols;
+
+impl Symbols for NoSymbols {
+    fn resolve(&self, _name: &str) -> Option<u16> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    Sp,
+    Pc,
+    P,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    C,
+    Z,
+    I,
+    D,
+    B,
+    U,
+    V,
+    N,
+}
+
+impl Flag {
+    pub fn mask(self) -> u8 {
+        match self {
+            Flag::C => FLAGS::c(),
+            Flag::Z => FLAGS::z(),
+            Flag::I => FLAGS::i(),
+            Flag::D => FLAGS::d(),
+            Flag::B => FLAGS::b(),
+            Flag::U => FLAGS::u(),
+            Flag::V => FLAGS::v(),
+            Flag::N => FLAGS::n(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    Rem,
+    Add,
+    Sub,
+    Shl,
+    Shr,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    BitAnd,
+    BitXor,
+    BitOr,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 10,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Shl | BinaryOp::Shr => 8,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 7,
+            BinaryOp::Eq | BinaryOp::Ne => 6,
+            BinaryOp::BitAnd => 5,
+            BinaryOp::BitXor => 4,
+            BinaryOp::BitOr => 3,
+            BinaryOp::And => 2,
+            BinaryOp::Or => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i64),
+    Register(Register),
+    Flag(Flag),
+    Symbol {
+        name: String,
+        column: usize,
+    },
+    Byte(Box<Expr>),
+    Word(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary {
+        op: BinaryOp,
+        column: usize,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, ExprError> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: text.chars().count() + 1,
+        };
+        let expr = parser.expr(0)?;
+        if let Some(t) = parser.peek() {
+            return Err(ExprError::new(t.column, format!("unexpected '{}'", t.text)));
+        }
+        Ok(expr)
+    }
+
+    // Replace symbol names with their addresses, so the expression can be
+    // evaluated later without the table
+    pub fn resolve(&self, symbols: &dyn Symbols) -> Result<Expr, ExprError> {
+        let boxed = |e: &Expr| e.resolve(symbols).map(Box::new);
+        Ok(match self {
+            Expr::Symbol { name, column } => match symbols.resolve(name) {
+                Some(addr) => Expr::Number(addr as i64),
+                None => {
+                    return Err(ExprError::new(
+                        *column,
+                        format!("unknown symbol '{}'", name),
+                    ))
+                }
+            },
+            Expr::Byte(e) => Expr::Byte(boxed(e)?),
+            Expr::Word(e) => Expr::Word(boxed(e)?),
+            Expr::Unary(op, e) => Expr::Unary(*op, boxed(e)?),
+            Expr::Binary {
+                op,
+                column,
+                left,
+                right,
+            } => Expr::Binary {
+                op: *op,
+                column: *column,
+                left: boxed(left)?,
+                right: boxed(right)?,
+            },
+            e => e.clone(),
+        })
+    }
+
+    pub fn eval(&self, cpu: &Cpu) -> Result<i64, ExprError> {
+        self.eval_with(cpu, &NoSymbols)
+    }
+
+    pub fn eval_with(&self, cpu: &Cpu, symbols: &dyn Symbols) -> Result<i64, ExprError> {
+        Ok(match self {
+            Expr::Number(n) => *n,
+            Expr::Register(r) => match r {
+                Register::A => cpu.acc as i64,
+                Register::X => cpu.x as i64,
+                Register::Y => cpu.y as i64,
+                Register::Sp => cpu.sp as i64,
+                Register::Pc => cpu.pc as i64,
+                Register::P => cpu.psr as i64,
+            },
+            Expr::Flag(f) => cpu.get_flag(f.mask()) as i64,
+            Expr::Symbol { name, column } => match symbols.resolve(name) {
+                Some(addr) => addr as i64,
+                None => {
+                    return Err(ExprError::new(
+                        *column,
+                        format!("unknown symbol '{}'", name),
+                    ))
+                }
+            },
+            Expr::Byte(addr) => cpu.peek(addr.eval_with(cpu, symbols)? as u16) as i64,
+            Expr::Word(addr) => {
+                let addr = addr.eval_with(cpu, symbols)? as u16;
+                let lo = cpu.peek(addr) as i64;
+                let hi = cpu.peek(addr.wrapping_add(1)) as i64;
+                (hi << 8) | lo
+            }
+            Expr::Unary(op, e) => {
+                let v = e.eval_with(cpu, symbols)?;
+                match op {
+                    UnaryOp::Neg => v.wrapping_neg(),
+                    UnaryOp::Not => (v == 0) as i64,
+                    UnaryOp::BitNot => !v,
+                }
+            }
+            Expr::Binary {
+                op,
+                column,
+                left,
+                right,
+            } => {
+                let l = left.eval_with(cpu, symbols)?;
+                // Short circuit so `[ptr] != 0 && [[ptr]] == 3` style guards work
+                match op {
+                    BinaryOp::And if l == 0 => return Ok(0),
+                    BinaryOp::Or if l != 0 => return Ok(1),
+                    _ => {}
+                }
+                let r = right.eval_with(cpu, symbols)?;
+                match op {
+                    BinaryOp::Mul => l.wrapping_mul(r),
+                    BinaryOp::Div | BinaryOp::Rem if r == 0 => {
+                        return Err(ExprError::new(*column, "division by zero"))
+                    }
+                    BinaryOp::Div => l.wrapping_div(r),
+                    BinaryOp::Rem => l.wrapping_rem(r),
+                    BinaryOp::Add => l.wrapping_add(r),
+                    BinaryOp::Sub => l.wrapping_sub(r),
+                    BinaryOp::Shl => l.wrapping_shl(r as u32),
+                    BinaryOp::Shr => l.wrapping_shr(r as u32),
+                    BinaryOp::Lt => (l < r) as i64,
+                    BinaryOp::Le => (l <= r) as i64,
+                    BinaryOp::Gt => (l > r) as i64,
+                    BinaryOp::Ge => (l >= r) as i64,
+                    BinaryOp::Eq => (l == r) as i64,
+                    BinaryOp::Ne => (l != r) as i64,
+                    BinaryOp::BitAnd => l & r,
+                    BinaryOp::BitXor => l ^ r,
+                    BinaryOp::BitOr => l | r,
+                    BinaryOp::And | BinaryOp::Or => (r != 0) as i64,
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TokenKind {
+    Number(i64),
+    Ident,
+    Punct,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    text: String,
+    column: usize,
+}
+
+const PUNCTUATION: [&str; 26] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*", "/", "%", "+", "-", "<", ">", "&", "^",
+    "|", "!", "~", "(", ")", "[", "]", "{", "}",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ExprError> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let word_end = |mut j: usize| {
+            while j < chars.len() && (chars[j].is_ascii_alphanumeric() || chars[j] == '_') {
+                j += 1;
+            }
+            j
+        };
+
+        // Numbers. A % followed by a binary digit is a literal, otherwise modulo.
+        let radix = match c {
+            '$' => Some((16, 1)),
+            '%' if chars.get(i + 1).is_some_and(|d| *d == '0' || *d == '1') => Some((2, 1)),
+            '0' if matches!(chars.get(i + 1), Some('x') | Some('X')) => Some((16, 2)),
+            d if d.is_ascii_digit() => Some((10, 0)),
+            _ => None,
+        };
+        if let Some((radix, prefix)) = radix {
+            let end = word_end(i + prefix);
+            let digits: String = chars[i + prefix..end].iter().collect();
+            let value = i64::from_str_radix(&digits, radix).map_err(|_| {
+                let literal: String = chars[i..end].iter().collect();
+                ExprError::new(column, format!("bad number '{}'", literal))
+            })?;
+            tokens.push(Token {
+                kind: TokenKind::Number(value),
+                text: chars[i..end].iter().collect(),
+                column,
+            });
+            i = end;
+            continue;
+        }
+
+        if c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '@' {
+            let end = word_end(i + 1);
+            tokens.push(Token {
+                kind: TokenKind::Ident,
+                text: chars[i..end].iter().collect(),
+                column,
+            });
+            i = end;
+            continue;
+        }
+
+        let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+        match PUNCTUATION.iter().find(|p| rest.starts_with(*p)) {
+            Some(p) => {
+                tokens.push(Token {
+                    kind: TokenKind::Punct,
+                    text: p.to_string(),
+                    column,
+                });
+                i += p.len();
+            }
+            None => return Err(ExprError::new(column, format!("unexpected '{}'", c))),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // Column just past the input, for "unexpected end" errors
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Result<Token, ExprError> {
+        let t = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| ExprError::new(self.end, "unexpected end of expression"))?;
+        self.pos += 1;
+        Ok(t)
+    }
+
+    fn binary_op(&self) -> Option<(BinaryOp, usize)> {
+        let t = self.peek()?;
+        if t.kind != TokenKind::Punct {
+            return None;
+        }
+        let op = match t.text.as_str() {
+            "*" => BinaryOp::Mul,
+            "/" => BinaryOp::Div,
+            "%" => BinaryOp::Rem,
+            "+" => BinaryOp::Add,
+            "-" => BinaryOp::Sub,
+            "<<" => BinaryOp::Shl,
+            ">>" => BinaryOp::Shr,
+            "<" => BinaryOp::Lt,
+            "<=" => BinaryOp::Le,
+            ">" => BinaryOp::Gt,
+            ">=" => BinaryOp::Ge,
+            "==" => BinaryOp::Eq,
+            "!=" => BinaryOp::Ne,
+            "&" => BinaryOp::BitAnd,
+            "^" => BinaryOp::BitXor,
+            "|" => BinaryOp::BitOr,
+            "&&" => BinaryOp::And,
+            "||" => BinaryOp::Or,
+            _ => return None,
+        };
+        Some((op, t.column))
+    }
+
+    // Precedence climbing, every binary operator is left associative
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr, ExprError> {
+        let mut left = self.unary()?;
+        while let Some((op, column)) = self.binary_op() {
+            if op.precedence() <= min_precedence {
+                break;
+            }
+            self.pos += 1;
+            let right = self.expr(op.precedence())?;
+            left = Expr::Binary {
+                op,
+                column,
+                left: Box::new(left),
+                right: Box::new(right),
+            };
+        }
+        Ok(left)
+    }
+
+    fn expect(&mut self, close: &str) -> Result<(), ExprError> {
+        let t = self.next()?;
+        if t.text != close {
+            return Err(ExprError::new(
+                t.column,
+                format!("expected '{}', found '{}'", close, t.text),
+            ));
+        }
+        Ok(())
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        let t = self.next()?;
+        match t.kind {
+            TokenKind::Number(n) => Ok(Expr::Number(n)),
+            TokenKind::Ident => Ok(ident(&t)),
+            TokenKind::Punct => match t.text.as_str() {
+                "-" => Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.unary()?))),
+                "!" => Ok(Expr::Unary(UnaryOp::Not, Box::new(self.unary()?))),
+                "~" => Ok(Expr::Unary(UnaryOp::BitNot, Box::new(self.unary()?))),
+                "(" => {
+                    let e = self.expr(0)?;
+                    self.expect(")")?;
+                    Ok(e)
+                }
+                "[" => {
+                    let addr = self.expr(0)?;
+                    self.expect("]")?;
+                    Ok(Expr::Byte(Box::new(addr)))
+                }
+                "{" => {
+                    let addr = self.expr(0)?;
+                    self.expect("}")?;
+                    Ok(Expr::Word(Box::new(addr)))
+                }
+                _ => Err(ExprError::new(
+                    t.column,
+                    format!("expected a value, found '{}'", t.text),
+                )),
+            },
+        }
+    }
+}
+
+fn ident(t: &Token) -> Expr {
+    match t.text.to_ascii_uppercase().as_str() {
+        "A" => Expr::Register(Register::A),
+        "X" => Expr::Register(Register::X),
+        "Y" => Expr::Register(Register::Y),
+        "SP" => Expr::Register(Register::Sp),
+        "PC" => Expr::Register(Register::Pc),
+        "P" => Expr::Register(Register::P),
+        "C" => Expr::Flag(Flag::C),
+        "Z" => Expr::Flag(Flag::Z),
+        "I" => Expr::Flag(Flag::I),
+        "D" => Expr::Flag(Flag::D),
+        "B" => Expr::Flag(Flag::B),
+        "U" => Expr::Flag(Flag::U),
+        "V" => Expr::Flag(Flag::V),
+        "N" => Expr::Flag(Flag::N),
+        _ => Expr::Symbol {
+            name: t.text.clone(),
+            column: t.column,
+        },
+    }
+}
@@ -0,0 +1,355 @@
+// Flow following disassembly, separating code from data.
+//
+// Decoding starts at the entry points given and the NMI, reset and IRQ
+// vectors when the image holds them, then follows branches, jumps and calls.
+// Bytes never reached are data. Two common ways of jumping through a table
+// are recognised within a block of straight line code:
+//
+//   lda lo,x / sta ptr / lda hi,x / sta ptr+1 / jmp (ptr)
+//   lda hi,x / pha / lda lo,x / pha / rts       (entries hold target - 1)
+//
+// Tables are read until an entry points outside the image or at data, or a
+// table would run into code.
+use crate::disasm::{AddrMode, Ca65, DecodedInstruction, Disassembler, Formatter};
+use crate::symbols::SymbolTable;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteKind {
+    Unknown,
+    // First byte of an instruction
+    Opcode,
+    Operand,
+    // Jump table entries
+    Data,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JumpTable {
+    // The JMP or RTS that goes through the table
+    pub jump: u16,
+    pub lo: u16,
+    pub hi: u16,
+    // 2 for a table of words, 1 for separate low and high byte tables
+    pub stride: u16,
+    // Added to each entry, 1 for return addresses used with RTS
+    pub adjust: u16,
+    pub targets: Vec<u16>,
+}
+
+const VECTORS: [(&str, u16); 3] = [("nmi", 0xFFFA), ("reset", 0xFFFC), ("irq", 0xFFFE)];
+
+#[derive(Debug, Clone)]
+pub struct CodeMap {
+    pub origin: u16,
+    pub image: Vec<u8>,
+    // One per byte of the image
+    pub kinds: Vec<ByteKind>,
+    pub instructions: BTreeMap<u16, DecodedInstruction>,
+    pub jump_tables: Vec<JumpTable>,
+    // The vectors by name and every other target as L followed by its address
+    pub labels: SymbolTable,
+}
+
+// How to write out a jump table byte: a whole entry of a word table, or the
+// low or high byte of a target less an adjustment
+#[derive(Clone, Copy)]
+enum TableByte {
+    Word(u16),
+    Lo(u16, u16),
+    Hi(u16, u16),
+}
+
+// What the block being decoded has done with values loaded from tables
+#[derive(Default)]
+struct Block {
+    loaded: Option<u16>,
+    stored: HashMap<u16, u16>,
+    pushed: Vec<Option<u16>>,
+}
+
+impl CodeMap {
+    // image is loaded at origin, anything past the top of memory is dropped
+    pub fn analyze(image: &[u8], origin: u16, entries: &[u16]) -> CodeMap {
+        let len = image.len().min(0x10000 - origin as usize);
+        let mut map = CodeMap {
+            origin,
+            image: image[..len].to_vec(),
+            kinds: vec![ByteKind::Unknown; len],
+            instructions: BTreeMap::new(),
+            jump_tables: Vec::new(),
+            labels: SymbolTable::new(),
+        };
+
+        let mut pending = Vec::new();
+        for (name, vector) in VECTORS.iter() {
+            if let (Some(lo), Some(hi)) = (map.byte(*vector), map.byte(vector + 1)) {
+                let addr = u16::from_le_bytes([lo, hi]);
+                map.labels.add_label(name, addr);
+                pending.push(addr);
+            }
+        }
+        pending.extend(entries.iter().rev());
+
+        let disassembler = Disassembler::new();
+        while let Some(addr) = pending.pop() {
+            map.label(addr);
+            map.follow(&disassembler, addr, &mut pending);
+        }
+        map
+    }
+
+    pub fn kind(&self, addr: u16) -> ByteKind {
+        self.offset(addr)
+            .map_or(ByteKind::Unknown, |offset| self.kinds[offset])
+    }
+
+    // ca65 source that assembles to the image. Labels from symbols are used
+    // before the generated ones; those not at an instruction or data byte of
+    // the image become equates.
+    pub fn to_ca65(&self, symbols: Option<&SymbolTable>) -> String {
+        let mut names = symbols.cloned().unwrap_or_default();
+        for (name, addr) in &self.labels.labels {
+            // Generated names are only wanted where nothing else names the address
+            let generated = name.starts_with('L') && names.label_at(*addr).is_some();
+            if names.labels.contains_key(name) || generated {
+                continue;
+            }
+            names.add_label(name, *addr);
+        }
+        let mut at: BTreeMap<u16, Vec<&str>> = BTreeMap::new();
+        let mut equates = Vec::new();
+        for (name, addr) in &names.labels {
+            match self.kind(*addr) {
+                ByteKind::Operand => equates.push((*addr, name.as_str())),
+                _ if self.offset(*addr).is_some() => at.entry(*addr).or_default().push(name),
+                _ => equates.push((*addr, name.as_str())),
+            }
+        }
+        equates.sort();
+        at.values_mut().for_each(|names| names.sort());
+
+        let mut table_bytes = HashMap::new();
+        for table in &self.jump_tables {
+            for (i, target) in table.targets.iter().enumerate() {
+                let i = i as u16 * table.stride;
+                let (lo, hi) = (table.lo.wrapping_add(i), table.hi.wrapping_add(i));
+                if table.stride == 2 && table.adjust == 0 {
+                    table_bytes.insert(lo, TableByte::Word(*target));
+                } else {
+                    table_bytes.insert(lo, TableByte::Lo(*target, table.adjust));
+                    table_bytes.insert(hi, TableByte::Hi(*target, table.adjust));
+                }
+            }
+        }
+
+        let end = self.origin as usize + self.image.len();
+        let mut out = format!(
+            "; ${:04X}-${:04X}\n.setcpu \"6502\"\n\n",
+            self.origin,
+            end.max(self.origin as usize + 1) - 1
+        );
+        for (addr, name) in &equates {
+            out.push_str(&format!("{} = ${:04X}\n", name, addr));
+        }
+        if !equates.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(&format!(".org ${:04X}\n", self.origin));
+
+        let mut addr = self.origin as usize;
+        while addr < end {
+            for name in at.get(&(addr as u16)).into_iter().flatten() {
+                out.push_str(&format!("{}:\n", name));
+            }
+            if let Some(ins) = self.instructions.get(&(addr as u16)) {
+                out.push_str(&format!("    {}\n", Ca65.format(ins, Some(&names))));
+                addr += ins.bytes.len();
+                continue;
+            }
+            if let Some(entry) = table_bytes.get(&(addr as u16)) {
+                let name = |target: u16, adjust: u16| {
+                    let name = match names.label_at(target) {
+                        Some(name) => name.to_string(),
+                        None => format!("${:04X}", target),
+                    };
+                    match adjust {
+                        0 => name,
+                        n => format!("({}-{})", name, n),
+                    }
+                };
+                let (line, size) = match *entry {
+                    TableByte::Word(target) => (format!(".word {}", name(target, 0)), 2),
+                    TableByte::Lo(target, adjust) => {
+                        (format!(".byte <{}", name(target, adjust)), 1)
+                    }
+                    TableByte::Hi(target, adjust) => {
+                        (format!(".byte >{}", name(target, adjust)), 1)
+                    }
+                };
+                out.push_str(&format!("    {}\n", line));
+                addr += size;
+                continue;
+            }
+            // Up to 8 plain data bytes, stopping at anything labelled
+            let mut bytes = Vec::new();
+            while addr < end && bytes.len() < 8 {
+                let a = addr as u16;
+                if (!bytes.is_empty() && at.contains_key(&a))
+                    || self.instructions.contains_key(&a)
+                    || table_bytes.contains_key(&a)
+                {
+                    break;
+                }
+                bytes.push(format!("${:02X}", self.image[addr - self.origin as usize]));
+                addr += 1;
+            }
+            out.push_str(&format!("    .byte {}\n", bytes.join(",")));
+        }
+        out
+    }
+
+    fn offset(&self, addr: u16) -> Option<usize> {
+        let offset = addr.wrapping_sub(self.origin) as usize;
+        if offset < self.image.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    fn byte(&self, addr: u16) -> Option<u8> {
+        self.offset(addr).map(|offset| self.image[offset])
+    }
+
+    fn label(&mut self, addr: u16) {
+        if self.offset(addr).is_some() && self.labels.label_at(addr).is_none() {
+            self.labels.add_label(&format!("L{:04X}", addr), addr);
+        }
+    }
+
+    // Decode straight line code from addr, queueing every target found
+    fn follow(&mut self, disassembler: &Disassembler, start: u16, pending: &mut Vec<u16>) {
+        let mut block = Block::default();
+        let mut addr = start;
+        loop {
+            let offset = match self.offset(addr) {
+                Some(offset) if self.kinds[offset] == ByteKind::Unknown => offset,
+                _ => return,
+            };
+            let ins = match disassembler.decode(&self.image[offset..], addr) {
+                Some(ins) if ins.mode != AddrMode::Unknown => ins,
+                _ => return,
+            };
+            let size = ins.bytes.len();
+            if self.kinds[offset..offset + size]
+                .iter()
+                .any(|k| *k != ByteKind::Unknown)
+            {
+                return;
+            }
+            self.kinds[offset] = ByteKind::Opcode;
+            for kind in &mut self.kinds[offset + 1..offset + size] {
+                *kind = ByteKind::Operand;
+            }
+
+            if let Some(target) = ins.target {
+                self.label(target);
+                pending.push(target);
+            }
+            let mnemonic = ins.mnemonic;
+            let table = self.track(&mut block, &ins);
+            let next = ins.next_addr();
+            self.instructions.insert(addr, ins);
+
+            if let Some((lo, hi, adjust)) = table {
+                self.jump_table(addr, lo, hi, adjust, pending);
+            }
+            if matches!(mnemonic, "JMP" | "RTS" | "RTI" | "BRK") || next < addr {
+                return;
+            }
+            addr = next;
+        }
+    }
+
+    // Follows table loads through the block, returning the low and high
+    // table addresses and the adjustment when ins jumps through them
+    fn track(&self, block: &mut Block, ins: &DecodedInstruction) -> Option<(u16, u16, u16)> {
+        let operand = ins.operand.unwrap_or(0);
+        match (ins.mnemonic, ins.mode) {
+            ("LDA", AddrMode::AbsoluteX) | ("LDA", AddrMode::AbsoluteY) => {
+                block.loaded = Some(operand)
+            }
+            ("LDA", _) => block.loaded = None,
+            ("STA", AddrMode::ZeroPage) | ("STA", AddrMode::Absolute) => {
+                match block.loaded {
+                    Some(table) => block.stored.insert(operand, table),
+                    None => block.stored.remove(&operand),
+                };
+            }
+            ("PHA", _) => block.pushed.push(block.loaded),
+            ("PLA", _) => {
+                block.pushed.pop();
+            }
+            ("JMP", AddrMode::Indirect) => {
+                let lo = block.stored.get(&operand)?;
+                let hi = block.stored.get(&operand.wrapping_add(1))?;
+                return Some((*lo, *hi, 0));
+            }
+            ("RTS", _) => {
+                let n = block.pushed.len();
+                if n >= 2 {
+                    return Some((block.pushed[n - 1]?, block.pushed[n - 2]?, 1));
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn jump_table(&mut self, jump: u16, lo: u16, hi: u16, adjust: u16, pending: &mut Vec<u16>) {
+        let stride = if hi == lo.wrapping_add(1) { 2 } else { 1 };
+        // Separate tables usually sit one after the other, which bounds them
+        let limit = if stride == 1 {
+            (hi as i32 - lo as i32).unsigned_abs().min(256) as u16
+        } else {
+            256
+        };
+
+        let mut targets = Vec::new();
+        for i in 0..limit {
+            let (l, h) = (lo.wrapping_add(i * stride), hi.wrapping_add(i * stride));
+            let (l, h) = match (self.offset(l), self.offset(h)) {
+                (Some(l), Some(h))
+                    if self.kinds[l] == ByteKind::Unknown && self.kinds[h] == ByteKind::Unknown =>
+                {
+                    (l, h)
+                }
+                _ => break,
+            };
+            let target = u16::from_le_bytes([self.image[l], self.image[h]]).wrapping_add(adjust);
+            match self.kind(target) {
+                ByteKind::Unknown | ByteKind::Opcode if self.offset(target).is_some() => {}
+                _ => break,
+            }
+            self.kinds[l] = ByteKind::Data;
+            self.kinds[h] = ByteKind::Data;
+            targets.push(target);
+        }
+        if targets.is_empty() {
+            return;
+        }
+        for target in &targets {
+            self.label(*target);
+            pending.push(*target);
+        }
+        self.jump_tables.push(JumpTable {
+            jump,
+            lo,
+            hi,
+            stride,
+            adjust,
+            targets,
+        });
+    }
+}
@@ -0,0 +1,146 @@
+// Bus access statistics.
+//
+// While AccessStats is attached to the Bus, every access through it is
+// counted per address: reads and writes by the cpu or any device using the
+// bus, and opcode and operand fetches as executes. Reads made for debuggers
+// and displays are not counted, and debuggers and loaders write straight into
+// Bus::ram, so their writes are not either. Writes also note the pc of the
+// instruction the cpu was running, which Cpu::clock keeps up to date.
+//
+// The heatmap is a 256x256 image with a pixel per address, a page per row,
+// writes in red, reads in green and executes in blue, each on a log scale up
+// to the busiest address of its kind.
+use crate::bus::{Access, Bus, RAM_SIZE};
+use crate::image;
+use crate::Cpu;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone)]
+pub struct AccessStats {
+    pub reads: Vec<u32>,
+    pub writes: Vec<u32>,
+    pub executes: Vec<u32>,
+    // Start of the instruction that last wrote each address
+    pub last_writer: Vec<Option<u16>>,
+    // Start of the instruction the cpu is running
+    pub pc: u16,
+}
+
+impl Default for AccessStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessStats {
+    pub fn new() -> Self {
+        AccessStats {
+            reads: vec![0; RAM_SIZE],
+            writes: vec![0; RAM_SIZE],
+            executes: vec![0; RAM_SIZE],
+            last_writer: vec![None; RAM_SIZE],
+            pc: 0,
+        }
+    }
+
+    pub fn record(&mut self, access: Access, addr: u16) {
+        let at = addr as usize;
+        let count = match access {
+            Access::Read => &mut self.reads[at],
+            Access::Write => {
+                self.last_writer[at] = Some(self.pc);
+                &mut self.writes[at]
+            }
+            Access::Execute => &mut self.executes[at],
+        };
+        *count = count.saturating_add(1);
+    }
+
+    pub fn counts(&self, access: Access) -> &[u32] {
+        match access {
+            Access::Read => &self.reads,
+            Access::Write => &self.writes,
+            Access::Execute => &self.executes,
+        }
+    }
+
+    pub fn total(&self, addr: u16) -> u64 {
+        let at = addr as usize;
+        self.reads[at] as u64 + self.writes[at] as u64 + self.executes[at] as u64
+    }
+
+    // The n most accessed addresses of a kind, busiest first
+    pub fn hottest(&self, access: Access, n: usize) -> Vec<(u16, u32)> {
+        let mut hot: Vec<(u16, u32)> = self
+            .counts(access)
+            .iter()
+            .enumerate()
+            .filter(|(_, count)| **count > 0)
+            .map(|(addr, count)| (addr as u16, *count))
+            .collect();
+        hot.sort_by_key(|(addr, count)| (std::cmp::Reverse(*count), *addr));
+        hot.truncate(n);
+        hot
+    }
+
+    // 256x256 RGB pixels
+    pub fn heatmap(&self) -> Vec<u8> {
+        let scale = |counts: &[u32]| {
+            let max = ((*counts.iter().max().unwrap_or(&0) as f64) + 1.0).ln();
+            move |count: u32| {
+                if count == 0 {
+                    0
+                } else {
+                    // Anything touched at all stays visible
+                    (64.0 + 191.0 * ((count as f64) + 1.0).ln() / max) as u8
+                }
+            }
+        };
+        let (red, green, blue) = (
+            scale(&self.writes),
+            scale(&self.reads),
+            scale(&self.executes),
+        );
+        let mut rgb = Vec::with_capacity(RAM_SIZE * 3);
+        for at in 0..RAM_SIZE {
+            rgb.push(red(self.writes[at]));
+            rgb.push(green(self.reads[at]));
+            rgb.push(blue(self.executes[at]));
+        }
+        rgb
+    }
+
+    // The heatmap with each address as a scale by scale block
+    pub fn write_png(&self, out: impl Write, scale: u32) -> io::Result<()> {
+        let side = 256 * scale.max(1);
+        let rgb = image::upscale(256, 256, &self.heatmap(), scale);
+        image::write_png(out, side, side, &rgb)
+    }
+}
+
+impl Bus {
+    pub fn enable_stats(&mut self) {
+        self.stats = Some(AccessStats::new());
+    }
+
+    pub fn disable_stats(&mut self) -> Option<AccessStats> {
+        self.stats.take()
+    }
+
+    // Opcode and operand fetches, counted as executes
+    pub fn fetch(&mut self, addr: u16) -> u8 {
+        if let Some(stats) = self.stats.as_mut() {
+            stats.record(Access::Execute, addr);
+        }
+        self.ram[addr as usize]
+    }
+}
+
+impl Cpu {
+    // Called on dispatch, so writes are put down to the instruction making them
+    pub(crate) fn stats_dispatch(&mut self) {
+        if let Some(stats) = self.bus.borrow_mut().stats.as_mut() {
+            stats.pc = self.pc;
+        }
+    }
+}
@@ -0,0 +1,111 @@
+// Reverse execution.
+//
+// While recording, every instruction (or interrupt sequence) dispatched by
+// Cpu::clock opens a step holding the cpu state from before it ran, and every
+// bus write it performs appends the byte it overwrote. Undoing a step puts the
+// bytes back in reverse order and restores the registers. Writes made by
+// devices directly on the bus are not recorded, use Rewind to cover those.
+use crate::bus::BusRead;
+use crate::Cpu;
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone)]
+pub struct Step {
+    // Cpu::save_registers from before the instruction
+    pub registers: Vec<u8>,
+    // (address, previous value) in the order the writes happened
+    pub writes: Vec<(u16, u8)>,
+    // Master cycles, cpu cycles, frame and cpu stall before the instruction,
+    // filled in when a System runs the cpu
+    pub clock: Option<(u64, u64, u64, u32)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct History {
+    // Oldest steps are forgotten past this many
+    pub capacity: usize,
+    pub steps: VecDeque<Step>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        History {
+            capacity,
+            steps: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+}
+
+impl Cpu {
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(History::new(capacity));
+    }
+
+    pub fn disable_history(&mut self) {
+        self.history = None;
+    }
+
+    // Called on dispatch, before the instruction touches anything
+    pub(crate) fn begin_step(&mut self) {
+        if self.history.is_none() {
+            return;
+        }
+        let registers = self.save_registers();
+        if let Some(history) = self.history.as_mut() {
+            if history.steps.len() >= history.capacity {
+                history.steps.pop_front();
+            }
+            history.steps.push_back(Step {
+                registers,
+                writes: Vec::new(),
+                clock: None,
+            });
+        }
+    }
+
+    pub(crate) fn record_write(&mut self, addr: u16) {
+        if self.history.is_none() {
+            return;
+        }
+        let old = self.bus.borrow_mut().read(addr, true);
+        if let Some(step) = self.history.as_mut().and_then(|h| h.steps.back_mut()) {
+            step.writes.push((addr, old));
+        }
+    }
+
+    // Undo the newest recorded instruction, including one still in flight.
+    // Returns false when there is nothing left to undo.
+    pub fn step_back(&mut self) -> bool {
+        let step = match self.history.as_mut().and_then(|h| h.steps.pop_back()) {
+            Some(step) => step,
+            None => return false,
+        };
+
+        {
+            let mut bus = self.bus.borrow_mut();
+            for &(addr, old) in step.writes.iter().rev() {
+                bus.ram[addr as usize] = old;
+            }
+        }
+        // Written by save_registers, so it always parses
+        self.load_registers(&step.registers).unwrap();
+        true
+    }
+
+    // Undo up to n instructions, returns how many were undone
+    pub fn step_back_n(&mut self, n: usize) -> usize {
+        let mut undone = 0;
+        while undone < n && self.step_back() {
+            undone += 1;
+        }
+        undone
+    }
+}
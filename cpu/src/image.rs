@@ -0,0 +1,30 @@
+// Image output for headless front ends.
+use std::io::{self, Write};
+
+// 8 bit RGB pixels, row by row, as a PNG
+pub fn write_png(out: impl Write, width: u32, height: u32, rgb: &[u8]) -> io::Result<()> {
+    let mut encoder = png::Encoder::new(out, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgb)?;
+    Ok(())
+}
+
+// Each pixel as a scale by scale block
+pub fn upscale(width: u32, height: u32, rgb: &[u8], scale: u32) -> Vec<u8> {
+    let (width, height, scale) = (width as usize, height as usize, scale.max(1) as usize);
+    let mut out = Vec::with_capacity(rgb.len() * scale * scale);
+    for y in 0..height {
+        let mut row = Vec::with_capacity(width * scale * 3);
+        for pixel in rgb[y * width * 3..(y + 1) * width * 3].chunks(3) {
+            for _ in 0..scale {
+                row.extend_from_slice(pixel);
+            }
+        }
+        for _ in 0..scale {
+            out.extend_from_slice(&row);
+        }
+    }
+    out
+}
@@ -0,0 +1,97 @@
+// Interrupt inputs of the 6502.
+//
+// IRQ is level sensitive: every source drives its own open collector output and the
+// cpu sees the line asserted while any of them is. NMI is edge sensitive: an
+// inactive -> active transition sets a latch which stays set until the cpu starts
+// servicing it, no matter how long the line is held.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqSource {
+    Ppu,
+    Apu,
+    Dmc,
+    Dma,
+    Mapper,
+    External,
+}
+
+impl IrqSource {
+    pub fn mask(self) -> u8 {
+        match self {
+            IrqSource::Ppu => 1 << 0,
+            IrqSource::Apu => 1 << 1,
+            IrqSource::Dmc => 1 << 2,
+            IrqSource::Dma => 1 << 3,
+            IrqSource::Mapper => 1 << 4,
+            IrqSource::External => 1 << 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+}
+
+impl Interrupt {
+    pub fn vector(self) -> u16 {
+        match self {
+            Interrupt::Nmi => 0xFFFA,
+            Interrupt::Irq => 0xFFFE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InterruptController {
+    // One bit per asserted IRQ source
+    pub irq_sources: u8,
+    // Current level of the NMI input
+    pub nmi_line: bool,
+    // Set on an NMI edge, cleared when the cpu begins the NMI sequence
+    pub nmi_pending: bool,
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        InterruptController::default()
+    }
+
+    pub fn set_irq(&mut self, source: IrqSource, asserted: bool) {
+        if asserted {
+            self.irq_sources |= source.mask();
+        } else {
+            self.irq_sources &= !source.mask();
+        }
+    }
+
+    pub fn irq_asserted(&self, source: IrqSource) -> bool {
+        self.irq_sources & source.mask() != 0
+    }
+
+    // The wired-OR of every source
+    pub fn irq_line(&self) -> bool {
+        self.irq_sources != 0
+    }
+
+    pub fn set_nmi(&mut self, asserted: bool) {
+        if asserted && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = asserted;
+    }
+
+    // Assert then release the NMI line, as a vblank or a push button would
+    pub fn pulse_nmi(&mut self) {
+        self.set_nmi(true);
+        self.set_nmi(false);
+    }
+
+    // Consume the NMI latch, returns whether it was set
+    pub fn acknowledge_nmi(&mut self) -> bool {
+        let pending = self.nmi_pending;
+        self.nmi_pending = false;
+        pending
+    }
+}
@@ -1,11 +1,37 @@
-#[allow(non_snake_case)]
+#![allow(non_snake_case)]
+pub mod asm;
+pub mod breakpoint;
 pub mod bus;
+pub mod cdl;
+pub mod disasm;
+pub mod expr;
+pub mod flow;
+pub mod heatmap;
+pub mod history;
+pub mod image;
+pub mod interrupt;
+pub mod loader;
 pub mod lookup_table;
-use bus::{Bus, BusRead, BusWrite};
+pub mod profile;
+pub mod region;
+pub mod rewind;
+pub mod savestate;
+pub mod stack;
+pub mod symbols;
+pub mod system;
+pub mod testing;
+pub mod trace;
+use breakpoint::Breakpoints;
+use bus::{Access, Bus, BusRead, BusWrite};
+use cdl::CodeDataLog;
+use disasm::{DecodedInstruction, Disassembler};
+use history::History;
+use interrupt::{Interrupt, InterruptController};
 use lookup_table::LookUpTable;
+use profile::Profiler;
+use stack::StackWatch;
 use std::{cell::RefCell, rc::Rc};
-
-use crate::bus::RAM_SIZE;
+use trace::Trace;
 
 pub struct Cpu {
     pub bus: Rc<RefCell<Bus>>,
@@ -21,6 +47,25 @@ pub struct Cpu {
     pub addr_mode_name: String,
     pub addr_abs: u16,
     pub cycles: u8,
+    pub interrupts: InterruptController,
+    // Interrupt recognised by the last poll, serviced instead of the next opcode fetch
+    pub pending_interrupt: Option<Interrupt>,
+    // Vector of a BRK/IRQ/NMI sequence in flight, read once NMI hijacking can no longer happen
+    pub pending_vector: Option<u16>,
+    // I flag as seen by the interrupt poll of the current instruction
+    pub irq_inhibit: bool,
+    // Remaining cycle count at which the current instruction polls for interrupts
+    pub poll_at: u8,
+    pub stack_watch: StackWatch,
+    // Undo log for reverse stepping, None while not recording
+    pub history: Option<History>,
+    // Execution trace, None while not tracing
+    pub trace: Option<Trace>,
+    // Code/data log of the rom, None while not logging
+    pub cdl: Option<CodeDataLog>,
+    // Cycle profile by routine, None while not profiling
+    pub profiler: Option<Profiler>,
+    pub breakpoints: Breakpoints,
 }
 pub enum FLAGS {
     C(u8), //Carry bit 1 = true
@@ -74,7 +119,7 @@ impl FLAGS {
 
 impl Cpu {
     pub fn new(bus: Rc<RefCell<Bus>>) -> Self {
-        let cpu = Cpu {
+        Cpu {
             bus: Rc::clone(&bus),
             x: 0x00,
             y: 0x00,
@@ -88,16 +133,43 @@ impl Cpu {
             addr_mode_name: "".to_string(),
             cycles: 0,
             opcode: 0x00,
-        };
-
-        cpu
+            interrupts: InterruptController::new(),
+            pending_interrupt: None,
+            pending_vector: None,
+            irq_inhibit: true,
+            poll_at: 1,
+            stack_watch: StackWatch::default(),
+            history: None,
+            trace: None,
+            cdl: None,
+            profiler: None,
+            breakpoints: Breakpoints::new(),
+        }
     }
 
     pub fn read(&mut self, addr: u16) -> u8 {
-        self.bus.borrow_mut().read(addr, false)
+        let data = self.bus.borrow_mut().read(addr, false);
+        self.check_watchpoints(Access::Read, addr, data);
+        self.trace_access(addr, data, false);
+        self.log_data(addr);
+        data
+    }
+
+    // Opcode and operand fetches, which data watchpoints ignore
+    fn read_code(&mut self, addr: u16) -> u8 {
+        self.log_code(addr);
+        self.bus.borrow_mut().fetch(addr)
+    }
+
+    // Side effect free read for debuggers and displays
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.bus.borrow_mut().read(addr, true)
     }
 
-    fn write(&mut self, addr: u16, data: u8) -> () {
+    fn write(&mut self, addr: u16, data: u8) {
+        self.check_watchpoints(Access::Write, addr, data);
+        self.record_write(addr);
+        self.trace_access(addr, data, true);
         self.bus.borrow_mut().write(addr, data);
     }
 
@@ -111,63 +183,81 @@ impl Cpu {
     fn set_flag(&mut self, f: u8, val: bool) {
         if val {
             self.psr |= f;
-        }
-        else {
+        } else {
             self.psr &= !f;
         }
-        
+    }
+
+    // Shared by the conditional branches. A taken branch costs a cycle, crossing
+    // a page costs another. A taken branch that stays on its page polls for
+    // interrupts one cycle early, delaying them by an instruction.
+    fn branch(&mut self, taken: bool) {
+        if !taken {
+            return;
+        }
+        self.cycles += 1;
+
+        self.addr_abs = self.pc.wrapping_add(self.addr_rel);
+
+        if (self.addr_abs & 0xFF00) != (self.pc & 0xFF00) {
+            self.cycles += 1;
+        } else {
+            self.poll_at = 2;
+        }
+        self.pc = self.addr_abs
     }
 
     // Addressing mode helpers
     //Accumulator
     pub fn ACC(cpu: &mut Cpu) -> u8 {
+        cpu.fetched = cpu.acc;
         0x00
     }
     //Immediate
     pub fn IMM(cpu: &mut Cpu) -> u8 {
-        cpu.addr_abs = cpu.pc + 1;
-        cpu.pc += 1;
+        cpu.addr_abs = cpu.pc;
+        cpu.pc = cpu.pc.wrapping_add(1);
         0x00
     }
     //Absolute
     pub fn ABS(cpu: &mut Cpu) -> u8 {
-        let lo = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-        let hi = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        let lo = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
+        let hi = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
         cpu.addr_abs = (hi << 8) | lo;
         0x00
     }
     //Zero page
     pub fn ZP(cpu: &mut Cpu) -> u8 {
-        cpu.addr_abs = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        cpu.addr_abs = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
         cpu.addr_abs &= 0x00FF;
         0x00
     }
     //Indirect zero page X
     pub fn ZPX(cpu: &mut Cpu) -> u8 {
-        cpu.addr_abs = (cpu.read(cpu.pc) + cpu.x) as u16;
-        cpu.pc += 1;
+        cpu.addr_abs = cpu.read_code(cpu.pc).wrapping_add(cpu.x) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
         cpu.addr_abs &= 0x00FF;
         0x00
     }
 
     //Indirect zero page Y
     pub fn ZPY(cpu: &mut Cpu) -> u8 {
-        cpu.addr_abs = (cpu.read(cpu.pc) + cpu.y) as u16;
-        cpu.pc += 1;
+        cpu.addr_abs = cpu.read_code(cpu.pc).wrapping_add(cpu.y) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
         cpu.addr_abs &= 0x00FF;
         0x00
     }
     //Indirect Absolute X
     pub fn ABSX(cpu: &mut Cpu) -> u8 {
-        let lo = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-        let hi = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        let lo = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
+        let hi = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
         cpu.addr_abs = (hi << 8) | lo;
-        cpu.addr_abs += cpu.x as u16;
+        cpu.addr_abs = cpu.addr_abs.wrapping_add(cpu.x as u16);
 
         if (cpu.addr_abs & 0xFF00) != (hi << 8) {
             return 1;
@@ -177,12 +267,12 @@ impl Cpu {
 
     //Indirect Absolute Y
     pub fn ABSY(cpu: &mut Cpu) -> u8 {
-        let lo = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-        let hi = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        let lo = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
+        let hi = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
         cpu.addr_abs = (hi << 8) | lo;
-        cpu.addr_abs += cpu.y as u16;
+        cpu.addr_abs = cpu.addr_abs.wrapping_add(cpu.y as u16);
 
         if (cpu.addr_abs & 0xFF00) != (hi << 8) {
             return 1;
@@ -196,17 +286,17 @@ impl Cpu {
     }
     //Relative
     pub fn REL(cpu: &mut Cpu) -> u8 {
-        cpu.addr_rel = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-        if cpu.addr_rel & 0x80 == 1 {
+        cpu.addr_rel = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
+        if cpu.addr_rel & 0x80 != 0 {
             cpu.addr_rel |= 0xFF00;
         }
         0x00
     }
     //Indirect indexed x
     pub fn INDX(cpu: &mut Cpu) -> u8 {
-        let t = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        let t = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
 
         let lo = cpu.read((t + cpu.x as u16) & 0x00FF) as u16;
         let hi = cpu.read((t + cpu.x as u16 + 1) & 0x00FF) as u16;
@@ -216,14 +306,14 @@ impl Cpu {
     }
     //Indirect indexed y
     pub fn INDY(cpu: &mut Cpu) -> u8 {
-        let t = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        let t = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
 
         let lo = cpu.read(t & 0x00FF) as u16;
         let hi = cpu.read((t + 1) & 0x00FF) as u16;
 
         cpu.addr_abs = (hi << 8) | lo;
-        cpu.addr_abs += cpu.y as u16;
+        cpu.addr_abs = cpu.addr_abs.wrapping_add(cpu.y as u16);
 
         if (cpu.addr_abs & 0xFF00) != (hi << 8) {
             return 1;
@@ -232,17 +322,16 @@ impl Cpu {
     }
     //Absolute indirect
     pub fn ABSIND(cpu: &mut Cpu) -> u8 {
-        let lo = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
-        let hi = cpu.read(cpu.pc) as u16;
-        cpu.pc += 1;
+        let lo = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
+        let hi = cpu.read_code(cpu.pc) as u16;
+        cpu.pc = cpu.pc.wrapping_add(1);
 
         let ptr = (hi << 8) | lo;
         if lo == 0x00FF {
-            cpu.addr_abs =
-                (((cpu.read(ptr & 0xFF00) as u16) << 8) | (cpu.read(ptr + 0) as u16)) as u16;
+            cpu.addr_abs = ((cpu.read(ptr & 0xFF00) as u16) << 8) | (cpu.read(ptr) as u16);
         } else {
-            cpu.addr_abs = (((cpu.read(ptr + 1) as u16) << 8) | (cpu.read(ptr + 1) as u16)) as u16;
+            cpu.addr_abs = ((cpu.read(ptr + 1) as u16) << 8) | (cpu.read(ptr) as u16);
         }
         0x00
     }
@@ -252,15 +341,15 @@ impl Cpu {
     pub fn ADC(cpu: &mut Cpu) -> u8 {
         cpu.fetch();
 
-        let temp = cpu.acc as u16 + cpu.fetched as u16;
+        let temp = cpu.acc as u16 + cpu.fetched as u16 + cpu.get_flag(FLAGS::c()) as u16;
         cpu.set_flag(FLAGS::c(), temp > 255);
 
         let n = ((temp & 0x00FF) as u8) & 0x80;
-        cpu.set_flag(FLAGS::n(), n == 1);
+        cpu.set_flag(FLAGS::n(), n != 0);
         cpu.set_flag(FLAGS::z(), (temp & 0x00FF) == 0);
         cpu.set_flag(
             FLAGS::v(),
-            ((cpu.acc & 0x80) ^ n) & !((cpu.acc & 0x80) ^ (cpu.fetched & 0x80)) == 1,
+            ((cpu.acc & 0x80) ^ n) & !((cpu.acc & 0x80) ^ (cpu.fetched & 0x80)) != 0,
         );
         cpu.acc = (temp & 0x00FF) as u8;
         0x01
@@ -268,8 +357,8 @@ impl Cpu {
 
     pub fn AND(cpu: &mut Cpu) -> u8 {
         cpu.fetch();
-        cpu.acc = cpu.acc & cpu.fetched;
-        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) == 1);
+        cpu.acc &= cpu.fetched;
+        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) != 0);
         cpu.set_flag(FLAGS::z(), cpu.acc == 0);
         0x01
     }
@@ -278,9 +367,9 @@ impl Cpu {
         cpu.fetch();
         let temp = (cpu.fetched as u16) << 1;
         cpu.set_flag(FLAGS::c(), (temp & 0xFF00) > 0);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.set_flag(FLAGS::n(), (temp & 0x0080) != 0);
         cpu.set_flag(FLAGS::z(), (temp & 0x00FF) == 0x00);
-        if cpu.addr_mode_name == "IMP" {
+        if cpu.addr_mode_name == "ACC" {
             cpu.acc = (temp & 0x00FF) as u8;
         } else {
             cpu.write(cpu.addr_abs, (temp & 0x00FF) as u8);
@@ -289,44 +378,20 @@ impl Cpu {
     }
 
     pub fn BCC(cpu: &mut Cpu) -> u8 {
-        if cpu.get_flag(FLAGS::c()) == 0 {
-            cpu.cycles += 1;
-
-            cpu.addr_abs = cpu.pc + cpu.addr_rel;
-
-            if (cpu.addr_abs & 0xFF00) != (cpu.pc & 0xFF00) {
-                cpu.cycles += 1;
-            }
-            cpu.pc = cpu.addr_abs
-        }
+        let taken = cpu.get_flag(FLAGS::c()) == 0;
+        cpu.branch(taken);
         0x00
     }
 
     pub fn BCS(cpu: &mut Cpu) -> u8 {
-        if cpu.get_flag(FLAGS::c()) == 1 {
-            cpu.cycles += 1;
-
-            cpu.addr_abs = cpu.pc + cpu.addr_rel;
-
-            if (cpu.addr_abs & 0xFF00) != (cpu.pc & 0xFF00) {
-                cpu.cycles += 1;
-            }
-            cpu.pc = cpu.addr_abs
-        }
+        let taken = cpu.get_flag(FLAGS::c()) == 1;
+        cpu.branch(taken);
         0x00
     }
 
     pub fn BEQ(cpu: &mut Cpu) -> u8 {
-        if cpu.get_flag(FLAGS::z()) == 0 {
-            cpu.cycles += 1;
-
-            cpu.addr_abs = cpu.pc + cpu.addr_rel;
-
-            if (cpu.addr_abs & 0xFF00) != (cpu.pc & 0xFF00) {
-                cpu.cycles += 1;
-            }
-            cpu.pc = cpu.addr_abs
-        }
+        let taken = cpu.get_flag(FLAGS::z()) == 1;
+        cpu.branch(taken);
         0x00
     }
 
@@ -334,98 +399,51 @@ impl Cpu {
         cpu.fetch();
         let temp = cpu.acc & cpu.fetched;
         cpu.set_flag(FLAGS::z(), (temp) == 0);
-        cpu.set_flag(FLAGS::n(), cpu.fetched & FLAGS::n() == 1);
-        cpu.set_flag(FLAGS::v(), cpu.fetched & FLAGS::v() == 1);
+        cpu.set_flag(FLAGS::n(), cpu.fetched & FLAGS::n() != 0);
+        cpu.set_flag(FLAGS::v(), cpu.fetched & FLAGS::v() != 0);
         0x00
     }
 
     pub fn BMI(cpu: &mut Cpu) -> u8 {
-        if cpu.get_flag(FLAGS::n()) == 1 {
-            cpu.cycles += 1;
-
-            cpu.addr_abs = cpu.pc + cpu.addr_rel;
-
-            if (cpu.addr_abs & 0xFF00) != (cpu.pc & 0xFF00) {
-                cpu.cycles += 1;
-            }
-            cpu.pc = cpu.addr_abs
-        }
+        let taken = cpu.get_flag(FLAGS::n()) == 1;
+        cpu.branch(taken);
         0x00
     }
 
     pub fn BNE(cpu: &mut Cpu) -> u8 {
-        if cpu.get_flag(FLAGS::z()) == 0 {
-            cpu.cycles += 1;
-
-            cpu.addr_abs = cpu.pc + cpu.addr_rel;
-
-            if (cpu.addr_abs & 0xFF00) != (cpu.pc & 0xFF00) {
-                cpu.cycles += 1;
-            }
-            cpu.pc = cpu.addr_abs
-        }
+        let taken = cpu.get_flag(FLAGS::z()) == 0;
+        cpu.branch(taken);
         0x00
     }
 
     pub fn BPL(cpu: &mut Cpu) -> u8 {
-        if cpu.get_flag(FLAGS::n()) == 0 {
-            cpu.cycles += 1;
-
-            cpu.addr_abs = cpu.pc + cpu.addr_rel;
-
-            if (cpu.addr_abs & 0xFF00) != (cpu.pc & 0xFF00) {
-                cpu.cycles += 1;
-            }
-            cpu.pc = cpu.addr_abs
-        }
+        let taken = cpu.get_flag(FLAGS::n()) == 0;
+        cpu.branch(taken);
         0x00
     }
 
     pub fn BRK(cpu: &mut Cpu) -> u8 {
-        cpu.pc += 1;
-
-        cpu.set_flag(FLAGS::i(), true);
-        cpu.write(0x0100 + cpu.sp as u16, ((cpu.pc & 0xFF00) >> 8) as u8);
-        cpu.sp -= 1;
-        cpu.write(0x0100 + cpu.sp as u16, (cpu.pc & 0x00FF) as u8);
-        cpu.sp -= 1;
+        cpu.pc = cpu.pc.wrapping_add(1);
 
-        cpu.set_flag(FLAGS::b(), true);
-        cpu.write(0x0100 + cpu.sp as u16, cpu.psr);
-        cpu.sp -= 1;
+        cpu.push_word(cpu.pc);
+        cpu.push(cpu.status_for_push(true));
 
-        cpu.set_flag(FLAGS::b(), false);
+        cpu.set_flag(FLAGS::i(), true);
 
-        cpu.pc = (cpu.read(0xFFFE) as u16) | ((cpu.read(0xFFFF) as u16) << 8);
+        cpu.pending_vector = Some(Interrupt::Irq.vector());
 
         0x00
     }
 
     pub fn BVC(cpu: &mut Cpu) -> u8 {
-        if cpu.get_flag(FLAGS::v()) == 0 {
-            cpu.cycles += 1;
-
-            cpu.addr_abs = cpu.pc + cpu.addr_rel;
-
-            if (cpu.addr_abs & 0xFF00) != (cpu.pc & 0xFF00) {
-                cpu.cycles += 1;
-            }
-            cpu.pc = cpu.addr_abs
-        }
+        let taken = cpu.get_flag(FLAGS::v()) == 0;
+        cpu.branch(taken);
         0x00
     }
 
     pub fn BVS(cpu: &mut Cpu) -> u8 {
-        if cpu.get_flag(FLAGS::v()) == 1 {
-            cpu.cycles += 1;
-
-            cpu.addr_abs = cpu.pc + cpu.addr_rel;
-
-            if (cpu.addr_abs & 0xFF00) != (cpu.pc & 0xFF00) {
-                cpu.cycles += 1;
-            }
-            cpu.pc = cpu.addr_abs
-        }
+        let taken = cpu.get_flag(FLAGS::v()) == 1;
+        cpu.branch(taken);
         0x00
     }
 
@@ -450,53 +468,51 @@ impl Cpu {
 
     pub fn CMP(cpu: &mut Cpu) -> u8 {
         cpu.fetch();
-        let temp = cpu.acc as u16 - cpu.fetched as u16;
+        let temp = cpu.acc.wrapping_sub(cpu.fetched);
         cpu.set_flag(FLAGS::z(), temp == 0);
         cpu.set_flag(FLAGS::c(), cpu.acc >= cpu.fetched);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
-        0x00
+        cpu.set_flag(FLAGS::n(), (temp & 0x80) != 0);
+        0x01
     }
 
     pub fn CPX(cpu: &mut Cpu) -> u8 {
         cpu.fetch();
-        let temp = cpu.x as u16 - cpu.fetched as u16;
+        let temp = cpu.x.wrapping_sub(cpu.fetched);
         cpu.set_flag(FLAGS::z(), temp == 0);
         cpu.set_flag(FLAGS::c(), cpu.x >= cpu.fetched);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.set_flag(FLAGS::n(), (temp & 0x80) != 0);
         0x00
     }
 
     pub fn CPY(cpu: &mut Cpu) -> u8 {
         cpu.fetch();
-        let temp = cpu.y as u16 - cpu.fetched as u16;
+        let temp = cpu.y.wrapping_sub(cpu.fetched);
         cpu.set_flag(FLAGS::z(), temp == 0);
         cpu.set_flag(FLAGS::c(), cpu.y >= cpu.fetched);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.set_flag(FLAGS::n(), (temp & 0x80) != 0);
         0x00
     }
 
     pub fn DEC(cpu: &mut Cpu) -> u8 {
         cpu.fetch();
-        let temp = cpu.fetched as u16 - 1;
-        cpu.write(cpu.addr_abs, (temp & 0x00FF) as u8);
+        let temp = cpu.fetched.wrapping_sub(1);
+        cpu.write(cpu.addr_abs, temp);
         cpu.set_flag(FLAGS::z(), temp == 0);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.set_flag(FLAGS::n(), (temp & 0x80) != 0);
         0x00
     }
 
     pub fn DEX(cpu: &mut Cpu) -> u8 {
-        let temp = cpu.x as u16 - 1;
-        cpu.x = (temp & 0x00FF) as u8;
-        cpu.set_flag(FLAGS::z(), temp == 0);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.x = cpu.x.wrapping_sub(1);
+        cpu.set_flag(FLAGS::z(), cpu.x == 0);
+        cpu.set_flag(FLAGS::n(), (cpu.x & 0x80) != 0);
         0x00
     }
 
     pub fn DEY(cpu: &mut Cpu) -> u8 {
-        let temp = cpu.y as u16 - 1;
-        cpu.y = (temp & 0x00FF) as u8;
-        cpu.set_flag(FLAGS::z(), temp == 0);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.y = cpu.y.wrapping_sub(1);
+        cpu.set_flag(FLAGS::z(), cpu.y == 0);
+        cpu.set_flag(FLAGS::n(), (cpu.y & 0x80) != 0);
         0x00
     }
 
@@ -504,34 +520,32 @@ impl Cpu {
         cpu.fetch();
         let temp = cpu.acc ^ cpu.fetched;
         cpu.acc = temp;
-        cpu.set_flag(FLAGS::n(), (temp & 0x80) == 1);
+        cpu.set_flag(FLAGS::n(), (temp & 0x80) != 0);
         cpu.set_flag(FLAGS::z(), temp == 0x00);
-        0x00
+        0x01
     }
 
     pub fn INC(cpu: &mut Cpu) -> u8 {
         cpu.fetch();
 
-        let temp = cpu.fetched as u16 + 1;
-        cpu.write(cpu.addr_abs, (temp & 0x00FF) as u8);
+        let temp = cpu.fetched.wrapping_add(1);
+        cpu.write(cpu.addr_abs, temp);
         cpu.set_flag(FLAGS::z(), temp == 0);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.set_flag(FLAGS::n(), (temp & 0x80) != 0);
         0x00
     }
 
     pub fn INX(cpu: &mut Cpu) -> u8 {
-        let temp = cpu.x as u16 + 1;
-        cpu.x = (temp & 0x00FF) as u8;
-        cpu.set_flag(FLAGS::z(), temp == 0);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.x = cpu.x.wrapping_add(1);
+        cpu.set_flag(FLAGS::z(), cpu.x == 0);
+        cpu.set_flag(FLAGS::n(), (cpu.x & 0x80) != 0);
         0x00
     }
 
     pub fn INY(cpu: &mut Cpu) -> u8 {
-        let temp = cpu.y as u16 + 1;
-        cpu.y = (temp & 0x00FF) as u8;
-        cpu.set_flag(FLAGS::z(), temp == 0);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.y = cpu.y.wrapping_add(1);
+        cpu.set_flag(FLAGS::z(), cpu.y == 0);
+        cpu.set_flag(FLAGS::n(), (cpu.y & 0x80) != 0);
         0x00
     }
 
@@ -541,11 +555,8 @@ impl Cpu {
     }
 
     pub fn JSR(cpu: &mut Cpu) -> u8 {
-        cpu.pc -= 1;
-        cpu.write(0x0100 + cpu.sp as u16, ((cpu.pc >> 8) & 0x00FF) as u8);
-        cpu.sp -= 1;
-        cpu.write(0x0100 + cpu.sp as u16, (cpu.pc & 0x00FF) as u8);
-        cpu.sp -= 1;
+        cpu.pc = cpu.pc.wrapping_sub(1);
+        cpu.push_word(cpu.pc);
 
         cpu.pc = cpu.addr_abs;
         0x00
@@ -556,8 +567,8 @@ impl Cpu {
 
         cpu.acc = cpu.fetched;
         cpu.set_flag(FLAGS::z(), cpu.acc == 0x00);
-        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) == 1);
-        0x00
+        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) != 0);
+        0x01
     }
 
     pub fn LDX(cpu: &mut Cpu) -> u8 {
@@ -565,8 +576,8 @@ impl Cpu {
 
         cpu.x = cpu.fetched;
         cpu.set_flag(FLAGS::z(), cpu.x == 0x00);
-        cpu.set_flag(FLAGS::n(), (cpu.x & 0x80) == 1);
-        0x00
+        cpu.set_flag(FLAGS::n(), (cpu.x & 0x80) != 0);
+        0x01
     }
 
     pub fn LDY(cpu: &mut Cpu) -> u8 {
@@ -574,8 +585,8 @@ impl Cpu {
 
         cpu.y = cpu.fetched;
         cpu.set_flag(FLAGS::z(), cpu.y == 0x00);
-        cpu.set_flag(FLAGS::n(), (cpu.y & 0x80) == 1);
-        0x00
+        cpu.set_flag(FLAGS::n(), (cpu.y & 0x80) != 0);
+        0x01
     }
 
     pub fn LSR(cpu: &mut Cpu) -> u8 {
@@ -583,9 +594,9 @@ impl Cpu {
         cpu.set_flag(FLAGS::c(), (cpu.fetched as u16 & 0x0001) == 1);
         let temp = (cpu.fetched as u16) >> 1;
         cpu.set_flag(FLAGS::z(), temp == 0x0000);
-        cpu.set_flag(FLAGS::n(), (temp & 0x8000) == 1);
+        cpu.set_flag(FLAGS::n(), (temp & 0x0080) != 0);
 
-        if cpu.addr_mode_name == "IMP" {
+        if cpu.addr_mode_name == "ACC" {
             cpu.acc = (temp & 0x00FF) as u8;
         } else {
             cpu.write(cpu.addr_abs, (temp & 0x00FF) as u8);
@@ -596,54 +607,49 @@ impl Cpu {
 
     pub fn NOP(cpu: &mut Cpu) -> u8 {
         match cpu.opcode {
-            0x01C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => return 0x01,
-            _ => return 0x00,
+            0x01C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => 0x01,
+            _ => 0x00,
         }
     }
 
     pub fn ORA(cpu: &mut Cpu) -> u8 {
         cpu.fetch();
-        cpu.acc = cpu.acc | cpu.fetched;
+        cpu.acc |= cpu.fetched;
         cpu.set_flag(FLAGS::z(), cpu.acc == 0);
-        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) == 1);
-        0x00
+        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) != 0);
+        0x01
     }
 
     pub fn PHA(cpu: &mut Cpu) -> u8 {
-        cpu.write(0x0100 + cpu.sp as u16, cpu.acc);
-        cpu.sp -= 1;
+        cpu.push(cpu.acc);
         0x00
     }
 
     pub fn PHP(cpu: &mut Cpu) -> u8 {
-        cpu.write(0x0100 + cpu.sp as u16, cpu.psr | FLAGS::b() | FLAGS::u());
-        cpu.sp -= 1;
+        cpu.push(cpu.status_for_push(true));
         0x00
     }
 
     pub fn PLA(cpu: &mut Cpu) -> u8 {
-        cpu.sp += 1;
-        cpu.acc = cpu.read(0x0100 + cpu.sp as u16);
+        cpu.acc = cpu.pull();
         cpu.set_flag(FLAGS::z(), cpu.acc == 0);
-        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) == 1);
+        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) != 0);
         0x00
     }
 
     pub fn PLP(cpu: &mut Cpu) -> u8 {
-        cpu.sp += 1;
-        cpu.psr = cpu.read(0x0100 + cpu.sp as u16);
-        cpu.set_flag(FLAGS::u(), true);
+        cpu.pull_status();
         0x00
     }
 
     pub fn ROL(cpu: &mut Cpu) -> u8 {
         cpu.fetch();
         let temp = ((cpu.fetched as u16) << 1) | cpu.get_flag(FLAGS::c()) as u16;
-        cpu.set_flag(FLAGS::c(), (temp & 0xFF00) == 1);
+        cpu.set_flag(FLAGS::c(), (temp & 0xFF00) != 0);
         cpu.set_flag(FLAGS::z(), (temp & 0x00FF) == 0);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.set_flag(FLAGS::n(), (temp & 0x0080) != 0);
 
-        if cpu.addr_mode_name == "IMP" {
+        if cpu.addr_mode_name == "ACC" {
             cpu.acc = (temp & 0x00FF) as u8;
         } else {
             cpu.write(cpu.addr_abs, (temp & 0x00FF) as u8);
@@ -657,9 +663,9 @@ impl Cpu {
         let temp = ((cpu.fetched as u16) >> 1) | ((cpu.get_flag(FLAGS::c()) as u16) << 7);
         cpu.set_flag(FLAGS::c(), (cpu.fetched & 0x01) == 1);
         cpu.set_flag(FLAGS::z(), (temp & 0x00FF) == 0);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.set_flag(FLAGS::n(), (temp & 0x0080) != 0);
 
-        if cpu.addr_mode_name == "IMP" {
+        if cpu.addr_mode_name == "ACC" {
             cpu.acc = (temp & 0x00FF) as u8;
         } else {
             cpu.write(cpu.addr_abs, (temp & 0x00FF) as u8);
@@ -669,25 +675,14 @@ impl Cpu {
     }
 
     pub fn RTI(cpu: &mut Cpu) -> u8 {
-        cpu.sp += 1;
-        cpu.psr = cpu.read(0x0100 + cpu.sp as u16);
-        cpu.psr &= !FLAGS::b();
-        cpu.psr &= !FLAGS::u();
-        cpu.sp += 1;
-
-        cpu.pc = cpu.read(0x0100 + cpu.sp as u16) as u16;
-        cpu.sp += 1;
-        cpu.pc |= (cpu.read(0x0100 + cpu.sp as u16) as u16) << 8;
+        cpu.pull_status();
+        cpu.pc = cpu.pull_word();
         0x00
     }
 
     pub fn RTS(cpu: &mut Cpu) -> u8 {
-        cpu.sp += 1;
-
-        cpu.pc = cpu.read(0x0100 + cpu.sp as u16) as u16;
-        cpu.sp += 1;
-        cpu.pc |= (cpu.read(0x0100 + cpu.sp as u16) as u16) << 8;
-        cpu.pc += 1;
+        cpu.pc = cpu.pull_word();
+        cpu.pc = cpu.pc.wrapping_add(1);
         0x00
     }
 
@@ -696,12 +691,12 @@ impl Cpu {
 
         let value = !cpu.fetched as u16;
         let temp = cpu.acc as u16 + value + cpu.get_flag(FLAGS::c()) as u16;
-        cpu.set_flag(FLAGS::c(), (temp & 0xFF00) == 1);
+        cpu.set_flag(FLAGS::c(), (temp & 0xFF00) != 0);
         cpu.set_flag(FLAGS::z(), (temp & 0x00FF) == 0);
-        cpu.set_flag(FLAGS::n(), (temp & 0x0080) == 1);
+        cpu.set_flag(FLAGS::n(), (temp & 0x0080) != 0);
         cpu.set_flag(
             FLAGS::v(),
-            ((temp ^ cpu.acc as u16) & (temp ^ value) & 0x0080) == 1,
+            ((temp ^ cpu.acc as u16) & (temp ^ value) & 0x0080) != 0,
         );
         cpu.acc = (temp & 0x00FF) as u8;
         0x01
@@ -740,28 +735,28 @@ impl Cpu {
     pub fn TAX(cpu: &mut Cpu) -> u8 {
         cpu.x = cpu.acc;
         cpu.set_flag(FLAGS::z(), cpu.x == 0x00);
-        cpu.set_flag(FLAGS::n(), (cpu.x & 0x80) == 1);
+        cpu.set_flag(FLAGS::n(), (cpu.x & 0x80) != 0);
         0x00
     }
 
     pub fn TAY(cpu: &mut Cpu) -> u8 {
         cpu.y = cpu.acc;
         cpu.set_flag(FLAGS::z(), cpu.y == 0x00);
-        cpu.set_flag(FLAGS::n(), (cpu.y & 0x80) == 1);
+        cpu.set_flag(FLAGS::n(), (cpu.y & 0x80) != 0);
         0x00
     }
 
     pub fn TSX(cpu: &mut Cpu) -> u8 {
         cpu.x = cpu.sp;
         cpu.set_flag(FLAGS::z(), cpu.x == 0x00);
-        cpu.set_flag(FLAGS::n(), (cpu.x & 0x80) == 1);
+        cpu.set_flag(FLAGS::n(), (cpu.x & 0x80) != 0);
         0x00
     }
 
     pub fn TXA(cpu: &mut Cpu) -> u8 {
         cpu.acc = cpu.x;
         cpu.set_flag(FLAGS::z(), cpu.acc == 0x00);
-        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) == 1);
+        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) != 0);
         0x00
     }
 
@@ -773,40 +768,93 @@ impl Cpu {
     pub fn TYA(cpu: &mut Cpu) -> u8 {
         cpu.acc = cpu.y;
         cpu.set_flag(FLAGS::z(), cpu.acc == 0x00);
-        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) == 1);
+        cpu.set_flag(FLAGS::n(), (cpu.acc & 0x80) != 0);
         0x00
     }
 
-    pub fn XXX(cpu: &mut Cpu) -> u8 {
+    pub fn XXX(_cpu: &mut Cpu) -> u8 {
         0x00
     }
 
     pub fn clock(&mut self, lookup: &mut LookUpTable) {
+        self.trace_cycle();
         if self.cycles == 0 {
-            self.opcode = self.read(self.pc);
-            self.set_flag(FLAGS::u(), true);
-            self.pc += 1;
+            self.begin_step();
+            self.begin_trace(lookup);
+            self.profile_dispatch();
+            self.stats_dispatch();
+
+            if let Some(interrupt) = self.pending_interrupt.take() {
+                self.interrupt(interrupt);
+            } else {
+                self.opcode = self.read_code(self.pc);
+                self.set_flag(FLAGS::u(), true);
+                self.pc = self.pc.wrapping_add(1);
 
-            self.cycles = lookup.table[self.opcode as usize].cycles;
+                self.cycles = lookup.table[self.opcode as usize].cycles;
 
-            self.addr_mode_name = lookup.table[self.opcode as usize].addr_name.to_string();
+                self.addr_mode_name = lookup.table[self.opcode as usize].addr_name.to_string();
 
-            let additional_cyles = { lookup.table[self.opcode as usize].address_mode }(self);
+                // CLI, SEI and PLP change I after the poll has already happened,
+                // so the poll sees the flag as it was before the instruction
+                let i_before = self.get_flag(FLAGS::i()) == 1;
+                self.poll_at = 1;
 
-            let additional_cycles_2 = { lookup.table[self.opcode as usize].operation }(self);
+                let additional_cyles = { lookup.table[self.opcode as usize].address_mode }(self);
+                self.trace_address();
 
-            self.cycles += additional_cycles_2 + additional_cyles;
+                let additional_cycles_2 = { lookup.table[self.opcode as usize].operation }(self);
+                self.log_indirect();
 
-            self.set_flag(FLAGS::u(), true);
+                // The extra page crossing cycle is only paid by instructions that read
+                // through an indexed mode which crossed a page
+                self.cycles += additional_cycles_2 & additional_cyles;
+
+                self.irq_inhibit = match self.opcode {
+                    0x58 | 0x78 | 0x28 => i_before,
+                    _ => self.get_flag(FLAGS::i()) == 1,
+                };
+
+                self.set_flag(FLAGS::u(), true);
+            }
         }
 
-        self.cycles -= 1
+        self.profile_cycle();
+        // Undocumented opcodes decode with a zero cycle count
+        self.cycles = self.cycles.saturating_sub(1);
+
+        // The vector is fetched in the last two cycles of BRK/IRQ/NMI. An NMI
+        // recognised before then hijacks the sequence and takes its vector instead.
+        if self.cycles == 2 {
+            if let Some(mut vector) = self.pending_vector.take() {
+                if vector != Interrupt::Nmi.vector() && self.interrupts.acknowledge_nmi() {
+                    vector = Interrupt::Nmi.vector();
+                }
+                let lo = self.read(vector) as u16;
+                let hi = self.read(vector + 1) as u16;
+                self.pc = (hi << 8) | lo;
+            }
+        }
+
+        if self.cycles == self.poll_at {
+            self.poll_interrupts();
+        }
+    }
+
+    // Sample the interrupt lines, deciding whether an interrupt sequence runs
+    // instead of the next instruction
+    fn poll_interrupts(&mut self) {
+        if self.interrupts.nmi_pending {
+            self.pending_interrupt = Some(Interrupt::Nmi);
+        } else if self.interrupts.irq_line() && !self.irq_inhibit {
+            self.pending_interrupt = Some(Interrupt::Irq);
+        }
     }
 
     pub fn reset(&mut self) {
         self.addr_abs = 0xFFFC;
 
-        let lo = self.read(self.addr_abs + 0) as u16;
+        let lo = self.read(self.addr_abs) as u16;
         let hi = self.read(self.addr_abs + 1) as u16;
 
         self.pc = (hi << 8) | lo;
@@ -815,63 +863,44 @@ impl Cpu {
         self.y = 0x00;
         self.x = 0x00;
         self.sp = 0xFD;
-        
-        self.psr = 0x00 | FLAGS::u();
+
+        self.psr = FLAGS::u() | FLAGS::i();
 
         self.fetched = 0x00;
         self.addr_rel = 0x00;
         self.addr_abs = 0x00;
 
+        self.interrupts.nmi_pending = false;
+        self.pending_interrupt = None;
+        self.pending_vector = None;
+        self.irq_inhibit = true;
+        // No interrupt is taken before the first instruction
+        self.poll_at = u8::MAX;
+
         self.cycles = 8;
     }
 
-    pub fn irq(&mut self) {
-        if self.get_flag(FLAGS::i()) == 0 {
-            self.write(0x0100 + self.sp as u16, ((self.pc >> 8) & 0x00FF) as u8);
-            self.sp -= 1;
-            self.write(0x0100 + self.sp as u16, (self.pc & 0x00FF) as u8);
-            self.sp -= 1;
-
-            self.set_flag(FLAGS::b(), false);
-            self.set_flag(FLAGS::u(), true);
-            self.set_flag(FLAGS::i(), true);
-
-            self.write(0x0100 + self.sp as u16, self.psr);
-            self.sp -= 1;
-
-            self.addr_abs = 0xFFFE;
-            let lo = self.read(self.addr_abs + 0) as u16;
-            let hi = self.read(self.addr_abs + 1) as u16;
+    // The hardware interrupt sequence, 7 cycles like BRK but without B in the pushed status
+    fn interrupt(&mut self, interrupt: Interrupt) {
+        self.check_interrupt_breakpoints(interrupt);
 
-            self.pc = (hi << 8) | lo;
+        self.push_word(self.pc);
+        self.push(self.status_for_push(false));
 
-            self.cycles = 7;
-        }
-    }
-    pub fn nmi(&mut self) {
-        self.write(0x0100 + self.sp as u16, ((self.pc >> 8) & 0x00FF) as u8);
-        self.sp -= 1;
-        self.write(0x0100 + self.sp as u16, (self.pc & 0x00FF) as u8);
-        self.sp -= 1;
-
-        self.set_flag(FLAGS::b(), false);
-        self.set_flag(FLAGS::u(), true);
         self.set_flag(FLAGS::i(), true);
 
-        self.write(0x0100 + self.sp as u16, self.psr);
-        self.sp -= 1;
-
-        self.addr_abs = 0xFFFA;
-        let lo = self.read(self.addr_abs + 0) as u16;
-        let hi = self.read(self.addr_abs + 1) as u16;
-
-        self.pc = (hi << 8) | lo;
+        if interrupt == Interrupt::Nmi {
+            self.interrupts.acknowledge_nmi();
+        }
+        self.pending_vector = Some(interrupt.vector());
 
-        self.cycles = 8;
+        // The handler's first instruction always runs before another interrupt
+        self.poll_at = u8::MAX;
+        self.cycles = 7;
     }
 
     pub fn fetch(&mut self) -> u8 {
-        if self.addr_mode_name != "IMP" {
+        if self.addr_mode_name != "IMP" && self.addr_mode_name != "ACC" {
             self.fetched = self.read(self.addr_abs);
         }
         self.fetched
@@ -881,106 +910,8 @@ impl Cpu {
         self.cycles == 0
     }
 
-    pub fn disassemble(&mut self, start: u16, stop: u16, lookup: &LookUpTable) -> Vec<String> {
-        let mut map_lines: Vec<String> = vec![String::new();RAM_SIZE];
-        let mut value = 0x00u8;
-        let mut hi = 0x00u8;
-        let mut lo = 0x00u8;
-        let mut addr = start;
-        let mut line_addr = 0u16;
-
-        while addr < stop {
-            line_addr = addr;
-            let mut map_line = String::new();
-
-            map_line.insert_str(map_line.len(), &format!("${:04x}: ", addr)[..]);
-            let opcode = self.read(addr);
-            addr += 1;
-            map_line.insert_str(
-                map_line.len(),
-                &format!("{} ", lookup.table[opcode as usize].name)[..],
-            );
-
-            if lookup.table[opcode as usize].addr_name == "IMP" {
-                map_line.insert_str(map_line.len(), " {IMP}");
-            } else if lookup.table[opcode as usize].addr_name == "IMM" {
-                value = self.read(addr);
-                addr += 1;
-                map_line.insert_str(map_line.len(), &format!(" ${:04x}, `{{`IMM`}}`", value)[..])
-            } else if lookup.table[opcode as usize].addr_name == "ZP" {
-                lo = self.read(addr);
-                addr += 1;
-                map_line.insert_str(map_line.len(), &format!(" ${:04x}, `{{`ZP`}}`", lo)[..])
-            } else if lookup.table[opcode as usize].addr_name == "ZPX" {
-                lo = self.read(addr);
-                addr += 1;
-                map_line.insert_str(map_line.len(), &format!(" ${:04x}, X `{{`ZPX`}}`", lo)[..])
-            } else if lookup.table[opcode as usize].addr_name == "ZPY" {
-                lo = self.read(addr);
-                addr += 1;
-                map_line.insert_str(map_line.len(), &format!(" ${:04x}, Y `{{`ZPY`}}`", lo)[..])
-            } else if lookup.table[opcode as usize].addr_name == "INDX" {
-                lo = self.read(addr);
-                addr += 1;
-                map_line.insert_str(
-                    map_line.len(),
-                    &format!(" ${:04x}, X) `{{`INDX`}}`", lo)[..],
-                )
-            } else if lookup.table[opcode as usize].addr_name == "INDY" {
-                lo = self.read(addr);
-                addr += 1;
-                map_line.insert_str(
-                    map_line.len(),
-                    &format!(" ${:04x}, Y) `{{`INDX`}}`", lo)[..],
-                )
-            } else if lookup.table[opcode as usize].addr_name == "ABS" {
-                lo = self.read(addr);
-                addr += 1;
-                hi = self.read(addr);
-                addr += 1;
-                map_line.insert_str(
-                    map_line.len(),
-                    &format!(" ${:04x}, `{{`ABS`}}`", ((hi as u16) << 8) | lo as u16)[..],
-                )
-            } else if lookup.table[opcode as usize].addr_name == "ABSX" {
-                lo = self.read(addr);
-                addr += 1;
-                hi = self.read(addr);
-                addr += 1;
-                map_line.insert_str(
-                    map_line.len(),
-                    &format!(" ${:04x}, X `{{`ABS`}}`", ((hi as u16) << 8) | lo as u16)[..],
-                )
-            } else if lookup.table[opcode as usize].addr_name == "ABSY" {
-                lo = self.read(addr);
-                addr += 1;
-                hi = self.read(addr);
-                addr += 1;
-                map_line.insert_str(
-                    map_line.len(),
-                    &format!(" ${:04x}, Y `{{`ABS`}}`", ((hi as u16) << 8) | lo as u16)[..],
-                )
-            } else if lookup.table[opcode as usize].addr_name == "ABSIND" {
-                lo = self.read(addr);
-                addr += 1;
-                hi = self.read(addr);
-                addr += 1;
-                map_line.insert_str(
-                    map_line.len(),
-                    &format!(" ${:04x}, `{{`ABSIND`}}`", ((hi as u16) << 8) | lo as u16)[..],
-                )
-            } else if lookup.table[opcode as usize].addr_name == "REL" {
-                value = self.read(addr);
-                addr += 1;
-                map_line.insert_str(
-                    map_line.len(),
-                    &format!(" ${:04x} [${:04x}] `{{`REL`}}`", value, addr)[..],
-                )
-            }
-            
-            map_lines[line_addr as usize] = map_line;
-        }
-        map_lines
+    // Instructions from start up to stop, read without side effects
+    pub fn disassemble(&self, start: u16, stop: u16) -> Vec<DecodedInstruction> {
+        Disassembler::new().decode_range(|addr| self.peek(addr), start, stop)
     }
 }
-
@@ -0,0 +1,354 @@
+// Program loaders.
+//
+// Reads a program into pieces of memory: raw binaries at an origin, Intel HEX
+// and Motorola S-record files (checksums checked, extended addresses and
+// start records understood) and C64 PRG files, whose first two bytes are the
+// little endian load address. Every piece has to fit below $10000 and no two
+// may overlap, so a program is either loaded whole or not at all.
+use crate::bus::{Bus, RAM_SIZE};
+use std::path::Path;
+use std::{fmt, fs, io};
+
+const RESET_VECTOR: u16 = 0xFFFC;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Raw,
+    IntelHex,
+    SRecord,
+    C64Prg,
+}
+
+impl Format {
+    // From the file extension, falling back to the contents. Anything that
+    // does not look like a text format is raw.
+    pub fn detect(path: &Path, data: &[u8]) -> Format {
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("hex") | Some("ihx") | Some("ihex") => return Format::IntelHex,
+            Some("s19") | Some("s28") | Some("s37") | Some("srec") | Some("mot") => {
+                return Format::SRecord
+            }
+            Some("prg") => return Format::C64Prg,
+            _ => {}
+        }
+        let text = data
+            .iter()
+            .all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace());
+        match data {
+            [b':', ..] if text => Format::IntelHex,
+            [b'S', b'0'..=b'9', ..] if text => Format::SRecord,
+            _ => Format::Raw,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    // 1 based line of a text format
+    Parse {
+        line: usize,
+        message: String,
+    },
+    Checksum {
+        line: usize,
+        expected: u8,
+        found: u8,
+    },
+    // len bytes at addr do not fit in the 64K address space
+    OutOfRange {
+        addr: u32,
+        len: usize,
+    },
+    // len bytes at addr land on bytes an earlier piece already put at other
+    Overlap {
+        addr: u16,
+        len: usize,
+        other: u16,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{}", e),
+            LoadError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            LoadError::Checksum {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "line {}: checksum is ${:02X}, expected ${:02X}",
+                line, found, expected
+            ),
+            LoadError::OutOfRange { addr, len } => {
+                write!(f, "{} bytes at ${:04X} run past $FFFF", len, addr)
+            }
+            LoadError::Overlap { addr, len, other } => write!(
+                f,
+                "{} bytes at ${:04X} overlap the data already loaded at ${:04X}",
+                len, addr, other
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+fn parse_error(line: usize, message: impl Into<String>) -> LoadError {
+    LoadError::Parse {
+        line: line + 1,
+        message: message.into(),
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    // Address and bytes of each piece, in the order they were loaded
+    pub chunks: Vec<(u16, Vec<u8>)>,
+    // Entry point from a start record, if the file had one
+    pub start: Option<u16>,
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Program::default()
+    }
+
+    // origin is only used for raw binaries
+    pub fn load(path: impl AsRef<Path>, origin: u16) -> Result<Program, LoadError> {
+        let path = path.as_ref();
+        let data = fs::read(path)?;
+        Program::parse(Format::detect(path, &data), &data, origin)
+    }
+
+    pub fn parse(format: Format, data: &[u8], origin: u16) -> Result<Program, LoadError> {
+        match format {
+            Format::Raw => Program::raw(data, origin),
+            Format::C64Prg => Program::c64_prg(data),
+            Format::IntelHex | Format::SRecord => {
+                let text = std::str::from_utf8(data).map_err(|e| LoadError::Parse {
+                    line: 1 + data[..e.valid_up_to()]
+                        .iter()
+                        .filter(|b| **b == b'\n')
+                        .count(),
+                    message: "not a text file".to_string(),
+                })?;
+                if format == Format::IntelHex {
+                    Program::intel_hex(text)
+                } else {
+                    Program::srecord(text)
+                }
+            }
+        }
+    }
+
+    pub fn raw(data: &[u8], origin: u16) -> Result<Program, LoadError> {
+        let mut program = Program::new();
+        program.add(origin as u32, data)?;
+        Ok(program)
+    }
+
+    pub fn c64_prg(data: &[u8]) -> Result<Program, LoadError> {
+        if data.len() < 2 {
+            return Err(parse_error(0, "PRG file has no load address"));
+        }
+        Program::raw(&data[2..], u16::from_le_bytes([data[0], data[1]]))
+    }
+
+    pub fn intel_hex(text: &str) -> Result<Program, LoadError> {
+        let mut program = Program::new();
+        // Upper address bits from the last type 02 or 04 record
+        let mut base = 0u32;
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let record = line
+                .strip_prefix(':')
+                .ok_or_else(|| parse_error(n, "record does not start with ':'"))?;
+            let bytes = hex_bytes(n, record)?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(parse_error(n, "record length does not match its count"));
+            }
+            let (body, found) = bytes.split_at(bytes.len() - 1);
+            let expected = body
+                .iter()
+                .fold(0u8, |sum, b| sum.wrapping_add(*b))
+                .wrapping_neg();
+            check_sum(n, expected, found[0])?;
+
+            let addr = u16::from_be_bytes([body[1], body[2]]) as u32;
+            let data = &body[4..];
+            let word = |len: usize| {
+                if data.len() == len {
+                    Ok(data.iter().fold(0u32, |v, b| v << 8 | *b as u32))
+                } else {
+                    Err(parse_error(n, format!("record needs {} data bytes", len)))
+                }
+            };
+            match body[3] {
+                0x00 => program.add(base + addr, data)?,
+                0x01 => break,
+                0x02 => base = word(2)? << 4,
+                0x03 => {
+                    let cs_ip = word(4)?;
+                    program.set_start(((cs_ip >> 16) << 4) + (cs_ip & 0xFFFF))?;
+                }
+                0x04 => base = word(2)? << 16,
+                0x05 => program.set_start(word(4)?)?,
+                other => return Err(parse_error(n, format!("unknown record type {:02X}", other))),
+            }
+        }
+        Ok(program)
+    }
+
+    pub fn srecord(text: &str) -> Result<Program, LoadError> {
+        let mut program = Program::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let mut chars = line.chars();
+            let kind = match (chars.next(), chars.next()) {
+                (Some('S'), Some(kind @ '0'..='9')) => kind,
+                _ => return Err(parse_error(n, "record does not start with S0-S9")),
+            };
+            let bytes = hex_bytes(n, chars.as_str())?;
+            if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+                return Err(parse_error(n, "record length does not match its count"));
+            }
+            let (body, found) = bytes.split_at(bytes.len() - 1);
+            let expected = !body.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+            check_sum(n, expected, found[0])?;
+
+            let addr_len = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => return Err(parse_error(n, "S4 records are reserved")),
+            };
+            if body.len() < 1 + addr_len {
+                return Err(parse_error(n, "record is shorter than its address"));
+            }
+            let addr = body[1..1 + addr_len]
+                .iter()
+                .fold(0u32, |v, b| v << 8 | *b as u32);
+            let data = &body[1 + addr_len..];
+            match kind {
+                '1' | '2' | '3' => program.add(addr, data)?,
+                '7' | '8' | '9' => program.set_start(addr)?,
+                // Header and record counts
+                _ => {}
+            }
+        }
+        Ok(program)
+    }
+
+    // Add bytes at addr, growing the last piece when they follow straight on from it
+    pub fn add(&mut self, addr: u32, bytes: &[u8]) -> Result<(), LoadError> {
+        let len = bytes.len();
+        if addr as usize >= RAM_SIZE || addr as usize + len > RAM_SIZE {
+            return Err(LoadError::OutOfRange { addr, len });
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        let (addr, end) = (addr as u16, addr as usize + len);
+        if let Some((other, _)) = self.chunks.iter().find(|(at, data)| {
+            let at = *at as usize;
+            (addr as usize) < at + data.len() && at < end
+        }) {
+            return Err(LoadError::Overlap {
+                addr,
+                len,
+                other: *other,
+            });
+        }
+        match self.chunks.last_mut() {
+            Some((at, data)) if *at as usize + data.len() == addr as usize => {
+                data.extend_from_slice(bytes)
+            }
+            _ => self.chunks.push((addr, bytes.to_vec())),
+        }
+        Ok(())
+    }
+
+    fn set_start(&mut self, addr: u32) -> Result<(), LoadError> {
+        if addr as usize >= RAM_SIZE {
+            return Err(LoadError::OutOfRange { addr, len: 0 });
+        }
+        self.start = Some(addr as u16);
+        Ok(())
+    }
+
+    // Point the reset vector at addr. Fails if the program has its own vectors.
+    pub fn set_reset_vector(&mut self, addr: u16) -> Result<(), LoadError> {
+        self.add(RESET_VECTOR as u32, &addr.to_le_bytes())
+    }
+
+    // Where to run from: the start record, else the first byte loaded
+    pub fn entry(&self) -> Option<u16> {
+        self.start
+            .or_else(|| self.chunks.first().map(|(addr, _)| *addr))
+    }
+
+    pub fn covers(&self, addr: u16) -> bool {
+        self.chunks
+            .iter()
+            .any(|(at, data)| addr >= *at && (addr as usize) < *at as usize + data.len())
+    }
+
+    // Total number of bytes
+    pub fn len(&self) -> usize {
+        self.chunks.iter().map(|(_, data)| data.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn write(&self, bus: &mut Bus) {
+        for (addr, data) in &self.chunks {
+            let at = *addr as usize;
+            bus.ram[at..at + data.len()].copy_from_slice(data);
+        }
+    }
+}
+
+fn hex_bytes(line: usize, text: &str) -> Result<Vec<u8>, LoadError> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(parse_error(
+            line,
+            "record is not an even number of hex digits",
+        ));
+    }
+    Ok((0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap())
+        .collect())
+}
+
+fn check_sum(line: usize, expected: u8, found: u8) -> Result<(), LoadError> {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(LoadError::Checksum {
+            line: line + 1,
+            expected,
+            found,
+        })
+    }
+}
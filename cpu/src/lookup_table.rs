@@ -1,5 +1,4 @@
 use crate::Cpu;
-use std::{cell::RefCell, rc::Rc};
 
 pub struct Instruction<'a> {
     pub name: &'a str,
@@ -14,6 +13,12 @@ pub struct LookUpTable<'a> {
     pub table: Vec<Instruction<'a>>,
 }
 
+impl<'a> Default for LookUpTable<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<'a> LookUpTable<'a> {
     pub fn new() -> LookUpTable<'a> {
         LookUpTable {
@@ -0,0 +1,262 @@
+// Execution profiling.
+//
+// While a Profiler is attached, every cycle Cpu::clock runs is charged to the
+// routine on top of a shadow call stack. JSR, BRK and interrupt sequences
+// push the routine they land in; RTS and RTI pop every routine whose return
+// address they pulled, found by comparing sp with what it was at the call,
+// so routines that drop their return address or leave through a chain of
+// returns still come off. The routine running when profiling starts is the
+// root and never pops.
+//
+// Exclusive cycles are those run in a routine itself, inclusive ones add the
+// routines it called. A recursive routine's inclusive cycles are counted once,
+// at its outermost call.
+use crate::symbols::SymbolTable;
+use crate::Cpu;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RoutineStats {
+    pub calls: u64,
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+// One edge of the call graph
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CallStats {
+    pub calls: u64,
+    // Inclusive cycles of the callee when called from this caller
+    pub cycles: u64,
+}
+
+#[derive(Debug, Clone)]
+struct Frame {
+    routine: u16,
+    // sp from before the call, which returning restores
+    sp: u16,
+    start: u64,
+    // Cycles run in the routine itself not yet added up
+    pending: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Transition {
+    Call { sp: u8 },
+    Return,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    // Cpu cycles clocked since profiling started
+    pub cycles: u64,
+    pub routines: HashMap<u16, RoutineStats>,
+    // Keyed by caller and callee
+    pub calls: HashMap<(u16, u16), CallStats>,
+    // Exclusive cycles per call stack, root first
+    pub stacks: HashMap<Vec<u16>, u64>,
+    frames: Vec<Frame>,
+    // Call or return made by the instruction in flight, which takes effect
+    // once the next one is dispatched and pc holds where it went
+    transition: Option<Transition>,
+}
+
+impl Profiler {
+    pub fn new(root: u16) -> Self {
+        let mut routines = HashMap::new();
+        routines.insert(
+            root,
+            RoutineStats {
+                calls: 1,
+                ..RoutineStats::default()
+            },
+        );
+        Profiler {
+            cycles: 0,
+            routines,
+            calls: HashMap::new(),
+            stacks: HashMap::new(),
+            frames: vec![Frame {
+                routine: root,
+                sp: 0x100,
+                start: 0,
+                pending: 0,
+            }],
+            transition: None,
+        }
+    }
+
+    // Routines from the root to the one running
+    pub fn stack(&self) -> Vec<u16> {
+        self.frames.iter().map(|f| f.routine).collect()
+    }
+
+    fn flush(&mut self) {
+        let frame = self.frames.last_mut().expect("root frame");
+        if frame.pending == 0 {
+            return;
+        }
+        let cycles = std::mem::take(&mut frame.pending);
+        let routine = frame.routine;
+        self.routines.entry(routine).or_default().exclusive += cycles;
+        *self.stacks.entry(self.stack()).or_default() += cycles;
+    }
+
+    fn enter(&mut self, routine: u16, sp: u8) {
+        self.flush();
+        let caller = self.frames.last().expect("root frame").routine;
+        self.routines.entry(routine).or_default().calls += 1;
+        self.calls.entry((caller, routine)).or_default().calls += 1;
+        self.frames.push(Frame {
+            routine,
+            sp: sp as u16,
+            start: self.cycles,
+            pending: 0,
+        });
+    }
+
+    fn leave(&mut self) {
+        self.flush();
+        let frame = self.frames.pop().expect("root frame");
+        let cycles = self.cycles - frame.start;
+        if self.frames.iter().any(|f| f.routine == frame.routine) {
+            return;
+        }
+        self.routines.entry(frame.routine).or_default().inclusive += cycles;
+        let caller = self.frames.last().expect("root frame").routine;
+        self.calls
+            .entry((caller, frame.routine))
+            .or_default()
+            .cycles += cycles;
+    }
+
+    // Close every open routine, as if they had all returned now
+    pub fn finish(&mut self) {
+        while self.frames.len() > 1 {
+            self.leave();
+        }
+        self.flush();
+        let root = self.frames[0].routine;
+        self.routines.entry(root).or_default().inclusive = self.cycles - self.frames[0].start;
+        self.frames[0].start = self.cycles;
+    }
+
+    // Table of the routines taking the most cycles, at most limit of them
+    pub fn report(&self, symbols: Option<&SymbolTable>, limit: usize) -> String {
+        let mut routines: Vec<(&u16, &RoutineStats)> = self.routines.iter().collect();
+        routines.sort_by_key(|(addr, stats)| (std::cmp::Reverse(stats.exclusive), **addr));
+        let total = self.cycles.max(1) as f64;
+        let mut out = format!(
+            "{:<24} {:>8} {:>12} {:>12} {:>7}",
+            "routine", "calls", "inclusive", "exclusive", "self %"
+        );
+        for (addr, stats) in routines.into_iter().take(limit) {
+            out.push_str(&format!(
+                "\n{:<24} {:>8} {:>12} {:>12} {:>6.2}%",
+                routine_name(*addr, symbols),
+                stats.calls,
+                stats.inclusive,
+                stats.exclusive,
+                stats.exclusive as f64 * 100.0 / total
+            ));
+        }
+        out
+    }
+
+    // Call graph edges, the most expensive first
+    pub fn call_graph(&self, symbols: Option<&SymbolTable>) -> String {
+        let mut calls: Vec<(&(u16, u16), &CallStats)> = self.calls.iter().collect();
+        calls.sort_by_key(|(edge, stats)| (std::cmp::Reverse(stats.cycles), **edge));
+        let lines: Vec<String> = calls
+            .iter()
+            .map(|((caller, callee), stats)| {
+                format!(
+                    "{} -> {}  {} calls, {} cycles",
+                    routine_name(*caller, symbols),
+                    routine_name(*callee, symbols),
+                    stats.calls,
+                    stats.cycles
+                )
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    // Folded stacks, one `root;caller;callee cycles` line per call stack, as
+    // read by flamegraph.pl and inferno
+    pub fn folded(&self, symbols: Option<&SymbolTable>) -> String {
+        let mut lines: Vec<String> = self
+            .stacks
+            .iter()
+            .map(|(stack, cycles)| {
+                let names: Vec<String> = stack.iter().map(|a| routine_name(*a, symbols)).collect();
+                format!("{} {}\n", names.join(";"), cycles)
+            })
+            .collect();
+        lines.sort();
+        lines.concat()
+    }
+}
+
+pub fn routine_name(addr: u16, symbols: Option<&SymbolTable>) -> String {
+    match symbols.and_then(|s| s.label_at(addr)) {
+        Some(name) => name.to_string(),
+        None => format!("${:04X}", addr),
+    }
+}
+
+impl Cpu {
+    // Profile from the routine at pc on
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new(self.pc));
+    }
+
+    // Stop profiling, returning the profile with every open routine closed
+    pub fn disable_profiler(&mut self) -> Option<Profiler> {
+        let mut profiler = self.profiler.take()?;
+        profiler.finish();
+        Some(profiler)
+    }
+
+    // Called on every cpu cycle, after any dispatch so the cycle is charged
+    // to the routine the instruction belongs to
+    pub(crate) fn profile_cycle(&mut self) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.cycles += 1;
+            if let Some(frame) = profiler.frames.last_mut() {
+                frame.pending += 1;
+            }
+        }
+    }
+
+    // Called on dispatch, before the instruction or interrupt runs
+    pub(crate) fn profile_dispatch(&mut self) {
+        if self.profiler.is_none() {
+            return;
+        }
+        let (pc, sp) = (self.pc, self.sp);
+        let next = match self.pending_interrupt {
+            Some(_) => Some(Transition::Call { sp }),
+            None => match self.peek(pc) {
+                // JSR, BRK
+                0x20 | 0x00 => Some(Transition::Call { sp }),
+                // RTS, RTI
+                0x60 | 0x40 => Some(Transition::Return),
+                _ => None,
+            },
+        };
+        let profiler = self.profiler.as_mut().expect("profiling");
+        match profiler.transition.take() {
+            Some(Transition::Call { sp }) => profiler.enter(pc, sp),
+            Some(Transition::Return) => {
+                while profiler.frames.len() > 1
+                    && profiler.frames.last().expect("root frame").sp <= sp as u16
+                {
+                    profiler.leave();
+                }
+            }
+            None => {}
+        }
+        profiler.transition = next;
+    }
+}
@@ -0,0 +1,181 @@
+use crate::system::{ClockConfig, Console};
+
+pub const NTSC_MASTER_HZ: u32 = 21_477_272;
+pub const NES_PAL_MASTER_HZ: u32 = 26_601_712;
+pub const SNES_PAL_MASTER_HZ: u32 = 21_281_370;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    // Famiclone timing: PAL frame with an NTSC-like cpu:ppu ratio. Only exists for the NES.
+    Dendy,
+}
+
+// Timing parameters of a console in a given video region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionProfile {
+    pub console: Console,
+    pub region: Region,
+    pub master_hz: u32,
+    pub cpu_divider: u64,
+    pub ppu_divider: u64,
+    pub apu_divider: u64,
+    pub scanlines: u32,
+    pub vblank_scanlines: u32,
+    pub master_cycles_per_scanline: u64,
+}
+
+impl RegionProfile {
+    pub fn new(console: Console, region: Region) -> Self {
+        match (console, region) {
+            (Console::Nes, Region::Ntsc) => RegionProfile {
+                console,
+                region,
+                master_hz: NTSC_MASTER_HZ,
+                cpu_divider: 12,
+                ppu_divider: 4,
+                apu_divider: 24,
+                scanlines: 262,
+                vblank_scanlines: 20,
+                master_cycles_per_scanline: 341 * 4,
+            },
+            (Console::Nes, Region::Pal) => RegionProfile {
+                console,
+                region,
+                master_hz: NES_PAL_MASTER_HZ,
+                cpu_divider: 16,
+                ppu_divider: 5,
+                apu_divider: 32,
+                scanlines: 312,
+                vblank_scanlines: 70,
+                master_cycles_per_scanline: 341 * 5,
+            },
+            (Console::Nes, Region::Dendy) => RegionProfile {
+                console,
+                region,
+                master_hz: NES_PAL_MASTER_HZ,
+                cpu_divider: 15,
+                ppu_divider: 5,
+                apu_divider: 30,
+                scanlines: 312,
+                vblank_scanlines: 20,
+                master_cycles_per_scanline: 341 * 5,
+            },
+            (Console::Snes, Region::Ntsc) => RegionProfile {
+                console,
+                region,
+                master_hz: NTSC_MASTER_HZ,
+                cpu_divider: 8,
+                ppu_divider: 4,
+                apu_divider: 21,
+                scanlines: 262,
+                vblank_scanlines: 37,
+                master_cycles_per_scanline: 1364,
+            },
+            // There is no Dendy SNES, those carts run with PAL timing
+            (Console::Snes, Region::Pal) | (Console::Snes, Region::Dendy) => RegionProfile {
+                console,
+                region: Region::Pal,
+                master_hz: SNES_PAL_MASTER_HZ,
+                cpu_divider: 8,
+                ppu_divider: 4,
+                apu_divider: 21,
+                scanlines: 312,
+                vblank_scanlines: 87,
+                master_cycles_per_scanline: 1364,
+            },
+        }
+    }
+
+    pub fn master_cycles_per_frame(&self) -> u64 {
+        self.scanlines as u64 * self.master_cycles_per_scanline
+    }
+
+    // Whole cpu cycles in a frame, rounded down (NTSC NES frames are 29780.67 cycles)
+    pub fn cpu_cycles_per_frame(&self) -> u64 {
+        self.master_cycles_per_frame() / self.cpu_divider
+    }
+
+    pub fn ppu_dots_per_frame(&self) -> u64 {
+        self.master_cycles_per_frame() / self.ppu_divider
+    }
+
+    pub fn clock_config(&self) -> ClockConfig {
+        ClockConfig {
+            master_hz: self.master_hz,
+            cpu_divider: self.cpu_divider,
+            ppu_divider: self.ppu_divider,
+            apu_divider: self.apu_divider,
+            master_cycles_per_frame: self.master_cycles_per_frame(),
+        }
+    }
+}
+
+impl Region {
+    // iNES / NES 2.0 header. Returns None when the buffer is not an iNES image.
+    pub fn from_ines_header(rom: &[u8]) -> Option<Region> {
+        if rom.len() < 16 || &rom[0..4] != b"NES\x1A" {
+            return None;
+        }
+
+        // NES 2.0 keeps the timing mode in byte 12
+        if rom[7] & 0x0C == 0x08 {
+            return Some(match rom[12] & 0x03 {
+                1 => Region::Pal,
+                3 => Region::Dendy,
+                // 0 = NTSC, 2 = multi-region which runs fine as NTSC
+                _ => Region::Ntsc,
+            });
+        }
+
+        if rom[9] & 0x01 == 1 {
+            Some(Region::Pal)
+        } else {
+            Some(Region::Ntsc)
+        }
+    }
+
+    // SNES internal header destination code. Looks for a valid header at the
+    // LoROM ($7FC0) and HiROM ($FFC0) locations, skipping a 512 byte copier header.
+    pub fn from_snes_header(rom: &[u8]) -> Option<Region> {
+        let rom = if rom.len() % 1024 == 512 {
+            &rom[512..]
+        } else {
+            rom
+        };
+
+        for &base in [0x7FC0usize, 0xFFC0].iter() {
+            if rom.len() < base + 0x20 {
+                continue;
+            }
+            let header = &rom[base..base + 0x20];
+            let complement = header[0x1C] as u16 | ((header[0x1D] as u16) << 8);
+            let checksum = header[0x1E] as u16 | ((header[0x1F] as u16) << 8);
+            if complement ^ checksum != 0xFFFF {
+                continue;
+            }
+
+            return Some(match header[0x19] {
+                // Europe, Scandinavia, France, Holland, Spain, Germany, Italy,
+                // China, Indonesia and Australia
+                0x02..=0x0C | 0x11 => Region::Pal,
+                _ => Region::Ntsc,
+            });
+        }
+        None
+    }
+
+    // Region for a cartridge: the user's choice wins, then the header, then NTSC
+    pub fn select(console: Console, rom: &[u8], user: Option<Region>) -> Region {
+        if let Some(region) = user {
+            return region;
+        }
+
+        let detected = match console {
+            Console::Nes => Region::from_ines_header(rom),
+            Console::Snes => Region::from_snes_header(rom),
+        };
+        detected.unwrap_or(Region::Ntsc)
+    }
+}
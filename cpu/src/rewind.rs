@@ -0,0 +1,198 @@
+// Rewind buffer.
+//
+// Keeps the newest full snapshot plus a ring of deltas, each the XOR of a snapshot
+// against the one captured before it, run length compressed since most of memory
+// does not change between two captures. Stepping back XORs the newest delta into
+// the newest snapshot, which yields the previous one. When the deltas outgrow the
+// memory budget the oldest are dropped, so only the oldest history is lost.
+//
+// Rewind steps back one capture at a time, or to the last capture of an earlier
+// frame. Cpu::enable_history undoes single instructions exactly and is far
+// cheaper for that.
+use crate::savestate::SaveStateError;
+use crate::system::System;
+use std::collections::VecDeque;
+
+// Rewind snapshots never leave the process, so they are not tied to a rom
+const NO_ROM: &[u8] = &[];
+
+struct Delta {
+    // Length of the older snapshot, snapshots can differ in size
+    len: usize,
+    frame: u64,
+    data: Vec<u8>,
+}
+
+pub struct Rewind {
+    pub budget: usize,
+    newest: Vec<u8>,
+    newest_frame: u64,
+    deltas: VecDeque<Delta>,
+    used: usize,
+}
+
+impl Rewind {
+    // budget: bytes of compressed deltas to keep
+    pub fn new(budget: usize) -> Self {
+        Rewind {
+            budget,
+            newest: Vec::new(),
+            newest_frame: 0,
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    // Number of states that can be stepped back to
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.used + self.newest.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest.clear();
+        self.deltas.clear();
+        self.used = 0;
+    }
+
+    // Record the current state. Call once per frame or once per instruction,
+    // depending on how finely the debugger needs to step back.
+    pub fn capture(&mut self, system: &System) {
+        let snapshot = system.save_state(NO_ROM);
+
+        if !self.newest.is_empty() {
+            let delta = Delta {
+                len: self.newest.len(),
+                frame: self.newest_frame,
+                data: compress(&xor(&self.newest, &snapshot)),
+            };
+            self.used += delta.data.len();
+            self.deltas.push_back(delta);
+
+            while self.used > self.budget {
+                match self.deltas.pop_front() {
+                    Some(oldest) => self.used -= oldest.data.len(),
+                    None => break,
+                }
+            }
+        }
+
+        self.newest = snapshot;
+        self.newest_frame = system.frame;
+    }
+
+    // Restore the state captured before the newest one, so a step is however
+    // far apart the captures were. Returns false when there is no more history.
+    pub fn step_back(&mut self, system: &mut System) -> Result<bool, SaveStateError> {
+        self.go_back(system, 1)
+    }
+
+    // Back to the newest state captured in an earlier frame than the current one
+    pub fn step_back_frame(&mut self, system: &mut System) -> Result<bool, SaveStateError> {
+        let steps = self
+            .deltas
+            .iter()
+            .rev()
+            .position(|delta| delta.frame < self.newest_frame)
+            .map_or(self.deltas.len(), |n| n + 1);
+        self.go_back(system, steps)
+    }
+
+    // Undo the newest steps deltas in one load. The history is only shortened
+    // once the system took the state, so a failed load loses nothing.
+    fn go_back(&mut self, system: &mut System, steps: usize) -> Result<bool, SaveStateError> {
+        if steps == 0 || steps > self.deltas.len() {
+            return Ok(false);
+        }
+        let mut previous = self.newest.clone();
+        let mut frame = self.newest_frame;
+        for delta in self.deltas.iter().rev().take(steps) {
+            let diff = decompress(&delta.data, delta.len.max(previous.len()));
+            previous = xor(&previous, &diff);
+            previous.truncate(delta.len);
+            frame = delta.frame;
+        }
+
+        system.load_state(&previous, NO_ROM)?;
+        for _ in 0..steps {
+            if let Some(delta) = self.deltas.pop_back() {
+                self.used -= delta.data.len();
+            }
+        }
+        self.newest = previous;
+        self.newest_frame = frame;
+        Ok(true)
+    }
+}
+
+// XOR of two buffers, the shorter one padded with zeroes
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let len = a.len().max(b.len());
+    (0..len)
+        .map(|i| a.get(i).copied().unwrap_or(0) ^ b.get(i).copied().unwrap_or(0))
+        .collect()
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: usize) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn get_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut v = 0usize;
+    let mut shift = 0;
+    while let Some(&b) = data.get(*pos) {
+        *pos += 1;
+        v |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    v
+}
+
+// Pairs of (zero run length, literal length, literal bytes)
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeroes_start = i;
+        while i < data.len() && data[i] == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        while i < data.len() && data[i] != 0 {
+            i += 1;
+        }
+        put_varint(&mut out, literal_start - zeroes_start);
+        put_varint(&mut out, i - literal_start);
+        out.extend_from_slice(&data[literal_start..i]);
+    }
+    out
+}
+
+fn decompress(data: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+    while pos < data.len() {
+        let zeroes = get_varint(data, &mut pos);
+        out.resize(out.len() + zeroes, 0);
+        let literal = get_varint(data, &mut pos);
+        let end = (pos + literal).min(data.len());
+        out.extend_from_slice(&data[pos..end]);
+        pos = end;
+    }
+    out.resize(len, 0);
+    out
+}
@@ -0,0 +1,419 @@
+// Save state format.
+//
+//   magic    "6502SAVE"
+//   version  u16
+//   rom hash u64, FNV-1a of the loaded program/cartridge
+//   chunks   4 byte tag, u32 length, payload
+//
+// Everything is little endian. States made by older versions keep loading:
+// fields they did not write yet read as zero and chunks they did not have are
+// left alone. States from a newer version than FORMAT_VERSION are refused.
+use crate::interrupt::Interrupt;
+use crate::Cpu;
+use std::fmt;
+
+pub const MAGIC: &[u8; 8] = b"6502SAVE";
+pub const FORMAT_VERSION: u16 = 1;
+
+pub const CHUNK_CPU: [u8; 4] = *b"CPU ";
+pub const CHUNK_RAM: [u8; 4] = *b"RAM ";
+pub const CHUNK_SYSTEM: [u8; 4] = *b"SYS ";
+pub const CHUNK_PPU: [u8; 4] = *b"PPU ";
+pub const CHUNK_APU: [u8; 4] = *b"APU ";
+pub const CHUNK_DMA: [u8; 4] = *b"DMA ";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SaveStateError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    RomMismatch { expected: u64, found: u64 },
+    Truncated,
+    MissingChunk([u8; 4]),
+    Invalid(&'static str),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SaveStateError::BadMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(v) => write!(
+                f,
+                "save state version {} is newer than supported version {}",
+                v, FORMAT_VERSION
+            ),
+            SaveStateError::RomMismatch { expected, found } => write!(
+                f,
+                "save state was made with rom {:016x}, loaded rom is {:016x}",
+                found, expected
+            ),
+            SaveStateError::Truncated => write!(f, "save state is truncated"),
+            SaveStateError::MissingChunk(tag) => {
+                write!(
+                    f,
+                    "save state has no {} chunk",
+                    String::from_utf8_lossy(tag)
+                )
+            }
+            SaveStateError::Invalid(what) => write!(f, "invalid save state: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for SaveStateError {}
+
+// 64 bit FNV-1a, stored in the header to refuse states made with another rom
+pub fn rom_hash(rom: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for &b in rom {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    hash
+}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    pub buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter::default()
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.buf.push(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn chunk(&mut self, tag: [u8; 4], payload: &[u8]) {
+        self.buf.extend_from_slice(&tag);
+        self.bytes(payload);
+    }
+}
+
+// Reads fields in order. Reading past the end of a chunk yields zero so fields
+// added by later versions get a default when an older state is loaded.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.pos)
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.remaining() < n {
+            self.pos = self.data.len();
+            return None;
+        }
+        let s = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Some(s)
+    }
+
+    fn array<const N: usize>(&mut self) -> [u8; N] {
+        let mut out = [0u8; N];
+        if let Some(s) = self.take(N) {
+            out.copy_from_slice(s);
+        }
+        out
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        self.array::<1>()[0]
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.array())
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.array())
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.array())
+    }
+
+    // Unlike the scalar readers a short byte string is an error, there is no
+    // sensible default for half a memory image
+    pub fn bytes(&mut self) -> Result<&'a [u8], SaveStateError> {
+        let len = self.u32() as usize;
+        self.take(len).ok_or(SaveStateError::Truncated)
+    }
+}
+
+// A parsed save state: header plus its chunks in file order
+pub struct SaveState<'a> {
+    pub version: u16,
+    pub rom_hash: u64,
+    pub chunks: Vec<([u8; 4], &'a [u8])>,
+}
+
+impl<'a> SaveState<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, SaveStateError> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(SaveStateError::BadMagic);
+        }
+
+        let mut r = StateReader::new(&data[MAGIC.len()..]);
+        if r.remaining() < 10 {
+            return Err(SaveStateError::Truncated);
+        }
+        let version = r.u16();
+        if version > FORMAT_VERSION {
+            return Err(SaveStateError::UnsupportedVersion(version));
+        }
+        let rom_hash = r.u64();
+
+        let mut chunks = Vec::new();
+        while r.remaining() > 0 {
+            if r.remaining() < 8 {
+                return Err(SaveStateError::Truncated);
+            }
+            let tag = r.array::<4>();
+            chunks.push((tag, r.bytes()?));
+        }
+
+        Ok(SaveState {
+            version,
+            rom_hash,
+            chunks,
+        })
+    }
+
+    pub fn chunk(&self, tag: [u8; 4]) -> Option<&'a [u8]> {
+        self.chunks.iter().find(|(t, _)| *t == tag).map(|(_, d)| *d)
+    }
+
+    pub fn require(&self, tag: [u8; 4]) -> Result<&'a [u8], SaveStateError> {
+        self.chunk(tag).ok_or(SaveStateError::MissingChunk(tag))
+    }
+
+    pub fn check_rom(&self, rom: &[u8]) -> Result<(), SaveStateError> {
+        let expected = rom_hash(rom);
+        if self.rom_hash != expected {
+            return Err(SaveStateError::RomMismatch {
+                expected,
+                found: self.rom_hash,
+            });
+        }
+        Ok(())
+    }
+}
+
+pub fn write_header(w: &mut StateWriter, rom: &[u8]) {
+    w.buf.extend_from_slice(MAGIC);
+    w.u16(FORMAT_VERSION);
+    w.u64(rom_hash(rom));
+}
+
+fn interrupt_to_u8(i: Option<Interrupt>) -> u8 {
+    match i {
+        None => 0,
+        Some(Interrupt::Nmi) => 1,
+        Some(Interrupt::Irq) => 2,
+    }
+}
+
+fn interrupt_from_u8(v: u8) -> Result<Option<Interrupt>, SaveStateError> {
+    match v {
+        0 => Ok(None),
+        1 => Ok(Some(Interrupt::Nmi)),
+        2 => Ok(Some(Interrupt::Irq)),
+        _ => Err(SaveStateError::Invalid("pending interrupt")),
+    }
+}
+
+impl Cpu {
+    pub fn save_registers(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.u8(self.acc);
+        w.u8(self.x);
+        w.u8(self.y);
+        w.u8(self.sp);
+        w.u16(self.pc);
+        w.u8(self.psr);
+        w.u8(self.cycles);
+        w.u8(self.opcode);
+        w.u16(self.addr_abs);
+        w.u16(self.addr_rel);
+        w.u8(self.fetched);
+        w.bytes(self.addr_mode_name.as_bytes());
+        w.u8(self.interrupts.irq_sources);
+        w.bool(self.interrupts.nmi_line);
+        w.bool(self.interrupts.nmi_pending);
+        w.u8(interrupt_to_u8(self.pending_interrupt));
+        w.bool(self.pending_vector.is_some());
+        w.u16(self.pending_vector.unwrap_or(0));
+        w.bool(self.irq_inhibit);
+        w.u8(self.poll_at);
+        w.buf
+    }
+
+    pub fn load_registers(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        Registers::parse(data)?.apply(self);
+        Ok(())
+    }
+
+    // Snapshot of the cpu and the whole bus
+    pub fn save_state(&self, rom: &[u8]) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        write_header(&mut w, rom);
+        self.write_chunks(&mut w);
+        w.buf
+    }
+
+    pub fn load_state(&mut self, data: &[u8], rom: &[u8]) -> Result<(), SaveStateError> {
+        let state = SaveState::parse(data)?;
+        state.check_rom(rom)?;
+        self.read_chunks(&state)
+    }
+
+    pub fn write_chunks(&self, w: &mut StateWriter) {
+        w.chunk(CHUNK_CPU, &self.save_registers());
+        w.chunk(CHUNK_RAM, &self.bus.borrow().ram[..]);
+    }
+
+    pub fn read_chunks(&mut self, state: &SaveState) -> Result<(), SaveStateError> {
+        let chunks = self.parse_chunks(state)?;
+        self.apply_chunks(chunks);
+        Ok(())
+    }
+
+    // Checks the cpu and ram chunks without touching the machine, so a bad state
+    // is refused before anything has been overwritten
+    pub(crate) fn parse_chunks<'a>(
+        &self,
+        state: &SaveState<'a>,
+    ) -> Result<CpuChunks<'a>, SaveStateError> {
+        let registers = Registers::parse(state.require(CHUNK_CPU)?)?;
+        let ram = state.require(CHUNK_RAM)?;
+        if ram.len() != self.bus.borrow().ram.len() {
+            return Err(SaveStateError::Invalid("memory size"));
+        }
+        Ok(CpuChunks { registers, ram })
+    }
+
+    pub(crate) fn apply_chunks(&mut self, chunks: CpuChunks) {
+        chunks.registers.apply(self);
+        self.bus.borrow_mut().ram.copy_from_slice(chunks.ram);
+    }
+}
+
+pub(crate) struct CpuChunks<'a> {
+    registers: Registers,
+    ram: &'a [u8],
+}
+
+// The contents of a cpu chunk, read in full before any of it is applied
+struct Registers {
+    acc: u8,
+    x: u8,
+    y: u8,
+    sp: u8,
+    pc: u16,
+    psr: u8,
+    cycles: u8,
+    opcode: u8,
+    addr_abs: u16,
+    addr_rel: u16,
+    fetched: u8,
+    addr_mode_name: String,
+    irq_sources: u8,
+    nmi_line: bool,
+    nmi_pending: bool,
+    pending_interrupt: Option<Interrupt>,
+    pending_vector: Option<u16>,
+    irq_inhibit: bool,
+    poll_at: u8,
+}
+
+impl Registers {
+    fn parse(data: &[u8]) -> Result<Registers, SaveStateError> {
+        let mut r = StateReader::new(data);
+        Ok(Registers {
+            acc: r.u8(),
+            x: r.u8(),
+            y: r.u8(),
+            sp: r.u8(),
+            pc: r.u16(),
+            psr: r.u8(),
+            cycles: r.u8(),
+            opcode: r.u8(),
+            addr_abs: r.u16(),
+            addr_rel: r.u16(),
+            fetched: r.u8(),
+            addr_mode_name: String::from_utf8(r.bytes()?.to_vec())
+                .map_err(|_| SaveStateError::Invalid("addressing mode name"))?,
+            irq_sources: r.u8(),
+            nmi_line: r.bool(),
+            nmi_pending: r.bool(),
+            pending_interrupt: interrupt_from_u8(r.u8())?,
+            pending_vector: {
+                let has_vector = r.bool();
+                let vector = r.u16();
+                if has_vector {
+                    Some(vector)
+                } else {
+                    None
+                }
+            },
+            irq_inhibit: r.bool(),
+            poll_at: r.u8(),
+        })
+    }
+
+    fn apply(self, cpu: &mut Cpu) {
+        cpu.acc = self.acc;
+        cpu.x = self.x;
+        cpu.y = self.y;
+        cpu.sp = self.sp;
+        cpu.pc = self.pc;
+        cpu.psr = self.psr;
+        cpu.cycles = self.cycles;
+        cpu.opcode = self.opcode;
+        cpu.addr_abs = self.addr_abs;
+        cpu.addr_rel = self.addr_rel;
+        cpu.fetched = self.fetched;
+        cpu.addr_mode_name = self.addr_mode_name;
+        cpu.interrupts.irq_sources = self.irq_sources;
+        cpu.interrupts.nmi_line = self.nmi_line;
+        cpu.interrupts.nmi_pending = self.nmi_pending;
+        cpu.pending_interrupt = self.pending_interrupt;
+        cpu.pending_vector = self.pending_vector;
+        cpu.irq_inhibit = self.irq_inhibit;
+        cpu.poll_at = self.poll_at;
+    }
+}
@@ -0,0 +1,74 @@
+use crate::{Cpu, FLAGS};
+
+// The 6502 stack lives in page one and sp wraps around within it
+pub const STACK_BASE: u16 = 0x0100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackEvent {
+    // Pushed with sp at $00, the next push overwrites $01FF
+    Overflow { pc: u16 },
+    // Pulled with sp at $FF, the value comes from $0100
+    Underflow { pc: u16 },
+}
+
+// Optional record of sp wrapping around, for catching runaway recursion or
+// unbalanced pushes and pulls
+#[derive(Debug, Clone, Default)]
+pub struct StackWatch {
+    pub enabled: bool,
+    pub events: Vec<StackEvent>,
+}
+
+impl StackWatch {
+    pub fn take_events(&mut self) -> Vec<StackEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+impl Cpu {
+    pub fn push(&mut self, data: u8) {
+        self.write(STACK_BASE | self.sp as u16, data);
+        if self.sp == 0x00 && self.stack_watch.enabled {
+            let pc = self.pc;
+            self.stack_watch.events.push(StackEvent::Overflow { pc });
+        }
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    pub fn pull(&mut self) -> u8 {
+        if self.sp == 0xFF && self.stack_watch.enabled {
+            let pc = self.pc;
+            self.stack_watch.events.push(StackEvent::Underflow { pc });
+        }
+        self.sp = self.sp.wrapping_add(1);
+        self.read(STACK_BASE | self.sp as u16)
+    }
+
+    // High byte first, so the word sits little endian in memory
+    pub fn push_word(&mut self, data: u16) {
+        self.push((data >> 8) as u8);
+        self.push((data & 0x00FF) as u8);
+    }
+
+    pub fn pull_word(&mut self) -> u16 {
+        let lo = self.pull() as u16;
+        let hi = self.pull() as u16;
+        (hi << 8) | lo
+    }
+
+    // B and U only exist on the stack: U is always set, B tells BRK/PHP
+    // apart from IRQ/NMI
+    pub fn status_for_push(&self, brk: bool) -> u8 {
+        let status = self.psr | FLAGS::u();
+        if brk {
+            status | FLAGS::b()
+        } else {
+            status & !FLAGS::b()
+        }
+    }
+
+    // Status pulled by PLP/RTI, B is ignored and U reads as set
+    pub fn pull_status(&mut self) {
+        self.psr = (self.pull() & !FLAGS::b()) | FLAGS::u();
+    }
+}
@@ -0,0 +1,355 @@
+// Labels and source line information for a program, used to show names in
+// the debuggers, resolve names in expressions and map source lines to code.
+//
+// Loaders cover the debug info of ca65/ld65 (.dbg, labels and source lines),
+// VICE label files, Mesen .mlb and bsnes/wla-dx .sym files. Bank numbers are
+// dropped, every address is taken as a 16 bit cpu address.
+use crate::expr::Symbols;
+use std::collections::HashMap;
+use std::path::Path;
+use std::{fmt, fs, io};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolFormat {
+    Ca65Dbg,
+    Vice,
+    MesenMlb,
+    BsnesSym,
+}
+
+impl SymbolFormat {
+    // From the file extension, falling back to the contents
+    pub fn detect(path: &Path, text: &str) -> Option<SymbolFormat> {
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_ascii_lowercase());
+        match ext.as_deref() {
+            Some("dbg") => return Some(SymbolFormat::Ca65Dbg),
+            Some("mlb") => return Some(SymbolFormat::MesenMlb),
+            Some("sym") => return Some(SymbolFormat::BsnesSym),
+            Some("lbl") | Some("vs") | Some("labels") => return Some(SymbolFormat::Vice),
+            _ => {}
+        }
+        let first = text.lines().map(str::trim).find(|l| !l.is_empty())?;
+        if first.starts_with("version") && first.contains("major=") {
+            Some(SymbolFormat::Ca65Dbg)
+        } else if first.starts_with("al ") {
+            Some(SymbolFormat::Vice)
+        } else if first.starts_with('[') || first.starts_with(';') {
+            Some(SymbolFormat::BsnesSym)
+        } else if first.split(':').count() >= 3 {
+            Some(SymbolFormat::MesenMlb)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(io::Error),
+    UnknownFormat,
+    // 1 based line of the symbol file
+    Parse { line: usize, message: String },
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::Io(e) => write!(f, "{}", e),
+            SymbolError::UnknownFormat => write!(f, "unrecognised symbol file format"),
+            SymbolError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+impl From<io::Error> for SymbolError {
+    fn from(e: io::Error) -> Self {
+        SymbolError::Io(e)
+    }
+}
+
+fn parse_error(line: usize, message: impl Into<String>) -> SymbolError {
+    SymbolError::Parse {
+        line: line + 1,
+        message: message.into(),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: String,
+    // 1 based
+    pub line: u32,
+    // First byte of the code generated for the line
+    pub addr: u16,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    pub labels: HashMap<String, u16>,
+    pub lines: Vec<SourceLine>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<SymbolTable, SymbolError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let format = SymbolFormat::detect(path, &text).ok_or(SymbolError::UnknownFormat)?;
+        SymbolTable::parse(format, &text)
+    }
+
+    pub fn parse(format: SymbolFormat, text: &str) -> Result<SymbolTable, SymbolError> {
+        match format {
+            SymbolFormat::Ca65Dbg => parse_ca65_dbg(text),
+            SymbolFormat::Vice => parse_vice(text),
+            SymbolFormat::MesenMlb => parse_mesen_mlb(text),
+            SymbolFormat::BsnesSym => parse_bsnes_sym(text),
+        }
+    }
+
+    // Add the labels and lines of another table, its labels winning on clashes
+    pub fn extend(&mut self, other: SymbolTable) {
+        self.labels.extend(other.labels);
+        self.lines.extend(other.lines);
+    }
+
+    pub fn add_label(&mut self, name: &str, addr: u16) {
+        self.labels.insert(name.to_string(), addr);
+    }
+
+    pub fn add_line(&mut self, file: &str, line: u32, addr: u16) {
+        self.lines.push(SourceLine {
+            file: file.to_string(),
+            line,
+            addr,
+        });
+    }
+
+    // A label for addr, the alphabetically first when several share it
+    pub fn label_at(&self, addr: u16) -> Option<&str> {
+        self.labels
+            .iter()
+            .filter(|(_, a)| **a == addr)
+            .map(|(name, _)| name.as_str())
+            .min()
+    }
+
+    // The source line whose code starts at addr
+    pub fn line_at(&self, addr: u16) -> Option<&SourceLine> {
+        self.lines.iter().find(|l| l.addr == addr)
+    }
+
+    // The first line at or after the requested one that generated code, as
+    // editors let breakpoints be set on comments and blank lines. Paths match
+    // when one is a suffix of the other, so relative paths from the assembler
+    // line up with the absolute paths editors send.
+    pub fn line_to_addr(&self, file: &str, line: u32) -> Option<&SourceLine> {
+        self.lines
+            .iter()
+            .filter(|l| l.line >= line && same_file(&l.file, file))
+            .min_by_key(|l| (l.line, l.addr))
+    }
+}
+
+impl Symbols for SymbolTable {
+    fn resolve(&self, name: &str) -> Option<u16> {
+        self.labels.get(name).copied()
+    }
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    let (a, b) = (Path::new(a), Path::new(b));
+    a.ends_with(b) || b.ends_with(a)
+}
+
+// "0x8000", "$8000" or "32768"
+fn number(text: &str) -> Option<u32> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        u32::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+// ld65 --dbgfile output. Every record is `kind key=value,...`; labels come from
+// sym records, source lines from line records via their spans and segments.
+fn parse_ca65_dbg(text: &str) -> Result<SymbolTable, SymbolError> {
+    let mut files = HashMap::new();
+    let mut segs = HashMap::new();
+    let mut spans = HashMap::new();
+    // (file id, line, span ids)
+    let mut lines = Vec::new();
+    let mut table = SymbolTable::new();
+
+    for (n, record) in text.lines().enumerate() {
+        let record = record.trim();
+        let (kind, rest) = match record.split_once(char::is_whitespace) {
+            Some(split) => split,
+            None => continue,
+        };
+        let fields = dbg_fields(rest);
+        let field = |key: &str| fields.get(key).map(String::as_str);
+        let num = |key: &str| {
+            field(key)
+                .and_then(number)
+                .ok_or_else(|| parse_error(n, format!("{} record without a valid {}", kind, key)))
+        };
+
+        match kind {
+            "file" => {
+                files.insert(num("id")?, field("name").unwrap_or("").to_string());
+            }
+            "seg" => {
+                segs.insert(num("id")?, num("start")?);
+            }
+            "span" => {
+                spans.insert(num("id")?, (num("seg")?, num("start")?));
+            }
+            // Type 2 lines are macro expansions, which would shadow the invocation
+            "line" if field("type") != Some("2") => {
+                if let Some(span) = field("span") {
+                    let ids: Vec<u32> = span.split('+').filter_map(number).collect();
+                    lines.push((num("file")?, num("line")?, ids));
+                }
+            }
+            // Imports carry no value, the exporting module's record has it
+            "sym" if field("type") != Some("imp") => {
+                if let (Some(name), Some(val)) = (field("name"), field("val").and_then(number)) {
+                    table.add_label(name, val as u16);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (file, line, ids) in lines {
+        let addr = ids
+            .iter()
+            .filter_map(|id| spans.get(id))
+            .filter_map(|(seg, start)| Some(segs.get(seg)? + start))
+            .min();
+        if let (Some(name), Some(addr)) = (files.get(&file), addr) {
+            table.add_line(name, line, addr as u16);
+        }
+    }
+    Ok(table)
+}
+
+// key=value pairs separated by commas, values may be quoted
+fn dbg_fields(text: &str) -> HashMap<String, String> {
+    let mut fields = HashMap::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (key, after) = match rest.split_once('=') {
+            Some(split) => split,
+            None => break,
+        };
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let end = quoted.find('"').unwrap_or(quoted.len());
+                let after = quoted.get(end + 1..).unwrap_or("");
+                (&quoted[..end], after.strip_prefix(',').unwrap_or(after))
+            }
+            None => match after.split_once(',') {
+                Some(split) => split,
+                None => (after, ""),
+            },
+        };
+        fields.insert(key.trim().to_string(), value.to_string());
+        rest = after;
+    }
+    fields
+}
+
+// VICE monitor labels: `al C:080d .start`, the memory space prefix optional
+fn parse_vice(text: &str) -> Result<SymbolTable, SymbolError> {
+    let mut table = SymbolTable::new();
+    for (n, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("al") | Some("add_label") => {}
+            _ => continue,
+        }
+        let (addr, name) = match (words.next(), words.next()) {
+            (Some(addr), Some(name)) => (addr, name),
+            _ => return Err(parse_error(n, "expected `al <address> <label>`")),
+        };
+        let addr = addr.rsplit(':').next().unwrap_or(addr);
+        let addr = u16::from_str_radix(addr, 16)
+            .map_err(|_| parse_error(n, format!("bad address '{}'", addr)))?;
+        table.add_label(name.trim_start_matches('.'), addr);
+    }
+    Ok(table)
+}
+
+// Mesen labels: `type:address[-end]:label[:comment]`. PRG ROM offsets are
+// placed at $8000 as on NROM boards, save and work RAM at $6000; internal RAM
+// and register labels are cpu addresses already.
+fn parse_mesen_mlb(text: &str) -> Result<SymbolTable, SymbolError> {
+    let mut table = SymbolTable::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.splitn(4, ':');
+        let (kind, addr, name) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(kind), Some(addr), Some(name)) => (kind, addr, name),
+            _ => return Err(parse_error(n, "expected `type:address:label`")),
+        };
+        // Comment only entries
+        if name.is_empty() {
+            continue;
+        }
+        let start = addr.split('-').next().unwrap_or(addr);
+        let offset = u32::from_str_radix(start, 16)
+            .map_err(|_| parse_error(n, format!("bad address '{}'", addr)))?;
+        let addr = match kind {
+            "P" | "NesPrgRom" => 0x8000 + (offset & 0x7FFF),
+            "R" | "NesInternalRam" | "G" | "NesMemory" => offset,
+            "S" | "W" | "NesSaveRam" | "NesWorkRam" => 0x6000 + (offset & 0x1FFF),
+            // CHR and other memories the cpu cannot see
+            _ => continue,
+        };
+        table.add_label(name, addr as u16);
+    }
+    Ok(table)
+}
+
+// bsnes and wla-dx symbol files: `bank:address label` lines under [labels]
+// (or [symbols] in newer versions), `;` comments
+fn parse_bsnes_sym(text: &str) -> Result<SymbolTable, SymbolError> {
+    let mut table = SymbolTable::new();
+    let mut in_labels = true;
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split(';').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            in_labels = line == "[labels]" || line == "[symbols]";
+            continue;
+        }
+        if !in_labels {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let (addr, name) = match (words.next(), words.next()) {
+            (Some(addr), Some(name)) => (addr, name),
+            _ => return Err(parse_error(n, "expected `bank:address label`")),
+        };
+        let addr = addr.rsplit(':').next().unwrap_or(addr);
+        let addr = u32::from_str_radix(addr, 16)
+            .map_err(|_| parse_error(n, format!("bad address '{}'", addr)))?;
+        table.add_label(name, addr as u16);
+    }
+    Ok(table)
+}
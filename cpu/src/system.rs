@@ -0,0 +1,489 @@
+use crate::breakpoint::BreakHit;
+use crate::bus::Bus;
+use crate::interrupt::IrqSource;
+use crate::lookup_table::LookUpTable;
+use crate::region::{Region, RegionProfile};
+use crate::rewind::Rewind;
+use crate::savestate::{
+    write_header, SaveState, SaveStateError, StateReader, StateWriter, CHUNK_APU, CHUNK_DMA,
+    CHUNK_PPU, CHUNK_SYSTEM,
+};
+use crate::Cpu;
+use std::{cell::RefCell, rc::Rc};
+
+// Anything clocked by the scheduler alongside the cpu (PPU, APU, DMA controller...)
+pub trait Device {
+    // Advance the device by one of its own clock ticks
+    fn tick(&mut self, bus: &mut Bus);
+
+    // Number of cpu cycles the device wants the cpu halted for, e.g. while a DMA
+    // transfer owns the bus. Called once per cpu cycle.
+    fn take_cpu_stall(&mut self) -> u32 {
+        0
+    }
+
+    // Level of the device's interrupt outputs, sampled after every tick
+    fn irq(&self) -> bool {
+        false
+    }
+
+    fn nmi(&self) -> bool {
+        false
+    }
+
+    // Device state for save states, empty for stateless devices
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, _data: &[u8]) -> Result<(), SaveStateError> {
+        Ok(())
+    }
+
+    // Picture output as width, height and 8 bit RGB pixels, for devices that draw
+    fn framebuffer(&self) -> Option<(u32, u32, Vec<u8>)> {
+        None
+    }
+
+    // Mono samples produced since the last call, for devices that make sound
+    fn take_samples(&mut self) -> Vec<i16> {
+        Vec::new()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        44_100
+    }
+
+    // Buttons held on a controller port, for devices that read controllers.
+    // Bits 0-7 are A, B, Select, Start, Up, Down, Left and Right.
+    fn set_buttons(&mut self, _port: usize, _buttons: u8) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    Nes,
+    Snes,
+}
+
+// How the master clock is divided between the components of a console
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockConfig {
+    pub master_hz: u32,
+    pub cpu_divider: u64,
+    pub ppu_divider: u64,
+    pub apu_divider: u64,
+    pub master_cycles_per_frame: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    Break(Vec<BreakHit>),
+    // Ran the requested number of instructions without hitting anything
+    Limit,
+    // Reached the point a step over or step out was heading for
+    Done,
+}
+
+const OP_JSR: u8 = 0x20;
+const OP_RTS: u8 = 0x60;
+const OP_RTI: u8 = 0x40;
+
+// Owns the cpu and its peripherals and advances them in lockstep against the master clock
+pub struct System {
+    pub cpu: Cpu,
+    pub bus: Rc<RefCell<Bus>>,
+    pub lookup: LookUpTable<'static>,
+    pub profile: RegionProfile,
+    pub clock: ClockConfig,
+    pub ppu: Option<Box<dyn Device>>,
+    pub apu: Option<Box<dyn Device>>,
+    pub dma: Option<Box<dyn Device>>,
+    pub master_cycles: u64,
+    pub cpu_cycles: u64,
+    pub frame: u64,
+    cpu_stall: u32,
+    // Snapshot of every frame start, see enable_rewind
+    pub rewind: Option<Rewind>,
+}
+
+impl System {
+    pub fn new(console: Console) -> Self {
+        System::with_region(console, Region::Ntsc)
+    }
+
+    pub fn with_region(console: Console, region: Region) -> Self {
+        let profile = RegionProfile::new(console, region);
+        let bus = Rc::new(RefCell::new(Bus::new()));
+        System {
+            cpu: Cpu::new(Rc::clone(&bus)),
+            bus,
+            lookup: LookUpTable::new(),
+            profile,
+            clock: profile.clock_config(),
+            ppu: None,
+            apu: None,
+            dma: None,
+            master_cycles: 0,
+            cpu_cycles: 0,
+            frame: 0,
+            cpu_stall: 0,
+            rewind: None,
+        }
+    }
+
+    // Region from the cartridge header unless the user forces one
+    pub fn for_rom(console: Console, rom: &[u8], user: Option<Region>) -> Self {
+        System::with_region(console, Region::select(console, rom, user))
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.profile = RegionProfile::new(self.profile.console, region);
+        self.clock = self.profile.clock_config();
+    }
+
+    pub fn attach_ppu(&mut self, ppu: Box<dyn Device>) {
+        self.ppu = Some(ppu);
+    }
+
+    pub fn attach_apu(&mut self, apu: Box<dyn Device>) {
+        self.apu = Some(apu);
+    }
+
+    pub fn attach_dma(&mut self, dma: Box<dyn Device>) {
+        self.dma = Some(dma);
+    }
+
+    // Hand controller state to whichever attached device reads the controllers
+    pub fn set_buttons(&mut self, port: usize, buttons: u8) {
+        for device in [&mut self.ppu, &mut self.apu, &mut self.dma].iter_mut() {
+            if let Some(device) = device.as_mut() {
+                device.set_buttons(port, buttons);
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.master_cycles = 0;
+        self.cpu_cycles = 0;
+        self.frame = 0;
+        self.cpu_stall = 0;
+        if let Some(history) = self.cpu.history.as_mut() {
+            history.steps.clear();
+        }
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.clear();
+        }
+        self.capture_rewind();
+    }
+
+    // Advance every component by a single master clock cycle.
+    // Returns true if a cpu cycle was executed on this tick.
+    pub fn tick(&mut self) -> bool {
+        let m = self.master_cycles;
+        let mut cpu_ticked = false;
+
+        if m.is_multiple_of(self.clock.cpu_divider) {
+            if let Some(dma) = self.dma.as_mut() {
+                dma.tick(&mut self.bus.borrow_mut());
+                self.cpu_stall += dma.take_cpu_stall();
+                self.cpu.interrupts.set_irq(IrqSource::Dma, dma.irq());
+            }
+
+            if self.cpu_stall > 0 {
+                self.cpu_stall -= 1;
+            } else {
+                let counters = self.counters();
+                let dispatch = self.cpu.complete();
+                self.cpu.clock(&mut self.lookup);
+                if dispatch {
+                    // The step the cpu just opened for this instruction
+                    if let Some(step) = self.cpu.history.as_mut().and_then(|h| h.steps.back_mut()) {
+                        step.clock = Some(counters);
+                    }
+                }
+                cpu_ticked = true;
+            }
+            self.cpu_cycles += 1;
+        }
+
+        if m.is_multiple_of(self.clock.ppu_divider) {
+            if let Some(ppu) = self.ppu.as_mut() {
+                ppu.tick(&mut self.bus.borrow_mut());
+                self.cpu.interrupts.set_nmi(ppu.nmi());
+                self.cpu.interrupts.set_irq(IrqSource::Ppu, ppu.irq());
+            }
+        }
+
+        if m.is_multiple_of(self.clock.apu_divider) {
+            if let Some(apu) = self.apu.as_mut() {
+                apu.tick(&mut self.bus.borrow_mut());
+                self.cpu.interrupts.set_irq(IrqSource::Apu, apu.irq());
+            }
+        }
+
+        self.master_cycles += 1;
+        if self
+            .master_cycles
+            .is_multiple_of(self.clock.master_cycles_per_frame)
+        {
+            self.frame += 1;
+            self.capture_rewind();
+        }
+
+        cpu_ticked
+    }
+
+    // Keep a snapshot of the start of every frame, the oldest dropped once
+    // they take more than budget bytes, so rewind_frames can go back
+    pub fn enable_rewind(&mut self, budget: usize) {
+        self.rewind = Some(Rewind::new(budget));
+        self.capture_rewind();
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    fn capture_rewind(&mut self) {
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.capture(self);
+            self.rewind = Some(rewind);
+        }
+    }
+
+    // Back to the start of the previous frame, up to n times. Returns how many
+    // frames were undone. The instruction history no longer applies afterwards
+    // and is cleared.
+    pub fn rewind_frames(&mut self, n: usize) -> Result<usize, SaveStateError> {
+        let mut rewind = match self.rewind.take() {
+            Some(rewind) => rewind,
+            None => return Ok(0),
+        };
+        let mut undone = 0;
+        let mut result = Ok(());
+        while undone < n {
+            match rewind.step_back_frame(self) {
+                Ok(true) => undone += 1,
+                Ok(false) => break,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+        self.rewind = Some(rewind);
+        if undone > 0 {
+            if let Some(history) = self.cpu.history.as_mut() {
+                history.steps.clear();
+            }
+        }
+        result.map(|_| undone)
+    }
+
+    fn counters(&self) -> (u64, u64, u64, u32) {
+        (
+            self.master_cycles,
+            self.cpu_cycles,
+            self.frame,
+            self.cpu_stall,
+        )
+    }
+
+    // Reverse execution, see Cpu::enable_history. Undoes up to n instructions
+    // and rewinds the scheduler counters with them.
+    pub fn step_back(&mut self, n: usize) -> usize {
+        let mut undone = 0;
+        loop {
+            let clock = match self.cpu.history.as_ref().and_then(|h| h.steps.back()) {
+                Some(step) if undone < n => step.clock,
+                _ => break,
+            };
+            self.cpu.step_back();
+            if let Some((master, cpu, frame, stall)) = clock {
+                self.master_cycles = master;
+                self.cpu_cycles = cpu;
+                self.frame = frame;
+                self.cpu_stall = stall;
+            }
+            undone += 1;
+        }
+        undone
+    }
+
+    // Run until a breakpoint or watchpoint fires, or for at most limit
+    // instructions. A breakpoint on the current pc does not stop the first
+    // instruction, so continuing from a breakpoint makes progress.
+    pub fn run_until_break(&mut self, limit: Option<u64>) -> StopReason {
+        self.cpu.take_break_hits();
+        let mut executed = 0u64;
+        loop {
+            if executed > 0 {
+                let hits = self.cpu.check_execute_breakpoints();
+                if !hits.is_empty() {
+                    return StopReason::Break(hits);
+                }
+            }
+            if limit.is_some_and(|limit| executed >= limit) {
+                return StopReason::Limit;
+            }
+
+            self.step_instruction();
+            executed += 1;
+
+            let hits = self.cpu.take_break_hits();
+            if !hits.is_empty() {
+                return StopReason::Break(hits);
+            }
+        }
+    }
+
+    // Like run_until_break, but also stop with Done once done holds after an
+    // instruction. done is given the opcode that ran, None for an interrupt sequence.
+    pub fn run_until(
+        &mut self,
+        limit: u64,
+        mut done: impl FnMut(Option<u8>, &Cpu) -> bool,
+    ) -> StopReason {
+        self.cpu.take_break_hits();
+        for executed in 0..limit {
+            if executed > 0 {
+                let hits = self.cpu.check_execute_breakpoints();
+                if !hits.is_empty() {
+                    return StopReason::Break(hits);
+                }
+            }
+            let opcode = match self.cpu.pending_interrupt {
+                Some(_) => None,
+                None => Some(self.cpu.peek(self.cpu.pc)),
+            };
+
+            self.step_instruction();
+
+            let hits = self.cpu.take_break_hits();
+            if !hits.is_empty() {
+                return StopReason::Break(hits);
+            }
+            if done(opcode, &self.cpu) {
+                return StopReason::Done;
+            }
+        }
+        StopReason::Limit
+    }
+
+    // Run the next instruction, or a whole subroutine when it is a JSR
+    pub fn step_over(&mut self, limit: u64) -> StopReason {
+        let cpu = &self.cpu;
+        if cpu.pending_interrupt.is_some() || cpu.peek(cpu.pc) != OP_JSR {
+            return self.run_until(1, |_, _| true);
+        }
+        let (ret, sp) = (cpu.pc.wrapping_add(3), cpu.sp);
+        self.run_until(limit, |_, cpu| cpu.pc == ret && cpu.sp == sp)
+    }
+
+    // Run until the current subroutine or interrupt handler returns
+    pub fn step_out(&mut self, limit: u64) -> StopReason {
+        let sp = self.cpu.sp;
+        // The stack pointer wraps, so a return is only known by sp having moved
+        // up from where it was, not by it being the larger
+        self.run_until(limit, |opcode, cpu| {
+            matches!(opcode, Some(OP_RTS) | Some(OP_RTI)) && (cpu.sp.wrapping_sub(sp) as i8) > 0
+        })
+    }
+
+    // Run for n master clock cycles
+    pub fn run_cycles(&mut self, n: u64) {
+        for _ in 0..n {
+            self.tick();
+        }
+    }
+
+    // Run until the start of the next frame
+    pub fn run_frame(&mut self) {
+        let frame = self.frame;
+        while self.frame == frame {
+            self.tick();
+        }
+    }
+
+    // Run until the instruction in flight (or the next one) has completed
+    pub fn step_instruction(&mut self) {
+        loop {
+            if self.tick() && self.cpu.complete() {
+                break;
+            }
+        }
+    }
+
+    // Snapshot of the cpu, bus, scheduler and attached devices
+    pub fn save_state(&self, rom: &[u8]) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        write_header(&mut w, rom);
+        self.cpu.write_chunks(&mut w);
+
+        let mut sys = StateWriter::new();
+        sys.u64(self.master_cycles);
+        sys.u64(self.cpu_cycles);
+        sys.u64(self.frame);
+        sys.u32(self.cpu_stall);
+        w.chunk(CHUNK_SYSTEM, &sys.buf);
+
+        for &(tag, device) in [
+            (CHUNK_PPU, &self.ppu),
+            (CHUNK_APU, &self.apu),
+            (CHUNK_DMA, &self.dma),
+        ]
+        .iter()
+        {
+            if let Some(device) = device {
+                w.chunk(tag, &device.save_state());
+            }
+        }
+        w.buf
+    }
+
+    // Everything is checked before anything is overwritten. Device states can
+    // only be checked by loading them, so devices that took theirs are put
+    // back if a later one refuses its chunk.
+    pub fn load_state(&mut self, data: &[u8], rom: &[u8]) -> Result<(), SaveStateError> {
+        let state = SaveState::parse(data)?;
+        state.check_rom(rom)?;
+        let chunks = self.cpu.parse_chunks(&state)?;
+
+        let mut sys = StateReader::new(state.require(CHUNK_SYSTEM)?);
+        let master_cycles = sys.u64();
+        let cpu_cycles = sys.u64();
+        let frame = sys.u64();
+        let cpu_stall = sys.u32();
+
+        let mut devices = [
+            (CHUNK_PPU, &mut self.ppu),
+            (CHUNK_APU, &mut self.apu),
+            (CHUNK_DMA, &mut self.dma),
+        ];
+        let mut before = Vec::new();
+        for (tag, device) in devices.iter_mut() {
+            if let (Some(device), Some(data)) = (device.as_mut(), state.chunk(*tag)) {
+                before.push(Some(device.save_state()));
+                if let Err(e) = device.load_state(data) {
+                    // A device always takes back a state it saved itself
+                    for ((_, device), data) in devices.iter_mut().zip(&before) {
+                        if let (Some(device), Some(data)) = (device.as_mut(), data) {
+                            let _ = device.load_state(data);
+                        }
+                    }
+                    return Err(e);
+                }
+            } else {
+                before.push(None);
+            }
+        }
+
+        self.cpu.apply_chunks(chunks);
+        self.master_cycles = master_cycles;
+        self.cpu_cycles = cpu_cycles;
+        self.frame = frame;
+        self.cpu_stall = cpu_stall;
+        Ok(())
+    }
+}
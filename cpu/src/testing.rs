@@ -0,0 +1,191 @@
+// Helpers for cpu tests: asm6502! to write programs as assembly and
+// TestMachine to run them.
+//
+//   let m = TestMachine::new()
+//       .program(&asm6502!("lda #$40", "adc #$40", "brk"))
+//       .flag(FLAGS::c(), true)
+//       .run();
+//   assert_eq!(m.cpu().acc, 0x81);
+//
+// Programs go at $8000 unless asm6502! is given another origin, and run from
+// there until the next instruction is a BRK, which is left unexecuted.
+use crate::asm::Assembler;
+use crate::system::{Console, System};
+use crate::Cpu;
+
+// Instructions TestMachine::run allows before giving up on reaching a BRK
+pub const RUN_LIMIT: u64 = 100_000;
+
+// Assembly lines (or one string of several lines) to the bytes from the
+// origin on, $8000 unless written as `asm6502!(org 0xC000; ...)`. Assembled at
+// run time; panics with the assembler's message on an error.
+#[macro_export]
+macro_rules! asm6502 {
+    (org $org:expr; $($line:expr),+ $(,)?) => {
+        $crate::testing::assemble_at($org, &[$($line),+])
+    };
+    ($($line:expr),+ $(,)?) => {
+        $crate::testing::assemble_at(0x8000, &[$($line),+])
+    };
+}
+
+pub fn assemble_at(origin: u16, lines: &[&str]) -> Vec<u8> {
+    let mut assembler = Assembler::new();
+    assembler.origin = origin;
+    let assembly = assembler
+        .assemble(&lines.join("\n"), "asm6502!")
+        .unwrap_or_else(|e| panic!("{}", e));
+    let (start, image) = assembly.image();
+    if image.is_empty() {
+        return image;
+    }
+    if start < origin {
+        panic!("asm6502!: code at ${:04X} is below the origin", start);
+    }
+    let mut bytes = vec![0; (start - origin) as usize];
+    bytes.extend(image);
+    bytes
+}
+
+pub struct TestMachine {
+    pub system: System,
+    // Instructions run, BRK not included
+    pub executed: u64,
+    origin: u16,
+    limit: u64,
+    a: Option<u8>,
+    x: Option<u8>,
+    y: Option<u8>,
+    sp: Option<u8>,
+    // Flags to set and clear after reset
+    set: u8,
+    clear: u8,
+}
+
+impl Default for TestMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TestMachine {
+    pub fn new() -> Self {
+        TestMachine {
+            system: System::new(Console::Nes),
+            executed: 0,
+            origin: 0x8000,
+            limit: RUN_LIMIT,
+            a: None,
+            x: None,
+            y: None,
+            sp: None,
+            set: 0,
+            clear: 0,
+        }
+    }
+
+    // Load the program at $8000, or wherever origin put it last
+    pub fn program(self, bytes: &[u8]) -> Self {
+        let origin = self.origin;
+        self.memory(origin, bytes)
+    }
+
+    // Start address for the programs loaded after it
+    pub fn origin(mut self, addr: u16) -> Self {
+        self.origin = addr;
+        self
+    }
+
+    pub fn memory(self, addr: u16, bytes: &[u8]) -> Self {
+        let at = addr as usize;
+        self.system.bus.borrow_mut().ram[at..at + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    pub fn a(mut self, value: u8) -> Self {
+        self.a = Some(value);
+        self
+    }
+
+    pub fn x(mut self, value: u8) -> Self {
+        self.x = Some(value);
+        self
+    }
+
+    pub fn y(mut self, value: u8) -> Self {
+        self.y = Some(value);
+        self
+    }
+
+    pub fn sp(mut self, value: u8) -> Self {
+        self.sp = Some(value);
+        self
+    }
+
+    // Set or clear a flag given by its FLAGS mask
+    pub fn flag(mut self, mask: u8, on: bool) -> Self {
+        if on {
+            self.set |= mask;
+            self.clear &= !mask;
+        } else {
+            self.clear |= mask;
+            self.set &= !mask;
+        }
+        self
+    }
+
+    pub fn limit(mut self, instructions: u64) -> Self {
+        self.limit = instructions;
+        self
+    }
+
+    // Reset into the program and stop before its first instruction, with the
+    // registers and flags given, for tests that drive the System themselves
+    pub fn boot(mut self) -> Self {
+        {
+            let mut bus = self.system.bus.borrow_mut();
+            bus.ram[0xFFFC..0xFFFE].copy_from_slice(&self.origin.to_le_bytes());
+        }
+        self.system.reset();
+        // Finish the reset sequence before changing registers
+        self.system.step_instruction();
+
+        let cpu = &mut self.system.cpu;
+        cpu.acc = self.a.unwrap_or(cpu.acc);
+        cpu.x = self.x.unwrap_or(cpu.x);
+        cpu.y = self.y.unwrap_or(cpu.y);
+        cpu.sp = self.sp.unwrap_or(cpu.sp);
+        cpu.psr = (cpu.psr | self.set) & !self.clear;
+        self
+    }
+
+    // Reset into the program and run it up to a BRK. Panics when the limit
+    // is reached first.
+    pub fn run(self) -> Self {
+        let mut m = self.boot();
+        while m.system.cpu.pending_interrupt.is_some() || m.peek(m.system.cpu.pc) != 0x00 {
+            if m.executed >= m.limit {
+                panic!(
+                    "no BRK after {} instructions, pc at ${:04X}",
+                    m.limit, m.system.cpu.pc
+                );
+            }
+            m.system.step_instruction();
+            m.executed += 1;
+        }
+        m
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.system.cpu
+    }
+
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.system.cpu.peek(addr)
+    }
+
+    // A flag given by its FLAGS mask
+    pub fn flag_set(&self, mask: u8) -> bool {
+        self.system.cpu.psr & mask != 0
+    }
+}
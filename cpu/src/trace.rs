@@ -0,0 +1,426 @@
+// Execution tracing.
+//
+// While a Trace is attached, Cpu::clock opens an event each time it
+// dispatches an instruction or interrupt sequence and closes it at the next
+// dispatch, so an event holds everything its instruction did: the registers
+// before and after, the effective address and every data read and write.
+// Opcode and address operand fetches are left out, being in the instruction
+// bytes already; immediate operands are read as data. Events the filter
+// rejects are dropped as they close.
+//
+// Traces are saved in a compact binary log or as text in the layout of the
+// nestest log, so runs can be diffed against each other and against logs from
+// other emulators.
+use crate::disasm::{AddrMode, Disassembler, Formatter, Standard};
+use crate::interrupt::Interrupt;
+use crate::lookup_table::LookUpTable;
+use crate::symbols::SymbolTable;
+use crate::Cpu;
+use std::collections::HashSet;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 8] = b"6502TRC\x01";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Registers {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub p: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEvent {
+    // Cpu cycles since tracing started, at dispatch
+    pub cycle: u64,
+    // Set for an interrupt sequence
+    pub interrupt: Option<Interrupt>,
+    // Opcode and operand bytes, empty for an interrupt sequence
+    pub bytes: Vec<u8>,
+    pub before: Registers,
+    pub after: Registers,
+    // Memory operand address, for modes that have one
+    pub addr: Option<u16>,
+    pub accesses: Vec<MemoryAccess>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    // Only instructions with the pc in this inclusive range
+    pub range: Option<(u16, u16)>,
+    // Only these opcodes, which leaves interrupt sequences out
+    pub opcodes: Option<HashSet<u8>>,
+    // Only from this cycle on
+    pub after_cycle: u64,
+}
+
+impl TraceFilter {
+    pub fn accepts(&self, event: &TraceEvent) -> bool {
+        let pc = event.before.pc;
+        self.range
+            .is_none_or(|(start, end)| start <= pc && pc <= end)
+            && self.opcodes.as_ref().is_none_or(|opcodes| {
+                event.interrupt.is_none() && opcodes.contains(&event.bytes[0])
+            })
+            && event.cycle >= self.after_cycle
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    pub filter: TraceFilter,
+    pub events: Vec<TraceEvent>,
+    // Cpu cycles clocked since tracing started
+    pub cycles: u64,
+    // The event of the instruction in flight
+    current: Option<TraceEvent>,
+}
+
+impl Trace {
+    pub fn new(filter: TraceFilter) -> Self {
+        Trace {
+            filter,
+            ..Trace::default()
+        }
+    }
+
+    // Close the event in flight, the cpu being in the state it left
+    fn finish(&mut self, after: Registers) {
+        if let Some(mut event) = self.current.take() {
+            event.after = after;
+            if self.filter.accepts(&event) {
+                self.events.push(event);
+            }
+        }
+    }
+}
+
+impl Cpu {
+    pub fn enable_trace(&mut self, filter: TraceFilter) {
+        self.trace = Some(Trace::new(filter));
+    }
+
+    // Stop tracing, returning the trace with the instruction in flight included
+    pub fn disable_trace(&mut self) -> Option<Trace> {
+        let registers = self.registers();
+        let mut trace = self.trace.take()?;
+        trace.finish(registers);
+        Some(trace)
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            a: self.acc,
+            x: self.x,
+            y: self.y,
+            sp: self.sp,
+            p: self.psr,
+        }
+    }
+
+    // Called on every cpu cycle, before anything else
+    pub(crate) fn trace_cycle(&mut self) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.cycles += 1;
+        }
+    }
+
+    // Called on dispatch, before the instruction or interrupt runs
+    pub(crate) fn begin_trace(&mut self, lookup: &LookUpTable) {
+        if self.trace.is_none() {
+            return;
+        }
+        let before = self.registers();
+        let interrupt = self.pending_interrupt;
+        let bytes = match interrupt {
+            Some(_) => Vec::new(),
+            None => {
+                let opcode = self.peek(self.pc);
+                let size =
+                    AddrMode::from_name(lookup.table[opcode as usize].addr_name).operand_size();
+                (0..=size)
+                    .map(|i| self.peek(self.pc.wrapping_add(i)))
+                    .collect()
+            }
+        };
+        if let Some(trace) = self.trace.as_mut() {
+            trace.finish(before);
+            trace.current = Some(TraceEvent {
+                cycle: trace.cycles - 1,
+                interrupt,
+                bytes,
+                before,
+                after: before,
+                addr: None,
+                accesses: Vec::new(),
+            });
+        }
+    }
+
+    // Called once the addressing mode has worked out the operand address
+    pub(crate) fn trace_address(&mut self) {
+        let addr = match AddrMode::from_name(&self.addr_mode_name) {
+            AddrMode::Implied
+            | AddrMode::Accumulator
+            | AddrMode::Immediate
+            | AddrMode::Relative
+            | AddrMode::Unknown => return,
+            _ => self.addr_abs,
+        };
+        if let Some(event) = self.trace.as_mut().and_then(|t| t.current.as_mut()) {
+            event.addr = Some(addr);
+        }
+    }
+
+    pub(crate) fn trace_access(&mut self, addr: u16, value: u8, write: bool) {
+        if let Some(event) = self.trace.as_mut().and_then(|t| t.current.as_mut()) {
+            event.accesses.push(MemoryAccess { addr, value, write });
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    Io(io::Error),
+    Format(String),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::Io(e) => write!(f, "{}", e),
+            TraceError::Format(message) => write!(f, "bad trace: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+impl From<io::Error> for TraceError {
+    fn from(e: io::Error) -> Self {
+        TraceError::Io(e)
+    }
+}
+
+// Binary log: an 8 byte magic, then per event
+//   flags        bits 0-1 interrupt (1 NMI, 2 IRQ), bit 2 address present,
+//                bits 3-4 instruction length
+//   cycle        LEB128 delta from the previous event
+//   registers    before, then after: pc (little endian), a, x, y, sp, p
+//   bytes        the instruction
+//   address      2 bytes, when present
+//   accesses     LEB128 count, then address, value, 1 for a write
+pub fn write_binary(out: &mut impl Write, events: &[TraceEvent]) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    let mut last = 0;
+    let mut record = Vec::new();
+    for event in events {
+        record.clear();
+        let interrupt = match event.interrupt {
+            None => 0,
+            Some(Interrupt::Nmi) => 1,
+            Some(Interrupt::Irq) => 2,
+        };
+        let flags = interrupt | (event.addr.is_some() as u8) << 2 | (event.bytes.len() as u8) << 3;
+        record.push(flags);
+        leb128(&mut record, event.cycle.wrapping_sub(last));
+        last = event.cycle;
+        for r in &[event.before, event.after] {
+            record.extend_from_slice(&r.pc.to_le_bytes());
+            record.extend_from_slice(&[r.a, r.x, r.y, r.sp, r.p]);
+        }
+        record.extend_from_slice(&event.bytes);
+        if let Some(addr) = event.addr {
+            record.extend_from_slice(&addr.to_le_bytes());
+        }
+        leb128(&mut record, event.accesses.len() as u64);
+        for access in &event.accesses {
+            record.extend_from_slice(&access.addr.to_le_bytes());
+            record.extend_from_slice(&[access.value, access.write as u8]);
+        }
+        out.write_all(&record)?;
+    }
+    Ok(())
+}
+
+pub fn read_binary(input: &mut impl Read) -> Result<Vec<TraceEvent>, TraceError> {
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+    if !data.starts_with(MAGIC) {
+        return Err(TraceError::Format("not a trace log".to_string()));
+    }
+    let mut reader = Reader {
+        data: &data,
+        pos: MAGIC.len(),
+    };
+    let mut events = Vec::new();
+    let mut cycle = 0u64;
+    while reader.pos < data.len() {
+        let flags = reader.u8()?;
+        let interrupt = match flags & 3 {
+            0 => None,
+            1 => Some(Interrupt::Nmi),
+            2 => Some(Interrupt::Irq),
+            _ => return Err(reader.error("bad interrupt")),
+        };
+        cycle = cycle.wrapping_add(reader.leb128()?);
+        let before = reader.registers()?;
+        let after = reader.registers()?;
+        let len = (flags >> 3 & 3) as usize;
+        let bytes = reader.take(len)?.to_vec();
+        let addr = match flags & 4 {
+            0 => None,
+            _ => Some(reader.u16()?),
+        };
+        let count = reader.leb128()?;
+        let mut accesses = Vec::new();
+        for _ in 0..count {
+            let addr = reader.u16()?;
+            let value = reader.u8()?;
+            let write = reader.u8()? != 0;
+            accesses.push(MemoryAccess { addr, value, write });
+        }
+        events.push(TraceEvent {
+            cycle,
+            interrupt,
+            bytes,
+            before,
+            after,
+            addr,
+            accesses,
+        });
+    }
+    Ok(events)
+}
+
+fn leb128(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &str) -> TraceError {
+        TraceError::Format(format!("{} at byte {}", message, self.pos))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], TraceError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| self.error("truncated"))?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, TraceError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, TraceError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn leb128(&mut self) -> Result<u64, TraceError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(self.error("number too long"))
+    }
+
+    fn registers(&mut self) -> Result<Registers, TraceError> {
+        let pc = self.u16()?;
+        let r = self.take(5)?;
+        Ok(Registers {
+            pc,
+            a: r[0],
+            x: r[1],
+            y: r[2],
+            sp: r[3],
+            p: r[4],
+        })
+    }
+}
+
+// One line per event in the layout of the nestest log, registers as they
+// were before the instruction, followed by its memory accesses:
+//   C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7
+pub fn write_text(
+    out: &mut impl Write,
+    events: &[TraceEvent],
+    symbols: Option<&SymbolTable>,
+) -> io::Result<()> {
+    let disassembler = Disassembler::new();
+    for event in events {
+        let r = event.before;
+        let (hex, text) = match event.interrupt {
+            Some(Interrupt::Nmi) => (String::new(), "NMI".to_string()),
+            Some(Interrupt::Irq) => (String::new(), "IRQ".to_string()),
+            None => match disassembler.decode(&event.bytes, r.pc) {
+                Some(ins) => (ins.hex_bytes(), Standard.format(&ins, symbols)),
+                None => (String::new(), "???".to_string()),
+            },
+        };
+        write!(
+            out,
+            "{:04X}  {:<8}  {:<30}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            r.pc, hex, text, r.a, r.x, r.y, r.p, r.sp, event.cycle
+        )?;
+        for access in &event.accesses {
+            let kind = if access.write { 'w' } else { 'r' };
+            write!(out, " {}:{:04X}={:02X}", kind, access.addr, access.value)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+// Index of the first event where two traces part ways, comparing what the
+// instructions did and not when. None when one trace is a prefix of the other.
+pub fn first_difference(a: &[TraceEvent], b: &[TraceEvent]) -> Option<usize> {
+    a.iter().zip(b).position(|(a, b)| {
+        (
+            &a.interrupt,
+            &a.bytes,
+            &a.before,
+            &a.after,
+            &a.addr,
+            &a.accesses,
+        ) != (
+            &b.interrupt,
+            &b.bytes,
+            &b.before,
+            &b.after,
+            &b.addr,
+            &b.accesses,
+        )
+    })
+}
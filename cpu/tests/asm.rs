@@ -0,0 +1,157 @@
+use cpu::asm::{assemble, Assembler};
+use cpu::disasm::{AddrMode, Disassembler, Formatter, Standard};
+use cpu::flow::CodeMap;
+use cpu::lookup_table::LookUpTable;
+
+#[test]
+fn every_opcode_round_trips() {
+    let disassembler = Disassembler::new();
+    for (opcode, ins) in LookUpTable::new().table.iter().enumerate() {
+        if AddrMode::from_name(ins.addr_name) == AddrMode::Unknown {
+            continue;
+        }
+        let decoded = disassembler
+            .decode(&[opcode as u8, 0x34, 0x12], 0x8000)
+            .unwrap();
+        let text = Standard.format(&decoded, None);
+        let assembly = assemble(&format!(".org $8000\n{}", text))
+            .unwrap_or_else(|e| panic!("{}: {}", text, e));
+        assert_eq!(assembly.chunks[0].bytes, decoded.bytes, "{}", text);
+    }
+}
+
+#[test]
+fn labels_expressions_and_directives() {
+    let source = r#"
+ptr = $20
+count = end - table      ; defined by later labels
+        .org $8000
+reset:  ldx #count
+@loop:  lda table-1,x    ; table is absolute
+        sta (ptr),y
+        dex
+        bne @loop
+        jmp (vector)
+other:  ldy #<other
+@loop:  ldy #>other
+        beq @loop
+        lda a:ptr
+        lda ptr+1
+        jmp *
+table:  .byte 1, $02, %11, 'A', "hi", -1
+end:
+vector: .word reset, other + 2
+"#;
+    let assembly = assemble(source).unwrap();
+    assert_eq!(assembly.chunks.len(), 1);
+    assert_eq!(assembly.chunks[0].addr, 0x8000);
+    assert_eq!(
+        assembly.chunks[0].bytes,
+        [
+            0xA2, 0x07, // ldx #count
+            0xBD, 0x1A, 0x80, // lda table-1,x
+            0x91, 0x20, // sta (ptr),y
+            0xCA, // dex
+            0xD0, 0xF8, // bne @loop
+            0x6C, 0x22, 0x80, // jmp (vector)
+            0xA0, 0x0D, // ldy #<other
+            0xA0, 0x80, // ldy #>other
+            0xF0, 0xFC, // beq @loop
+            0xAD, 0x20, 0x00, // lda a:ptr
+            0xA5, 0x21, // lda ptr+1
+            0x4C, 0x18, 0x80, // jmp *
+            0x01, 0x02, 0x03, 0x41, 0x68, 0x69, 0xFF, // table
+            0x00, 0x80, 0x0F, 0x80, // vector
+        ]
+    );
+
+    let labels = &assembly.symbols.labels;
+    assert_eq!(labels["reset"], 0x8000);
+    assert_eq!(labels["reset@loop"], 0x8002);
+    assert_eq!(labels["other@loop"], 0x800F);
+    assert_eq!(labels["count"], 7);
+    assert_eq!(labels["ptr"], 0x20);
+    let line = assembly.symbols.line_at(0x8005).unwrap();
+    assert_eq!((line.file.as_str(), line.line), ("<source>", 7));
+}
+
+#[test]
+fn org_chunks_and_includes() {
+    let dir = std::env::temp_dir().join("asm-include-test");
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(
+        dir.join("main.s"),
+        ".include \"lib/vectors.s\"\n.org $8000\nreset: rti\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("lib/vectors.s"),
+        ".org $FFFA\n.word reset, reset, reset\n",
+    )
+    .unwrap();
+    let assembly = Assembler::new().assemble_file(dir.join("main.s")).unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+
+    assert_eq!(assembly.chunks.len(), 2);
+    assert_eq!(assembly.chunks[0].addr, 0xFFFA);
+    assert_eq!(assembly.chunks[1].bytes, [0x40]);
+    let (start, image) = assembly.image();
+    assert_eq!((start, image.len()), (0x8000, 0x8000));
+    assert_eq!(&image[0x7FFA..], &[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+    assert!(assembly
+        .symbols
+        .line_at(0xFFFA)
+        .unwrap()
+        .file
+        .ends_with("vectors.s"));
+
+    let mut ram = vec![0; 0x10000];
+    assembly.load(&mut ram);
+    assert_eq!(ram[0x8000], 0x40);
+    assert_eq!(ram[0xFFFD], 0x80);
+}
+
+#[test]
+fn reports_errors() {
+    let error = |source: &str| assemble(source).unwrap_err();
+    let e = error("nop\nfoo #1\n");
+    assert_eq!((e.line, e.message.as_str()), (2, "unknown instruction foo"));
+    assert!(error("bne far\n.org $9000\nfar:")
+        .message
+        .contains("out of range"));
+    assert!(error("x: nop\nx: nop").message.contains("already defined"));
+    assert!(error("lda missing").message.contains("undefined"));
+    assert!(error("a = b\nb = a").message.contains("undefined"));
+    assert!(error("stx $1234,y").message.contains("out of range"));
+    assert!(error("jmp #1").message.contains("no IMM"));
+    assert_eq!(
+        error("lda #1 +").to_string(),
+        "<source>:1: expression ends early"
+    );
+}
+
+#[test]
+fn assembles_exported_source() {
+    // The dispatch program from the flow tests
+    let image: &[u8] = &[
+        0xA2, 0x00, 0xBD, 0x1C, 0x80, 0x48, 0xBD, 0x1A, 0x80, 0x48, 0x60, 0x02, 0xFF, 0xE8, 0x60,
+        0x20, 0x18, 0x80, 0xD0, 0xFB, 0x4C, 0x0F, 0x80, 0xEA, 0x60, 0x00, 0x0C, 0x0E, 0x80, 0x80,
+    ];
+    let source = CodeMap::analyze(image, 0x8000, &[0x8000]).to_ca65(None);
+    let assembly = assemble(&source).unwrap_or_else(|e| panic!("{}\n{}", e, source));
+    assert_eq!(assembly.image(), (0x8000, image.to_vec()));
+}
+
+#[test]
+fn expressions_wrap_instead_of_overflowing() {
+    let bytes = |source: &str| assemble(source).unwrap().chunks[0].bytes.clone();
+    let min = "(0-9223372036854775807-1)";
+    // Negating or dividing the most negative value by -1 gives it back
+    assert_eq!(bytes(&format!("lda #<(-{}+1)", min)), [0xA9, 0x01]);
+    assert_eq!(bytes(&format!("lda #<({}/-1+2)", min)), [0xA9, 0x02]);
+    assert_eq!(bytes(&format!("lda #<({}%-1+3)", min)), [0xA9, 0x03]);
+    let e = assemble(&format!("lda #-{}", min)).unwrap_err();
+    assert!(e.message.contains("out of range"), "{}", e);
+    let e = assemble(&format!("lda #{}/-1", min)).unwrap_err();
+    assert!(e.message.contains("out of range"), "{}", e);
+}
@@ -0,0 +1,93 @@
+use cpu::breakpoint::{AddrRange, BreakKind};
+use cpu::expr::Expr;
+use cpu::interrupt::{Interrupt, IrqSource};
+use cpu::system::{StopReason, System};
+use cpu::testing::TestMachine;
+use cpu::FLAGS;
+
+// loop: INC $0200, LDA $0200, CMP #$40, BNE loop, BRK
+const PROGRAM: &[u8] = &[
+    0xEE, 0x00, 0x02, 0xAD, 0x00, 0x02, 0xC9, 0x40, 0xD0, 0xF6, 0x00,
+];
+
+fn machine() -> System {
+    // IRQ handler: NOP
+    TestMachine::new()
+        .program(PROGRAM)
+        .memory(0x9000, &[0xEA])
+        .memory(0xFFFE, &[0x00, 0x90])
+        .boot()
+        .system
+}
+
+fn stopped_on(reason: StopReason) -> Vec<u32> {
+    match reason {
+        StopReason::Break(hits) => hits.iter().map(|h| h.id).collect(),
+        StopReason::Limit | StopReason::Done => Vec::new(),
+    }
+}
+
+#[test]
+fn pc_breakpoint_stops_before_the_instruction() {
+    let mut system = machine();
+    let id = system.cpu.breakpoints.add(BreakKind::Pc(0x8006));
+
+    assert_eq!(stopped_on(system.run_until_break(None)), vec![id]);
+    assert_eq!(system.cpu.pc, 0x8006);
+
+    // Continuing steps off the breakpoint and comes back round the loop
+    assert_eq!(stopped_on(system.run_until_break(None)), vec![id]);
+    assert_eq!(system.bus.borrow().ram[0x0200], 2);
+}
+
+#[test]
+fn write_watchpoint_with_hit_count() {
+    let mut system = machine();
+    let id = system
+        .cpu
+        .breakpoints
+        .add(BreakKind::Write(AddrRange::new(0x0200, 0x02FF)));
+    system.cpu.breakpoints.get_mut(id).unwrap().ignore_count = 4;
+
+    let hits = match system.run_until_break(None) {
+        StopReason::Break(hits) => hits,
+        StopReason::Limit | StopReason::Done => panic!("no hit"),
+    };
+    assert_eq!(hits[0].addr, 0x0200);
+    assert_eq!(hits[0].value, 5);
+    assert_eq!(system.cpu.breakpoints.list[0].hit_count, 5);
+}
+
+#[test]
+fn conditional_breakpoint() {
+    let mut system = machine();
+    let condition = Expr::parse("A == $20 && [$0200] > 3").unwrap();
+    let id = system
+        .cpu
+        .breakpoints
+        .add_conditional(BreakKind::Pc(0x8006), condition);
+
+    assert_eq!(stopped_on(system.run_until_break(None)), vec![id]);
+    assert_eq!(system.cpu.acc, 0x20);
+}
+
+#[test]
+fn opcode_and_interrupt_breakpoints() {
+    let mut system = machine();
+    let brk = system.cpu.breakpoints.add(BreakKind::Opcode(0x00));
+
+    assert_eq!(stopped_on(system.run_until_break(None)), vec![brk]);
+    assert_eq!(system.cpu.pc, 0x800A);
+    assert_eq!(system.bus.borrow().ram[0x0200], 0x40);
+
+    // BRK is not an interrupt sequence, a real IRQ is
+    let mut system = machine();
+    let irq = system
+        .cpu
+        .breakpoints
+        .add(BreakKind::Interrupt(Some(Interrupt::Irq)));
+    system.cpu.psr &= !FLAGS::i();
+    system.cpu.interrupts.set_irq(IrqSource::External, true);
+    assert_eq!(stopped_on(system.run_until_break(Some(10))), vec![irq]);
+    assert_eq!(system.cpu.pc, 0x9000);
+}
@@ -0,0 +1,93 @@
+use cpu::asm6502;
+use cpu::cdl::{CdlError, CodeDataLog, CODE, DATA, INDIRECT_CODE, INDIRECT_DATA};
+use cpu::testing::TestMachine;
+
+fn logged() -> CodeDataLog {
+    let program = asm6502!(
+        "        ldx #1",
+        "        lda table,x",
+        "        lda #<table2",
+        "        sta $20",
+        "        lda #>table2",
+        "        sta $21",
+        "        ldy #1",
+        "        lda ($20),y",
+        "        jmp (vector)",
+        "vector: .word target",
+        "table:  .byte 1, 2",
+        "table2: .byte 3, 4",
+        "        .byte 0, 0",
+        "target: nop",
+        "        brk"
+    );
+    let mut m = TestMachine::new().program(&program);
+    m.system
+        .cpu
+        .enable_cdl(CodeDataLog::new(0x8000, 0x4000, 0x2000));
+    let mut m = m.run();
+    m.system.cpu.disable_cdl().unwrap()
+}
+
+#[test]
+fn flags_code_and_data() {
+    let cdl = logged();
+    // ldx #1 and the operand
+    assert_eq!(cdl.prg[0], CODE);
+    assert_eq!(cdl.prg[1], CODE);
+    // table+1 read, table+0 not
+    assert_eq!(cdl.flags(0x8016), 0);
+    assert_eq!(cdl.flags(0x8017), DATA);
+    // table2+1 read through the pointer
+    assert_eq!(cdl.flags(0x8018), 0);
+    assert_eq!(cdl.flags(0x8019), DATA | INDIRECT_DATA);
+    // The vector of JMP (ind) is data, its target reached indirectly
+    assert_eq!(cdl.flags(0x8014), DATA);
+    assert_eq!(cdl.flags(0x8015), DATA);
+    assert_eq!(cdl.flags(0x801C), CODE | INDIRECT_CODE);
+    // The unexecuted BRK and padding
+    assert_eq!(cdl.flags(0x801A), 0);
+    assert_eq!(cdl.flags(0x801D), 0);
+
+    // The reset vector, read through the $E000 window of the mirrored 16K
+    assert_eq!(cdl.offset(0xFFFC), Some(0x3FFC));
+    assert_eq!(cdl.prg[0x3FFC], DATA | 0x0C);
+    assert_eq!(cdl.offset(0x7FFF), None);
+
+    let stats = cdl.stats();
+    assert_eq!(stats.code, 0x14 + 1);
+    assert_eq!(stats.data, 6);
+    assert_eq!(stats.indirect_data, 1);
+    assert_eq!(stats.unused, 0x4000 - 0x15 - 6);
+    assert_eq!(cdl.unused()[0], (0x16, 0x16));
+    assert_eq!(*cdl.unused().last().unwrap(), (0x3FFE, 0x3FFF));
+}
+
+#[test]
+fn files() {
+    let mut cdl = logged();
+    cdl.chr[0x10] = 0x01;
+    let data = cdl.save();
+    assert_eq!(data.len(), 0x6000);
+    assert_eq!(CodeDataLog::load(&data, 0x8000, 0x4000).unwrap(), cdl);
+
+    // Mesen 2 header and crc are skipped
+    let mut mesen = b"CDLv2\x12\x34\x56\x78".to_vec();
+    mesen.extend_from_slice(&data);
+    assert_eq!(CodeDataLog::load(&mesen, 0x8000, 0x4000).unwrap(), cdl);
+
+    assert_eq!(
+        CodeDataLog::load(&data, 0x8000, 0x8000),
+        Err(CdlError::SizeMismatch {
+            prg: 0x8000,
+            file: 0x6000
+        })
+    );
+
+    let mut other = CodeDataLog::new(0x8000, 0x4000, 0x2000);
+    other.mark(0xA000, CODE);
+    assert_eq!(other.prg[0x2000], CODE | 0x04);
+    other.merge(&cdl);
+    assert_eq!(other.prg[0x2000], CODE | 0x04);
+    assert_eq!(other.prg[0x19], DATA | INDIRECT_DATA);
+    assert_eq!(other.chr[0x10], 0x01);
+}
@@ -0,0 +1,133 @@
+use cpu::bus::Bus;
+use cpu::disasm::{AddrMode, Ca65, DecodedInstruction, Disassembler, Formatter, Json, Standard};
+use cpu::symbols::SymbolTable;
+use cpu::Cpu;
+use std::{cell::RefCell, rc::Rc};
+
+// One instruction of every addressing mode, at $8000
+const CODE: &[u8] = &[
+    0xEA, // nop
+    0x0A, // asl a
+    0xA9, 0x05, // lda #$05
+    0xA5, 0x10, // lda $10
+    0xB5, 0x10, // lda $10,x
+    0xB6, 0x10, // ldx $10,y
+    0xD0, 0xFE, // bne *
+    0xAD, 0x34, 0x12, // lda $1234
+    0xBD, 0x10, 0x00, // lda a:$0010,x
+    0xB9, 0x34, 0x12, // lda $1234,y
+    0x6C, 0xFC, 0xFF, // jmp ($fffc)
+    0xA1, 0x20, // lda ($20,x)
+    0xB1, 0x20, // lda ($20),y
+    0x02, // not an instruction
+];
+
+fn decoded() -> Vec<DecodedInstruction> {
+    Disassembler::new().decode_slice(CODE, 0x8000)
+}
+
+#[test]
+fn decodes_every_mode() {
+    let decoded = decoded();
+    let modes: Vec<AddrMode> = decoded.iter().map(|ins| ins.mode).collect();
+    assert_eq!(
+        modes,
+        [
+            AddrMode::Implied,
+            AddrMode::Accumulator,
+            AddrMode::Immediate,
+            AddrMode::ZeroPage,
+            AddrMode::ZeroPageX,
+            AddrMode::ZeroPageY,
+            AddrMode::Relative,
+            AddrMode::Absolute,
+            AddrMode::AbsoluteX,
+            AddrMode::AbsoluteY,
+            AddrMode::Indirect,
+            AddrMode::IndirectX,
+            AddrMode::IndirectY,
+            AddrMode::Unknown,
+        ]
+    );
+
+    let bne = &decoded[6];
+    assert_eq!((bne.addr, bne.mnemonic), (0x800A, "BNE"));
+    assert_eq!(bne.bytes, [0xD0, 0xFE]);
+    assert_eq!((bne.operand, bne.target), (Some(0xFE), Some(0x800A)));
+    assert_eq!(bne.next_addr(), 0x800C);
+    assert_eq!(decoded[7].operand, Some(0x1234));
+    assert_eq!(decoded[7].target, None);
+    assert_eq!(decoded[13].mnemonic, "???");
+
+    // A truncated instruction at the end is left out
+    assert_eq!(
+        Disassembler::new()
+            .decode_slice(&[0xEA, 0x20, 0x00], 0)
+            .len(),
+        1
+    );
+    assert!(Disassembler::new().decode(&[0xAD, 0x00], 0).is_none());
+}
+
+#[test]
+fn formats() {
+    let standard: Vec<String> = decoded().iter().map(|i| Standard.format(i, None)).collect();
+    assert_eq!(
+        standard,
+        [
+            "NOP",
+            "ASL A",
+            "LDA #$05",
+            "LDA $10",
+            "LDA $10,X",
+            "LDX $10,Y",
+            "BNE $800A",
+            "LDA $1234",
+            "LDA $0010,X",
+            "LDA $1234,Y",
+            "JMP ($FFFC)",
+            "LDA ($20,X)",
+            "LDA ($20),Y",
+            ".BYTE $02",
+        ]
+    );
+
+    let ca65: Vec<String> = decoded().iter().map(|i| Ca65.format(i, None)).collect();
+    assert_eq!(ca65[1], "asl a");
+    assert_eq!(ca65[8], "lda a:$0010,x");
+    assert_eq!(ca65[12], "lda ($20),y");
+    assert_eq!(ca65[13], ".byte $02");
+
+    let mut symbols = SymbolTable::new();
+    symbols.add_label("loop", 0x800A);
+    assert_eq!(
+        Json.format(&decoded()[6], Some(&symbols)),
+        r#"{"address":32778,"bytes":[208,254],"mnemonic":"BNE","mode":"REL","operand":254,"target":32778,"text":"BNE loop"}"#
+    );
+    assert_eq!(
+        Json.format(&decoded()[0], None),
+        r#"{"address":32768,"bytes":[234],"mnemonic":"NOP","mode":"IMP","operand":null,"target":null,"text":"NOP"}"#
+    );
+}
+
+#[test]
+fn disassembles_memory() {
+    let bus = Rc::new(RefCell::new(Bus::new()));
+    bus.borrow_mut().ram[0x8000..0x8000 + CODE.len()].copy_from_slice(CODE);
+    let cpu = Cpu::new(bus);
+    assert_eq!(
+        cpu.disassemble(0x8000, 0x8000 + CODE.len() as u16),
+        decoded()
+    );
+
+    // Operands wrap around the top of memory and decoding stops there
+    cpu.bus.borrow_mut().ram[0xFFFF] = 0x4C;
+    cpu.bus.borrow_mut().ram[0x0000..0x0002].copy_from_slice(&[0x34, 0x12]);
+    let last = cpu.disassemble(0xFFFF, 0xFFFF);
+    assert!(last.is_empty());
+    let ins = Disassembler::new().decode_range(|a| cpu.peek(a), 0xFFFE, 0xFFFF);
+    assert_eq!(ins.len(), 1);
+    let ins = Disassembler::new().decode_at(|a| cpu.peek(a), 0xFFFF);
+    assert_eq!(ins.bytes, [0x4C, 0x34, 0x12]);
+    assert_eq!(ins.target, Some(0x1234));
+}
@@ -0,0 +1,103 @@
+use cpu::bus::Bus;
+use cpu::expr::{Expr, Symbols};
+use cpu::{Cpu, FLAGS};
+use std::{cell::RefCell, rc::Rc};
+
+struct Labels;
+
+impl Symbols for Labels {
+    fn resolve(&self, name: &str) -> Option<u16> {
+        match name {
+            "player_x" => Some(0x0040),
+            "ptr" => Some(0x0010),
+            _ => None,
+        }
+    }
+}
+
+fn cpu() -> Cpu {
+    let bus = Rc::new(RefCell::new(Bus::new()));
+    {
+        let mut bus = bus.borrow_mut();
+        bus.ram[0x0010] = 0x00;
+        bus.ram[0x0011] = 0x03;
+        bus.ram[0x0040] = 0x7F;
+        bus.ram[0x0300] = 0x2A;
+    }
+    let mut cpu = Cpu::new(bus);
+    cpu.acc = 0x40;
+    cpu.x = 3;
+    cpu.pc = 0x8123;
+    cpu.psr = FLAGS::c() | FLAGS::u();
+    cpu
+}
+
+fn eval(text: &str) -> i64 {
+    Expr::parse(text)
+        .unwrap()
+        .eval_with(&cpu(), &Labels)
+        .unwrap()
+}
+
+fn error_column(text: &str) -> usize {
+    Expr::parse(text).unwrap_err().column
+}
+
+#[test]
+fn literals_registers_and_flags() {
+    assert_eq!(eval("$1F + 0x10 + %101 + 10"), 0x1F + 0x10 + 5 + 10);
+    assert_eq!(eval("a + X"), 0x43);
+    assert_eq!(eval("pc"), 0x8123);
+    assert_eq!(eval("C"), 1);
+    assert_eq!(eval("Z"), 0);
+}
+
+#[test]
+fn precedence_and_operators() {
+    assert_eq!(eval("2 + 3 * 4"), 14);
+    assert_eq!(eval("(2 + 3) * 4"), 20);
+    assert_eq!(eval("10 - 4 - 3"), 3);
+    assert_eq!(eval("1 << 4 | 1"), 0x11);
+    assert_eq!(eval("A & $F0 == $40"), 0);
+    assert_eq!(eval("(A & $F0) == $40"), 1);
+    assert_eq!(eval("7 % 4"), 3);
+    assert_eq!(eval("-1 + ~0 + !0"), -1);
+    assert_eq!(eval("X > 2 && A <= $40 || 0"), 1);
+}
+
+#[test]
+fn memory_and_symbols() {
+    assert_eq!(eval("[player_x]"), 0x7F);
+    assert_eq!(eval("{ptr}"), 0x0300);
+    assert_eq!(eval("[{ptr}]"), 0x2A);
+    assert_eq!(eval("[$003D + X]"), 0x7F);
+}
+
+#[test]
+fn errors_report_columns() {
+    assert_eq!(error_column("A == "), 6);
+    assert_eq!(error_column("[$02 > 1"), 9);
+    assert_eq!(error_column("1 + # 2"), 5);
+    assert_eq!(error_column("$ZZ + 1"), 1);
+    assert_eq!(error_column("(1 + 2"), 7);
+    assert_eq!(error_column("1 2"), 3);
+
+    let e = Expr::parse("[nowhere] + 1")
+        .unwrap()
+        .eval_with(&cpu(), &Labels)
+        .unwrap_err();
+    assert_eq!(e.column, 2);
+    assert_eq!(e.to_string(), "column 2: unknown symbol 'nowhere'");
+
+    let e = Expr::parse("A / (X - 3)")
+        .unwrap()
+        .eval(&cpu())
+        .unwrap_err();
+    assert_eq!(e.column, 3);
+}
+
+#[test]
+fn short_circuit_skips_bad_operands() {
+    assert_eq!(eval("0 && missing"), 0);
+    assert_eq!(eval("1 || 1 / 0"), 1);
+}
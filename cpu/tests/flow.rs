@@ -0,0 +1,135 @@
+use cpu::flow::{ByteKind, CodeMap};
+use cpu::symbols::SymbolTable;
+
+// Dispatch through split tables of return addresses, at $8000
+const RTS_DISPATCH: &[u8] = &[
+    0xA2, 0x00, // ldx #0
+    0xBD, 0x1C, 0x80, // lda hi,x
+    0x48, // pha
+    0xBD, 0x1A, 0x80, // lda lo,x
+    0x48, // pha
+    0x60, // rts
+    0x02, 0xFF, // never reached
+    0xE8, 0x60, // $800D: inx; rts
+    0x20, 0x18, 0x80, // $800F: jsr $8018
+    0xD0, 0xFB, // bne $800F
+    0x4C, 0x0F, 0x80, // jmp $800F
+    0xEA, // never reached
+    0x60, // $8018: rts
+    0x00, // never reached
+    0x0C, 0x0E, // $801A: lo
+    0x80, 0x80, // $801C: hi
+];
+
+#[test]
+fn follows_code_and_rts_tables() {
+    let map = CodeMap::analyze(RTS_DISPATCH, 0x8000, &[0x8000]);
+
+    let starts: Vec<u16> = map.instructions.keys().copied().collect();
+    assert_eq!(
+        starts,
+        [
+            0x8000, 0x8002, 0x8005, 0x8006, 0x8009, 0x800A, 0x800D, 0x800E, 0x800F, 0x8012, 0x8014,
+            0x8018
+        ]
+    );
+    assert_eq!(map.kind(0x800B), ByteKind::Unknown);
+    assert_eq!(map.kind(0x8017), ByteKind::Unknown);
+    assert_eq!(map.kind(0x8003), ByteKind::Operand);
+    assert_eq!(map.kind(0x801A), ByteKind::Data);
+    assert_eq!(map.kind(0x801D), ByteKind::Data);
+
+    assert_eq!(map.jump_tables.len(), 1);
+    let table = &map.jump_tables[0];
+    assert_eq!((table.jump, table.lo, table.hi), (0x800A, 0x801A, 0x801C));
+    assert_eq!((table.stride, table.adjust), (1, 1));
+    assert_eq!(table.targets, [0x800D, 0x800F]);
+
+    for (name, addr) in &[("L8000", 0x8000), ("L800D", 0x800D), ("L8018", 0x8018)] {
+        assert_eq!(map.labels.labels.get(*name), Some(addr));
+    }
+
+    let source = map.to_ca65(None);
+    let lines: Vec<&str> = source.lines().map(str::trim).collect();
+    for expected in &[
+        ".org $8000",
+        "L8000:",
+        "lda $801c,x",
+        ".byte $02,$FF",
+        "jsr L8018",
+        "bne L800F",
+        ".byte $EA",
+        ".byte <(L800D-1)",
+        ".byte >(L800F-1)",
+    ] {
+        assert!(
+            lines.contains(expected),
+            "{} missing from\n{}",
+            expected,
+            source
+        );
+    }
+}
+
+// Dispatch through a table of words with JMP (ind), reached from the vectors
+const JMP_DISPATCH: &[u8] = &[
+    0xA5, 0x10, // $FFE0: lda $10
+    0x0A, // asl a
+    0xAA, // tax
+    0xBD, 0xF1, 0xFF, // lda table,x
+    0x85, 0x00, // sta $00
+    0xBD, 0xF2, 0xFF, // lda table+1,x
+    0x85, 0x01, // sta $01
+    0x6C, 0x00, 0x00, // jmp ($0000)
+    0xF5, 0xFF, 0xF7, 0xFF, // $FFF1: table
+    0xE8, // $FFF5: inx
+    0x40, // $FFF6: rti
+    0xCA, 0x40, // $FFF7: dex; rti
+    0xFF, // never reached
+    0xF6, 0xFF, 0xE0, 0xFF, 0xF6, 0xFF, // vectors
+];
+
+#[test]
+fn follows_vectors_and_word_tables() {
+    let map = CodeMap::analyze(JMP_DISPATCH, 0xFFE0, &[]);
+    assert_eq!(map.labels.labels["reset"], 0xFFE0);
+    assert_eq!(map.labels.labels["nmi"], 0xFFF6);
+    assert_eq!(map.labels.labels["irq"], 0xFFF6);
+
+    assert_eq!(map.jump_tables.len(), 1);
+    let table = &map.jump_tables[0];
+    assert_eq!(
+        (table.lo, table.hi, table.stride, table.adjust),
+        (0xFFF1, 0xFFF2, 2, 0)
+    );
+    assert_eq!(table.targets, [0xFFF5, 0xFFF7]);
+    assert_eq!(map.kind(0xFFF5), ByteKind::Opcode);
+    assert_eq!(map.kind(0xFFF8), ByteKind::Opcode);
+    assert_eq!(map.kind(0xFFF9), ByteKind::Unknown);
+
+    let mut symbols = SymbolTable::new();
+    symbols.add_label("counter", 0x0010);
+    symbols.add_label("start", 0xFFE0);
+    let source = map.to_ca65(Some(&symbols));
+    let lines: Vec<&str> = source.lines().map(str::trim).collect();
+    for expected in &[
+        "counter = $0010",
+        "start:",
+        "lda counter",
+        "asl a",
+        "jmp ($0000)",
+        ".word LFFF5",
+        ".word LFFF7",
+        "reset:",
+        "irq:",
+        "nmi:",
+        ".byte $FF,$F6,$FF,$E0,$FF,$F6,$FF",
+    ] {
+        assert!(
+            lines.contains(expected),
+            "{} missing from\n{}",
+            expected,
+            source
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use cpu::asm6502;
+use cpu::bus::Access;
+use cpu::heatmap::AccessStats;
+use cpu::testing::TestMachine;
+use std::io;
+
+fn counted() -> AccessStats {
+    let program = asm6502!(
+        "        ldx #3",
+        "loop:   inc $10",
+        "        dex",
+        "        bne loop",
+        "        lda $10",
+        "        sta $0200",
+        "        brk"
+    );
+    let m = TestMachine::new().program(&program);
+    m.system.bus.borrow_mut().enable_stats();
+    let m = m.run();
+    let stats = m.system.bus.borrow_mut().disable_stats().unwrap();
+    stats
+}
+
+#[test]
+fn counts_accesses() {
+    let stats = counted();
+    assert_eq!(stats.reads[0x10], 4);
+    assert_eq!(stats.writes[0x10], 3);
+    assert_eq!(stats.total(0x10), 7);
+    assert_eq!(stats.last_writer[0x10], Some(0x8002));
+    assert_eq!(stats.last_writer[0x0200], Some(0x8009));
+    assert_eq!(stats.last_writer[0x0201], None);
+
+    // Opcode and operand of the loop body, not counted as reads
+    assert_eq!(stats.executes[0x8002], 3);
+    assert_eq!(stats.executes[0x8003], 3);
+    assert_eq!(stats.reads[0x8002], 0);
+    assert_eq!(stats.executes[0x8000], 1);
+    // The reset vector
+    assert_eq!(stats.reads[0xFFFC], 1);
+
+    assert_eq!(
+        stats.hottest(Access::Execute, 2),
+        vec![(0x8002, 3), (0x8003, 3)]
+    );
+    assert_eq!(
+        stats.hottest(Access::Write, 9),
+        vec![(0x10, 3), (0x0200, 1)]
+    );
+}
+
+#[test]
+fn png_heatmap() {
+    let stats = counted();
+    let mut data = Vec::new();
+    stats.write_png(&mut data, 2).unwrap();
+
+    let decoder = png::Decoder::new(io::Cursor::new(data));
+    let mut reader = decoder.read_info().unwrap();
+    let mut rgb = vec![0; reader.output_buffer_size().unwrap()];
+    let info = reader.next_frame(&mut rgb).unwrap();
+    assert_eq!((info.width, info.height), (512, 512));
+
+    let pixel = |addr: usize| {
+        let (x, y) = (addr % 256 * 2, addr / 256 * 2);
+        let at = (y * 512 + x) * 3;
+        (rgb[at], rgb[at + 1], rgb[at + 2])
+    };
+    assert_eq!(pixel(0x0000), (0, 0, 0));
+    // $10 is the busiest for reads and writes, the loop for executes
+    assert_eq!(pixel(0x0010), (255, 255, 0));
+    assert_eq!(pixel(0x8002), (0, 0, 255));
+    let (_, _, blue) = pixel(0x8000);
+    assert!((64..255).contains(&blue));
+}
@@ -0,0 +1,94 @@
+use cpu::interrupt::IrqSource;
+use cpu::system::System;
+use cpu::testing::TestMachine;
+
+// LDX #$00, loop: INX, STX $0200,X, TXA, PHA, JSR sub, BNE loop / sub: DEC $10, RTS
+const PROGRAM: &[u8] = &[
+    0xA2, 0x00, 0xE8, 0x9D, 0x00, 0x02, 0x8A, 0x48, 0x20, 0x10, 0x80, 0xD0, 0xF5, 0xEA, 0xEA, 0xEA,
+    0xC6, 0x10, 0x60,
+];
+
+fn machine() -> System {
+    // NMI and IRQ handler: RTI
+    let mut system = TestMachine::new()
+        .program(PROGRAM)
+        .memory(0x9000, &[0x40])
+        .memory(0xFFFA, &[0x00, 0x90])
+        .memory(0xFFFE, &[0x00, 0x90])
+        .boot()
+        .system;
+    system.cpu.enable_history(1000);
+    system
+}
+
+#[test]
+fn undoes_instructions_exactly() {
+    let mut system = machine();
+    let mut states = Vec::new();
+
+    for i in 0..200 {
+        // Interrupt inputs change between instructions, so the expected state
+        // is taken after driving them
+        match i {
+            50 => system.cpu.interrupts.pulse_nmi(),
+            80 => {
+                system.cpu.psr &= !0x04;
+                system.cpu.interrupts.set_irq(IrqSource::External, true);
+            }
+            81 => system.cpu.interrupts.set_irq(IrqSource::External, false),
+            _ => {}
+        }
+        states.push(system.cpu.save_state(&[]));
+        system.step_instruction();
+    }
+
+    while let Some(expected) = states.pop() {
+        let cycles = system.cpu_cycles;
+        assert_eq!(system.step_back(1), 1);
+        assert!(system.cpu_cycles < cycles);
+        assert!(
+            system.cpu.save_state(&[]) == expected,
+            "state differs after undoing to step {}",
+            states.len()
+        );
+    }
+    assert_eq!(system.step_back(1), 0);
+}
+
+#[test]
+fn history_is_bounded() {
+    let mut system = machine();
+    system.cpu.enable_history(10);
+    for _ in 0..100 {
+        system.step_instruction();
+    }
+    assert_eq!(system.step_back(50), 10);
+}
+
+// The master clock is put back to the tick the instruction was dispatched on,
+// which can be a few ticks after the previous one completed, so compare the
+// cpu cycles and frame
+#[test]
+fn counters_stay_with_their_steps() {
+    let mut system = machine();
+    let mut counters = Vec::new();
+    for _ in 0..5 {
+        counters.push((system.cpu_cycles, system.frame));
+        system.step_instruction();
+    }
+
+    // Undoing on the cpu alone does not put later steps out of line
+    assert!(system.cpu.step_back());
+    assert_eq!(system.step_back(1), 1);
+    assert_eq!((system.cpu_cycles, system.frame), counters[3]);
+
+    // Nor does starting the history over
+    system.cpu.disable_history();
+    system.step_instruction();
+    system.cpu.enable_history(1000);
+    let start = (system.cpu_cycles, system.frame);
+    system.step_instruction();
+    system.step_instruction();
+    assert_eq!(system.step_back(10), 2);
+    assert_eq!((system.cpu_cycles, system.frame), start);
+}
@@ -0,0 +1,250 @@
+use cpu::asm6502;
+use cpu::testing::TestMachine;
+use cpu::FLAGS;
+
+fn run(program: &[u8]) -> TestMachine {
+    TestMachine::new().program(program).run()
+}
+
+#[test]
+fn adc_and_sbc_set_carry_and_overflow() {
+    let m = TestMachine::new()
+        .program(&asm6502!("lda #$40", "adc #$40", "brk"))
+        .flag(FLAGS::c(), true)
+        .run();
+    assert_eq!(m.cpu().acc, 0x81);
+    assert!(m.flag_set(FLAGS::v()) && m.flag_set(FLAGS::n()));
+    assert!(!m.flag_set(FLAGS::c()));
+
+    let m = run(&asm6502!("sec", "lda #$50", "sbc #$F0", "brk"));
+    assert_eq!(m.cpu().acc, 0x60);
+    assert!(!m.flag_set(FLAGS::c()), "borrowed");
+    assert!(!m.flag_set(FLAGS::v()));
+
+    let m = run(&asm6502!("clc", "lda #$FF", "adc #$01", "brk"));
+    assert_eq!(m.cpu().acc, 0x00);
+    assert!(m.flag_set(FLAGS::z()) && m.flag_set(FLAGS::c()));
+}
+
+#[test]
+fn loops_and_compares() {
+    // 10 * 3 by repeated addition
+    let m = run(&asm6502!(
+        "        ldy #10",
+        "        lda #0",
+        "        clc",
+        "loop:   adc #3",
+        "        dey",
+        "        bne loop",
+        "        sta $02",
+        "        cmp #30",
+        "        brk",
+    ));
+    assert_eq!(m.peek(0x02), 30);
+    assert_eq!(m.cpu().y, 0);
+    assert!(m.flag_set(FLAGS::z()) && m.flag_set(FLAGS::c()));
+    assert_eq!(m.executed, 35);
+}
+
+#[test]
+fn subroutines_use_the_stack() {
+    let m = TestMachine::new()
+        .program(&asm6502!(
+            "        jsr double",
+            "        jsr double",
+            "        brk",
+            "double: asl a",
+            "        pha",
+            "        pla",
+            "        rts",
+        ))
+        .a(3)
+        .sp(0xF0)
+        .run();
+    assert_eq!(m.cpu().acc, 12);
+    assert_eq!(m.cpu().sp, 0xF0);
+    // The last return address pushed, pointing at the second JSR's last byte
+    assert_eq!((m.peek(0x01F0), m.peek(0x01EF)), (0x80, 0x05));
+}
+
+#[test]
+fn indexed_and_indirect_addressing() {
+    let m = TestMachine::new()
+        .program(&asm6502!(
+            "ptr = $20",
+            "        lda #<table",
+            "        sta ptr",
+            "        lda #>table",
+            "        sta ptr+1",
+            "        ldy #2",
+            "        lda (ptr),y",
+            "        ldx #1",
+            "        adc table,x",
+            "        sta $0300,x",
+            "        brk",
+            "table:  .byte 10, 20, 30",
+        ))
+        .flag(FLAGS::c(), false)
+        .run();
+    assert_eq!(m.cpu().acc, 50);
+    assert_eq!(m.peek(0x0301), 50);
+}
+
+#[test]
+fn shifts_rotate_through_carry() {
+    let m = TestMachine::new()
+        .program(&asm6502!(
+            "lda #%10000001",
+            "rol a",
+            "sta $10",
+            "ror $10",
+            "ror $10",
+            "brk"
+        ))
+        .flag(FLAGS::c(), false)
+        .run();
+    assert_eq!(m.cpu().acc, 0b0000_0010);
+    // The carry out of ROL goes into bit 7, then bit 0 back into the carry
+    assert_eq!(m.peek(0x10), 0b0100_0000);
+    assert!(m.flag_set(FLAGS::c()));
+}
+
+#[test]
+fn programs_can_live_elsewhere() {
+    let code = asm6502!(org 0xC000; "start: jmp next", "nop", "next: lda #<start", "brk");
+    assert_eq!(code[..3], [0x4C, 0x04, 0xC0]);
+    let m = TestMachine::new().origin(0xC000).program(&code).run();
+    assert_eq!(m.cpu().acc, 0x00);
+    assert_eq!(m.cpu().pc, 0xC006);
+}
+
+#[test]
+#[should_panic(expected = "no BRK after 100 instructions")]
+fn stops_runaway_programs() {
+    TestMachine::new()
+        .program(&asm6502!("loop: jmp loop"))
+        .limit(100)
+        .run();
+}
+
+#[test]
+fn negative_flag_follows_bit_7() {
+    for program in [
+        asm6502!("lda #$80", "brk"),
+        asm6502!("ldx #$FF", "brk"),
+        asm6502!("ldy #$90", "brk"),
+        asm6502!("lda #$F0", "and #$80", "brk"),
+        asm6502!("lda #$01", "ora #$80", "brk"),
+        asm6502!("lda #$7F", "eor #$FF", "brk"),
+        asm6502!("ldx #$7F", "inx", "brk"),
+        asm6502!("ldy #$00", "dey", "brk"),
+        asm6502!("lda #$01", "cmp #$02", "brk"),
+        asm6502!("lda #$40", "asl a", "brk"),
+        asm6502!("lda #$C0", "pha", "lda #$00", "pla", "brk"),
+    ]
+    .iter()
+    {
+        let m = run(program);
+        assert!(m.flag_set(FLAGS::n()), "{:02X?}", program);
+        assert!(!m.flag_set(FLAGS::z()), "{:02X?}", program);
+    }
+
+    // LSR always shifts a zero into bit 7
+    let m = run(&asm6502!("lda #$FF", "lsr a", "brk"));
+    assert_eq!(m.cpu().acc, 0x7F);
+    assert!(!m.flag_set(FLAGS::n()) && m.flag_set(FLAGS::c()));
+}
+
+#[test]
+fn bit_copies_bits_6_and_7() {
+    let m = run(&asm6502!(
+        "lda #$C0", "sta $10", "lda #$01", "bit $10", "brk"
+    ));
+    assert!(m.flag_set(FLAGS::n()) && m.flag_set(FLAGS::v()));
+    assert!(m.flag_set(FLAGS::z()));
+}
+
+#[test]
+fn branches_go_backwards() {
+    // The offset is signed: bmi back jumps 4 bytes before the next instruction
+    let m = run(&asm6502!(
+        "        ldx #$FE",
+        "back:   inx",
+        "        bmi back",
+        "        brk",
+    ));
+    assert_eq!(m.cpu().x, 0x00);
+    assert_eq!(m.executed, 5);
+}
+
+#[test]
+fn rol_carries_out_of_bit_7() {
+    let m = TestMachine::new()
+        .program(&asm6502!("lda #$80", "rol a", "brk"))
+        .flag(FLAGS::c(), false)
+        .run();
+    assert_eq!(m.cpu().acc, 0x00);
+    assert!(m.flag_set(FLAGS::c()) && m.flag_set(FLAGS::z()));
+}
+
+#[test]
+fn immediate_reads_the_operand_byte() {
+    let m = run(&asm6502!("lda #$12", "ldx #$34", "ldy #$56", "brk"));
+    assert_eq!((m.cpu().acc, m.cpu().x, m.cpu().y), (0x12, 0x34, 0x56));
+    // Two bytes each, stopped on the brk
+    assert_eq!(m.cpu().pc, 0x8006);
+}
+
+#[test]
+fn jmp_indirect() {
+    let m = TestMachine::new()
+        .program(&asm6502!("jmp ($0300)", "brk", "target: lda #1", "brk"))
+        .memory(0x0300, &[0x04, 0x80])
+        .run();
+    assert_eq!(m.cpu().acc, 1);
+
+    // A pointer at the end of a page takes its high byte from the start of the same page
+    let m = TestMachine::new()
+        .program(&asm6502!("jmp ($03FF)", "brk", "target: lda #1", "brk"))
+        .memory(0x0300, &[0x80])
+        .memory(0x03FF, &[0x04, 0x90])
+        .run();
+    assert_eq!(m.cpu().acc, 1);
+}
+
+#[test]
+fn logic_and_compare_results() {
+    let m = run(&asm6502!(
+        "lda #$0F", "ora #$30", "sta $10", "eor #$FF", "brk"
+    ));
+    assert_eq!(m.peek(0x10), 0x3F);
+    assert_eq!(m.cpu().acc, 0xC0);
+
+    let m = run(&asm6502!("lda #$40", "cmp #$40", "brk"));
+    assert!(m.flag_set(FLAGS::z()) && m.flag_set(FLAGS::c()));
+    let m = run(&asm6502!("lda #$40", "cmp #$41", "brk"));
+    assert!(!m.flag_set(FLAGS::z()) && !m.flag_set(FLAGS::c()));
+    assert_eq!(m.cpu().acc, 0x40);
+}
+
+// Cycles taken by the one instruction at $8000
+fn cycles(program: &[u8], x: u8) -> u64 {
+    let mut m = TestMachine::new().program(program).x(x).y(x).boot();
+    let start = m.system.cpu_cycles;
+    m.system.step_instruction();
+    m.system.cpu_cycles - start
+}
+
+#[test]
+fn page_crossings_cost_reads_a_cycle() {
+    assert_eq!(cycles(&asm6502!("lda $02F0,x"), 0x0F), 4);
+    assert_eq!(cycles(&asm6502!("lda $02F0,x"), 0x10), 5);
+    assert_eq!(cycles(&asm6502!("ora $02F0,y"), 0x10), 5);
+    assert_eq!(cycles(&asm6502!("eor $02F0,x"), 0x10), 5);
+    assert_eq!(cycles(&asm6502!("cmp $02F0,x"), 0x10), 5);
+    // Stores always take the extra cycle, whether or not they cross
+    assert_eq!(cycles(&asm6502!("sta $02F0,x"), 0x0F), 5);
+    assert_eq!(cycles(&asm6502!("sta $02F0,x"), 0x10), 5);
+    // Only indexed modes can cross
+    assert_eq!(cycles(&asm6502!("lda #$10"), 0x10), 2);
+}
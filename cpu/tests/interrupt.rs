@@ -0,0 +1,143 @@
+use cpu::bus::Bus;
+use cpu::interrupt::IrqSource;
+use cpu::lookup_table::LookUpTable;
+use cpu::{Cpu, FLAGS};
+use std::{cell::RefCell, rc::Rc};
+
+// Program at $8000, NMI handler at $A000, IRQ/BRK handler at $9000, both filled with NOPs
+fn machine(program: &[u8]) -> (Cpu, LookUpTable<'static>) {
+    let bus = Rc::new(RefCell::new(Bus::new()));
+    {
+        let mut bus = bus.borrow_mut();
+        for b in bus.ram[0x8000..0xB000].iter_mut() {
+            *b = 0xEA;
+        }
+        bus.ram[0x8000..0x8000 + program.len()].copy_from_slice(program);
+        bus.ram[0xFFFA] = 0x00;
+        bus.ram[0xFFFB] = 0xA0;
+        bus.ram[0xFFFC] = 0x00;
+        bus.ram[0xFFFD] = 0x80;
+        bus.ram[0xFFFE] = 0x00;
+        bus.ram[0xFFFF] = 0x90;
+    }
+    let mut cpu = Cpu::new(bus);
+    let mut lookup = LookUpTable::new();
+    cpu.reset();
+    step(&mut cpu, &mut lookup);
+    (cpu, lookup)
+}
+
+fn step(cpu: &mut Cpu, lookup: &mut LookUpTable) {
+    loop {
+        cpu.clock(lookup);
+        if cpu.complete() {
+            break;
+        }
+    }
+}
+
+#[test]
+fn irq_is_level_triggered_and_masked_by_i() {
+    let (mut cpu, mut lookup) = machine(&[0xEA, 0xEA]);
+    cpu.interrupts.set_irq(IrqSource::Apu, true);
+
+    // I is set on reset
+    step(&mut cpu, &mut lookup);
+    step(&mut cpu, &mut lookup);
+    assert_eq!(cpu.pc, 0x8002);
+
+    cpu.interrupts.set_irq(IrqSource::Mapper, true);
+    cpu.interrupts.set_irq(IrqSource::Apu, false);
+    assert!(cpu.interrupts.irq_line());
+    cpu.interrupts.set_irq(IrqSource::Mapper, false);
+    assert!(!cpu.interrupts.irq_line());
+}
+
+#[test]
+fn cli_delays_irq_by_one_instruction() {
+    let (mut cpu, mut lookup) = machine(&[0x58, 0xEA, 0xEA]);
+    cpu.interrupts.set_irq(IrqSource::External, true);
+
+    step(&mut cpu, &mut lookup); // CLI
+    step(&mut cpu, &mut lookup); // NOP still runs
+    assert_eq!(cpu.pc, 0x8002);
+
+    step(&mut cpu, &mut lookup); // IRQ sequence
+    assert_eq!(cpu.pc, 0x9000);
+    assert_eq!(cpu.get_flag(FLAGS::i()), 1);
+
+    let bus = cpu.bus.borrow();
+    assert_eq!(bus.ram[0x01FD], 0x80);
+    assert_eq!(bus.ram[0x01FC], 0x02);
+    assert_eq!(bus.ram[0x01FB] & FLAGS::b(), 0);
+}
+
+#[test]
+fn sei_still_lets_a_pending_irq_through() {
+    let (mut cpu, mut lookup) = machine(&[0x58, 0x78, 0xEA]);
+    step(&mut cpu, &mut lookup); // CLI
+    cpu.interrupts.set_irq(IrqSource::External, true);
+
+    step(&mut cpu, &mut lookup); // SEI polls with I still clear
+    step(&mut cpu, &mut lookup);
+    assert_eq!(cpu.pc, 0x9000);
+}
+
+#[test]
+fn nmi_is_edge_triggered() {
+    let (mut cpu, mut lookup) = machine(&[0xEA, 0xEA]);
+    cpu.interrupts.set_nmi(true);
+
+    step(&mut cpu, &mut lookup); // NOP polls the latch
+    step(&mut cpu, &mut lookup); // NMI sequence
+    assert_eq!(cpu.pc, 0xA000);
+    let sp = cpu.sp;
+
+    // Holding the line does not retrigger
+    for _ in 0..4 {
+        step(&mut cpu, &mut lookup);
+    }
+    assert_eq!(cpu.pc, 0xA004);
+    assert_eq!(cpu.sp, sp);
+
+    cpu.interrupts.set_nmi(false);
+    cpu.interrupts.set_nmi(true);
+    step(&mut cpu, &mut lookup);
+    step(&mut cpu, &mut lookup);
+    assert_eq!(cpu.pc, 0xA000);
+    assert_eq!(cpu.sp, sp.wrapping_sub(3));
+}
+
+#[test]
+fn taken_branch_without_page_cross_delays_irq() {
+    // CLI, NOP, BNE +0 (Z clear after reset), NOP
+    let (mut cpu, mut lookup) = machine(&[0x58, 0xEA, 0xD0, 0x00, 0xEA]);
+    step(&mut cpu, &mut lookup); // CLI
+    step(&mut cpu, &mut lookup); // NOP
+
+    // Raised after the branch's early poll but before its last cycle
+    cpu.clock(&mut lookup);
+    cpu.interrupts.set_irq(IrqSource::External, true);
+    cpu.clock(&mut lookup);
+    cpu.clock(&mut lookup);
+    assert!(cpu.complete());
+    assert_eq!(cpu.pc, 0x8004);
+
+    step(&mut cpu, &mut lookup); // NOP runs first
+    assert_eq!(cpu.pc, 0x8005);
+    step(&mut cpu, &mut lookup);
+    assert_eq!(cpu.pc, 0x9000);
+}
+
+#[test]
+fn nmi_hijacks_brk() {
+    let (mut cpu, mut lookup) = machine(&[0x00, 0x00]);
+    cpu.clock(&mut lookup);
+    cpu.interrupts.pulse_nmi();
+    step(&mut cpu, &mut lookup);
+
+    assert_eq!(cpu.pc, 0xA000);
+    assert!(!cpu.interrupts.nmi_pending);
+    // The pushed status still says BRK
+    assert_ne!(cpu.bus.borrow().ram[0x01FB] & FLAGS::b(), 0);
+}
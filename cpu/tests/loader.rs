@@ -0,0 +1,109 @@
+use cpu::bus::Bus;
+use cpu::loader::{Format, LoadError, Program};
+use std::path::Path;
+
+// lda #1, sta $10, brk at $8000
+const HEX: &str = "\
+:020000040000FA
+:04800000A90185103D
+:01800400007B
+:040000050000800077
+:00000001FF
+";
+
+const SREC: &str = "\
+S007000064656D6F53
+S1078000A901851039
+S2050080040076
+S5030002FA
+S90380007C
+";
+
+fn expected() -> Program {
+    Program {
+        chunks: vec![(0x8000, vec![0xA9, 0x01, 0x85, 0x10, 0x00])],
+        start: Some(0x8000),
+    }
+}
+
+#[test]
+fn reads_text_formats() {
+    assert_eq!(Program::intel_hex(HEX).unwrap(), expected());
+    assert_eq!(Program::srecord(SREC).unwrap(), expected());
+
+    // Extended linear address $0001 puts the data past $FFFF
+    let high = HEX.replacen(":020000040000FA", ":020000040001F9", 1);
+    assert!(matches!(
+        Program::intel_hex(&high),
+        Err(LoadError::OutOfRange {
+            addr: 0x18000,
+            len: 4
+        })
+    ));
+
+    let bad = HEX.replacen("3D", "3E", 1);
+    let err = Program::intel_hex(&bad).unwrap_err();
+    assert_eq!(err.to_string(), "line 2: checksum is $3E, expected $3D");
+    let bad = SREC.replacen("39", "00", 1);
+    assert!(matches!(
+        Program::srecord(&bad),
+        Err(LoadError::Checksum {
+            line: 2,
+            expected: 0x39,
+            found: 0x00
+        })
+    ));
+    assert!(matches!(
+        Program::srecord("S1078000A90185"),
+        Err(LoadError::Parse { line: 1, .. })
+    ));
+}
+
+#[test]
+fn reads_binary_formats() {
+    let program = Program::raw(&[1, 2, 3], 0x0300).unwrap();
+    assert_eq!(program.chunks, vec![(0x0300, vec![1, 2, 3])]);
+    assert_eq!(program.entry(), Some(0x0300));
+
+    let program = Program::c64_prg(&[0x01, 0x08, 0x0B, 0x08]).unwrap();
+    assert_eq!(program.chunks, vec![(0x0801, vec![0x0B, 0x08])]);
+    assert!(Program::c64_prg(&[0x01]).is_err());
+
+    let err = Program::raw(&[0; 0x20], 0xFFF0).unwrap_err();
+    assert_eq!(err.to_string(), "32 bytes at $FFF0 run past $FFFF");
+}
+
+#[test]
+fn rejects_overlaps_and_sets_reset_vector() {
+    let mut program = Program::raw(&[0xEA; 0x10], 0x8000).unwrap();
+    program.add(0x8010, &[0x00]).unwrap();
+    assert_eq!(program.chunks.len(), 1);
+    let err = program.add(0x800F, &[0, 0]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "2 bytes at $800F overlap the data already loaded at $8000"
+    );
+
+    program.set_reset_vector(0x8000).unwrap();
+    assert!(program.covers(0xFFFD));
+    assert!(program.set_reset_vector(0x9000).is_err());
+
+    let mut bus = Bus::new();
+    program.write(&mut bus);
+    assert_eq!(bus.ram[0x8010], 0x00);
+    assert_eq!(bus.ram[0x800F], 0xEA);
+    assert_eq!(&bus.ram[0xFFFC..], &[0x00, 0x80, 0x00, 0x00]);
+    assert_eq!(program.len(), 0x13);
+}
+
+#[test]
+fn detects_formats() {
+    let detect = |name: &str, data: &[u8]| Format::detect(Path::new(name), data);
+    assert_eq!(detect("a.ihx", b""), Format::IntelHex);
+    assert_eq!(detect("a.S19", b""), Format::SRecord);
+    assert_eq!(detect("a.prg", b""), Format::C64Prg);
+    assert_eq!(detect("a.txt", HEX.as_bytes()), Format::IntelHex);
+    assert_eq!(detect("a.txt", SREC.as_bytes()), Format::SRecord);
+    assert_eq!(detect("a.bin", b":\x00\xff"), Format::Raw);
+    assert_eq!(detect("a.bin", b"S\x01"), Format::Raw);
+}
@@ -0,0 +1,114 @@
+use cpu::asm6502;
+use cpu::profile::{CallStats, Profiler, RoutineStats};
+use cpu::symbols::SymbolTable;
+use cpu::testing::TestMachine;
+
+// Profile from $8001 to the next BRK, the program starting with one so the
+// TestMachine stops right after reset
+fn profile(m: TestMachine) -> Profiler {
+    let mut m = m.run();
+    m.system.cpu.pc = 0x8001;
+    m.system.cpu.enable_profiler();
+    while m.peek(m.system.cpu.pc) != 0x00 {
+        m.system.step_instruction();
+    }
+    m.system.cpu.disable_profiler().unwrap()
+}
+
+#[test]
+fn routines_and_call_graph() {
+    let program = asm6502!(
+        "        brk",
+        "main:   jsr a",
+        "        jsr b",
+        "        brk",
+        "a:      ldx #3",
+        "loop:   jsr b",
+        "        dex",
+        "        bne loop",
+        "        rts",
+        "b:      nop",
+        "        rts"
+    );
+    let profiler = profile(TestMachine::new().program(&program));
+    let (main, a, b) = (0x8001, 0x8008, 0x8011);
+
+    let stats = |calls, inclusive, exclusive| RoutineStats {
+        calls,
+        inclusive,
+        exclusive,
+    };
+    assert_eq!(profiler.cycles, 84);
+    assert_eq!(profiler.routines[&main], stats(1, 84, 12));
+    assert_eq!(profiler.routines[&a], stats(1, 64, 40));
+    assert_eq!(profiler.routines[&b], stats(4, 32, 32));
+    assert_eq!(
+        profiler.calls[&(a, b)],
+        CallStats {
+            calls: 3,
+            cycles: 24
+        }
+    );
+    assert_eq!(profiler.calls[&(main, b)].cycles, 8);
+
+    let mut symbols = SymbolTable::new();
+    symbols.add_label("main", main);
+    symbols.add_label("a", a);
+    let folded = profiler.folded(Some(&symbols));
+    assert_eq!(
+        folded,
+        "main 12\nmain;$8011 8\nmain;a 40\nmain;a;$8011 24\n"
+    );
+
+    let report = profiler.report(Some(&symbols), 2);
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[1].starts_with("a "), "{}", report);
+    assert!(lines[1].ends_with("47.62%"), "{}", report);
+    assert!(profiler
+        .call_graph(Some(&symbols))
+        .starts_with("main -> a  1 calls, 64 cycles\n"));
+}
+
+#[test]
+fn interrupts_and_dropped_returns() {
+    let program = asm6502!(
+        "        brk",
+        "main:   jsr outer",
+        "        nop",
+        "        nop",
+        "        brk",
+        "outer:  jsr inner",
+        "        rts",
+        "inner:  pla",
+        "        pla",
+        "        rts"
+    );
+    let mut m = TestMachine::new()
+        .program(&program)
+        .memory(0x9000, &asm6502!(org 0x9000; "rti"))
+        .memory(0xFFFA, &[0x00, 0x90])
+        .run();
+    m.system.cpu.pc = 0x8001;
+    m.system.cpu.enable_profiler();
+    m.system.step_instruction();
+    m.system.cpu.interrupts.pulse_nmi();
+    while m.peek(m.system.cpu.pc) != 0x00 {
+        m.system.step_instruction();
+    }
+    let profiler = m.system.cpu.profiler.as_ref().unwrap();
+    // inner returned straight to main, taking outer with it
+    assert_eq!(profiler.stack(), vec![0x8001]);
+    assert_eq!(profiler.routines[&0x9000].calls, 1);
+    assert!(profiler
+        .stacks
+        .keys()
+        .any(|stack| stack.last() == Some(&0x9000)));
+
+    let profiler = m.system.cpu.disable_profiler().unwrap();
+    let main = profiler.routines[&0x8001];
+    assert_eq!(main.inclusive, profiler.cycles);
+    let exclusive: u64 = profiler.routines.values().map(|r| r.exclusive).sum();
+    assert_eq!(exclusive, profiler.cycles);
+    assert_eq!(profiler.stacks.values().sum::<u64>(), profiler.cycles);
+}
@@ -0,0 +1,126 @@
+use cpu::region::{Region, RegionProfile};
+use cpu::system::{Console, System};
+
+// JMP $8000 forever, so a whole frame can run on an otherwise empty bus
+fn spin_system(console: Console, region: Region) -> System {
+    let mut system = System::with_region(console, region);
+    {
+        let mut bus = system.bus.borrow_mut();
+        bus.ram[0x8000..0x8003].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        bus.ram[0xFFFC] = 0x00;
+        bus.ram[0xFFFD] = 0x80;
+    }
+    system.reset();
+    system
+}
+
+#[test]
+fn nes_cycles_per_frame() {
+    let ntsc = RegionProfile::new(Console::Nes, Region::Ntsc);
+    assert_eq!(ntsc.master_cycles_per_frame(), 357_368);
+    assert_eq!(ntsc.cpu_cycles_per_frame(), 29_780);
+    assert_eq!(ntsc.ppu_dots_per_frame(), 262 * 341);
+
+    let pal = RegionProfile::new(Console::Nes, Region::Pal);
+    assert_eq!(pal.master_cycles_per_frame(), 531_960);
+    assert_eq!(pal.cpu_cycles_per_frame(), 33_247);
+    assert_eq!(pal.ppu_dots_per_frame(), 312 * 341);
+
+    let dendy = RegionProfile::new(Console::Nes, Region::Dendy);
+    assert_eq!(dendy.master_cycles_per_frame(), 531_960);
+    assert_eq!(dendy.cpu_cycles_per_frame(), 35_464);
+    assert_eq!(dendy.ppu_dots_per_frame(), 312 * 341);
+}
+
+#[test]
+fn snes_cycles_per_frame() {
+    let ntsc = RegionProfile::new(Console::Snes, Region::Ntsc);
+    assert_eq!(ntsc.master_cycles_per_frame(), 357_368);
+    assert_eq!(ntsc.cpu_cycles_per_frame(), 44_671);
+
+    let pal = RegionProfile::new(Console::Snes, Region::Pal);
+    assert_eq!(pal.master_cycles_per_frame(), 425_568);
+    assert_eq!(pal.cpu_cycles_per_frame(), 53_196);
+
+    assert_eq!(
+        RegionProfile::new(Console::Snes, Region::Dendy).region,
+        Region::Pal
+    );
+}
+
+#[test]
+fn run_frame_matches_profile() {
+    for &(console, region) in [
+        (Console::Nes, Region::Ntsc),
+        (Console::Nes, Region::Pal),
+        (Console::Nes, Region::Dendy),
+        (Console::Snes, Region::Ntsc),
+        (Console::Snes, Region::Pal),
+    ]
+    .iter()
+    {
+        let mut system = spin_system(console, region);
+        let profile = system.profile;
+
+        system.run_frame();
+        assert_eq!(system.frame, 1);
+        assert_eq!(system.master_cycles, profile.master_cycles_per_frame());
+
+        system.run_frame();
+        assert_eq!(system.master_cycles, 2 * profile.master_cycles_per_frame());
+        assert_eq!(
+            system.cpu_cycles,
+            (2 * profile.master_cycles_per_frame()).div_ceil(profile.cpu_divider)
+        );
+    }
+}
+
+#[test]
+fn ines_header_detection() {
+    let mut header = [0u8; 16];
+    header[0..4].copy_from_slice(b"NES\x1A");
+    assert_eq!(Region::from_ines_header(&header), Some(Region::Ntsc));
+
+    header[9] = 0x01;
+    assert_eq!(Region::from_ines_header(&header), Some(Region::Pal));
+
+    // NES 2.0 timing byte takes precedence
+    header[7] = 0x08;
+    header[12] = 0x03;
+    assert_eq!(Region::from_ines_header(&header), Some(Region::Dendy));
+
+    assert_eq!(Region::from_ines_header(&[0u8; 16]), None);
+}
+
+#[test]
+fn snes_header_detection() {
+    let mut rom = vec![0u8; 0x8000];
+    rom[0x7FDC] = 0xFF;
+    rom[0x7FDD] = 0xFF;
+    rom[0x7FD9] = 0x02;
+    assert_eq!(Region::from_snes_header(&rom), Some(Region::Pal));
+
+    rom[0x7FD9] = 0x01;
+    assert_eq!(Region::from_snes_header(&rom), Some(Region::Ntsc));
+
+    // Invalid checksum complement, no header found
+    rom[0x7FDC] = 0x00;
+    assert_eq!(Region::from_snes_header(&rom), None);
+}
+
+#[test]
+fn user_override_wins() {
+    let mut header = [0u8; 16];
+    header[0..4].copy_from_slice(b"NES\x1A");
+    header[9] = 0x01;
+
+    assert_eq!(Region::select(Console::Nes, &header, None), Region::Pal);
+    assert_eq!(
+        Region::select(Console::Nes, &header, Some(Region::Ntsc)),
+        Region::Ntsc
+    );
+    assert_eq!(Region::select(Console::Nes, &[], None), Region::Ntsc);
+
+    let system = System::for_rom(Console::Nes, &header, None);
+    assert_eq!(system.profile.region, Region::Pal);
+}
@@ -0,0 +1,139 @@
+use cpu::bus::Bus;
+use cpu::rewind::Rewind;
+use cpu::savestate::SaveStateError;
+use cpu::system::{Device, System};
+use cpu::testing::TestMachine;
+
+// INX, STX $10, JMP $8000
+fn machine() -> System {
+    TestMachine::new()
+        .program(&[0xE8, 0x86, 0x10, 0x4C, 0x00, 0x80])
+        .boot()
+        .system
+}
+
+#[test]
+fn steps_back_a_capture_at_a_time() {
+    let mut system = machine();
+    let mut rewind = Rewind::new(1 << 20);
+    rewind.capture(&system);
+
+    let mut trail = Vec::new();
+    for _ in 0..30 {
+        trail.push((system.cpu.pc, system.cpu.x, system.bus.borrow().ram[0x10]));
+        system.step_instruction();
+        rewind.capture(&system);
+    }
+
+    while let Some(expected) = trail.pop() {
+        assert!(rewind.step_back(&mut system).unwrap());
+        assert_eq!(
+            (system.cpu.pc, system.cpu.x, system.bus.borrow().ram[0x10]),
+            expected
+        );
+    }
+    assert!(!rewind.step_back(&mut system).unwrap());
+}
+
+#[test]
+fn steps_back_by_frame() {
+    let mut system = machine();
+    let mut rewind = Rewind::new(1 << 20);
+    rewind.capture(&system);
+
+    let mut starts = Vec::new();
+    for _ in 0..3 {
+        starts.push(system.master_cycles);
+        system.run_frame();
+        rewind.capture(&system);
+    }
+    assert_eq!(system.frame, 3);
+
+    assert!(rewind.step_back_frame(&mut system).unwrap());
+    assert_eq!(system.frame, 2);
+    assert!(rewind.step_back_frame(&mut system).unwrap());
+    assert!(rewind.step_back_frame(&mut system).unwrap());
+    assert_eq!(system.master_cycles, starts[0]);
+}
+
+#[test]
+fn respects_memory_budget() {
+    let mut system = machine();
+    let mut rewind = Rewind::new(1024);
+    for _ in 0..1000 {
+        system.step_instruction();
+        rewind.capture(&system);
+    }
+    assert!(rewind.len() < 1000);
+    assert!(rewind.memory_used() <= 1024 + system.save_state(&[]).len());
+    // Every delta is tiny, a few registers and one byte of memory
+    assert!(rewind.len() > 5);
+}
+
+// Saves a state but refuses to take one back
+struct Stubborn;
+
+impl Device for Stubborn {
+    fn tick(&mut self, _bus: &mut Bus) {}
+
+    fn save_state(&self) -> Vec<u8> {
+        vec![0]
+    }
+
+    fn load_state(&mut self, _data: &[u8]) -> Result<(), SaveStateError> {
+        Err(SaveStateError::Invalid("stubborn"))
+    }
+}
+
+#[test]
+fn failed_loads_keep_the_history() {
+    let mut system = machine();
+    system.attach_ppu(Box::new(Stubborn));
+    let mut rewind = Rewind::new(1 << 20);
+    rewind.capture(&system);
+    for _ in 0..2 {
+        system.run_frame();
+        rewind.capture(&system);
+    }
+    let x = system.cpu.x;
+
+    let err = SaveStateError::Invalid("stubborn");
+    assert_eq!(rewind.step_back(&mut system), Err(err.clone()));
+    assert_eq!(rewind.step_back_frame(&mut system), Err(err));
+    assert_eq!(rewind.len(), 2);
+    assert_eq!(system.cpu.x, x);
+
+    // Once the device goes, the same history still loads
+    system.ppu = None;
+    assert!(rewind.step_back_frame(&mut system).unwrap());
+    assert_eq!(system.frame, 1);
+    assert!(rewind.step_back(&mut system).unwrap());
+    assert_eq!(system.frame, 0);
+    assert!(rewind.is_empty());
+}
+
+#[test]
+fn system_captures_every_frame() {
+    let mut system = machine();
+    system.cpu.enable_history(100);
+    system.enable_rewind(1 << 20);
+    let (start, x) = (system.master_cycles, system.cpu.x);
+    for _ in 0..3 {
+        system.run_frame();
+    }
+    system.run_cycles(1000);
+    let per_frame = system.clock.master_cycles_per_frame;
+
+    assert_eq!(system.rewind_frames(1), Ok(1));
+    assert_eq!((system.frame, system.master_cycles), (2, 2 * per_frame));
+    assert!(system.cpu.history.as_ref().unwrap().is_empty());
+
+    // Running on records the frames again from there
+    system.run_frame();
+    assert_eq!(system.rewind_frames(1), Ok(1));
+    assert_eq!(system.frame, 2);
+
+    assert_eq!(system.rewind_frames(5), Ok(2));
+    assert_eq!((system.master_cycles, system.cpu.x), (start, x));
+    assert_eq!(system.rewind_frames(1), Ok(0));
+}
@@ -0,0 +1,158 @@
+use cpu::bus::Bus;
+use cpu::savestate::{
+    write_header, SaveState, SaveStateError, StateWriter, CHUNK_APU, CHUNK_CPU, CHUNK_RAM,
+    CHUNK_SYSTEM, MAGIC,
+};
+use cpu::system::{Device, System};
+use cpu::testing::TestMachine;
+use std::{cell::RefCell, rc::Rc};
+
+const ROM: &[u8] = &[0xA9, 0x01, 0x69, 0x02, 0x4C, 0x00, 0x80];
+
+struct Counter(u32);
+
+impl Device for Counter {
+    fn tick(&mut self, _bus: &mut Bus) {
+        self.0 += 1;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.0.to_le_bytes().to_vec()
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut b = [0u8; 4];
+        if data.len() != 4 {
+            return Err(SaveStateError::Invalid("counter"));
+        }
+        b.copy_from_slice(data);
+        self.0 = u32::from_le_bytes(b);
+        Ok(())
+    }
+}
+
+fn machine() -> System {
+    let mut system = TestMachine::new().program(ROM).boot().system;
+    system.attach_ppu(Box::new(Counter(0)));
+    system
+}
+
+#[test]
+fn system_round_trip() {
+    let mut system = machine();
+    system.run_cycles(1000);
+    let state = system.save_state(ROM);
+    let (pc, acc, cycles) = (system.cpu.pc, system.cpu.acc, system.master_cycles);
+
+    system.run_cycles(5000);
+    system.bus.borrow_mut().ram[0x0200] = 0x55;
+    assert_ne!(system.master_cycles, cycles);
+
+    system.load_state(&state, ROM).unwrap();
+    assert_eq!(system.cpu.pc, pc);
+    assert_eq!(system.cpu.acc, acc);
+    assert_eq!(system.master_cycles, cycles);
+    assert_eq!(system.bus.borrow().ram[0x0200], 0x00);
+    assert_eq!(system.save_state(ROM), state);
+}
+
+#[test]
+fn rejects_other_roms_and_garbage() {
+    let system = machine();
+    let state = system.save_state(ROM);
+    let mut other = machine();
+
+    match other.load_state(&state, &[0xEA]) {
+        Err(SaveStateError::RomMismatch { .. }) => {}
+        r => panic!("{:?}", r),
+    }
+    assert_eq!(
+        other.load_state(b"not a state", ROM),
+        Err(SaveStateError::BadMagic)
+    );
+    assert_eq!(
+        other.load_state(&state[..state.len() - 10], ROM),
+        Err(SaveStateError::Truncated)
+    );
+}
+
+#[test]
+fn loads_older_states_and_skips_unknown_chunks() {
+    let bus = Rc::new(RefCell::new(Bus::new()));
+    let mut cpu = cpu::Cpu::new(Rc::clone(&bus));
+
+    // A cpu chunk holding only the registers an early version knew about,
+    // followed by a chunk from some future version
+    let mut regs = StateWriter::new();
+    regs.u8(0x11);
+    regs.u8(0x22);
+    regs.u8(0x33);
+    regs.u8(0xF0);
+    regs.u16(0x1234);
+
+    let mut w = StateWriter::new();
+    w.buf.extend_from_slice(MAGIC);
+    w.u16(1);
+    w.u64(cpu::savestate::rom_hash(ROM));
+    w.chunk(*b"FUTR", &[1, 2, 3]);
+    w.chunk(CHUNK_CPU, &regs.buf);
+    w.chunk(CHUNK_RAM, &[0xEA; 0x10000]);
+
+    cpu.load_state(&w.buf, ROM).unwrap();
+    assert_eq!(
+        (cpu.acc, cpu.x, cpu.y, cpu.sp, cpu.pc),
+        (0x11, 0x22, 0x33, 0xF0, 0x1234)
+    );
+    assert_eq!(cpu.pending_vector, None);
+    assert_eq!(bus.borrow().ram[0x4000], 0xEA);
+}
+
+// The chunks of state with tag replaced by data, or dropped when data is None
+fn edit(state: &[u8], tag: [u8; 4], data: Option<&[u8]>) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    write_header(&mut w, ROM);
+    for &(t, d) in SaveState::parse(state).unwrap().chunks.iter() {
+        match (t == tag, data) {
+            (false, _) => w.chunk(t, d),
+            (true, Some(data)) => w.chunk(t, data),
+            (true, None) => {}
+        }
+    }
+    w.buf
+}
+
+#[test]
+fn failed_loads_change_nothing() {
+    let mut system = machine();
+    system.attach_apu(Box::new(Counter(0)));
+    system.run_cycles(1000);
+    let state = system.save_state(ROM);
+    system.run_cycles(1000);
+    system.bus.borrow_mut().ram[0x0200] = 0x55;
+    let now = system.save_state(ROM);
+
+    // Refused after the cpu and ram chunks were read
+    assert_eq!(
+        system.load_state(&edit(&state, CHUNK_SYSTEM, None), ROM),
+        Err(SaveStateError::MissingChunk(CHUNK_SYSTEM))
+    );
+    assert_eq!(system.save_state(ROM), now);
+
+    // Refused by the apu after the ppu took its chunk
+    assert_eq!(
+        system.load_state(&edit(&state, CHUNK_APU, Some(&[1])), ROM),
+        Err(SaveStateError::Invalid("counter"))
+    );
+    assert_eq!(system.save_state(ROM), now);
+
+    // A cpu chunk cut off in the addressing mode name leaves the registers alone
+    let regs = SaveState::parse(&state).unwrap().chunk(CHUNK_CPU).unwrap();
+    assert_eq!(
+        system.load_state(&edit(&state, CHUNK_CPU, Some(&regs[..18])), ROM),
+        Err(SaveStateError::Truncated)
+    );
+    assert_eq!(system.save_state(ROM), now);
+
+    system.load_state(&state, ROM).unwrap();
+    assert_eq!(system.save_state(ROM), state);
+}
@@ -0,0 +1,57 @@
+use cpu::bus::Bus;
+use cpu::stack::StackEvent;
+use cpu::{Cpu, FLAGS};
+use std::{cell::RefCell, rc::Rc};
+
+fn cpu() -> Cpu {
+    Cpu::new(Rc::new(RefCell::new(Bus::new())))
+}
+
+#[test]
+fn push_and_pull_wrap_within_page_one() {
+    let mut cpu = cpu();
+    cpu.stack_watch.enabled = true;
+    cpu.sp = 0x00;
+    cpu.pc = 0x1234;
+
+    cpu.push_word(0xBEEF);
+    assert_eq!(cpu.sp, 0xFE);
+    assert_eq!(cpu.bus.borrow().ram[0x0100], 0xBE);
+    assert_eq!(cpu.bus.borrow().ram[0x01FF], 0xEF);
+    assert_eq!(
+        cpu.stack_watch.take_events(),
+        vec![StackEvent::Overflow { pc: 0x1234 }]
+    );
+
+    assert_eq!(cpu.pull_word(), 0xBEEF);
+    assert_eq!(cpu.sp, 0x00);
+    assert_eq!(
+        cpu.stack_watch.take_events(),
+        vec![StackEvent::Underflow { pc: 0x1234 }]
+    );
+}
+
+#[test]
+fn watch_is_off_by_default() {
+    let mut cpu = cpu();
+    cpu.sp = 0x00;
+    cpu.push(0x01);
+    cpu.pull();
+    assert!(cpu.stack_watch.events.is_empty());
+}
+
+#[test]
+fn pushed_status_has_u_and_b_only_for_brk() {
+    let mut cpu = cpu();
+    cpu.psr = FLAGS::c() | FLAGS::b();
+
+    assert_eq!(
+        cpu.status_for_push(true),
+        FLAGS::c() | FLAGS::b() | FLAGS::u()
+    );
+    assert_eq!(cpu.status_for_push(false), FLAGS::c() | FLAGS::u());
+
+    cpu.push(FLAGS::b() | FLAGS::n());
+    cpu.pull_status();
+    assert_eq!(cpu.psr, FLAGS::n() | FLAGS::u());
+}
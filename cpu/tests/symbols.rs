@@ -0,0 +1,108 @@
+use cpu::disasm::{Disassembler, Formatter, Standard};
+use cpu::symbols::{SymbolError, SymbolFormat, SymbolTable};
+use std::path::Path;
+
+const CA65_DBG: &str = r#"version	major=2,minor=0
+info	csym=0,file=2,lib=0,line=6,mod=1,scope=1,seg=2,span=4,sym=3,type=4
+file	id=0,name="src/main.s",size=120,mtime=0x5F000000,mod=0
+file	id=1,name="src/macros.inc",size=40,mtime=0x5F000000,mod=0
+line	id=0,file=0,line=3,span=0
+line	id=1,file=0,line=4,span=1
+line	id=2,file=0,line=9,span=2+3
+line	id=3,file=1,line=2,type=2,span=3
+line	id=4,file=0,line=1
+seg	id=0,name="CODE",start=0x008000,size=0x0020,addrsize=absolute,type=ro,oname="game.nes",ooffs=16
+seg	id=1,name="ZEROPAGE",start=0x000000,size=0x0002,addrsize=zeropage,type=rw
+span	id=0,seg=0,start=0,size=2
+span	id=1,seg=0,start=2,size=3
+span	id=2,seg=0,start=16,size=1
+span	id=3,seg=0,start=17,size=2
+sym	id=0,name="reset",addrsize=absolute,scope=0,def=0,ref=1,val=0x8000,seg=0,type=lab
+sym	id=1,name="counter",addrsize=zeropage,scope=0,def=1,val=0x10,seg=1,type=lab
+sym	id=2,name="PPUCTRL",addrsize=absolute,scope=0,def=2,val=0x2000,type=equ
+sym	id=3,name="extern",addrsize=absolute,scope=0,def=3,type=imp
+"#;
+
+#[test]
+fn ca65_debug_info() {
+    let table = SymbolTable::parse(SymbolFormat::Ca65Dbg, CA65_DBG).unwrap();
+    assert_eq!(table.labels["reset"], 0x8000);
+    assert_eq!(table.labels["counter"], 0x0010);
+    assert_eq!(table.labels["PPUCTRL"], 0x2000);
+    assert!(!table.labels.contains_key("extern"));
+
+    assert_eq!(table.line_at(0x8002).unwrap().line, 4);
+    // Several spans map to the lowest address, macro lines are left out
+    assert_eq!(table.line_at(0x8010).unwrap().line, 9);
+    assert_eq!(table.lines.len(), 3);
+    let line = table.line_to_addr("/work/game/src/main.s", 5).unwrap();
+    assert_eq!((line.line, line.addr), (9, 0x8010));
+}
+
+#[test]
+fn vice_mesen_and_bsnes_labels() {
+    let vice = "al C:8000 .reset\nal 0010 .counter\nbreak 8000\n";
+    let table = SymbolTable::parse(SymbolFormat::Vice, vice).unwrap();
+    assert_eq!(table.labels["reset"], 0x8000);
+    assert_eq!(table.labels["counter"], 0x0010);
+
+    let mlb = "P:0000:reset\nP:4010:nmi:vblank handler\nR:0010-0011:ptr\nS:0000:save\nG:2000:PPUCTRL\nC:0000:tiles\nP:0020::just a comment\nNesInternalRam:0300:oam\n";
+    let table = SymbolTable::parse(SymbolFormat::MesenMlb, mlb).unwrap();
+    assert_eq!(table.labels["reset"], 0x8000);
+    assert_eq!(table.labels["nmi"], 0xC010);
+    assert_eq!(table.labels["ptr"], 0x0010);
+    assert_eq!(table.labels["save"], 0x6000);
+    assert_eq!(table.labels["PPUCTRL"], 0x2000);
+    assert_eq!(table.labels["oam"], 0x0300);
+    assert_eq!(table.labels.len(), 6);
+
+    let sym = "; bsnes symbols\n[labels]\n00:8000 reset\n7e:0010 counter ; zero page\n[comments]\n00:8000 not a label\n";
+    let table = SymbolTable::parse(SymbolFormat::BsnesSym, sym).unwrap();
+    assert_eq!(table.labels["reset"], 0x8000);
+    assert_eq!(table.labels["counter"], 0x0010);
+    assert_eq!(table.labels.len(), 2);
+}
+
+#[test]
+fn detects_formats_and_reports_errors() {
+    let detect = |path: &str, text: &str| SymbolFormat::detect(Path::new(path), text);
+    assert_eq!(detect("game.dbg", ""), Some(SymbolFormat::Ca65Dbg));
+    assert_eq!(detect("game.mlb", ""), Some(SymbolFormat::MesenMlb));
+    assert_eq!(detect("game.sym", ""), Some(SymbolFormat::BsnesSym));
+    assert_eq!(
+        detect("game.txt", "al C:8000 .reset"),
+        Some(SymbolFormat::Vice)
+    );
+    assert_eq!(detect("game.txt", CA65_DBG), Some(SymbolFormat::Ca65Dbg));
+    assert_eq!(detect("game.txt", "hello"), None);
+
+    match SymbolTable::parse(SymbolFormat::Vice, "al C:8000 .ok\nal zz .bad\n") {
+        Err(SymbolError::Parse { line, .. }) => assert_eq!(line, 2),
+        r => panic!("{:?}", r),
+    }
+
+    let path = std::env::temp_dir().join("symbols-test.mlb");
+    std::fs::write(&path, "P:0000:reset\n").unwrap();
+    assert_eq!(SymbolTable::load(&path).unwrap().labels["reset"], 0x8000);
+    std::fs::remove_file(&path).ok();
+    assert!(matches!(SymbolTable::load(&path), Err(SymbolError::Io(_))));
+}
+
+#[test]
+fn disassembly_names_operands() {
+    // lda counter; jsr sub; bne reset
+    let code = [0xA5, 0x10, 0x20, 0x10, 0x80, 0xD0, 0xF9];
+    let decoded = Disassembler::new().decode_slice(&code, 0x8000);
+
+    let mut symbols = SymbolTable::new();
+    symbols.add_label("reset", 0x8000);
+    symbols.add_label("counter", 0x0010);
+    symbols.add_label("sub", 0x8010);
+
+    let text: Vec<String> = decoded
+        .iter()
+        .map(|ins| Standard.format(ins, Some(&symbols)))
+        .collect();
+    assert_eq!(text, ["LDA counter", "JSR sub", "BNE reset"]);
+    assert_eq!(Standard.format(&decoded[2], None), "BNE $8000");
+}
@@ -0,0 +1,136 @@
+use cpu::asm6502;
+use cpu::bus::Bus;
+use cpu::region::Region;
+use cpu::system::{Console, Device, StopReason, System};
+use cpu::testing::TestMachine;
+use std::cell::Cell;
+use std::rc::Rc;
+
+// Counts its ticks, and asks for a cpu stall on its first tick
+struct Probe {
+    ticks: Rc<Cell<u64>>,
+    stall: u32,
+}
+
+impl Device for Probe {
+    fn tick(&mut self, _bus: &mut Bus) {
+        self.ticks.set(self.ticks.get() + 1);
+    }
+
+    fn take_cpu_stall(&mut self) -> u32 {
+        std::mem::take(&mut self.stall)
+    }
+}
+
+fn probe(stall: u32) -> (Box<Probe>, Rc<Cell<u64>>) {
+    let ticks = Rc::new(Cell::new(0));
+    let device = Probe {
+        ticks: Rc::clone(&ticks),
+        stall,
+    };
+    (Box::new(device), ticks)
+}
+
+// NOPs from $8000 on, two cycles each
+fn nops() -> System {
+    TestMachine::new().program(&[0xEA; 0x100]).boot().system
+}
+
+#[test]
+fn devices_run_at_their_clock_ratios() {
+    let mut system = nops();
+    let (ppu, ppu_ticks) = probe(0);
+    let (apu, apu_ticks) = probe(0);
+    system.attach_ppu(ppu);
+    system.attach_apu(apu);
+    let (master, cycles) = (system.master_cycles, system.cpu_cycles);
+
+    // 100 NTSC cpu cycles: 3 ppu dots per cpu cycle, an apu tick every other
+    system.run_cycles(12 * 100);
+    assert_eq!(system.master_cycles - master, 1200);
+    assert_eq!(system.cpu_cycles - cycles, 100);
+    assert_eq!(ppu_ticks.get(), 300);
+    assert_eq!(apu_ticks.get(), 50);
+
+    let mut pal = TestMachine::new().program(&[0xEA; 0x100]).boot().system;
+    pal.set_region(Region::Pal);
+    let (ppu, ppu_ticks) = probe(0);
+    pal.attach_ppu(ppu);
+    let cycles = pal.cpu_cycles;
+    pal.run_cycles(16 * 5 * 10);
+    // 3.2 ppu dots per cpu cycle
+    assert_eq!(pal.cpu_cycles - cycles, 50);
+    assert_eq!(ppu_ticks.get(), 160);
+}
+
+#[test]
+fn step_instruction_and_run_frame() {
+    let mut system = nops();
+    assert!(system.cpu.complete());
+    assert_eq!(system.cpu.pc, 0x8000);
+
+    let cycles = system.cpu_cycles;
+    system.step_instruction();
+    assert_eq!(system.cpu.pc, 0x8001);
+    assert_eq!(system.cpu_cycles - cycles, 2);
+    assert!(system.cpu.complete());
+
+    let frame = system.frame;
+    system.run_frame();
+    assert_eq!(system.frame, frame + 1);
+    assert_eq!(
+        system.master_cycles % system.clock.master_cycles_per_frame,
+        0
+    );
+}
+
+#[test]
+fn stalls_hold_the_cpu_but_not_the_clock() {
+    let mut system = nops();
+    let (dma, dma_ticks) = probe(10);
+    system.attach_dma(dma);
+    let cycles = system.cpu_cycles;
+
+    // 10 stalled cycles, then a single NOP in the last two
+    system.run_cycles(12 * 12);
+    assert_eq!(system.cpu_cycles - cycles, 12);
+    assert_eq!(dma_ticks.get(), 12);
+    assert_eq!(system.cpu.pc, 0x8001);
+    assert!(system.cpu.complete());
+
+    let mut free = nops();
+    free.run_cycles(12 * 12);
+    assert_eq!(free.cpu.pc, 0x8006);
+}
+
+#[test]
+fn snes_clock() {
+    let mut system = System::new(Console::Snes);
+    let (ppu, ppu_ticks) = probe(0);
+    system.attach_ppu(ppu);
+    system.run_cycles(8 * 21 * 4);
+    // cpu every 8 master cycles, ppu every 4
+    assert_eq!(system.cpu_cycles, 84);
+    assert_eq!(ppu_ticks.get(), 168);
+}
+
+#[test]
+fn step_out_across_the_bottom_of_the_stack() {
+    let mut system = TestMachine::new()
+        .program(&asm6502!(
+            "        jsr sub",
+            "        brk",
+            "sub:    jsr inner",
+            "        rts",
+            "inner:  rts",
+        ))
+        .sp(0x01)
+        .boot()
+        .system;
+    system.step_instruction();
+    assert_eq!((system.cpu.pc, system.cpu.sp), (0x8004, 0xFF));
+
+    // The inner return leaves sp where it was, the outer one wraps it to $01
+    assert_eq!(system.step_out(100), StopReason::Done);
+    assert_eq!((system.cpu.pc, system.cpu.sp), (0x8003, 0x01));
+}
@@ -0,0 +1,167 @@
+use cpu::asm6502;
+use cpu::interrupt::Interrupt;
+use cpu::symbols::SymbolTable;
+use cpu::testing::TestMachine;
+use cpu::trace::{self, MemoryAccess, TraceError, TraceEvent, TraceFilter};
+
+fn traced(program: &[u8], filter: TraceFilter) -> Vec<TraceEvent> {
+    let mut m = TestMachine::new().program(program);
+    m.system.cpu.enable_trace(filter);
+    let mut m = m.run();
+    m.system.cpu.disable_trace().unwrap().events
+}
+
+fn program() -> Vec<u8> {
+    asm6502!("ldx #2", "lda #$55", "sta $0200,x", "inc $10", "brk")
+}
+
+#[test]
+fn records_instructions() {
+    let events = traced(&program(), TraceFilter::default());
+    assert_eq!(events.len(), 4);
+
+    let ldx = &events[0];
+    assert_eq!(ldx.bytes, vec![0xA2, 0x02]);
+    assert_eq!(ldx.interrupt, None);
+    assert_eq!(ldx.before.pc, 0x8000);
+    assert_eq!((ldx.after.pc, ldx.after.x), (0x8002, 2));
+    assert_eq!(ldx.addr, None);
+    // The immediate operand is read as data
+    assert_eq!(
+        ldx.accesses,
+        vec![MemoryAccess {
+            addr: 0x8001,
+            value: 0x02,
+            write: false
+        }]
+    );
+
+    let sta = &events[2];
+    assert_eq!(sta.bytes, vec![0x9D, 0x00, 0x02]);
+    assert_eq!(sta.addr, Some(0x0202));
+    assert_eq!(
+        sta.accesses.last(),
+        Some(&MemoryAccess {
+            addr: 0x0202,
+            value: 0x55,
+            write: true
+        })
+    );
+
+    let inc = &events[3];
+    assert_eq!(inc.addr, Some(0x0010));
+    assert!(!inc.accesses[0].write);
+    assert_eq!(
+        inc.accesses.last(),
+        Some(&MemoryAccess {
+            addr: 0x0010,
+            value: 0x01,
+            write: true
+        })
+    );
+
+    // LDX and LDA take 2 cycles each, STA abs,X 5
+    let cycles: Vec<u64> = events.iter().map(|e| e.cycle - events[0].cycle).collect();
+    assert_eq!(cycles, vec![0, 2, 4, 9]);
+}
+
+#[test]
+fn filters() {
+    let range = traced(
+        &program(),
+        TraceFilter {
+            range: Some((0x8002, 0x8004)),
+            ..TraceFilter::default()
+        },
+    );
+    let pcs: Vec<u16> = range.iter().map(|e| e.before.pc).collect();
+    assert_eq!(pcs, vec![0x8002, 0x8004]);
+
+    let opcodes = traced(
+        &program(),
+        TraceFilter {
+            opcodes: Some([0xE6].iter().copied().collect()),
+            ..TraceFilter::default()
+        },
+    );
+    assert_eq!(opcodes.len(), 1);
+    assert_eq!(opcodes[0].bytes, vec![0xE6, 0x10]);
+
+    let all = traced(&program(), TraceFilter::default());
+    let late = traced(
+        &program(),
+        TraceFilter {
+            after_cycle: all[2].cycle,
+            ..TraceFilter::default()
+        },
+    );
+    assert_eq!(late, all[2..].to_vec());
+}
+
+#[test]
+fn binary_and_text_logs() {
+    let events = traced(&program(), TraceFilter::default());
+    let mut log = Vec::new();
+    trace::write_binary(&mut log, &events).unwrap();
+    assert_eq!(trace::read_binary(&mut log.as_slice()).unwrap(), events);
+    assert_eq!(trace::first_difference(&events, &events), None);
+
+    let truncated = &log[..log.len() - 1];
+    match trace::read_binary(&mut &truncated[..]) {
+        Err(TraceError::Format(_)) => {}
+        other => panic!("{:?}", other),
+    }
+    assert!(trace::read_binary(&mut &b"not a trace"[..]).is_err());
+
+    let mut changed = events.clone();
+    changed[2].accesses[0].value = 0x56;
+    assert_eq!(trace::first_difference(&events, &changed), Some(2));
+
+    let mut symbols = SymbolTable::new();
+    symbols.add_label("buffer", 0x0200);
+    let mut text = Vec::new();
+    trace::write_text(&mut text, &events, Some(&symbols)).unwrap();
+    let text = String::from_utf8(text).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("8000  A2 02     LDX #$02"), "{}", text);
+    assert!(
+        lines[0].contains("A:00 X:00 Y:00 P:24 SP:FD CYC:"),
+        "{}",
+        text
+    );
+    assert!(lines[2].contains("STA buffer,X"), "{}", text);
+    assert!(lines[2].ends_with(" w:0202=55"), "{}", text);
+}
+
+#[test]
+fn interrupts() {
+    let mut m = TestMachine::new()
+        .program(&asm6502!("nop", "nop", "nop", "brk"))
+        .memory(0x9000, &asm6502!(org 0x9000; "rti"))
+        .memory(0xFFFA, &[0x00, 0x90])
+        .run();
+    let system = &mut m.system;
+    system.cpu.pc = 0x8000;
+    system.cpu.enable_trace(TraceFilter::default());
+    system.cpu.interrupts.pulse_nmi();
+    for _ in 0..4 {
+        system.step_instruction();
+    }
+    let events = system.cpu.disable_trace().unwrap().events;
+
+    let nmi = events
+        .iter()
+        .find(|e| e.interrupt == Some(Interrupt::Nmi))
+        .expect("no NMI traced");
+    assert!(nmi.bytes.is_empty());
+    assert_eq!(nmi.after.pc, 0x9000);
+    // Pushes pc and p, then reads the vector
+    let writes = nmi.accesses.iter().filter(|a| a.write).count();
+    assert_eq!(writes, 3);
+    assert!(nmi.accesses.iter().any(|a| a.addr == 0xFFFA && !a.write));
+
+    let mut text = Vec::new();
+    trace::write_text(&mut text, &events, None).unwrap();
+    assert!(String::from_utf8(text).unwrap().contains("NMI"));
+}
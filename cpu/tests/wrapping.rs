@@ -0,0 +1,95 @@
+// Random programs in a debug build: any unchecked overflow in the core panics
+use cpu::bus::Bus;
+use cpu::lookup_table::LookUpTable;
+use cpu::Cpu;
+use proptest::prelude::*;
+use std::{cell::RefCell, rc::Rc};
+
+fn run(memory: &[u8], regs: (u8, u8, u8, u8, u16, u8), instructions: usize) -> Cpu {
+    let bus = Rc::new(RefCell::new(Bus::new()));
+    bus.borrow_mut().ram.copy_from_slice(memory);
+
+    let mut cpu = Cpu::new(bus);
+    let mut lookup = LookUpTable::new();
+    cpu.reset();
+    let (acc, x, y, sp, pc, psr) = regs;
+    cpu.acc = acc;
+    cpu.x = x;
+    cpu.y = y;
+    cpu.sp = sp;
+    cpu.pc = pc;
+    cpu.psr = psr;
+    cpu.cycles = 0;
+
+    for _ in 0..instructions {
+        loop {
+            cpu.clock(&mut lookup);
+            if cpu.complete() {
+                break;
+            }
+        }
+    }
+    cpu
+}
+
+fn memory() -> impl Strategy<Value = Vec<u8>> {
+    proptest::collection::vec(any::<u8>(), 0x10000)
+}
+
+fn registers() -> impl Strategy<Value = (u8, u8, u8, u8, u16, u8)> {
+    (
+        any::<u8>(),
+        any::<u8>(),
+        any::<u8>(),
+        any::<u8>(),
+        any::<u16>(),
+        any::<u8>(),
+    )
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn random_programs_do_not_panic(memory in memory(), regs in registers()) {
+        run(&memory, regs, 2000);
+    }
+
+    // Registers parked at the edges of their ranges, where overflow happens
+    #[test]
+    fn edge_registers_do_not_panic(
+        memory in memory(),
+        acc in prop_oneof![Just(0x00u8), Just(0x7F), Just(0x80), Just(0xFF)],
+        x in prop_oneof![Just(0x00u8), Just(0xFF)],
+        y in prop_oneof![Just(0x00u8), Just(0xFF)],
+        sp in prop_oneof![Just(0x00u8), Just(0xFF)],
+        pc in prop_oneof![Just(0xFFFFu16), Just(0xFFFE), Just(0xFFFD), Just(0x0000)],
+        psr in any::<u8>(),
+    ) {
+        run(&memory, (acc, x, y, sp, pc, psr), 200);
+    }
+
+    #[test]
+    fn every_opcode_at_the_top_of_memory(opcode in any::<u8>(), fill in any::<u8>(), x in any::<u8>(), y in any::<u8>(), sp in any::<u8>()) {
+        let mut memory = vec![fill; 0x10000];
+        memory[0xFFFF] = opcode;
+        run(&memory, (0xFF, x, y, sp, 0xFFFF, 0x00), 4);
+    }
+}
+
+#[test]
+fn counters_wrap_like_hardware() {
+    // DEX, INY, INC $10, DEC $11, CMP #$01
+    let mut memory = vec![0xEA; 0x10000];
+    memory[0x8000..0x8009].copy_from_slice(&[0xCA, 0xC8, 0xE6, 0x10, 0xC6, 0x11, 0xC9, 0x01, 0xEA]);
+    memory[0x10] = 0xFF;
+    memory[0x11] = 0x00;
+
+    let cpu = run(&memory, (0x00, 0x00, 0xFF, 0xFD, 0x8000, 0x20), 5);
+    assert_eq!(cpu.x, 0xFF);
+    assert_eq!(cpu.y, 0x00);
+    assert_eq!(cpu.bus.borrow().ram[0x10], 0x00);
+    assert_eq!(cpu.bus.borrow().ram[0x11], 0xFF);
+    // 0 - 1 borrows: carry clear, negative set
+    assert_eq!(cpu.psr & 0x81, 0x80);
+}
@@ -0,0 +1,727 @@
+// Debug Adapter Protocol server.
+//
+// Requests arrive as JSON framed by Content-Length headers. The Adapter turns
+// each request into its response plus any events; while the program runs the
+// front end calls run_slice between requests, so pause and breakpoint changes
+// take effect promptly. Source breakpoints go through the SymbolTable line map
+// given at launch, everything else maps directly onto the cpu crate.
+use cpu::breakpoint::BreakKind;
+use cpu::disasm::{Disassembler, Formatter, Standard};
+use cpu::expr::Expr;
+use cpu::loader::Program;
+use cpu::symbols::SymbolTable;
+use cpu::system::{Console, StopReason, System};
+use cpu::FLAGS;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+pub const THREAD_ID: i64 = 1;
+
+// Instructions run per slice while continuing
+const RUN_SLICE: u64 = 20_000;
+// Instructions a step over or step out may take before giving up
+const STEP_LIMIT: u64 = 10_000_000;
+
+const REGISTERS_REF: i64 = 1;
+const FLAGS_REF: i64 = 2;
+const MEMORY_REF: i64 = 3;
+// REGIONS[i] has reference REGION_REF + i
+const REGION_REF: i64 = 10;
+
+// (name, start, length) of the memory shown in the variables view
+const REGIONS: [(&str, u16, u16); 3] = [
+    ("Zero page", 0x0000, 0x100),
+    ("Stack", 0x0100, 0x100),
+    ("Vectors", 0xFFFA, 6),
+];
+
+// Read one message, None at end of input
+pub fn read_message(input: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && len.is_some() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            len = value.trim().parse::<usize>().ok();
+        }
+    }
+    let mut body = vec![0; len.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message(output: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+#[derive(Default)]
+pub struct Adapter {
+    pub system: Option<System>,
+    pub symbols: SymbolTable,
+    // Set by disconnect, the front end exits after sending the replies
+    pub terminated: bool,
+    seq: i64,
+    running: bool,
+    // The first slice after resuming lets a breakpoint on the current pc through
+    resumed: bool,
+    stop_on_entry: bool,
+    // Breakpoint ids of each source, replaced by every setBreakpoints
+    source_breakpoints: HashMap<String, Vec<u32>>,
+    instruction_breakpoints: Vec<u32>,
+}
+
+impl Adapter {
+    pub fn new() -> Self {
+        Adapter::default()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    // The response to a request followed by the events it caused
+    pub fn handle(&mut self, request: &Value) -> Vec<Value> {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        let mut events = Vec::new();
+
+        let response = match self.dispatch(command, args, &mut events) {
+            Ok(body) => json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": true,
+                "body": body,
+            }),
+            Err(message) => json!({
+                "type": "response",
+                "request_seq": request["seq"],
+                "command": command,
+                "success": false,
+                "message": message,
+            }),
+        };
+
+        let mut out = vec![response];
+        out.extend(events);
+        self.number(out)
+    }
+
+    // Run a slice of the program when continuing, returning a stopped event
+    // once something stops it
+    pub fn run_slice(&mut self) -> Vec<Value> {
+        if !self.running {
+            return Vec::new();
+        }
+        let system = match self.system.as_mut() {
+            Some(system) => system,
+            None => return Vec::new(),
+        };
+        // run_until_break lets its first instruction through, so check the pc
+        // breakpoints at slice boundaries here
+        if !self.resumed {
+            let hits = system.cpu.check_execute_breakpoints();
+            if !hits.is_empty() {
+                self.running = false;
+                let stopped = stopped_event(&StopReason::Break(hits));
+                return self.number(vec![stopped]);
+            }
+        }
+        self.resumed = false;
+
+        match system.run_until_break(Some(RUN_SLICE)) {
+            StopReason::Limit => Vec::new(),
+            stop => {
+                self.running = false;
+                let stopped = stopped_event(&stop);
+                self.number(vec![stopped])
+            }
+        }
+    }
+
+    fn number(&mut self, mut messages: Vec<Value>) -> Vec<Value> {
+        for message in messages.iter_mut() {
+            self.seq += 1;
+            message["seq"] = json!(self.seq);
+        }
+        messages
+    }
+
+    fn dispatch(
+        &mut self,
+        command: &str,
+        args: &Value,
+        events: &mut Vec<Value>,
+    ) -> Result<Value, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsDisassembleRequest": true,
+                "supportsSetVariable": true,
+                "supportsEvaluateForHovers": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" | "attach" => {
+                if command == "launch" {
+                    self.launch(args)?;
+                } else {
+                    self.attach(args)?;
+                }
+                // Breakpoints need the line map, so configuration starts now
+                events.push(event("initialized", json!({})));
+                Ok(json!({}))
+            }
+            "configurationDone" => {
+                if self.stop_on_entry {
+                    events.push(stopped("entry", Vec::new()));
+                } else {
+                    self.running = true;
+                    self.resumed = true;
+                }
+                Ok(json!({}))
+            }
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "6502" }] })),
+            "stackTrace" => Ok(self.stack_trace()?),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Flags", "variablesReference": FLAGS_REF, "expensive": false },
+                { "name": "Memory", "variablesReference": MEMORY_REF, "expensive": false },
+            ]})),
+            "variables" => self.variables(args["variablesReference"].as_i64().unwrap_or(0)),
+            "setVariable" => self.set_variable(args),
+            "evaluate" => {
+                let text = args["expression"].as_str().ok_or("expression missing")?;
+                let value = self.eval(text)?;
+                Ok(json!({ "result": format_value(value), "variablesReference": 0 }))
+            }
+            "continue" => {
+                self.system()?;
+                self.running = true;
+                self.resumed = true;
+                Ok(json!({ "allThreadsContinued": true }))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let system = self.system_mut()?;
+                let stop = match command {
+                    "next" => system.step_over(STEP_LIMIT),
+                    "stepOut" => system.step_out(STEP_LIMIT),
+                    _ => system.run_until(1, |_, _| true),
+                };
+                events.push(stopped_event(&stop));
+                Ok(json!({}))
+            }
+            "pause" => {
+                if self.running {
+                    self.running = false;
+                    events.push(stopped("pause", Vec::new()));
+                }
+                Ok(json!({}))
+            }
+            "disassemble" => self.disassemble(args),
+            "disconnect" | "terminate" => {
+                self.running = false;
+                self.terminated = true;
+                events.push(event("terminated", json!({})));
+                Ok(json!({}))
+            }
+            _ => Err(format!("unsupported request '{}'", command)),
+        }
+    }
+
+    fn system(&self) -> Result<&System, String> {
+        self.system
+            .as_ref()
+            .ok_or_else(|| "no program is loaded".to_string())
+    }
+
+    fn system_mut(&mut self) -> Result<&mut System, String> {
+        self.system
+            .as_mut()
+            .ok_or_else(|| "no program is loaded".to_string())
+    }
+
+    // program (raw binary, Intel HEX, S-record or C64 .prg), loadAddress of a
+    // raw binary ($8000), startAddress (reset vector, else the start record or
+    // the first byte loaded), console ("nes" or "snes"), stopOnEntry, symbols,
+    // labels, lineMap
+    fn launch(&mut self, args: &Value) -> Result<(), String> {
+        let program = args["program"].as_str().ok_or("program missing")?;
+        let load = match &args["loadAddress"] {
+            Value::Null => 0x8000,
+            value => address(value)?,
+        };
+        let image = Program::load(program, load).map_err(|e| format!("{}: {}", program, e))?;
+
+        let mut system = System::new(console(args)?);
+        image.write(&mut system.bus.borrow_mut());
+        system.reset();
+        system.step_instruction();
+        match &args["startAddress"] {
+            Value::Null if system.cpu.pc == 0 => system.cpu.pc = image.entry().unwrap_or(load),
+            Value::Null => {}
+            value => system.cpu.pc = address(value)?,
+        }
+
+        self.load_symbols(args)?;
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.system = Some(system);
+        Ok(())
+    }
+
+    // saveState, program (the rom the state was saved with), console, symbols,
+    // labels, lineMap. Stops on entry unless told otherwise.
+    fn attach(&mut self, args: &Value) -> Result<(), String> {
+        let path = args["saveState"].as_str().ok_or("saveState missing")?;
+        let state = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        let rom = match args["program"].as_str() {
+            Some(program) => fs::read(program).map_err(|e| format!("{}: {}", program, e))?,
+            None => Vec::new(),
+        };
+
+        let mut system = System::new(console(args)?);
+        system
+            .load_state(&state, &rom)
+            .map_err(|e| format!("{}: {}", path, e))?;
+
+        self.load_symbols(args)?;
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(true);
+        self.system = Some(system);
+        Ok(())
+    }
+
+    // symbols: a symbol file path or a list of them (see cpu::symbols),
+    // labels: { "name": address }, lineMap: [{ "source", "line", "address" }]
+    fn load_symbols(&mut self, args: &Value) -> Result<(), String> {
+        let mut symbols = SymbolTable::new();
+        let files = match &args["symbols"] {
+            Value::String(path) => vec![Value::String(path.clone())],
+            Value::Array(paths) => paths.clone(),
+            _ => Vec::new(),
+        };
+        for path in files {
+            let path = path.as_str().ok_or("symbols must be file paths")?;
+            let table = SymbolTable::load(path).map_err(|e| format!("{}: {}", path, e))?;
+            symbols.extend(table);
+        }
+        if let Some(labels) = args["labels"].as_object() {
+            for (name, addr) in labels {
+                symbols.add_label(name, address(addr)?);
+            }
+        }
+        if let Some(lines) = args["lineMap"].as_array() {
+            for entry in lines {
+                let source = entry["source"]
+                    .as_str()
+                    .ok_or("lineMap entry without source")?;
+                let line = entry["line"].as_u64().ok_or("lineMap entry without line")?;
+                symbols.add_line(source, line as u32, address(&entry["address"])?);
+            }
+        }
+        self.symbols = symbols;
+        Ok(())
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"]
+            .as_str()
+            .ok_or("source path missing")?
+            .to_string();
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+
+        let old = self.source_breakpoints.remove(&path).unwrap_or_default();
+        let mut ids = Vec::new();
+        let mut result = Vec::new();
+        for b in requested {
+            let line = b["line"].as_u64().unwrap_or(0) as u32;
+            let found = self
+                .symbols
+                .line_to_addr(&path, line)
+                .map(|l| (l.line, l.addr));
+            let (line, addr) = match found {
+                Some(found) => found,
+                None => {
+                    result.push(json!({
+                        "verified": false,
+                        "line": line,
+                        "message": "no code at or after this line",
+                    }));
+                    continue;
+                }
+            };
+            match self.add_breakpoint(BreakKind::Pc(addr), b["condition"].as_str()) {
+                Ok(id) => {
+                    ids.push(id);
+                    result.push(json!({
+                        "id": id,
+                        "verified": true,
+                        "line": line,
+                        "instructionReference": reference(addr),
+                    }));
+                }
+                Err(message) => result.push(json!({
+                    "verified": false,
+                    "line": line,
+                    "message": message,
+                })),
+            }
+        }
+
+        let breakpoints = &mut self.system_mut()?.cpu.breakpoints;
+        for id in old {
+            breakpoints.remove(id);
+        }
+        self.source_breakpoints.insert(path, ids);
+        Ok(json!({ "breakpoints": result }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        let old = std::mem::take(&mut self.instruction_breakpoints);
+        {
+            let breakpoints = &mut self.system_mut()?.cpu.breakpoints;
+            for id in old {
+                breakpoints.remove(id);
+            }
+        }
+
+        let mut result = Vec::new();
+        for b in requested {
+            let added = address(&b["instructionReference"]).and_then(|addr| {
+                let addr = (addr as i64 + b["offset"].as_i64().unwrap_or(0)) as u16;
+                self.add_breakpoint(BreakKind::Pc(addr), b["condition"].as_str())
+            });
+            match added {
+                Ok(id) => {
+                    self.instruction_breakpoints.push(id);
+                    result.push(json!({ "id": id, "verified": true }));
+                }
+                Err(message) => result.push(json!({ "verified": false, "message": message })),
+            }
+        }
+        Ok(json!({ "breakpoints": result }))
+    }
+
+    fn add_breakpoint(&mut self, kind: BreakKind, condition: Option<&str>) -> Result<u32, String> {
+        let condition = match condition {
+            Some(text) if !text.trim().is_empty() => Some(
+                Expr::parse(text)
+                    .and_then(|e| e.resolve(&self.symbols))
+                    .map_err(|e| e.to_string())?,
+            ),
+            _ => None,
+        };
+        let breakpoints = &mut self.system_mut()?.cpu.breakpoints;
+        Ok(match condition {
+            Some(condition) => breakpoints.add_conditional(kind, condition),
+            None => breakpoints.add(kind),
+        })
+    }
+
+    // A single frame, the 6502 has no frame chain to walk
+    fn stack_trace(&self) -> Result<Value, String> {
+        let pc = self.system()?.cpu.pc;
+        let name = match self.symbols.label_at(pc) {
+            Some(label) => label.to_string(),
+            None => format!("${:04X}", pc),
+        };
+        let mut frame = json!({
+            "id": 1,
+            "name": name,
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": reference(pc),
+        });
+        if let Some(line) = self.symbols.line_at(pc) {
+            frame["source"] = source(&line.file);
+            frame["line"] = json!(line.line);
+            frame["column"] = json!(1);
+        }
+        Ok(json!({ "stackFrames": [frame], "totalFrames": 1 }))
+    }
+
+    fn variables(&self, reference: i64) -> Result<Value, String> {
+        let cpu = &self.system()?.cpu;
+        let variables: Vec<Value> = match reference {
+            REGISTERS_REF => vec![
+                variable("A", format_byte(cpu.acc)),
+                variable("X", format_byte(cpu.x)),
+                variable("Y", format_byte(cpu.y)),
+                variable("SP", format_byte(cpu.sp)),
+                variable("PC", format!("${:04X}", cpu.pc)),
+                variable("P", format_byte(cpu.psr)),
+            ],
+            FLAGS_REF => FLAG_NAMES
+                .iter()
+                .filter_map(|name| {
+                    let mask = flag_mask(name)?;
+                    Some(variable(name, cpu.get_flag(mask).to_string()))
+                })
+                .collect(),
+            MEMORY_REF => REGIONS
+                .iter()
+                .enumerate()
+                .map(|(i, (name, start, len))| {
+                    json!({
+                        "name": name,
+                        "value": format!("${:04X}-${:04X}", start, start + (len - 1)),
+                        "variablesReference": REGION_REF + i as i64,
+                        "memoryReference": self::reference(*start),
+                    })
+                })
+                .collect(),
+            r if r >= REGION_REF && ((r - REGION_REF) as usize) < REGIONS.len() => {
+                let (_, start, len) = REGIONS[(r - REGION_REF) as usize];
+                (0..len)
+                    .step_by(16)
+                    .map(|row| {
+                        let addr = start + row;
+                        let bytes: Vec<String> = (0..16.min(len - row))
+                            .map(|i| format!("{:02X}", cpu.peek(addr + i)))
+                            .collect();
+                        variable(&format!("${:04X}", addr), bytes.join(" "))
+                    })
+                    .collect()
+            }
+            _ => return Err(format!("unknown variables reference {}", reference)),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        let name = args["name"].as_str().ok_or("name missing")?;
+        let value = self.eval(args["value"].as_str().ok_or("value missing")?)?;
+        let cpu = &mut self.system_mut()?.cpu;
+        let shown = match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REF) => {
+                match name {
+                    "A" => cpu.acc = value as u8,
+                    "X" => cpu.x = value as u8,
+                    "Y" => cpu.y = value as u8,
+                    "SP" => cpu.sp = value as u8,
+                    "PC" => cpu.pc = value as u16,
+                    "P" => cpu.psr = value as u8,
+                    _ => return Err(format!("unknown register '{}'", name)),
+                }
+                if name == "PC" {
+                    format!("${:04X}", value as u16)
+                } else {
+                    format_byte(value as u8)
+                }
+            }
+            Some(FLAGS_REF) => {
+                let mask = flag_mask(name).ok_or_else(|| format!("unknown flag '{}'", name))?;
+                if value != 0 {
+                    cpu.psr |= mask;
+                } else {
+                    cpu.psr &= !mask;
+                }
+                cpu.get_flag(mask).to_string()
+            }
+            _ => return Err("only registers and flags can be set".to_string()),
+        };
+        Ok(json!({ "value": shown }))
+    }
+
+    fn eval(&self, text: &str) -> Result<i64, String> {
+        let cpu = &self.system()?.cpu;
+        Expr::parse(text)
+            .and_then(|e| e.eval_with(cpu, &self.symbols))
+            .map_err(|e| e.to_string())
+    }
+
+    // Decoding has to start on an instruction boundary, which is only known
+    // for the requested address, so instructions before it are decoded from a
+    // little further back and may be misaligned.
+    fn disassemble(&self, args: &Value) -> Result<Value, String> {
+        let system = self.system()?;
+        let base = (address(&args["memoryReference"])? as i64)
+            .saturating_add(args["offset"].as_i64().unwrap_or(0));
+        let skip = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"]
+            .as_u64()
+            .ok_or("instructionCount missing")?;
+        // Every address in memory is more than any client will want to show
+        if count > 0x10000 {
+            return Err(format!("instructionCount {} is too large", count));
+        }
+        let count = count as i64;
+
+        let start = base
+            .saturating_add(skip.min(0).saturating_mul(3))
+            .clamp(0, 0xFFFF) as u16;
+        let stop = base
+            .saturating_add(skip.max(0).saturating_add(count + 1).saturating_mul(3))
+            .clamp(1, 0xFFFF) as u16;
+        let decoded = Disassembler::new().decode_range(
+            |a| system.cpu.peek(a),
+            start,
+            stop.max(start.saturating_add(1)),
+        );
+
+        let first = decoded
+            .iter()
+            .position(|ins| ins.addr as i64 >= base)
+            .unwrap_or(decoded.len());
+        let mut instructions = Vec::new();
+        for n in 0..count {
+            let i = (first as i64).saturating_add(skip).saturating_add(n);
+            let ins = match decoded.get(i.max(0) as usize) {
+                Some(ins) if i >= 0 => ins,
+                _ => {
+                    let addr = base
+                        .saturating_add(skip.saturating_add(n).saturating_mul(3))
+                        .clamp(0, 0xFFFF) as u16;
+                    instructions.push(json!({
+                        "address": reference(addr),
+                        "instruction": "??",
+                        "presentationHint": "invalid",
+                    }));
+                    continue;
+                }
+            };
+            let mut instruction = json!({
+                "address": reference(ins.addr),
+                "instruction": Standard.format(ins, Some(&self.symbols)),
+                "instructionBytes": ins.hex_bytes(),
+            });
+            if let Some(label) = self.symbols.label_at(ins.addr) {
+                instruction["symbol"] = json!(label);
+            }
+            if let Some(line) = self.symbols.line_at(ins.addr) {
+                instruction["location"] = source(&line.file);
+                instruction["line"] = json!(line.line);
+            }
+            instructions.push(instruction);
+        }
+        Ok(json!({ "instructions": instructions }))
+    }
+}
+
+// Most significant bit first, the order the flags appear in P
+const FLAG_NAMES: [&str; 8] = ["N", "V", "U", "B", "D", "I", "Z", "C"];
+
+fn flag_mask(name: &str) -> Option<u8> {
+    Some(match name {
+        "N" => FLAGS::n(),
+        "V" => FLAGS::v(),
+        "U" => FLAGS::u(),
+        "B" => FLAGS::b(),
+        "D" => FLAGS::d(),
+        "I" => FLAGS::i(),
+        "Z" => FLAGS::z(),
+        "C" => FLAGS::c(),
+        _ => return None,
+    })
+}
+
+fn event(name: &str, body: Value) -> Value {
+    json!({ "type": "event", "event": name, "body": body })
+}
+
+fn stopped(reason: &str, hit_ids: Vec<u32>) -> Value {
+    let mut body = json!({
+        "reason": reason,
+        "threadId": THREAD_ID,
+        "allThreadsStopped": true,
+    });
+    if !hit_ids.is_empty() {
+        body["hitBreakpointIds"] = json!(hit_ids);
+    }
+    event("stopped", body)
+}
+
+fn stopped_event(stop: &StopReason) -> Value {
+    match stop {
+        StopReason::Break(hits) => {
+            let ids = hits.iter().map(|h| h.id).collect();
+            let code = hits.iter().any(|h| {
+                matches!(
+                    h.kind,
+                    BreakKind::Pc(_) | BreakKind::Execute(_) | BreakKind::Opcode(_)
+                )
+            });
+            stopped(
+                if code {
+                    "breakpoint"
+                } else {
+                    "data breakpoint"
+                },
+                ids,
+            )
+        }
+        StopReason::Limit | StopReason::Done => stopped("step", Vec::new()),
+    }
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+fn source(path: &str) -> Value {
+    let name = Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string());
+    json!({ "name": name, "path": path })
+}
+
+fn reference(addr: u16) -> String {
+    format!("0x{:04X}", addr)
+}
+
+fn format_byte(value: u8) -> String {
+    format!("${:02X}", value)
+}
+
+fn format_value(value: i64) -> String {
+    if value < 0 {
+        value.to_string()
+    } else {
+        format!("${:X} ({})", value, value)
+    }
+}
+
+fn console(args: &Value) -> Result<Console, String> {
+    match args["console"].as_str() {
+        None | Some("nes") => Ok(Console::Nes),
+        Some("snes") => Ok(Console::Snes),
+        Some(other) => Err(format!("unknown console '{}'", other)),
+    }
+}
+
+// A JSON number, or a string in decimal, $hex or 0xhex
+fn address(value: &Value) -> Result<u16, String> {
+    let parsed = match value {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => {
+            if let Some(hex) = s.strip_prefix('$').or_else(|| s.strip_prefix("0x")) {
+                u64::from_str_radix(hex, 16).ok()
+            } else {
+                s.parse().ok()
+            }
+        }
+        _ => None,
+    };
+    match parsed {
+        Some(addr) if addr <= 0xFFFF => Ok(addr as u16),
+        _ => Err(format!("bad address {}", value)),
+    }
+}
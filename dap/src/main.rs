@@ -0,0 +1,50 @@
+// Debug adapter speaking DAP over stdin and stdout.
+//
+// A reader thread forwards requests so the program can keep running between
+// them, and a pause is handled while a continue is in progress.
+use dap::{read_message, write_message, Adapter};
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+fn main() {
+    let (requests, incoming) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        while let Ok(Some(request)) = read_message(&mut input) {
+            if requests.send(request).is_err() {
+                break;
+            }
+        }
+    });
+
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let mut adapter = Adapter::new();
+
+    while !adapter.terminated {
+        let request = if adapter.is_running() {
+            match incoming.try_recv() {
+                Ok(request) => Some(request),
+                Err(mpsc::TryRecvError::Empty) => None,
+                Err(mpsc::TryRecvError::Disconnected) => break,
+            }
+        } else {
+            match incoming.recv() {
+                Ok(request) => Some(request),
+                Err(_) => break,
+            }
+        };
+
+        let messages = match request {
+            Some(request) => adapter.handle(&request),
+            None => adapter.run_slice(),
+        };
+        for message in messages {
+            if write_message(&mut output, &message).is_err() {
+                return;
+            }
+        }
+    }
+}
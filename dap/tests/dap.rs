@@ -0,0 +1,271 @@
+use dap::{read_message, write_message, Adapter};
+use serde_json::{json, Value};
+use std::io::Cursor;
+
+// main.s:
+//  1 reset:  ldx #0
+//  2         jsr sub
+//  3 ; store the result
+//  4         sta counter
+//  5 loop:   jmp loop
+// 10 sub:    lda #5
+// 11         jsr inner
+// 12         rts
+// 15 inner:  inx
+// 16         rts
+const CODE: &[(u16, &[u8])] = &[
+    (0x8000, &[0xA2, 0x00]),
+    (0x8002, &[0x20, 0x10, 0x80]),
+    (0x8005, &[0x8D, 0x00, 0x02]),
+    (0x8008, &[0x4C, 0x08, 0x80]),
+    (0x8010, &[0xA9, 0x05]),
+    (0x8012, &[0x20, 0x20, 0x80]),
+    (0x8015, &[0x60]),
+    (0x8020, &[0xE8]),
+    (0x8021, &[0x60]),
+];
+const LINES: [u32; 9] = [1, 2, 4, 5, 10, 11, 12, 15, 16];
+
+struct Session {
+    adapter: Adapter,
+    seq: i64,
+}
+
+impl Session {
+    fn request(&mut self, command: &str, arguments: Value) -> Vec<Value> {
+        self.seq += 1;
+        let out = self.adapter.handle(&json!({
+            "seq": self.seq,
+            "type": "request",
+            "command": command,
+            "arguments": arguments,
+        }));
+        assert_eq!(out[0]["type"], "response");
+        assert_eq!(out[0]["request_seq"], self.seq);
+        out
+    }
+
+    // The body of a successful response
+    fn ok(&mut self, command: &str, arguments: Value) -> Value {
+        let out = self.request(command, arguments);
+        assert_eq!(out[0]["success"], true, "{}", out[0]);
+        out[0]["body"].clone()
+    }
+
+    fn run_until_stopped(&mut self) -> Value {
+        for _ in 0..1000 {
+            if let Some(event) = self.adapter.run_slice().into_iter().next() {
+                return event;
+            }
+        }
+        panic!("never stopped");
+    }
+}
+
+fn launch() -> Session {
+    let mut program = vec![0u8; 0x22];
+    for (addr, bytes) in CODE {
+        let at = (*addr - 0x8000) as usize;
+        program[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+    let path = std::env::temp_dir().join(format!("dap-test-{:?}.bin", std::thread::current().id()));
+    std::fs::write(&path, program).unwrap();
+
+    let line_map: Vec<Value> = CODE
+        .iter()
+        .zip(LINES.iter())
+        .map(|((addr, _), line)| json!({ "source": "src/main.s", "line": line, "address": addr }))
+        .collect();
+
+    let mut s = Session {
+        adapter: Adapter::new(),
+        seq: 0,
+    };
+    let caps = s.ok("initialize", json!({ "adapterID": "6502" }));
+    assert_eq!(caps["supportsDisassembleRequest"], true);
+
+    let out = s.request(
+        "launch",
+        json!({
+            "program": path.to_str().unwrap(),
+            "loadAddress": "$8000",
+            "stopOnEntry": true,
+            "labels": { "reset": "0x8000", "sub": 0x8010, "inner": 0x8020, "counter": "$0200" },
+            "lineMap": line_map,
+        }),
+    );
+    std::fs::remove_file(path).ok();
+    assert_eq!(out[0]["success"], true, "{}", out[0]);
+    assert_eq!(out[1]["event"], "initialized");
+    s
+}
+
+#[test]
+fn framing_round_trip() {
+    let message = json!({ "seq": 1, "type": "request", "command": "threads" });
+    let mut buf = Vec::new();
+    write_message(&mut buf, &message).unwrap();
+    assert!(buf.starts_with(b"Content-Length: "));
+
+    let mut input = Cursor::new(buf);
+    assert_eq!(read_message(&mut input).unwrap(), Some(message));
+    assert_eq!(read_message(&mut input).unwrap(), None);
+}
+
+#[test]
+fn source_breakpoints_and_stepping() {
+    let mut s = launch();
+
+    let body = s.ok(
+        "setBreakpoints",
+        json!({
+            "source": { "path": "/home/dev/game/src/main.s" },
+            "breakpoints": [{ "line": 3 }, { "line": 99 }],
+        }),
+    );
+    let bps = body["breakpoints"].as_array().unwrap();
+    // A comment line moves to the next line with code
+    assert_eq!(bps[0]["verified"], true);
+    assert_eq!(bps[0]["line"], 4);
+    assert_eq!(bps[1]["verified"], false);
+
+    let out = s.request("configurationDone", json!({}));
+    assert_eq!(out[1]["event"], "stopped");
+    assert_eq!(out[1]["body"]["reason"], "entry");
+
+    let frames = s.ok("stackTrace", json!({ "threadId": 1 }));
+    let frame = &frames["stackFrames"][0];
+    assert_eq!(frame["name"], "reset");
+    assert_eq!(frame["line"], 1);
+    assert_eq!(frame["source"]["name"], "main.s");
+
+    s.ok("continue", json!({ "threadId": 1 }));
+    assert!(s.adapter.is_running());
+    let stopped = s.run_until_stopped();
+    assert_eq!(stopped["body"]["reason"], "breakpoint");
+    assert_eq!(stopped["body"]["hitBreakpointIds"][0], bps[0]["id"]);
+    assert_eq!(s.adapter.system.as_ref().unwrap().cpu.pc, 0x8005);
+
+    // Step into the subroutine from the start, over the nested call, then out
+    s.ok(
+        "setVariable",
+        json!({ "variablesReference": 1, "name": "PC", "value": "sub" }),
+    );
+    let out = s.request("next", json!({ "threadId": 1 }));
+    assert_eq!(out[1]["body"]["reason"], "step");
+    let out = s.request("stepIn", json!({ "threadId": 1 }));
+    assert_eq!(out[1]["body"]["reason"], "step");
+    assert_eq!(s.adapter.system.as_ref().unwrap().cpu.pc, 0x8020);
+    s.request("stepOut", json!({ "threadId": 1 }));
+    assert_eq!(s.adapter.system.as_ref().unwrap().cpu.pc, 0x8015);
+
+    // Clearing the source's breakpoints removes them from the cpu
+    s.ok(
+        "setBreakpoints",
+        json!({ "source": { "path": "/home/dev/game/src/main.s" }, "breakpoints": [] }),
+    );
+    assert!(s
+        .adapter
+        .system
+        .as_ref()
+        .unwrap()
+        .cpu
+        .breakpoints
+        .is_empty());
+
+    let out = s.request("disconnect", json!({}));
+    assert_eq!(out[1]["event"], "terminated");
+    assert!(s.adapter.terminated);
+}
+
+#[test]
+fn variables_evaluate_and_disassembly() {
+    let mut s = launch();
+    s.request("configurationDone", json!({}));
+
+    let scopes = s.ok("scopes", json!({ "frameId": 1 }));
+    assert_eq!(scopes["scopes"].as_array().unwrap().len(), 3);
+
+    s.ok(
+        "setVariable",
+        json!({ "variablesReference": 1, "name": "A", "value": "$42" }),
+    );
+    s.ok(
+        "setVariable",
+        json!({ "variablesReference": 2, "name": "C", "value": "1" }),
+    );
+    let regs = s.ok("variables", json!({ "variablesReference": 1 }));
+    assert_eq!(
+        regs["variables"][0],
+        json!({ "name": "A", "value": "$42", "variablesReference": 0 })
+    );
+    let flags = s.ok("variables", json!({ "variablesReference": 2 }));
+    assert_eq!(flags["variables"][7]["name"], "C");
+    assert_eq!(flags["variables"][7]["value"], "1");
+
+    let memory = s.ok("variables", json!({ "variablesReference": 3 }));
+    let stack = memory["variables"][1]["variablesReference"].clone();
+    let rows = s.ok("variables", json!({ "variablesReference": stack }));
+    assert_eq!(rows["variables"].as_array().unwrap().len(), 16);
+    assert_eq!(rows["variables"][0]["name"], "$0100");
+
+    let result = s.ok("evaluate", json!({ "expression": "A + inner - sub" }));
+    assert_eq!(result["result"], "$52 (82)");
+    let out = s.request("evaluate", json!({ "expression": "A +" }));
+    assert_eq!(out[0]["success"], false);
+
+    let body = s.ok(
+        "disassemble",
+        json!({ "memoryReference": "0x8000", "instructionCount": 4, "resolveSymbols": true }),
+    );
+    let ins = body["instructions"].as_array().unwrap();
+    assert_eq!(ins.len(), 4);
+    assert_eq!(ins[0]["address"], "0x8000");
+    assert_eq!(ins[0]["instructionBytes"], "A2 00");
+    assert_eq!(ins[0]["symbol"], "reset");
+    assert_eq!(ins[1]["address"], "0x8002");
+    assert_eq!(ins[2]["line"], 4);
+
+    let body = s.ok(
+        "disassemble",
+        json!({ "memoryReference": "0x8000", "instructionOffset": -2, "instructionCount": 3 }),
+    );
+    let ins = body["instructions"].as_array().unwrap();
+    assert_eq!(ins.len(), 3);
+    assert_eq!(ins[2]["address"], "0x8000");
+
+    // Requests at the edges of memory or with absurd offsets do not overflow
+    let body = s.ok(
+        "disassemble",
+        json!({ "memoryReference": "0xFFFF", "instructionCount": 2 }),
+    );
+    assert_eq!(body["instructions"].as_array().unwrap().len(), 2);
+    let body = s.ok(
+        "disassemble",
+        json!({ "memoryReference": 0, "instructionOffset": i64::MIN, "instructionCount": 0x10000 }),
+    );
+    assert_eq!(body["instructions"].as_array().unwrap().len(), 0x10000);
+    let out = s.request(
+        "disassemble",
+        json!({ "memoryReference": 0, "instructionCount": u64::MAX }),
+    );
+    assert_eq!(out[0]["success"], false);
+
+    // Instruction breakpoints with a condition on a label
+    let body = s.ok(
+        "setInstructionBreakpoints",
+        json!({ "breakpoints": [{ "instructionReference": "0x8020", "condition": "[counter] == 0" }] }),
+    );
+    assert_eq!(body["breakpoints"][0]["verified"], true);
+    s.ok("continue", json!({ "threadId": 1 }));
+    assert_eq!(s.run_until_stopped()["body"]["reason"], "breakpoint");
+    assert_eq!(s.adapter.system.as_ref().unwrap().cpu.pc, 0x8020);
+
+    // Pause while running
+    s.ok("setInstructionBreakpoints", json!({ "breakpoints": [] }));
+    s.ok("continue", json!({ "threadId": 1 }));
+    s.adapter.run_slice();
+    let out = s.request("pause", json!({ "threadId": 1 }));
+    assert_eq!(out[1]["body"]["reason"], "pause");
+    assert!(!s.adapter.is_running());
+}
@@ -0,0 +1,481 @@
+// GDB remote serial protocol stub.
+//
+// Serves one debugger connection over any byte stream. The register file is
+// a, x, y, p, sp (8 bits each) and pc (16 bits, little endian), described to
+// the client by TARGET_XML. Breakpoints and watchpoints map onto the cpu's
+// Breakpoints; software and hardware breakpoints are the same thing here since
+// neither needs to patch memory.
+use cpu::breakpoint::{AddrRange, BreakHit, BreakKind};
+use cpu::system::{StopReason, System};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.6502.core">
+    <flags id="status" size="1">
+      <field name="C" start="0" end="0"/>
+      <field name="Z" start="1" end="1"/>
+      <field name="I" start="2" end="2"/>
+      <field name="D" start="3" end="3"/>
+      <field name="B" start="4" end="4"/>
+      <field name="U" start="5" end="5"/>
+      <field name="V" start="6" end="6"/>
+      <field name="N" start="7" end="7"/>
+    </flags>
+    <reg name="a" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="status"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// Instructions run between checks for a client interrupt while continuing
+const RUN_CHUNK: u64 = 10_000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// A connection to the debugger
+pub trait Transport: Read + Write {
+    // Whether the client sent a break (Ctrl-C) while the target runs. Must not block.
+    fn interrupt_requested(&mut self) -> io::Result<bool>;
+}
+
+// Consume one pending byte without blocking, Ok(None) when nothing is waiting
+fn poll_byte(stream: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    match stream.read(&mut byte) {
+        Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+        Ok(_) => Ok(Some(byte[0])),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl Transport for TcpStream {
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let byte = poll_byte(self);
+        self.set_nonblocking(false)?;
+        Ok(byte? == Some(0x03))
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    fn interrupt_requested(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let byte = poll_byte(self);
+        self.set_nonblocking(false)?;
+        Ok(byte? == Some(0x03))
+    }
+}
+
+// Accept a single debugger on a TCP address such as "127.0.0.1:2345" and serve it
+pub fn listen_tcp(system: &mut System, addr: impl ToSocketAddrs) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    serve(system, stream)
+}
+
+#[cfg(unix)]
+pub fn listen_unix(system: &mut System, path: impl AsRef<std::path::Path>) -> io::Result<()> {
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    let (stream, _) = listener.accept()?;
+    serve(system, stream)
+}
+
+// Handle packets until the client detaches, kills the target or disconnects
+pub fn serve<T: Transport>(system: &mut System, conn: T) -> io::Result<()> {
+    let mut stub = GdbStub {
+        system,
+        conn,
+        no_ack: false,
+        last_reply: Vec::new(),
+        breakpoints: HashMap::new(),
+    };
+    loop {
+        let packet = match stub.read_packet() {
+            Ok(packet) => packet,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        match packet {
+            Incoming::Interrupt => stub.send(&stop_signal(SIGINT))?,
+            Incoming::Packet(data) => {
+                let (reply, done) = stub.handle(&data)?;
+                if let Some(reply) = reply {
+                    stub.send(&reply)?;
+                }
+                if done {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+enum Incoming {
+    Packet(Vec<u8>),
+    // A bare 0x03 between packets
+    Interrupt,
+}
+
+struct GdbStub<'a, T: Transport> {
+    system: &'a mut System,
+    conn: T,
+    no_ack: bool,
+    // Resent when the client answers with '-'
+    last_reply: Vec<u8>,
+    // (Z packet type, address, length) to breakpoint id
+    breakpoints: HashMap<(u8, u16, u16), u32>,
+}
+
+impl<'a, T: Transport> GdbStub<'a, T> {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut byte = [0u8];
+        self.conn.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn read_packet(&mut self) -> io::Result<Incoming> {
+        loop {
+            match self.read_byte()? {
+                b'$' => {}
+                0x03 => return Ok(Incoming::Interrupt),
+                b'-' => {
+                    let reply = self.last_reply.clone();
+                    self.conn.write_all(&reply)?;
+                    continue;
+                }
+                // Acks and line noise
+                _ => continue,
+            }
+
+            let mut data = Vec::new();
+            let mut sum = 0u8;
+            loop {
+                let b = self.read_byte()?;
+                if b == b'#' {
+                    break;
+                }
+                sum = sum.wrapping_add(b);
+                data.push(b);
+            }
+            let checksum = [self.read_byte()?, self.read_byte()?];
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                == Some(sum);
+
+            if !self.no_ack {
+                self.conn.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Incoming::Packet(unescape(&data)));
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        let sum = reply.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        let packet = format!("${}#{:02x}", reply, sum).into_bytes();
+        self.conn.write_all(&packet)?;
+        self.conn.flush()?;
+        self.last_reply = packet;
+        Ok(())
+    }
+
+    // Returns the reply, None for no reply, and whether the session is over
+    fn handle(&mut self, data: &[u8]) -> io::Result<(Option<String>, bool)> {
+        let text = String::from_utf8_lossy(data).into_owned();
+        let (command, args) = match text.char_indices().nth(1) {
+            Some((i, _)) => text.split_at(i),
+            None => (text.as_str(), ""),
+        };
+
+        let reply = match command {
+            "?" => stop_signal(SIGTRAP),
+            "g" => self.registers(),
+            "G" => match decode_hex(args) {
+                Some(bytes) if bytes.len() >= 7 => {
+                    for (n, b) in bytes[..5].iter().enumerate() {
+                        self.set_register(n, *b as u16);
+                    }
+                    self.set_register(5, u16::from_le_bytes([bytes[5], bytes[6]]));
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16) {
+                Ok(n) if n < 6 => encode_hex(&self.register(n)),
+                _ => "E01".to_string(),
+            },
+            "P" => {
+                let parsed = args.split_once('=').and_then(|(n, value)| {
+                    Some((usize::from_str_radix(n, 16).ok()?, decode_hex(value)?))
+                });
+                match parsed {
+                    Some((n, bytes)) if n < 6 && !bytes.is_empty() => {
+                        let lo = bytes[0] as u16;
+                        let hi = bytes.get(1).copied().unwrap_or(0) as u16;
+                        self.set_register(n, (hi << 8) | lo);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "m" => match parse_addr_len(args) {
+                Some((addr, len)) => {
+                    let bytes: Vec<u8> = (0..len)
+                        .map(|i| self.system.cpu.peek(addr.wrapping_add(i as u16)))
+                        .collect();
+                    encode_hex(&bytes)
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let parsed = args
+                    .split_once(':')
+                    .and_then(|(range, data)| Some((parse_addr_len(range)?, decode_hex(data)?)));
+                match parsed {
+                    Some(((addr, len), bytes)) if bytes.len() == len as usize => {
+                        // Straight into memory, a debugger poke is not a bus access
+                        let ram = &mut self.system.bus.borrow_mut().ram;
+                        ram[addr as usize..addr as usize + bytes.len()].copy_from_slice(&bytes);
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "s" | "c" => {
+                if let Ok(addr) = u16::from_str_radix(args, 16) {
+                    self.system.cpu.pc = addr;
+                }
+                if command == "s" {
+                    self.system.cpu.take_break_hits();
+                    self.system.step_instruction();
+                    match self.system.cpu.take_break_hits().first() {
+                        Some(hit) => stop_reply(hit),
+                        None => stop_signal(SIGTRAP),
+                    }
+                } else {
+                    self.resume()?
+                }
+            }
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "D" => {
+                self.send("OK")?;
+                return Ok((None, true));
+            }
+            "k" => return Ok((None, true)),
+            "q" | "Q" => self.query(&text),
+            // Anything else, vCont included, is unsupported and gets the empty reply
+            _ => String::new(),
+        };
+        Ok((Some(reply), false))
+    }
+
+    fn query(&mut self, text: &str) -> String {
+        if text.starts_with("qSupported") {
+            "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;hwbreak+;swbreak+".to_string()
+        } else if text == "QStartNoAckMode" {
+            // Acknowledged one last time, then acks stop in both directions
+            self.no_ack = true;
+            "OK".to_string()
+        } else if let Some(args) = text.strip_prefix("qXfer:features:read:target.xml:") {
+            match parse_addr_len(args) {
+                Some((offset, len)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + len as usize).min(xml.len());
+                    let marker = if end == xml.len() { 'l' } else { 'm' };
+                    format!("{}{}", marker, escape(&xml[start..end]))
+                }
+                None => "E01".to_string(),
+            }
+        } else if text == "qAttached" {
+            "1".to_string()
+        } else if text == "qC" {
+            "QC1".to_string()
+        } else if text == "qfThreadInfo" {
+            "m1".to_string()
+        } else if text == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    // Run until a breakpoint fires or the client interrupts
+    fn resume(&mut self) -> io::Result<String> {
+        let mut first = true;
+        loop {
+            // run_until_break lets the first instruction through, so check the
+            // pc breakpoints at chunk boundaries here
+            if !first {
+                if let Some(hit) = self.system.cpu.check_execute_breakpoints().first() {
+                    return Ok(stop_reply(hit));
+                }
+            }
+            first = false;
+
+            if let StopReason::Break(hits) = self.system.run_until_break(Some(RUN_CHUNK)) {
+                return Ok(stop_reply(&hits[0]));
+            }
+            if self.conn.interrupt_requested()? {
+                return Ok(stop_signal(SIGINT));
+            }
+        }
+    }
+
+    // Z/z type,addr,kind. Types 0 and 1 break on execution, 2 on write, 3 on
+    // read and 4 on any access of kind bytes.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let (kind, addr, len) = match parse_breakpoint(args) {
+            Some(p) => p,
+            None => return "E01".to_string(),
+        };
+        let range = AddrRange::new(addr, addr.saturating_add(len - 1));
+        let break_kind = match kind {
+            0 | 1 => BreakKind::Pc(addr),
+            2 => BreakKind::Write(range),
+            3 => BreakKind::Read(range),
+            4 => BreakKind::Access(range),
+            _ => return String::new(),
+        };
+
+        let key = (kind, addr, len);
+        let breakpoints = &mut self.system.cpu.breakpoints;
+        if insert {
+            self.breakpoints
+                .entry(key)
+                .or_insert_with(|| breakpoints.add(break_kind));
+            "OK".to_string()
+        } else {
+            match self.breakpoints.remove(&key) {
+                Some(id) => {
+                    breakpoints.remove(id);
+                    "OK".to_string()
+                }
+                None => "E01".to_string(),
+            }
+        }
+    }
+
+    // Little endian bytes of register n
+    fn register(&self, n: usize) -> Vec<u8> {
+        let cpu = &self.system.cpu;
+        match n {
+            0 => vec![cpu.acc],
+            1 => vec![cpu.x],
+            2 => vec![cpu.y],
+            3 => vec![cpu.psr],
+            4 => vec![cpu.sp],
+            _ => cpu.pc.to_le_bytes().to_vec(),
+        }
+    }
+
+    fn registers(&self) -> String {
+        let bytes: Vec<u8> = (0..6).flat_map(|n| self.register(n)).collect();
+        encode_hex(&bytes)
+    }
+
+    fn set_register(&mut self, n: usize, value: u16) {
+        let cpu = &mut self.system.cpu;
+        match n {
+            0 => cpu.acc = value as u8,
+            1 => cpu.x = value as u8,
+            2 => cpu.y = value as u8,
+            3 => cpu.psr = value as u8,
+            4 => cpu.sp = value as u8,
+            _ => cpu.pc = value,
+        }
+    }
+}
+
+fn stop_signal(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn stop_reply(hit: &BreakHit) -> String {
+    let watch = match hit.kind {
+        BreakKind::Write(_) => "watch",
+        BreakKind::Read(_) => "rwatch",
+        BreakKind::Access(_) => "awatch",
+        _ => return stop_signal(SIGTRAP),
+    };
+    format!("T{:02x}{}:{:04x};", SIGTRAP, watch, hit.addr)
+}
+
+// "type,addr,kind", the kind of a watchpoint being its length in bytes
+fn parse_breakpoint(text: &str) -> Option<(u8, u16, u16)> {
+    let mut fields = text.split(',');
+    let kind = fields.next()?.parse::<u8>().ok()?;
+    let addr = u16::from_str_radix(fields.next()?, 16).ok()?;
+    let len = u16::from_str_radix(fields.next()?, 16).ok()?.max(1);
+    Some((kind, addr, len))
+}
+
+// "addr,length" in hex, the length cut short at the top of memory
+fn parse_addr_len(text: &str) -> Option<(u16, u32)> {
+    let (addr, len) = text.split_once(',')?;
+    let addr = u32::from_str_radix(addr, 16).ok()?;
+    let len = u32::from_str_radix(len, 16).ok()?;
+    if addr > 0xFFFF {
+        return None;
+    }
+    Some((addr as u16, len.min(0x10000 - addr)))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// '}' escapes the following byte, XORed with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'}' {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn escape(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len());
+    for &b in data {
+        if matches!(b, b'#' | b'$' | b'}' | b'*') {
+            out.push('}');
+            out.push((b ^ 0x20) as char);
+        } else {
+            out.push(b as char);
+        }
+    }
+    out
+}
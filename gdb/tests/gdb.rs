@@ -0,0 +1,148 @@
+use cpu::heatmap::AccessStats;
+use cpu::system::System;
+use cpu::testing::TestMachine;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+// lda #$42; sta $0200; inx; jmp $8003
+const ROM: &[u8] = &[0xA9, 0x42, 0x8D, 0x00, 0x02, 0xE8, 0x4C, 0x05, 0x80];
+
+fn machine() -> System {
+    TestMachine::new().program(ROM).boot().system
+}
+
+struct Client {
+    stream: TcpStream,
+    ack: bool,
+}
+
+impl Client {
+    // Start a stub on a local port and connect to it
+    fn connect() -> (Client, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut system = machine();
+            system.bus.borrow_mut().stats = Some(AccessStats::new());
+            gdb::serve(&mut system, stream).unwrap();
+            // Debugger pokes at $0300 are not bus traffic
+            let stats = system.bus.borrow_mut().stats.take().unwrap();
+            assert_eq!(stats.writes[0x0300], 0);
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        (Client { stream, ack: true }, server)
+    }
+
+    fn byte(&mut self) -> u8 {
+        let mut b = [0u8];
+        self.stream.read_exact(&mut b).unwrap();
+        b[0]
+    }
+
+    fn send(&mut self, data: &str) {
+        let sum = data.bytes().fold(0u8, |s, b| s.wrapping_add(b));
+        write!(self.stream, "${}#{:02x}", data, sum).unwrap();
+        if self.ack {
+            assert_eq!(self.byte(), b'+');
+        }
+    }
+
+    fn reply(&mut self) -> String {
+        assert_eq!(self.byte(), b'$');
+        let mut data = Vec::new();
+        loop {
+            match self.byte() {
+                b'#' => break,
+                b => data.push(b),
+            }
+        }
+        let checksum = [self.byte(), self.byte()];
+        let sum = data.iter().fold(0u8, |s, b| s.wrapping_add(*b));
+        assert_eq!(
+            std::str::from_utf8(&checksum).unwrap(),
+            format!("{:02x}", sum)
+        );
+        if self.ack {
+            self.stream.write_all(b"+").unwrap();
+        }
+        String::from_utf8(data).unwrap()
+    }
+
+    fn ask(&mut self, data: &str) -> String {
+        self.send(data);
+        self.reply()
+    }
+}
+
+#[test]
+fn registers_memory_and_target_description() {
+    let (mut c, server) = Client::connect();
+
+    assert!(c
+        .ask("qSupported:swbreak+")
+        .contains("qXfer:features:read+"));
+    assert_eq!(c.ask("QStartNoAckMode"), "OK");
+    c.ack = false;
+
+    let xml = c.ask("qXfer:features:read:target.xml:0,fff");
+    assert!(xml.starts_with("l<?xml"));
+    assert!(xml.contains("name=\"pc\" bitsize=\"16\""));
+    let part = c.ask("qXfer:features:read:target.xml:0,10");
+    assert_eq!(part.len(), 17);
+    assert!(part.starts_with('m'));
+
+    assert_eq!(c.ask("?"), "S05");
+    // a x y p sp pc
+    assert_eq!(c.ask("g"), "00000024fd0080");
+    assert_eq!(c.ask("P1=7f"), "OK");
+    assert_eq!(c.ask("p1"), "7f");
+    assert_eq!(c.ask("G1122334455aa80"), "OK");
+    assert_eq!(c.ask("p5"), "aa80");
+    assert_eq!(c.ask("P5=0080"), "OK");
+
+    assert_eq!(c.ask("m8000,3"), "a9428d");
+    assert_eq!(c.ask("M0300,2:beef"), "OK");
+    assert_eq!(c.ask("m0300,2"), "beef");
+    assert_eq!(c.ask("M0300,2:be"), "E01");
+    // Lengths run up to the top of memory and no further
+    assert_eq!(c.ask("m0,10000").len(), 0x20000);
+    assert_eq!(c.ask("mfffe,10"), "0000");
+    assert_eq!(c.ask("Mfffe,4:01020304"), "E01");
+    assert_eq!(c.ask("vMustReplyEmpty"), "");
+
+    c.send("k");
+    server.join().unwrap();
+}
+
+#[test]
+fn step_continue_breakpoints_and_interrupt() {
+    let (mut c, server) = Client::connect();
+
+    assert_eq!(c.ask("s"), "S05");
+    assert_eq!(c.ask("p0"), "42");
+    assert_eq!(c.ask("p5"), "0280");
+
+    // A write watchpoint stops after the store with the watched address
+    assert_eq!(c.ask("Z2,0200,1"), "OK");
+    assert_eq!(c.ask("c"), "T05watch:0200;");
+    assert_eq!(c.ask("p5"), "0580");
+    assert_eq!(c.ask("z2,0200,1"), "OK");
+    assert_eq!(c.ask("z2,0200,1"), "E01");
+
+    assert_eq!(c.ask("Z0,8005,1"), "OK");
+    assert_eq!(c.ask("c"), "S05");
+    assert_eq!(c.ask("p5"), "0580");
+    assert_eq!(c.ask("p1"), "01");
+    assert_eq!(c.ask("z0,8005,1"), "OK");
+
+    // With nothing to stop it the loop runs until the client breaks in
+    c.send("c");
+    thread::sleep(std::time::Duration::from_millis(50));
+    c.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(c.reply(), "S02");
+
+    assert_eq!(c.ask("D"), "OK");
+    server.join().unwrap();
+}
@@ -0,0 +1,393 @@
+// Batch runner without a window.
+//
+// Loads a program, runs it until a limit, a breakpoint or a trap, then reports
+// the registers and any memory asked for, and saves the picture and sound of
+// attached devices. Meant for CI jobs and scripted experiments; a Rhai
+// script given with --script can drive input, watch memory and end the run.
+//
+// Programs are iNES images (NROM only, as there are no mappers), SNES images
+// (.sfc/.smc, bank 0 as the 6502 sees it), or anything cpu::loader reads:
+// raw binaries loaded at an address, Intel HEX, S-records and C64 PRG files.
+// A program that does not cover the reset vector gets one pointing at its
+// start record, or else its first byte.
+use cpu::breakpoint::BreakKind;
+use cpu::image;
+use cpu::loader::{self, Format};
+use cpu::region::Region;
+use cpu::system::{Console, System};
+use script::{Script, ScriptError};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+pub const USAGE: &str = "\
+usage: headless [options] <file>
+  --load <addr>          load address of a raw binary ($8000)
+                         .hex, .s19/.srec and .prg files carry their own
+  --region ntsc|pal|dendy
+                         override the region from the header
+  --frames <n>           run for n frames (60 unless --cycles is given)
+  --cycles <n>           run for n cpu cycles
+  --break <addr>         stop when pc reaches addr, can be repeated
+  --trap                 stop on an instruction that jumps to itself
+  --dump <start>-<end>   print memory after the run, can be repeated
+  --png <file>           save the picture of the attached ppu
+  --wav <file>           save the sound of the attached apu
+  --script <file>        run a Rhai script along, see the script crate
+numbers are decimal unless written $hex or 0xhex";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Options {
+    pub file: String,
+    pub load: Option<u16>,
+    pub region: Option<Region>,
+    pub frames: Option<u64>,
+    pub cycles: Option<u64>,
+    pub breaks: Vec<u16>,
+    pub trap: bool,
+    pub dumps: Vec<(u16, u16)>,
+    pub png: Option<String>,
+    pub wav: Option<String>,
+    pub script: Option<String>,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .map(String::as_str)
+                    .ok_or_else(|| format!("{} needs a value", arg))
+            };
+            match arg.as_str() {
+                "--load" => options.load = Some(addr(value()?)?),
+                "--region" => {
+                    options.region = Some(match value()? {
+                        "ntsc" => Region::Ntsc,
+                        "pal" => Region::Pal,
+                        "dendy" => Region::Dendy,
+                        other => return Err(format!("unknown region {}", other)),
+                    })
+                }
+                "--frames" => options.frames = Some(number(value()?)?),
+                "--cycles" => options.cycles = Some(number(value()?)?),
+                "--break" => options.breaks.push(addr(value()?)?),
+                "--trap" => options.trap = true,
+                "--dump" => {
+                    let range = value()?;
+                    let (start, end) = range
+                        .split_once('-')
+                        .ok_or_else(|| format!("{}: expected <start>-<end>", range))?;
+                    let (start, end) = (addr(start)?, addr(end)?);
+                    if end < start {
+                        return Err(format!("{}: end is before start", range));
+                    }
+                    options.dumps.push((start, end));
+                }
+                "--png" => options.png = Some(value()?.to_string()),
+                "--wav" => options.wav = Some(value()?.to_string()),
+                "--script" => options.script = Some(value()?.to_string()),
+                flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+                file if options.file.is_empty() => options.file = file.to_string(),
+                extra => return Err(format!("unexpected argument {}", extra)),
+            }
+        }
+        if options.file.is_empty() {
+            return Err("no file given".to_string());
+        }
+        if options.frames.is_none() && options.cycles.is_none() {
+            options.frames = Some(60);
+        }
+        Ok(options)
+    }
+}
+
+fn number(text: &str) -> Result<u64, String> {
+    let parsed = if let Some(hex) = text.strip_prefix('$') {
+        u64::from_str_radix(hex, 16)
+    } else if let Some(hex) = text.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else {
+        text.parse()
+    };
+    parsed.map_err(|_| format!("{}: not a number", text))
+}
+
+fn addr(text: &str) -> Result<u16, String> {
+    match number(text)? {
+        n @ 0..=0xFFFF => Ok(n as u16),
+        _ => Err(format!("{}: address out of range", text)),
+    }
+}
+
+// A program ready to copy into memory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub console: Console,
+    // Address and bytes of each piece
+    pub chunks: Vec<(u16, Vec<u8>)>,
+}
+
+impl Program {
+    // name is only used for its extension
+    pub fn from_bytes(name: &str, data: &[u8], load: Option<u16>) -> Result<Program, String> {
+        let lower = name.to_ascii_lowercase();
+        if data.starts_with(b"NES\x1A") {
+            Program::ines(data)
+        } else if lower.ends_with(".sfc") || lower.ends_with(".smc") {
+            Program::snes(data)
+        } else {
+            Program::loaded(name, data, load.unwrap_or(0x8000))
+        }
+    }
+
+    fn ines(data: &[u8]) -> Result<Program, String> {
+        if data.len() < 16 {
+            return Err("iNES header is truncated".to_string());
+        }
+        let mapper = (data[6] >> 4) | (data[7] & 0xF0);
+        if mapper != 0 {
+            return Err(format!("mapper {} is not supported, only NROM (0)", mapper));
+        }
+        let start = if data[6] & 0x04 != 0 { 16 + 512 } else { 16 };
+        let size = data[4] as usize * 0x4000;
+        let prg = data
+            .get(start..start + size)
+            .ok_or("iNES image is shorter than its header says")?;
+        let chunks = match size {
+            0x4000 => vec![(0x8000, prg.to_vec()), (0xC000, prg.to_vec())],
+            0x8000 => vec![(0x8000, prg.to_vec())],
+            _ => return Err(format!("NROM has 16K or 32K of prg, not {}K", size / 1024)),
+        };
+        Ok(Program {
+            console: Console::Nes,
+            chunks,
+        })
+    }
+
+    // Bank 0 from $8000 up: the first 32K of a LoROM, the second of a HiROM
+    fn snes(data: &[u8]) -> Result<Program, String> {
+        let rom = if data.len() % 1024 == 512 {
+            &data[512..]
+        } else {
+            data
+        };
+        let header = |base: usize| {
+            rom.get(base..base + 0x20).is_some_and(|h| {
+                let complement = u16::from_le_bytes([h[0x1C], h[0x1D]]);
+                let checksum = u16::from_le_bytes([h[0x1E], h[0x1F]]);
+                complement ^ checksum == 0xFFFF
+            })
+        };
+        // A header can check out in a rom too short to hold the bank it heads
+        let bank = if header(0x7FC0) {
+            rom.get(..0x8000)
+        } else if header(0xFFC0) {
+            rom.get(0x8000..0x10000)
+        } else {
+            None
+        }
+        .ok_or("no valid SNES header at $7FC0 or $FFC0")?;
+        Ok(Program {
+            console: Console::Snes,
+            chunks: vec![(0x8000, bank.to_vec())],
+        })
+    }
+
+    // Raw, Intel HEX, S-record or C64 PRG, by extension or contents
+    fn loaded(name: &str, data: &[u8], load: u16) -> Result<Program, String> {
+        let format = Format::detect(Path::new(name), data);
+        let mut program = loader::Program::parse(format, data, load).map_err(|e| e.to_string())?;
+        if !program.covers(0xFFFC) && !program.covers(0xFFFD) {
+            let entry = program.entry().unwrap_or(load);
+            program.set_reset_vector(entry).map_err(|e| e.to_string())?;
+        }
+        Ok(Program {
+            console: Console::Nes,
+            chunks: program.chunks,
+        })
+    }
+
+    pub fn load(&self, system: &mut System) {
+        let mut bus = system.bus.borrow_mut();
+        for (addr, bytes) in &self.chunks {
+            let at = *addr as usize;
+            bus.ram[at..at + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    // Ran the frames or cycles asked for
+    Limit,
+    Break(u16),
+    Trap(u16),
+    // The script called stop()
+    Script,
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stop::Limit => write!(f, "limit reached"),
+            Stop::Break(pc) => write!(f, "breakpoint at ${:04X}", pc),
+            Stop::Trap(pc) => write!(f, "trapped at ${:04X}", pc),
+            Stop::Script => write!(f, "stopped by the script"),
+        }
+    }
+}
+
+// A system with the file of the options loaded and reset
+pub fn load(options: &Options) -> Result<System, String> {
+    let data = fs::read(&options.file).map_err(|e| format!("{}: {}", options.file, e))?;
+    let program = Program::from_bytes(&options.file, &data, options.load)
+        .map_err(|e| format!("{}: {}", options.file, e))?;
+    let mut system = System::for_rom(program.console, &data, options.region);
+    program.load(&mut system);
+    system.reset();
+    // Finish the reset sequence, so a script starts out at the entry point
+    system.step_instruction();
+    Ok(system)
+}
+
+// Run as the options say, with whatever devices are attached. The cpu has to
+// be between instructions, as load() leaves it. The script, if any, has to be
+// started already; its hooks run along and it can end the run with stop() or
+// fail it with a throw.
+pub fn run(
+    system: &mut System,
+    options: &Options,
+    script: Option<&mut Script>,
+) -> Result<Stop, String> {
+    let ids: Vec<u32> = options
+        .breaks
+        .iter()
+        .map(|addr| system.cpu.breakpoints.add(BreakKind::Pc(*addr)))
+        .collect();
+    let stop = run_until_stop(system, options, script);
+    for id in ids {
+        system.cpu.breakpoints.remove(id);
+    }
+    stop.map_err(|e| format!("script: {}", e))
+}
+
+fn run_until_stop(
+    system: &mut System,
+    options: &Options,
+    mut script: Option<&mut Script>,
+) -> Result<Stop, ScriptError> {
+    let (cycles, frame) = (system.cpu_cycles, system.frame);
+    let mut first = true;
+    loop {
+        if script.as_ref().is_some_and(|s| s.stopped()) {
+            return Ok(Stop::Script);
+        }
+        if options
+            .cycles
+            .is_some_and(|n| system.cpu_cycles - cycles >= n)
+            || options.frames.is_some_and(|n| system.frame - frame >= n)
+        {
+            return Ok(Stop::Limit);
+        }
+        let mut hits = system.cpu.check_execute_breakpoints();
+        if let Some(script) = script.as_deref_mut() {
+            hits = script.dispatch(system, hits)?;
+            if script.stopped() {
+                return Ok(Stop::Script);
+            }
+        }
+        // A breakpoint at the start address does not stop the run before it begins
+        if !first && !hits.is_empty() {
+            return Ok(Stop::Break(system.cpu.pc));
+        }
+        first = false;
+
+        let (pc, interrupt) = (system.cpu.pc, system.cpu.pending_interrupt.is_some());
+        let frame = system.frame;
+        system.step_instruction();
+        let hits = system.cpu.take_break_hits();
+        if let Some(script) = script.as_deref_mut() {
+            script.dispatch(system, hits)?;
+            if system.frame != frame {
+                script.frame_end(system)?;
+            }
+        }
+        if options.trap && !interrupt && system.cpu.pc == pc {
+            return Ok(Stop::Trap(pc));
+        }
+    }
+}
+
+// Registers, scheduler counters and the memory dumps asked for
+pub fn report(system: &System, options: &Options, stop: Stop) -> String {
+    let cpu = &system.cpu;
+    let mut out = format!(
+        "{}\nPC:{:04X} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{:02X} cycles:{} frames:{}",
+        stop, cpu.pc, cpu.acc, cpu.x, cpu.y, cpu.sp, cpu.psr, system.cpu_cycles, system.frame
+    );
+    for (start, end) in &options.dumps {
+        for row in (*start as u32..=*end as u32).step_by(16) {
+            let last = (row + 15).min(*end as u32);
+            let bytes: Vec<String> = (row..=last)
+                .map(|addr| format!("{:02X}", cpu.peek(addr as u16)))
+                .collect();
+            out.push_str(&format!("\n${:04X}: {}", row, bytes.join(" ")));
+        }
+    }
+    out
+}
+
+// Save the picture and sound the options ask for, returning what was written
+pub fn save_outputs(system: &mut System, options: &Options) -> Result<Vec<String>, String> {
+    let mut written = Vec::new();
+    if let Some(file) = &options.png {
+        let (width, height, rgb) = system
+            .ppu
+            .as_ref()
+            .and_then(|ppu| ppu.framebuffer())
+            .ok_or("no picture to save: no ppu with a framebuffer is attached")?;
+        fs::File::create(file)
+            .and_then(|f| image::write_png(f, width, height, &rgb))
+            .map_err(|e| format!("{}: {}", file, e))?;
+        written.push(format!("wrote {}x{} picture to {}", width, height, file));
+    }
+    if let Some(file) = &options.wav {
+        let apu = system
+            .apu
+            .as_mut()
+            .ok_or("no sound to save: no apu is attached")?;
+        let (rate, samples) = (apu.sample_rate(), apu.take_samples());
+        fs::File::create(file)
+            .and_then(|f| write_wav(f, rate, &samples))
+            .map_err(|e| format!("{}: {}", file, e))?;
+        written.push(format!("wrote {} samples to {}", samples.len(), file));
+    }
+    Ok(written)
+}
+
+// 16 bit mono PCM
+pub fn write_wav(mut out: impl Write, rate: u32, samples: &[i16]) -> io::Result<()> {
+    let data = samples.len() as u32 * 2;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // PCM, one channel
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&rate.to_le_bytes())?;
+    out.write_all(&(rate * 2).to_le_bytes())?;
+    // Block align, bits per sample
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data.to_le_bytes())?;
+    for sample in samples {
+        out.write_all(&sample.to_le_bytes())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,80 @@
+// headless [options] <file>
+//
+// Runs a program without a window and prints where it stopped, see USAGE.
+// Exits with 1 on errors, including a script that throws, and 2 when a
+// --break, --trap or script did not stop the run, so a CI job can tell a test
+// rom that never finished.
+use headless::{load, report, run, save_outputs, Options, Stop, USAGE};
+use script::Script;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let options = match Options::parse(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("error: {}\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
+    let mut system = match load(&options) {
+        Ok(system) => system,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut script = match options.script.as_ref() {
+        Some(file) => {
+            let started = Script::load(file, &system).and_then(|mut script| {
+                script.start(&mut system)?;
+                Ok(script)
+            });
+            match started {
+                Ok(script) => Some(script),
+                Err(e) => {
+                    eprintln!("error: {}: {}", file, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let stop = run(&mut system, &options, script.as_mut());
+    if let Some(script) = script.as_mut() {
+        script
+            .take_output()
+            .iter()
+            .for_each(|line| println!("{}", line));
+    }
+    let stop = match stop {
+        Ok(stop) => stop,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    println!("{}", report(&system, &options, stop));
+    if let Some(script) = script.as_ref() {
+        for text in script.text() {
+            println!("text {},{}: {}", text.x, text.y, text.message);
+        }
+    }
+    match save_outputs(&mut system, &options) {
+        Ok(written) => written.iter().for_each(|line| println!("{}", line)),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if stop == Stop::Limit
+        && (options.trap || !options.breaks.is_empty() || options.script.is_some())
+    {
+        std::process::exit(2);
+    }
+}
@@ -0,0 +1,228 @@
+use cpu::asm6502;
+use cpu::bus::Bus;
+use cpu::system::{Console, Device};
+use headless::{load, report, run, save_outputs, write_wav, Options, Program, Stop};
+use script::Script;
+
+// Counts x up to 5 in $10, then loops on itself
+fn counter() -> Vec<u8> {
+    asm6502!(
+        "        ldx #0",
+        "loop:   inx",
+        "        stx $10",
+        "        cpx #5",
+        "        bne loop",
+        "done:   jmp done"
+    )
+}
+
+fn options(args: &[&str]) -> Options {
+    let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+    Options::parse(&args).unwrap()
+}
+
+fn temp(name: &str, data: &[u8]) -> String {
+    let path = std::env::temp_dir().join(name);
+    std::fs::write(&path, data).unwrap();
+    path.display().to_string()
+}
+
+#[test]
+fn parses_options() {
+    let o = options(&[
+        "--cycles", "$100", "--break", "0x8005", "--dump", "$10-$1F", "a.bin",
+    ]);
+    assert_eq!(o.file, "a.bin");
+    assert_eq!((o.cycles, o.frames), (Some(0x100), None));
+    assert_eq!(o.breaks, vec![0x8005]);
+    assert_eq!(o.dumps, vec![(0x10, 0x1F)]);
+    assert_eq!(options(&["a.bin"]).frames, Some(60));
+
+    let parse = |args: &[&str]| {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        Options::parse(&args)
+    };
+    assert!(parse(&[]).is_err());
+    assert!(parse(&["--frames"]).is_err());
+    assert!(parse(&["--dump", "$20-$10", "a.bin"]).is_err());
+    assert!(parse(&["--load", "$10000", "a.bin"]).is_err());
+    assert!(parse(&["--bogus", "a.bin"]).is_err());
+}
+
+#[test]
+fn runs_raw_binary_to_a_trap() {
+    let file = temp("headless-counter.bin", &counter());
+    let o = options(&["--trap", "--dump", "$10-$10", &file]);
+    let mut system = load(&o).unwrap();
+    std::fs::remove_file(&file).ok();
+
+    let stop = run(&mut system, &o, None).unwrap();
+    assert_eq!(stop, Stop::Trap(0x8009));
+    let out = report(&system, &o, stop);
+    assert!(
+        out.starts_with("trapped at $8009\nPC:8009 A:00 X:05"),
+        "{}",
+        out
+    );
+    assert!(out.ends_with("\n$0010: 05"), "{}", out);
+}
+
+#[test]
+fn stops_at_breakpoints_and_limits() {
+    let program = Program::from_bytes("counter.bin", &counter(), Some(0x8000)).unwrap();
+    assert_eq!(program.chunks[1], (0xFFFC, vec![0x00, 0x80]));
+
+    let mut system = cpu::system::System::new(Console::Nes);
+    program.load(&mut system);
+    system.reset();
+    system.step_instruction();
+    // Stops at the store of each pass, the second run ends on the second pass
+    let o = options(&["--break", "$8003", "x"]);
+    assert_eq!(run(&mut system, &o, None).unwrap(), Stop::Break(0x8003));
+    assert_eq!(run(&mut system, &o, None).unwrap(), Stop::Break(0x8003));
+    assert_eq!(system.cpu.x, 2);
+
+    let o = options(&["--cycles", "1000", "x"]);
+    let start = system.cpu_cycles;
+    assert_eq!(run(&mut system, &o, None).unwrap(), Stop::Limit);
+    assert!(system.cpu_cycles - start >= 1000);
+}
+
+#[test]
+fn loads_cartridges() {
+    // NROM-128: 16K of prg mirrored at $C000
+    let mut nes = b"NES\x1A\x01\x01\x00\x00".to_vec();
+    nes.resize(16, 0);
+    let mut prg = vec![0xEA; 0x4000];
+    prg[0x3FFC..].copy_from_slice(&[0x00, 0xC0, 0x00, 0x00]);
+    nes.extend_from_slice(&prg);
+    nes.extend_from_slice(&[0; 0x2000]);
+    let program = Program::from_bytes("game.nes", &nes, None).unwrap();
+    assert_eq!(program.console, Console::Nes);
+    assert_eq!(program.chunks.len(), 2);
+    assert_eq!(program.chunks[1].0, 0xC000);
+
+    nes[6] = 0x10;
+    assert_eq!(
+        Program::from_bytes("game.nes", &nes, None),
+        Err("mapper 1 is not supported, only NROM (0)".to_string())
+    );
+
+    // LoROM with a valid header and a copier header in front
+    let mut rom = vec![0; 512 + 0x20000];
+    let header = 512 + 0x7FC0;
+    rom[header + 0x1C..header + 0x20].copy_from_slice(&[0x34, 0x12, 0xCB, 0xED]);
+    rom[512] = 0xA9;
+    let program = Program::from_bytes("game.SFC", &rom, None).unwrap();
+    assert_eq!(program.console, Console::Snes);
+    assert_eq!(program.chunks[0].0, 0x8000);
+    assert_eq!(program.chunks[0].1.len(), 0x8000);
+    assert_eq!(program.chunks[0].1[0], 0xA9);
+    assert!(Program::from_bytes("game.sfc", &[0; 0x8000], None).is_err());
+
+    // Headers that fit in the file, but whose bank does not
+    for base in [0x7FC0, 0xFFC0].iter() {
+        let mut short = vec![0; base + 0x20];
+        short[base + 0x1C..].copy_from_slice(&[0x34, 0x12, 0xCB, 0xED]);
+        let err = Program::from_bytes("short.sfc", &short, None).unwrap_err();
+        assert_eq!(err, "no valid SNES header at $7FC0 or $FFC0");
+    }
+
+    assert!(Program::from_bytes("big.bin", &[0; 0x100], Some(0xFF80)).is_err());
+}
+
+struct Screen;
+
+impl Device for Screen {
+    fn tick(&mut self, _bus: &mut Bus) {}
+
+    fn framebuffer(&self) -> Option<(u32, u32, Vec<u8>)> {
+        Some((4, 2, vec![0x80; 4 * 2 * 3]))
+    }
+}
+
+struct Beeper(i16);
+
+impl Device for Beeper {
+    fn tick(&mut self, _bus: &mut Bus) {
+        self.0 = self.0.wrapping_add(1);
+    }
+
+    fn take_samples(&mut self) -> Vec<i16> {
+        vec![self.0, -self.0]
+    }
+
+    fn sample_rate(&self) -> u32 {
+        48_000
+    }
+}
+
+#[test]
+fn saves_picture_and_sound() {
+    let dir = std::env::temp_dir();
+    let png = dir.join("headless-screen.png").display().to_string();
+    let wav = dir.join("headless-sound.wav").display().to_string();
+    let file = temp("headless-screen.bin", &counter());
+    let o = options(&["--frames", "1", "--png", &png, "--wav", &wav, &file]);
+    let mut system = load(&o).unwrap();
+    std::fs::remove_file(&file).ok();
+    assert!(save_outputs(&mut system, &o)
+        .unwrap_err()
+        .contains("no ppu"));
+
+    system.attach_ppu(Box::new(Screen));
+    system.attach_apu(Box::new(Beeper(0)));
+    assert_eq!(run(&mut system, &o, None).unwrap(), Stop::Limit);
+    let written = save_outputs(&mut system, &o).unwrap();
+    assert_eq!(written[0], format!("wrote 4x2 picture to {}", png));
+    assert_eq!(written[1], format!("wrote 2 samples to {}", wav));
+
+    let picture = std::fs::read(&png).unwrap();
+    let sound = std::fs::read(&wav).unwrap();
+    std::fs::remove_file(&png).ok();
+    std::fs::remove_file(&wav).ok();
+    assert!(picture.starts_with(b"\x89PNG"));
+    let mut expected = Vec::new();
+    write_wav(
+        &mut expected,
+        48_000,
+        &[sound_sample(&sound), -sound_sample(&sound)],
+    )
+    .unwrap();
+    assert_eq!(sound, expected);
+    assert_eq!(&sound[..4], b"RIFF");
+    assert_eq!(
+        u32::from_le_bytes([sound[24], sound[25], sound[26], sound[27]]),
+        48_000
+    );
+    assert_eq!(sound.len(), 44 + 4);
+}
+
+fn sound_sample(wav: &[u8]) -> i16 {
+    i16::from_le_bytes([wav[44], wav[45]])
+}
+
+#[test]
+fn runs_a_script_along() {
+    let file = temp("headless-script.bin", &counter());
+    let script_file = temp(
+        "headless-script.rhai",
+        b"on_write(0x10, 0x10, |addr, value| { if value == 3 { print(`x is ${reg(\"x\")}`); stop(); } });",
+    );
+    let o = options(&["--script", &script_file, &file]);
+    let mut system = load(&o).unwrap();
+    let mut script = Script::load(&script_file, &system).unwrap();
+    std::fs::remove_file(&file).ok();
+    std::fs::remove_file(&script_file).ok();
+
+    script.start(&mut system).unwrap();
+    assert_eq!(run(&mut system, &o, Some(&mut script)), Ok(Stop::Script));
+    assert_eq!(script.take_output(), vec!["x is 3"]);
+    script.finish(&mut system);
+    assert!(system.cpu.breakpoints.is_empty());
+
+    let mut script = Script::new("on_break(0x8002, |addr| throw \"no\");", &system).unwrap();
+    script.start(&mut system).unwrap();
+    let err = run(&mut system, &o, Some(&mut script)).unwrap_err();
+    assert!(err.starts_with("script: "), "{}", err);
+}
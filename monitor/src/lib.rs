@@ -0,0 +1,796 @@
+// Terminal monitor.
+//
+// Each command is one line of text run against a System through its public
+// api, so the same code serves the interactive binary and sessions piped in
+// over stdin or ssh. Numeric arguments are debugger expressions (see cpu::expr)
+// without spaces, so `$8000`, `pc+3` and `{$FFFC}` all work as addresses.
+use cpu::breakpoint::{AddrRange, BreakKind};
+use cpu::bus::Access;
+use cpu::cdl::CodeDataLog;
+use cpu::disasm::{Disassembler, Formatter, Standard};
+use cpu::expr::Expr;
+use cpu::flow::{ByteKind, CodeMap};
+use cpu::loader::{Format, Program};
+use cpu::symbols::SymbolTable;
+use cpu::system::{StopReason, System};
+use cpu::trace::{self, TraceEvent, TraceFilter};
+use cpu::{Cpu, FLAGS};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+// Instructions next/finish/continue run before handing control back
+pub const RUN_LIMIT: u64 = 10_000_000;
+
+pub const HELP: &str = "\
+execution
+  s, step [n]              run n instructions (1)
+  n, next                  step over a JSR
+  finish                   run until the current subroutine returns
+  c, continue              run until a breakpoint fires
+  reset                    reset the cpu
+  rewind on [kbytes]       snapshot the start of every frame, keeping kbytes of them (16384)
+  rewind off               stop and forget the snapshots
+  rw, rewind [n]           back to the start of the previous frame, n times (1)
+registers
+  r, regs                  show registers
+  r <reg> <value>          set A X Y SP PC P or a flag N V B D I Z C
+memory
+  m, mem <addr> [len]      hex dump (64 bytes)
+  poke <addr> <byte>...    write bytes
+  fill <start> <end> <byte>
+  search <start> <end> <byte>...
+  d, dis [addr] [count]    disassemble (pc, 10 instructions)
+  load <file> [addr]       copy a program into memory, raw binaries at addr
+                           (Intel HEX, S-record and C64 .prg carry their own)
+  export <file> <start> <end>
+                           write ca65 source, following code from pc and the vectors
+trace
+  trace on [start end]     record instructions, optionally only those in a range
+  trace off                stop recording
+  trace save <file>        write the trace, binary if the file ends in .bin
+profile
+  profile on               count cycles per subroutine from here on
+  profile [off]            show the routines taking the most cycles, off also stops
+  profile calls            show the call graph
+  profile save <file>      write folded stacks for flamegraph.pl or inferno
+access heatmap
+  heat on|off              count bus reads, writes and executes per address
+  heat [addr]              show the busiest addresses, or the counts of one
+  heat save <file> [scale] write a png, a pixel per address and a page per row (2)
+code/data log
+  cdl on <base> <size>     flag rom bytes as code or data, prg of size bytes at base
+  cdl load <file> <base> <size>
+                           continue logging from an FCEUX/Mesen .cdl file
+  cdl save <file>          write the log as an FCEUX/Mesen .cdl file
+  cdl [off]                show what has been used, off also stops logging
+symbols
+  sym <file>               load labels (ca65 .dbg, VICE, Mesen .mlb, bsnes .sym)
+  labels [text]            list labels, optionally only those containing text
+breakpoints
+  b, break <addr> [if <cond>]
+  watch <start> [end] [if <cond>]     stop on writes
+  rwatch <start> [end] [if <cond>]    stop on reads
+  awatch <start> [end] [if <cond>]    stop on reads and writes
+  bl, breaks               list breakpoints
+  del <id>|all, enable <id>, disable <id>
+  q, quit
+numbers are decimal unless written $hex or %binary, labels can be used as numbers,
+an empty line repeats the last command";
+
+pub struct Monitor {
+    pub system: System,
+    // Set by quit, the front end stops reading commands
+    pub quit: bool,
+    pub symbols: SymbolTable,
+    // Source text of breakpoint conditions, for listing
+    conditions: HashMap<u32, String>,
+    // Events of the last trace turned off
+    trace: Vec<TraceEvent>,
+    last: String,
+}
+
+impl Monitor {
+    pub fn new(mut system: System) -> Self {
+        // Finish a reset sequence in flight so the first step runs an instruction
+        if !system.cpu.complete() {
+            system.step_instruction();
+        }
+        Monitor {
+            system,
+            quit: false,
+            symbols: SymbolTable::new(),
+            conditions: HashMap::new(),
+            trace: Vec::new(),
+            last: String::new(),
+        }
+    }
+
+    // Run one command line, returning its output
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        let line = match line.trim() {
+            "" => self.last.clone(),
+            line => {
+                self.last = line.to_string();
+                line.to_string()
+            }
+        };
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(String::new()),
+        };
+        let args: Vec<&str> = words.collect();
+
+        match command {
+            "h" | "help" | "?" => Ok(HELP.to_string()),
+            "s" | "step" => {
+                let n = match args.first() {
+                    Some(n) => self.value(n)?.max(0) as u64,
+                    None => 1,
+                };
+                let stop = match self.system.run_until(n, |_, _| false) {
+                    // Running out of instructions is the point of a step
+                    StopReason::Limit => StopReason::Done,
+                    stop => stop,
+                };
+                Ok(self.report(stop))
+            }
+            "n" | "next" => {
+                let stop = self.system.step_over(RUN_LIMIT);
+                Ok(self.report(stop))
+            }
+            "finish" => {
+                let stop = self.system.step_out(RUN_LIMIT);
+                Ok(self.report(stop))
+            }
+            "c" | "continue" => {
+                let stop = self.system.run_until_break(Some(RUN_LIMIT));
+                Ok(self.report(stop))
+            }
+            "reset" => {
+                self.system.reset();
+                self.system.step_instruction();
+                Ok(self.status())
+            }
+            "rw" | "rewind" => match args.as_slice() {
+                ["on", kbytes @ ..] if kbytes.len() <= 1 => {
+                    let kbytes = match kbytes.first() {
+                        Some(kbytes) => self.value(kbytes)?.max(1) as usize,
+                        None => 16 * 1024,
+                    };
+                    self.system.enable_rewind(kbytes * 1024);
+                    Ok(format!("rewinding, {} KiB of frames", kbytes))
+                }
+                ["off"] if self.system.rewind.is_some() => {
+                    self.system.disable_rewind();
+                    Ok(String::new())
+                }
+                ["off"] => Err("not rewinding".to_string()),
+                [] | [_] => {
+                    let n = match args.first() {
+                        Some(n) => self.value(n)?.max(1) as usize,
+                        None => 1,
+                    };
+                    if self.system.rewind.is_none() {
+                        return Err("not rewinding, start with rewind on".to_string());
+                    }
+                    match self.system.rewind_frames(n).map_err(|e| e.to_string())? {
+                        0 => Err("no earlier frame".to_string()),
+                        undone => Ok(format!(
+                            "back {} frames to frame {}\n{}",
+                            undone,
+                            self.system.frame,
+                            self.status()
+                        )),
+                    }
+                }
+                _ => Err("usage: rewind on [kbytes] | off | [n]".to_string()),
+            },
+            "r" | "regs" => match args.as_slice() {
+                [] => Ok(self.status()),
+                [reg, value] => {
+                    let value = self.value(value)?;
+                    self.set_register(reg, value)?;
+                    Ok(self.status())
+                }
+                _ => Err("usage: r [<reg> <value>]".to_string()),
+            },
+            "m" | "mem" => {
+                let addr = self.addr(args.first().ok_or("usage: m <addr> [len]")?)?;
+                let len = match args.get(1) {
+                    Some(len) => self.value(len)?.clamp(1, 0x10000) as usize,
+                    None => 64,
+                };
+                Ok(self.dump(addr, len))
+            }
+            "poke" => {
+                if args.len() < 2 {
+                    return Err("usage: poke <addr> <byte>...".to_string());
+                }
+                let addr = self.addr(args[0])?;
+                let bytes = self.bytes(&args[1..])?;
+                let mut bus = self.system.bus.borrow_mut();
+                for (i, b) in bytes.iter().enumerate() {
+                    bus.ram[addr.wrapping_add(i as u16) as usize] = *b;
+                }
+                Ok(String::new())
+            }
+            "fill" => match args.as_slice() {
+                [start, end, value] => {
+                    let range = self.range(start, Some(*end))?;
+                    let value = self.byte(value)?;
+                    let mut bus = self.system.bus.borrow_mut();
+                    for addr in range.start..=range.end {
+                        bus.ram[addr as usize] = value;
+                    }
+                    Ok(String::new())
+                }
+                _ => Err("usage: fill <start> <end> <byte>".to_string()),
+            },
+            "search" => {
+                if args.len() < 3 {
+                    return Err("usage: search <start> <end> <byte>...".to_string());
+                }
+                let range = self.range(args[0], Some(args[1]))?;
+                let needle = self.bytes(&args[2..])?;
+                let bus = self.system.bus.borrow();
+                let haystack = &bus.ram[range.start as usize..=range.end as usize];
+                let found: Vec<String> = haystack
+                    .windows(needle.len())
+                    .enumerate()
+                    .filter(|(_, w)| *w == needle.as_slice())
+                    .map(|(i, _)| format!("${:04X}", range.start as usize + i))
+                    .collect();
+                if found.is_empty() {
+                    Ok("not found".to_string())
+                } else {
+                    Ok(found.join("\n"))
+                }
+            }
+            "d" | "dis" => {
+                let addr = match args.first() {
+                    Some(addr) => self.addr(addr)?,
+                    None => self.system.cpu.pc,
+                };
+                let count = match args.get(1) {
+                    Some(count) => self.value(count)?.clamp(1, 0x10000) as usize,
+                    None => 10,
+                };
+                Ok(self.disassemble(addr, count).join("\n"))
+            }
+            "load" => {
+                let (file, origin) = match args.as_slice() {
+                    [file] => (*file, None),
+                    [file, addr] => (*file, Some(self.addr(addr)?)),
+                    _ => return Err("usage: load <file> [addr]".to_string()),
+                };
+                let data = fs::read(file).map_err(|e| format!("{}: {}", file, e))?;
+                let format = Format::detect(Path::new(file), &data);
+                if format == Format::Raw && origin.is_none() {
+                    return Err(format!("{}: a raw binary needs a load address", file));
+                }
+                let program = Program::parse(format, &data, origin.unwrap_or(0))
+                    .map_err(|e| format!("{}: {}", file, e))?;
+                program.write(&mut self.system.bus.borrow_mut());
+                let mut lines: Vec<String> = program
+                    .chunks
+                    .iter()
+                    .map(|(addr, bytes)| {
+                        format!(
+                            "loaded {} bytes at ${:04X}-${:04X}",
+                            bytes.len(),
+                            addr,
+                            *addr as usize + bytes.len() - 1
+                        )
+                    })
+                    .collect();
+                if let Some(start) = program.start {
+                    lines.push(format!("start ${:04X}", start));
+                }
+                Ok(lines.join("\n"))
+            }
+            "export" => match args.as_slice() {
+                [file, start, end] => {
+                    let (start, end) = (self.addr(start)?, self.addr(end)?);
+                    if end < start {
+                        return Err("end is before start".to_string());
+                    }
+                    let cpu = &self.system.cpu;
+                    let image: Vec<u8> = (start..=end).map(|a| cpu.peek(a)).collect();
+                    let map = CodeMap::analyze(&image, start, &[cpu.pc]);
+                    fs::write(file, map.to_ca65(Some(&self.symbols)))
+                        .map_err(|e| format!("{}: {}", file, e))?;
+                    let code = map
+                        .kinds
+                        .iter()
+                        .filter(|k| **k != ByteKind::Unknown)
+                        .count();
+                    Ok(format!(
+                        "wrote {} instructions, {} jump tables, {} of {} bytes as code",
+                        map.instructions.len(),
+                        map.jump_tables.len(),
+                        code,
+                        image.len()
+                    ))
+                }
+                _ => Err("usage: export <file> <start> <end>".to_string()),
+            },
+            "trace" => match args.as_slice() {
+                ["on"] => {
+                    self.system.cpu.enable_trace(TraceFilter::default());
+                    Ok("tracing".to_string())
+                }
+                ["on", start, end] => {
+                    let range = self.range(start, Some(*end))?;
+                    self.system.cpu.enable_trace(TraceFilter {
+                        range: Some((range.start, range.end)),
+                        ..TraceFilter::default()
+                    });
+                    Ok(format!("tracing ${:04X}-${:04X}", range.start, range.end))
+                }
+                ["off"] => {
+                    let trace = self.system.cpu.disable_trace().ok_or("not tracing")?;
+                    self.trace = trace.events;
+                    Ok(format!("{} instructions traced", self.trace.len()))
+                }
+                ["save", file] => {
+                    let events = match &self.system.cpu.trace {
+                        Some(trace) => &trace.events,
+                        None => &self.trace,
+                    };
+                    let mut out = Vec::new();
+                    if file.ends_with(".bin") {
+                        trace::write_binary(&mut out, events)
+                    } else {
+                        trace::write_text(&mut out, events, Some(&self.symbols))
+                    }
+                    .and_then(|_| fs::write(file, out))
+                    .map_err(|e| format!("{}: {}", file, e))?;
+                    Ok(format!("wrote {} instructions", events.len()))
+                }
+                _ => Err("usage: trace on [start end] | off | save <file>".to_string()),
+            },
+            "profile" => match args.as_slice() {
+                ["on"] => {
+                    self.system.cpu.enable_profiler();
+                    Ok("profiling".to_string())
+                }
+                [] | ["off"] | ["calls"] | ["save", _] => {
+                    let mut profiler = match &self.system.cpu.profiler {
+                        Some(profiler) => profiler.clone(),
+                        None => return Err("not profiling".to_string()),
+                    };
+                    profiler.finish();
+                    let symbols = Some(&self.symbols);
+                    match args.as_slice() {
+                        ["off"] => {
+                            self.system.cpu.disable_profiler();
+                            Ok(profiler.report(symbols, 20))
+                        }
+                        ["calls"] => Ok(profiler.call_graph(symbols)),
+                        ["save", file] => {
+                            fs::write(file, profiler.folded(symbols))
+                                .map_err(|e| format!("{}: {}", file, e))?;
+                            Ok(format!(
+                                "wrote {} stacks, {} cycles",
+                                profiler.stacks.len(),
+                                profiler.cycles
+                            ))
+                        }
+                        _ => Ok(profiler.report(symbols, 20)),
+                    }
+                }
+                _ => Err("usage: profile on | off | calls | save <file>".to_string()),
+            },
+            "heat" => match args.as_slice() {
+                ["on"] => {
+                    self.system.bus.borrow_mut().enable_stats();
+                    Ok("counting accesses".to_string())
+                }
+                ["off"] => match self.system.bus.borrow_mut().disable_stats() {
+                    Some(_) => Ok(String::new()),
+                    None => Err("not counting".to_string()),
+                },
+                ["save", file, scale @ ..] if scale.len() <= 1 => {
+                    let scale = match scale.first() {
+                        Some(scale) => self.value(scale)?.clamp(1, 16) as u32,
+                        None => 2,
+                    };
+                    let bus = self.system.bus.borrow();
+                    let stats = bus.stats.as_ref().ok_or("not counting")?;
+                    let mut png = Vec::new();
+                    stats
+                        .write_png(&mut png, scale)
+                        .and_then(|_| fs::write(file, png))
+                        .map_err(|e| format!("{}: {}", file, e))?;
+                    Ok(format!("wrote {}x{} heatmap", 256 * scale, 256 * scale))
+                }
+                [addr] => {
+                    let addr = self.addr(addr)?;
+                    let bus = self.system.bus.borrow();
+                    let stats = bus.stats.as_ref().ok_or("not counting")?;
+                    let at = addr as usize;
+                    let writer = match stats.last_writer[at] {
+                        Some(pc) => format!(", last written by ${:04X}", pc),
+                        None => String::new(),
+                    };
+                    Ok(format!(
+                        "${:04X}: {} reads, {} writes, {} executes{}",
+                        addr, stats.reads[at], stats.writes[at], stats.executes[at], writer
+                    ))
+                }
+                [] => {
+                    let bus = self.system.bus.borrow();
+                    let stats = bus.stats.as_ref().ok_or("not counting")?;
+                    let mut lines = Vec::new();
+                    for (name, access) in &[
+                        ("reads", Access::Read),
+                        ("writes", Access::Write),
+                        ("executes", Access::Execute),
+                    ] {
+                        let hot: Vec<String> = stats
+                            .hottest(*access, 8)
+                            .iter()
+                            .map(|(addr, count)| format!("${:04X}:{}", addr, count))
+                            .collect();
+                        lines.push(format!("{:<9} {}", name, hot.join(" ")));
+                    }
+                    Ok(lines.join("\n"))
+                }
+                _ => Err("usage: heat on | off | <addr> | save <file> [scale]".to_string()),
+            },
+            "cdl" => match args.as_slice() {
+                ["on", base, size] => {
+                    let base = self.addr(base)?;
+                    let size = self.value(size)?.clamp(1, 0x10000) as usize;
+                    self.system.cpu.enable_cdl(CodeDataLog::new(base, size, 0));
+                    Ok(format!("logging {} bytes of prg at ${:04X}", size, base))
+                }
+                ["load", file, base, size] => {
+                    let base = self.addr(base)?;
+                    let size = self.value(size)?.clamp(1, 0x10000) as usize;
+                    let data = fs::read(file).map_err(|e| format!("{}: {}", file, e))?;
+                    let log = CodeDataLog::load(&data, base, size)
+                        .map_err(|e| format!("{}: {}", file, e))?;
+                    self.system.cpu.enable_cdl(log);
+                    Ok(self.cdl_stats())
+                }
+                ["save", file] => {
+                    let cdl = self.system.cpu.cdl.as_ref().ok_or("not logging")?;
+                    fs::write(file, cdl.save()).map_err(|e| format!("{}: {}", file, e))?;
+                    Ok(self.cdl_stats())
+                }
+                [] => Ok(self.cdl_stats()),
+                ["off"] => {
+                    let stats = self.cdl_stats();
+                    self.system.cpu.disable_cdl();
+                    Ok(stats)
+                }
+                _ => Err(
+                    "usage: cdl on <base> <size> | load <file> <base> <size> | save <file> | off"
+                        .to_string(),
+                ),
+            },
+            "sym" => {
+                let file = args.first().ok_or("usage: sym <file>")?;
+                let table = SymbolTable::load(file).map_err(|e| format!("{}: {}", file, e))?;
+                let (labels, lines) = (table.labels.len(), table.lines.len());
+                self.symbols.extend(table);
+                Ok(format!(
+                    "loaded {} labels and {} source lines",
+                    labels, lines
+                ))
+            }
+            "labels" => {
+                let filter = args.first().copied().unwrap_or("");
+                let mut labels: Vec<(&String, &u16)> = self
+                    .symbols
+                    .labels
+                    .iter()
+                    .filter(|(name, _)| name.contains(filter))
+                    .collect();
+                labels.sort_by_key(|(name, addr)| (**addr, name.as_str()));
+                let lines: Vec<String> = labels
+                    .iter()
+                    .map(|(name, addr)| format!("${:04X} {}", addr, name))
+                    .collect();
+                Ok(lines.join("\n"))
+            }
+            "b" | "break" => {
+                let (args, condition) = split_condition(&args);
+                match args.as_slice() {
+                    [addr] => {
+                        let addr = self.addr(addr)?;
+                        self.add_breakpoint(BreakKind::Pc(addr), condition)
+                    }
+                    _ => Err("usage: break <addr> [if <cond>]".to_string()),
+                }
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let (args, condition) = split_condition(&args);
+                let range = match args.as_slice() {
+                    [start] => self.range(start, None)?,
+                    [start, end] => self.range(start, Some(*end))?,
+                    _ => return Err(format!("usage: {} <start> [end] [if <cond>]", command)),
+                };
+                let kind = match command {
+                    "watch" => BreakKind::Write(range),
+                    "rwatch" => BreakKind::Read(range),
+                    _ => BreakKind::Access(range),
+                };
+                self.add_breakpoint(kind, condition)
+            }
+            "bl" | "breaks" => Ok(self.list_breakpoints()),
+            "del" | "delete" => match args.as_slice() {
+                ["all"] => {
+                    self.system.cpu.breakpoints.clear();
+                    self.conditions.clear();
+                    Ok(String::new())
+                }
+                [id] => {
+                    let id = self.value(id)? as u32;
+                    self.conditions.remove(&id);
+                    if self.system.cpu.breakpoints.remove(id) {
+                        Ok(String::new())
+                    } else {
+                        Err(format!("no breakpoint #{}", id))
+                    }
+                }
+                _ => Err("usage: del <id>|all".to_string()),
+            },
+            "enable" | "disable" => {
+                let id = self.value(args.first().ok_or("usage: enable|disable <id>")?)? as u32;
+                match self.system.cpu.breakpoints.get_mut(id) {
+                    Some(b) => {
+                        b.enabled = command == "enable";
+                        Ok(String::new())
+                    }
+                    None => Err(format!("no breakpoint #{}", id)),
+                }
+            }
+            "q" | "quit" | "exit" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            _ => Err(format!("unknown command '{}', try help", command)),
+        }
+    }
+
+    // Registers and the instruction about to run
+    pub fn status(&self) -> String {
+        let cpu = &self.system.cpu;
+        let mut flags = String::new();
+        for (mask, name) in [
+            (FLAGS::n(), 'N'),
+            (FLAGS::v(), 'V'),
+            (FLAGS::u(), '-'),
+            (FLAGS::b(), 'B'),
+            (FLAGS::d(), 'D'),
+            (FLAGS::i(), 'I'),
+            (FLAGS::z(), 'Z'),
+            (FLAGS::c(), 'C'),
+        ] {
+            flags.push(if cpu.psr & mask != 0 {
+                name
+            } else {
+                name.to_ascii_lowercase()
+            });
+        }
+        let mut out = format!(
+            "PC=${:04X} A=${:02X} X=${:02X} Y=${:02X} SP=${:02X} P=${:02X} {} cyc={}",
+            cpu.pc, cpu.acc, cpu.x, cpu.y, cpu.sp, cpu.psr, flags, self.system.cpu_cycles
+        );
+        for line in self.disassemble(cpu.pc, 1) {
+            out.push('\n');
+            out.push_str(&line);
+        }
+        out
+    }
+
+    fn report(&self, stop: StopReason) -> String {
+        let mut out = String::new();
+        match stop {
+            StopReason::Done => {}
+            StopReason::Limit => {
+                out.push_str(&format!("stopped after {} instructions\n", RUN_LIMIT))
+            }
+            StopReason::Break(hits) => {
+                for hit in hits {
+                    out.push_str(&format!("break #{} ({})", hit.id, hit.kind));
+                    if let BreakKind::Pc(_) | BreakKind::Execute(_) | BreakKind::Opcode(_) =
+                        hit.kind
+                    {
+                        out.push('\n');
+                    } else {
+                        out.push_str(&format!(
+                            " ${:04X} = ${:02X} at pc ${:04X}\n",
+                            hit.addr, hit.value, hit.pc
+                        ));
+                    }
+                }
+            }
+        }
+        out.push_str(&self.status());
+        out
+    }
+
+    fn add_breakpoint(
+        &mut self,
+        kind: BreakKind,
+        condition: Option<String>,
+    ) -> Result<String, String> {
+        let breakpoints = &mut self.system.cpu.breakpoints;
+        let symbols = &self.symbols;
+        let id = match condition {
+            Some(text) => {
+                // Labels are resolved now, conditions are evaluated without the table
+                let expr = Expr::parse(&text)
+                    .and_then(|e| e.resolve(symbols))
+                    .map_err(|e| format!("{}: {}", text, e))?;
+                let id = breakpoints.add_conditional(kind, expr);
+                self.conditions.insert(id, text);
+                id
+            }
+            None => breakpoints.add(kind),
+        };
+        Ok(format!("breakpoint #{} ({})", id, kind))
+    }
+
+    fn list_breakpoints(&self) -> String {
+        let list = &self.system.cpu.breakpoints.list;
+        if list.is_empty() {
+            return "no breakpoints".to_string();
+        }
+        let lines: Vec<String> = list
+            .iter()
+            .map(|b| {
+                let mut line = format!("#{} {} hits={}", b.id, b.kind, b.hit_count);
+                if !b.enabled {
+                    line.push_str(" disabled");
+                }
+                if let Some(text) = self.conditions.get(&b.id) {
+                    line.push_str(&format!(" if {}", text));
+                }
+                line
+            })
+            .collect();
+        lines.join("\n")
+    }
+
+    fn set_register(&mut self, name: &str, value: i64) -> Result<(), String> {
+        let cpu = &mut self.system.cpu;
+        let flag = |cpu: &mut Cpu, mask: u8| {
+            if value != 0 {
+                cpu.psr |= mask;
+            } else {
+                cpu.psr &= !mask;
+            }
+        };
+        match name.to_ascii_uppercase().as_str() {
+            "A" => cpu.acc = value as u8,
+            "X" => cpu.x = value as u8,
+            "Y" => cpu.y = value as u8,
+            "SP" => cpu.sp = value as u8,
+            "PC" => cpu.pc = value as u16,
+            "P" => cpu.psr = value as u8,
+            "N" => flag(cpu, FLAGS::n()),
+            "V" => flag(cpu, FLAGS::v()),
+            "B" => flag(cpu, FLAGS::b()),
+            "D" => flag(cpu, FLAGS::d()),
+            "I" => flag(cpu, FLAGS::i()),
+            "Z" => flag(cpu, FLAGS::z()),
+            "C" => flag(cpu, FLAGS::c()),
+            _ => return Err(format!("unknown register '{}'", name)),
+        }
+        Ok(())
+    }
+
+    fn dump(&self, addr: u16, len: usize) -> String {
+        let cpu = &self.system.cpu;
+        let mut lines = Vec::new();
+        for row in (0..len).step_by(16) {
+            let start = addr.wrapping_add(row as u16);
+            let bytes: Vec<u8> = (0..16.min(len - row))
+                .map(|i| cpu.peek(start.wrapping_add(i as u16)))
+                .collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let text: String = bytes
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            lines.push(format!("${:04X}: {:<47}  {}", start, hex.join(" "), text));
+        }
+        lines.join("\n")
+    }
+
+    // count instructions from addr, each preceded by its label when it has one
+    fn disassemble(&self, addr: u16, count: usize) -> Vec<String> {
+        let cpu = &self.system.cpu;
+        let mut lines = Vec::new();
+        for ins in Disassembler::new().decode_count(|a| cpu.peek(a), addr, count) {
+            if let Some(label) = self.symbols.label_at(ins.addr) {
+                lines.push(format!("{}:", label));
+            }
+            let marker = if ins.addr == cpu.pc { "> " } else { "" };
+            lines.push(format!(
+                "{}${:04X}  {:<8}  {}",
+                marker,
+                ins.addr,
+                ins.hex_bytes(),
+                Standard.format(&ins, Some(&self.symbols))
+            ));
+        }
+        lines
+    }
+
+    fn value(&self, text: &str) -> Result<i64, String> {
+        Expr::parse(text)
+            .and_then(|e| e.eval_with(&self.system.cpu, &self.symbols))
+            .map_err(|e| format!("{}: {}", text, e))
+    }
+
+    fn addr(&self, text: &str) -> Result<u16, String> {
+        match self.value(text)? {
+            v @ 0..=0xFFFF => Ok(v as u16),
+            v => Err(format!("{}: address ${:X} out of range", text, v)),
+        }
+    }
+
+    fn cdl_stats(&self) -> String {
+        let cdl = match &self.system.cpu.cdl {
+            Some(cdl) => cdl,
+            None => return "not logging".to_string(),
+        };
+        let stats = cdl.stats();
+        let mut out = format!(
+            "{} bytes code, {} data ({} indirect), {} unused",
+            stats.code, stats.data, stats.indirect_data, stats.unused
+        );
+        let unused = cdl.unused();
+        for (start, end) in unused.iter().take(8) {
+            out.push_str(&format!("\n  unused prg ${:05X}-${:05X}", start, end));
+        }
+        if unused.len() > 8 {
+            out.push_str(&format!("\n  {} more unused ranges", unused.len() - 8));
+        }
+        out
+    }
+
+    fn byte(&self, text: &str) -> Result<u8, String> {
+        match self.value(text)? {
+            v @ -0x80..=0xFF => Ok(v as u8),
+            v => Err(format!("{}: {} does not fit in a byte", text, v)),
+        }
+    }
+
+    fn bytes(&self, args: &[&str]) -> Result<Vec<u8>, String> {
+        args.iter().map(|a| self.byte(a)).collect()
+    }
+
+    fn range(&self, start: &str, end: Option<&str>) -> Result<AddrRange, String> {
+        let start = self.addr(start)?;
+        let end = match end {
+            Some(end) => self.addr(end)?,
+            None => start,
+        };
+        if end < start {
+            return Err(format!("range ${:04X}-${:04X} is backwards", start, end));
+        }
+        Ok(AddrRange::new(start, end))
+    }
+}
+
+// Separate `... if <condition>` from the arguments before it
+fn split_condition<'a>(args: &[&'a str]) -> (Vec<&'a str>, Option<String>) {
+    match args.iter().position(|a| *a == "if") {
+        Some(i) => (args[..i].to_vec(), Some(args[i + 1..].join(" "))),
+        None => (args.to_vec(), None),
+    }
+}
@@ -0,0 +1,114 @@
+// monitor [--snes] [--symbols file] [--gdb host:port | --gdb-unix path] [file [load address]]
+//
+// Reads commands from stdin until quit or end of input, so it can run under a
+// terminal or with a script piped in. With --gdb the loaded program is served
+// to a remote debugger instead.
+use cpu::system::{Console, System};
+use monitor::Monitor;
+use std::io::{self, BufRead, Write};
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let console = match args.iter().position(|a| a == "--snes") {
+        Some(i) => {
+            args.remove(i);
+            Console::Snes
+        }
+        None => Console::Nes,
+    };
+    let gdb = option(&mut args, "--gdb");
+    let gdb_unix = option(&mut args, "--gdb-unix");
+    let symbols = option(&mut args, "--symbols");
+
+    let mut system = System::new(console);
+    system.reset();
+    let mut monitor = Monitor::new(system);
+
+    if let Some(file) = symbols {
+        match monitor.execute(&format!("sym {}", file)) {
+            Ok(out) => println!("{}", out),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(file) = args.first() {
+        let addr = args.get(1).map(String::as_str).unwrap_or("$8000");
+        let loaded = monitor
+            .execute(&format!("load {} {}", file, addr))
+            .and_then(|out| {
+                monitor.execute(&format!("r pc {}", addr))?;
+                Ok(out)
+            });
+        match loaded {
+            Ok(out) => println!("{}", out),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+    println!("{}", monitor.status());
+
+    if let Some(addr) = gdb {
+        println!("waiting for gdb on {}", addr);
+        if let Err(e) = gdb::listen_tcp(&mut monitor.system, addr.as_str()) {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(path) = gdb_unix {
+        println!("waiting for gdb on {}", path);
+        if let Err(e) = serve_unix(&mut monitor.system, &path) {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut line = String::new();
+    while !monitor.quit {
+        print!("> ");
+        io::stdout().flush().ok();
+
+        line.clear();
+        match input.read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        match monitor.execute(&line) {
+            Ok(out) if out.is_empty() => {}
+            Ok(out) => println!("{}", out),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+// Remove `name value` from the arguments, returning the value
+fn option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|a| a == name)?;
+    args.remove(i);
+    if i < args.len() {
+        Some(args.remove(i))
+    } else {
+        None
+    }
+}
+
+#[cfg(unix)]
+fn serve_unix(system: &mut System, path: &str) -> io::Result<()> {
+    gdb::listen_unix(system, path)
+}
+
+#[cfg(not(unix))]
+fn serve_unix(_system: &mut System, _path: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "unix sockets are not available on this platform",
+    ))
+}
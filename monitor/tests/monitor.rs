@@ -0,0 +1,300 @@
+use cpu::system::{Console, System};
+use monitor::Monitor;
+
+const PROGRAM: &[(u16, &[u8])] = &[
+    // ldx #0; jsr $8010; sta $0200; jmp *
+    (
+        0x8000,
+        &[
+            0xA2, 0x00, 0x20, 0x10, 0x80, 0x8D, 0x00, 0x02, 0x4C, 0x08, 0x80,
+        ],
+    ),
+    // lda #5; jsr $8020; rts
+    (0x8010, &[0xA9, 0x05, 0x20, 0x20, 0x80, 0x60]),
+    // inx; rts
+    (0x8020, &[0xE8, 0x60]),
+];
+
+fn monitor() -> Monitor {
+    let mut system = System::new(Console::Nes);
+    {
+        let mut bus = system.bus.borrow_mut();
+        for (addr, bytes) in PROGRAM {
+            let addr = *addr as usize;
+            bus.ram[addr..addr + bytes.len()].copy_from_slice(bytes);
+        }
+        bus.ram[0xFFFD] = 0x80;
+    }
+    system.reset();
+    Monitor::new(system)
+}
+
+fn run(m: &mut Monitor, line: &str) -> String {
+    m.execute(line)
+        .unwrap_or_else(|e| panic!("{}: {}", line, e))
+}
+
+#[test]
+fn step_next_and_finish() {
+    let mut m = monitor();
+    assert_eq!(m.system.cpu.pc, 0x8000);
+
+    assert!(run(&mut m, "step").starts_with("PC=$8002"));
+    // Over the whole subroutine, nested call included
+    assert!(run(&mut m, "next").starts_with("PC=$8005"));
+    assert_eq!((m.system.cpu.acc, m.system.cpu.x), (5, 1));
+
+    run(&mut m, "r pc $8000");
+    run(&mut m, "s 3");
+    assert_eq!(m.system.cpu.pc, 0x8012);
+    run(&mut m, "finish");
+    assert_eq!(m.system.cpu.pc, 0x8005);
+
+    // An empty line repeats the last command
+    run(&mut m, "r pc $8000");
+    run(&mut m, "s");
+    run(&mut m, "");
+    assert_eq!(m.system.cpu.pc, 0x8010);
+}
+
+#[test]
+fn registers_and_memory() {
+    let mut m = monitor();
+    let out = run(&mut m, "r a $7F");
+    assert!(out.contains("A=$7F"), "{}", out);
+    run(&mut m, "r c 1");
+    assert!(run(&mut m, "regs").contains("nv-bdIzC"));
+
+    run(&mut m, "fill $0200 $020F $AA");
+    run(&mut m, "poke $0204 1 2 3");
+    let dump = run(&mut m, "m $0200 16");
+    assert!(
+        dump.starts_with("$0200: AA AA AA AA 01 02 03 AA"),
+        "{}",
+        dump
+    );
+
+    assert_eq!(run(&mut m, "search $0200 $02FF 2 3"), "$0205");
+    assert_eq!(run(&mut m, "search $0200 $02FF 4 4"), "not found");
+
+    let dis = run(&mut m, "d $8000 3");
+    assert_eq!(dis.lines().count(), 3);
+    assert!(dis.starts_with("> $8000"), "{}", dis);
+
+    let file = std::env::temp_dir().join("monitor-load-test.bin");
+    std::fs::write(&file, [0xDE, 0xAD, 0xBE, 0xEF]).unwrap();
+    let out = run(&mut m, &format!("load {} $0300", file.display()));
+    assert_eq!(out, "loaded 4 bytes at $0300-$0303");
+    assert_eq!(m.system.bus.borrow().ram[0x0303], 0xEF);
+    let err = m.execute(&format!("load {}", file.display())).unwrap_err();
+    assert!(err.contains("needs a load address"), "{}", err);
+    std::fs::remove_file(file).ok();
+
+    let file = std::env::temp_dir().join("monitor-load-test.hex");
+    std::fs::write(&file, ":020400001234B4\n:0400000500000400F3\n:00000001FF\n").unwrap();
+    let out = run(&mut m, &format!("load {}", file.display()));
+    assert_eq!(out, "loaded 2 bytes at $0400-$0401\nstart $0400");
+    assert_eq!(m.system.bus.borrow().ram[0x0401], 0x34);
+    std::fs::remove_file(file).ok();
+}
+
+#[test]
+fn breakpoints() {
+    let mut m = monitor();
+    assert_eq!(run(&mut m, "b $8020"), "breakpoint #1 (pc $8020)");
+    assert_eq!(
+        run(&mut m, "watch $0200 if A == 5"),
+        "breakpoint #2 (write $0200)"
+    );
+
+    let out = run(&mut m, "c");
+    assert!(out.starts_with("break #1 (pc $8020)\nPC=$8020"), "{}", out);
+
+    let out = run(&mut m, "c");
+    assert!(
+        out.starts_with("break #2 (write $0200) $0200 = $05"),
+        "{}",
+        out
+    );
+
+    run(&mut m, "disable 2");
+    let list = run(&mut m, "bl");
+    assert!(
+        list.contains("#2 write $0200 hits=1 disabled if A == 5"),
+        "{}",
+        list
+    );
+
+    run(&mut m, "del all");
+    assert_eq!(run(&mut m, "bl"), "no breakpoints");
+}
+
+#[test]
+fn reports_errors() {
+    let mut m = monitor();
+    assert!(m.execute("frobnicate").is_err());
+    assert!(m.execute("m").is_err());
+    assert!(m.execute("m $10000").is_err());
+    assert!(m.execute("b $8000 if A ==").unwrap_err().contains("column"));
+    assert!(m.execute("del 9").is_err());
+    run(&mut m, "q");
+    assert!(m.quit);
+}
+
+#[test]
+fn symbols() {
+    let mut m = monitor();
+    let path = std::env::temp_dir().join("monitor-test.mlb");
+    std::fs::write(&path, "P:0010:sub\nP:0020:inner\nR:0200:result\n").unwrap();
+    run(&mut m, &format!("sym {}", path.display()));
+    std::fs::remove_file(&path).ok();
+
+    assert!(run(&mut m, "labels").contains("inner"));
+    let dis = run(&mut m, "d $8000 3");
+    assert!(dis.contains("sub"), "{}", dis);
+    assert!(dis.contains("result"), "{}", dis);
+
+    run(&mut m, "b inner if x == 0");
+    let out = run(&mut m, "c");
+    assert_eq!(m.system.cpu.pc, 0x8020, "{}", out);
+    assert!(run(&mut m, "m result 1").contains("00"));
+}
+
+#[test]
+fn export_source() {
+    let mut m = monitor();
+    let path = std::env::temp_dir().join("monitor-export.s");
+    let out = run(&mut m, &format!("export {} $8000 $8021", path.display()));
+    let source = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+
+    assert!(out.starts_with("wrote 9 instructions"), "{}", out);
+    assert!(
+        source.contains("L8000:\n    ldx #$00\n    jsr L8010\n"),
+        "{}",
+        source
+    );
+    // Between the routines is data
+    assert!(
+        source.contains("    .byte $00,$00,$00,$00,$00\nL8010:"),
+        "{}",
+        source
+    );
+}
+
+#[test]
+fn trace_to_file() {
+    let mut m = monitor();
+    let path = std::env::temp_dir().join("monitor-trace.txt");
+    run(&mut m, "trace on $8010 $8030");
+    run(&mut m, "step 6");
+    assert_eq!(run(&mut m, "trace off"), "4 instructions traced");
+    assert!(m.execute("trace off").is_err());
+
+    run(&mut m, &format!("trace save {}", path.display()));
+    let text = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].starts_with("8010  A9 05     LDA #$05"), "{}", text);
+    assert!(lines[3].starts_with("8021  60        RTS"), "{}", text);
+}
+
+#[test]
+fn code_data_log() {
+    let mut m = monitor();
+    let path = std::env::temp_dir().join("monitor.cdl");
+    assert_eq!(run(&mut m, "cdl"), "not logging");
+    run(&mut m, "cdl on $8000 $40");
+    run(&mut m, "step 6");
+    let out = run(&mut m, &format!("cdl save {}", path.display()));
+    assert!(out.starts_with("12 bytes code, 0 data"), "{}", out);
+    assert!(out.contains("unused prg $00005-$0000F"), "{}", out);
+    run(&mut m, "cdl off");
+
+    let out = run(&mut m, &format!("cdl load {} $8000 $40", path.display()));
+    std::fs::remove_file(&path).ok();
+    assert!(out.starts_with("12 bytes code"), "{}", out);
+    assert_eq!(m.system.cpu.cdl.as_ref().unwrap().prg[0x20], 0x01);
+}
+
+#[test]
+fn profile() {
+    let mut m = monitor();
+    let path = std::env::temp_dir().join("monitor-profile.folded");
+    run(&mut m, "profile on");
+    run(&mut m, "step 6");
+    let report = run(&mut m, "profile");
+    assert!(
+        report.lines().nth(1).unwrap().starts_with("$8000 "),
+        "{}",
+        report
+    );
+    let calls = run(&mut m, "profile calls");
+    assert!(
+        calls.contains("$8010 -> $8020  1 calls, 8 cycles"),
+        "{}",
+        calls
+    );
+    run(&mut m, &format!("profile save {}", path.display()));
+    let folded = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert!(folded.contains("$8000;$8010;$8020 8\n"), "{}", folded);
+    run(&mut m, "profile off");
+    assert!(m.execute("profile").is_err());
+}
+
+#[test]
+fn heatmap() {
+    let mut m = monitor();
+    let path = std::env::temp_dir().join("monitor-heat.png");
+    assert!(m.execute("heat").is_err());
+    run(&mut m, "heat on");
+    run(&mut m, "step 6");
+    let hot = run(&mut m, "heat");
+    assert!(
+        hot.contains("writes    $01FA:1 $01FB:1 $01FC:1 $01FD:1"),
+        "{}",
+        hot
+    );
+    assert_eq!(
+        run(&mut m, "heat $01FB"),
+        "$01FB: 1 reads, 1 writes, 0 executes, last written by $8012"
+    );
+    assert_eq!(
+        run(&mut m, &format!("heat save {} 1", path.display())),
+        "wrote 256x256 heatmap"
+    );
+    let png = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).ok();
+    assert!(png.starts_with(b"\x89PNG"));
+    run(&mut m, "heat off");
+}
+
+#[test]
+fn rewind() {
+    let mut m = monitor();
+    assert!(m.execute("rewind").unwrap_err().contains("not rewinding"));
+    assert_eq!(run(&mut m, "rewind on 64"), "rewinding, 64 KiB of frames");
+    assert_eq!(m.execute("rw").unwrap_err(), "no earlier frame");
+
+    // Spinning on the jmp * runs through a few frames
+    run(&mut m, "s 40000");
+    let frame = m.system.frame;
+    assert!(frame >= 3);
+    let out = run(&mut m, "rw");
+    assert!(
+        out.starts_with(&format!("back 1 frames to frame {}\nPC=$", frame - 1)),
+        "{}",
+        out
+    );
+    assert_eq!(
+        m.system.master_cycles,
+        (frame - 1) * m.system.clock.master_cycles_per_frame
+    );
+    assert!(run(&mut m, "rw 100").starts_with(&format!("back {} frames to frame 0", frame - 1)));
+    assert_eq!(m.system.bus.borrow().ram[0x0200], 0x00);
+
+    run(&mut m, "rewind off");
+    assert!(m.execute("rw").is_err());
+}
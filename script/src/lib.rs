@@ -0,0 +1,484 @@
+// Rhai scripting.
+//
+// A script runs once when started, to set things up and register hooks, and
+// from then on its hooks run as the system does:
+//
+//   on_frame(|frame| ...)                      after every frame
+//   on_break(addr, |addr| ...)                 before the instruction at addr
+//   on_read(start, end, |addr, value| ...)     after an instruction reads the range
+//   on_write(start, end, |addr, value| ...)    after an instruction writes the range
+//
+// The top level and the hooks can use
+//
+//   peek(addr)  peek16(addr)  poke(addr, value)
+//   reg(name)  set_reg(name, value)            a x y sp pc p
+//   frame()  cycles()
+//   press(buttons)  press(buttons, frames)  release(buttons)
+//   press(port, buttons, frames)  release(port, buttons)
+//   text(x, y, message)  clear_text()
+//   print(message)  stop()
+//
+// Buttons are names joined with '+', like "start" or "a+right", on port 0
+// unless given. A press with a frame count lets go after that many frames.
+// Text is kept until clear_text; cpu-test draws it over its window and
+// headless prints it after the run. stop() ends the run and `throw "message"`
+// fails it. Register changes take effect when the hook returns; memory changes
+// go straight to the bus.
+use cpu::breakpoint::{AddrRange, BreakHit, BreakKind};
+use cpu::bus::Bus;
+use cpu::system::System;
+use cpu::trace::Registers;
+use rhai::{Dynamic, Engine, EvalAltResult, FnPtr, AST, INT};
+use std::cell::RefCell;
+use std::path::Path;
+use std::rc::Rc;
+use std::{fmt, fs, io};
+
+const BUTTONS: [&str; 8] = ["a", "b", "select", "start", "up", "down", "left", "right"];
+
+#[derive(Debug)]
+pub enum ScriptError {
+    Io(io::Error),
+    Parse(String),
+    // A script error or a throw
+    Runtime(String),
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError::Io(e) => write!(f, "{}", e),
+            ScriptError::Parse(message) => write!(f, "{}", message),
+            ScriptError::Runtime(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl From<io::Error> for ScriptError {
+    fn from(e: io::Error) -> Self {
+        ScriptError::Io(e)
+    }
+}
+
+impl From<Box<EvalAltResult>> for ScriptError {
+    fn from(e: Box<EvalAltResult>) -> Self {
+        ScriptError::Runtime(e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Text {
+    pub x: i64,
+    pub y: i64,
+    pub message: String,
+}
+
+#[derive(Clone)]
+struct Hook {
+    // None for frame hooks
+    kind: Option<BreakKind>,
+    func: FnPtr,
+    // Breakpoint backing the hook, once installed
+    id: Option<u32>,
+}
+
+// What the script functions see and change
+struct State {
+    bus: Rc<RefCell<Bus>>,
+    regs: Registers,
+    regs_changed: bool,
+    frame: u64,
+    cycles: u64,
+    buttons: [u8; 2],
+    // Port, buttons and frames left of presses that let go by themselves
+    holds: Vec<(usize, u8, u64)>,
+    hooks: Vec<Hook>,
+    text: Vec<Text>,
+    output: Vec<String>,
+    stopped: bool,
+}
+
+type Shared = Rc<RefCell<State>>;
+
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    state: Shared,
+}
+
+impl Script {
+    pub fn new(source: &str, system: &System) -> Result<Script, ScriptError> {
+        let state = Rc::new(RefCell::new(State {
+            bus: Rc::clone(&system.bus),
+            regs: Registers::default(),
+            regs_changed: false,
+            frame: 0,
+            cycles: 0,
+            buttons: [0; 2],
+            holds: Vec::new(),
+            hooks: Vec::new(),
+            text: Vec::new(),
+            output: Vec::new(),
+            stopped: false,
+        }));
+        let engine = engine(&state);
+        let ast = engine
+            .compile(source)
+            .map_err(|e| ScriptError::Parse(e.to_string()))?;
+        Ok(Script { engine, ast, state })
+    }
+
+    pub fn load(path: impl AsRef<Path>, system: &System) -> Result<Script, ScriptError> {
+        Script::new(&fs::read_to_string(path)?, system)
+    }
+
+    // Run the top level, which registers the hooks
+    pub fn start(&mut self, system: &mut System) -> Result<(), ScriptError> {
+        self.sync_in(system);
+        let result = self.engine.run_ast(&self.ast);
+        self.sync_out(system);
+        Ok(result?)
+    }
+
+    // Run the hooks of the hits that belong to the script and hand back the rest
+    pub fn dispatch(
+        &mut self,
+        system: &mut System,
+        hits: Vec<BreakHit>,
+    ) -> Result<Vec<BreakHit>, ScriptError> {
+        let mut rest = Vec::new();
+        for hit in hits {
+            let hook = self
+                .state
+                .borrow()
+                .hooks
+                .iter()
+                .find(|h| h.id == Some(hit.id))
+                .cloned();
+            let hook = match hook {
+                Some(hook) => hook,
+                None => {
+                    rest.push(hit);
+                    continue;
+                }
+            };
+            let args = match hook.kind {
+                Some(BreakKind::Pc(_)) => vec![Dynamic::from(hit.addr as INT)],
+                _ => vec![
+                    Dynamic::from(hit.addr as INT),
+                    Dynamic::from(hit.value as INT),
+                ],
+            };
+            self.call(system, &hook.func, args)?;
+        }
+        Ok(rest)
+    }
+
+    // Called once a frame has finished: lets go of timed presses, then runs the frame hooks
+    pub fn frame_end(&mut self, system: &mut System) -> Result<(), ScriptError> {
+        {
+            let mut state = self.state.borrow_mut();
+            let mut released = Vec::new();
+            state.holds.retain_mut(|(port, buttons, frames)| {
+                *frames = frames.saturating_sub(1);
+                if *frames == 0 {
+                    released.push((*port, *buttons));
+                }
+                *frames > 0
+            });
+            for (port, buttons) in released {
+                state.buttons[port] &= !buttons;
+            }
+        }
+        let frame_hooks: Vec<FnPtr> = self
+            .state
+            .borrow()
+            .hooks
+            .iter()
+            .filter(|h| h.kind.is_none())
+            .map(|h| h.func.clone())
+            .collect();
+        if frame_hooks.is_empty() {
+            self.sync_out(system);
+        }
+        for func in frame_hooks {
+            let frame = Dynamic::from(system.frame as INT);
+            self.call(system, &func, vec![frame])?;
+        }
+        Ok(())
+    }
+
+    // Remove the breakpoints backing the hooks
+    pub fn finish(&mut self, system: &mut System) {
+        for hook in self.state.borrow_mut().hooks.iter_mut() {
+            if let Some(id) = hook.id.take() {
+                system.cpu.breakpoints.remove(id);
+            }
+        }
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.state.borrow().stopped
+    }
+
+    // Buttons the script holds on a port, None past the last port
+    pub fn buttons(&self, port: usize) -> Option<u8> {
+        self.state.borrow().buttons.get(port).copied()
+    }
+
+    // On-screen text for the frontend to draw
+    pub fn text(&self) -> Vec<Text> {
+        self.state.borrow().text.clone()
+    }
+
+    // Lines printed since the last call
+    pub fn take_output(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.state.borrow_mut().output)
+    }
+
+    fn call(
+        &mut self,
+        system: &mut System,
+        func: &FnPtr,
+        args: Vec<Dynamic>,
+    ) -> Result<(), ScriptError> {
+        self.sync_in(system);
+        let result = func.call::<Dynamic>(&self.engine, &self.ast, args);
+        self.sync_out(system);
+        result.map(|_| ()).map_err(ScriptError::from)
+    }
+
+    fn sync_in(&mut self, system: &System) {
+        let mut state = self.state.borrow_mut();
+        state.regs = system.cpu.registers();
+        state.regs_changed = false;
+        state.frame = system.frame;
+        state.cycles = system.cpu_cycles;
+    }
+
+    // Apply register and button changes and back any new hooks with breakpoints
+    fn sync_out(&mut self, system: &mut System) {
+        let mut state = self.state.borrow_mut();
+        if state.regs_changed {
+            let cpu = &mut system.cpu;
+            let r = state.regs;
+            cpu.pc = r.pc;
+            cpu.acc = r.a;
+            cpu.x = r.x;
+            cpu.y = r.y;
+            cpu.sp = r.sp;
+            cpu.psr = r.p;
+        }
+        for (port, buttons) in state.buttons.iter().enumerate() {
+            system.set_buttons(port, *buttons);
+        }
+        for hook in state.hooks.iter_mut() {
+            if let (Some(kind), None) = (hook.kind, hook.id) {
+                hook.id = Some(system.cpu.breakpoints.add(kind));
+            }
+        }
+    }
+}
+
+fn engine(state: &Shared) -> Engine {
+    let mut engine = Engine::new();
+
+    let s = Rc::clone(state);
+    engine.on_print(move |line| s.borrow_mut().output.push(line.to_string()));
+
+    let s = Rc::clone(state);
+    engine.register_fn(
+        "peek",
+        move |addr: INT| -> Result<INT, Box<EvalAltResult>> {
+            Ok(s.borrow().bus.borrow().ram[address(addr)? as usize] as INT)
+        },
+    );
+    let s = Rc::clone(state);
+    engine.register_fn(
+        "peek16",
+        move |addr: INT| -> Result<INT, Box<EvalAltResult>> {
+            let addr = address(addr)?;
+            let state = s.borrow();
+            let bus = state.bus.borrow();
+            let lo = bus.ram[addr as usize];
+            let hi = bus.ram[addr.wrapping_add(1) as usize];
+            Ok(u16::from_le_bytes([lo, hi]) as INT)
+        },
+    );
+    let s = Rc::clone(state);
+    engine.register_fn(
+        "poke",
+        move |addr: INT, value: INT| -> Result<(), Box<EvalAltResult>> {
+            let (addr, value) = (address(addr)?, byte(value)?);
+            s.borrow().bus.borrow_mut().ram[addr as usize] = value;
+            Ok(())
+        },
+    );
+
+    let s = Rc::clone(state);
+    engine.register_fn(
+        "reg",
+        move |name: &str| -> Result<INT, Box<EvalAltResult>> {
+            let r = s.borrow().regs;
+            Ok(match name {
+                "a" => r.a as INT,
+                "x" => r.x as INT,
+                "y" => r.y as INT,
+                "sp" => r.sp as INT,
+                "p" => r.p as INT,
+                "pc" => r.pc as INT,
+                _ => return Err(format!("unknown register {}", name).into()),
+            })
+        },
+    );
+    let s = Rc::clone(state);
+    engine.register_fn(
+        "set_reg",
+        move |name: &str, value: INT| -> Result<(), Box<EvalAltResult>> {
+            let mut state = s.borrow_mut();
+            let r = &mut state.regs;
+            match name {
+                "a" => r.a = byte(value)?,
+                "x" => r.x = byte(value)?,
+                "y" => r.y = byte(value)?,
+                "sp" => r.sp = byte(value)?,
+                "p" => r.p = byte(value)?,
+                "pc" => r.pc = address(value)?,
+                _ => return Err(format!("unknown register {}", name).into()),
+            }
+            state.regs_changed = true;
+            Ok(())
+        },
+    );
+
+    let s = Rc::clone(state);
+    engine.register_fn("frame", move || s.borrow().frame as INT);
+    let s = Rc::clone(state);
+    engine.register_fn("cycles", move || s.borrow().cycles as INT);
+
+    let press = {
+        let s = Rc::clone(state);
+        move |port: INT, names: &str, frames: INT| -> Result<(), Box<EvalAltResult>> {
+            let (port, buttons) = (controller(port)?, buttons(names)?);
+            let mut state = s.borrow_mut();
+            state.buttons[port] |= buttons;
+            if frames > 0 {
+                state.holds.push((port, buttons, frames as u64));
+            }
+            Ok(())
+        }
+    };
+    let p = press.clone();
+    engine.register_fn("press", move |names: &str| p(0, names, 0));
+    let p = press.clone();
+    engine.register_fn("press", move |names: &str, frames: INT| p(0, names, frames));
+    engine.register_fn("press", press);
+    let release = {
+        let s = Rc::clone(state);
+        move |port: INT, names: &str| -> Result<(), Box<EvalAltResult>> {
+            let (port, buttons) = (controller(port)?, buttons(names)?);
+            let mut state = s.borrow_mut();
+            state.buttons[port] &= !buttons;
+            state
+                .holds
+                .retain(|(p, b, _)| !(*p == port && b & buttons != 0));
+            Ok(())
+        }
+    };
+    let r = release.clone();
+    engine.register_fn("release", move |names: &str| r(0, names));
+    engine.register_fn("release", release);
+
+    let s = Rc::clone(state);
+    engine.register_fn("text", move |x: INT, y: INT, message: &str| {
+        s.borrow_mut().text.push(Text {
+            x,
+            y,
+            message: message.to_string(),
+        })
+    });
+    let s = Rc::clone(state);
+    engine.register_fn("clear_text", move || s.borrow_mut().text.clear());
+    let s = Rc::clone(state);
+    engine.register_fn("stop", move || s.borrow_mut().stopped = true);
+
+    let s = Rc::clone(state);
+    engine.register_fn("on_frame", move |func: FnPtr| {
+        add_hook(&s, None, func);
+    });
+    let s = Rc::clone(state);
+    engine.register_fn(
+        "on_break",
+        move |addr: INT, func: FnPtr| -> Result<(), Box<EvalAltResult>> {
+            add_hook(&s, Some(BreakKind::Pc(address(addr)?)), func);
+            Ok(())
+        },
+    );
+    let s = Rc::clone(state);
+    engine.register_fn(
+        "on_read",
+        move |start: INT, end: INT, func: FnPtr| -> Result<(), Box<EvalAltResult>> {
+            add_hook(&s, Some(BreakKind::Read(range(start, end)?)), func);
+            Ok(())
+        },
+    );
+    let s = Rc::clone(state);
+    engine.register_fn(
+        "on_write",
+        move |start: INT, end: INT, func: FnPtr| -> Result<(), Box<EvalAltResult>> {
+            add_hook(&s, Some(BreakKind::Write(range(start, end)?)), func);
+            Ok(())
+        },
+    );
+    engine
+}
+
+fn add_hook(state: &Shared, kind: Option<BreakKind>, func: FnPtr) {
+    state.borrow_mut().hooks.push(Hook {
+        kind,
+        func,
+        id: None,
+    });
+}
+
+fn address(addr: INT) -> Result<u16, Box<EvalAltResult>> {
+    match addr {
+        0..=0xFFFF => Ok(addr as u16),
+        _ => Err(format!("address {} out of range", addr).into()),
+    }
+}
+
+fn byte(value: INT) -> Result<u8, Box<EvalAltResult>> {
+    match value {
+        0..=0xFF => Ok(value as u8),
+        _ => Err(format!("{} does not fit in a byte", value).into()),
+    }
+}
+
+fn range(start: INT, end: INT) -> Result<AddrRange, Box<EvalAltResult>> {
+    let (start, end) = (address(start)?, address(end)?);
+    if end < start {
+        return Err(format!("range ${:04X}-${:04X} ends before it starts", start, end).into());
+    }
+    Ok(AddrRange::new(start, end))
+}
+
+fn controller(port: INT) -> Result<usize, Box<EvalAltResult>> {
+    match port {
+        0 | 1 => Ok(port as usize),
+        _ => Err(format!("no controller port {}", port).into()),
+    }
+}
+
+// "a+start" to its bit mask
+fn buttons(names: &str) -> Result<u8, Box<EvalAltResult>> {
+    names.split('+').try_fold(0u8, |mask, name| {
+        let name = name.trim().to_ascii_lowercase();
+        match BUTTONS.iter().position(|b| *b == name) {
+            Some(bit) => Ok(mask | 1 << bit),
+            None => Err(format!("unknown button {}", name).into()),
+        }
+    })
+}
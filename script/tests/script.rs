@@ -0,0 +1,142 @@
+use cpu::asm6502;
+use cpu::bus::Bus;
+use cpu::system::{Console, Device, System};
+use script::{Script, ScriptError, Text};
+
+// Shows the buttons of port 0 at $4016, like a controller that needs no strobe
+struct Pad(u8);
+
+impl Device for Pad {
+    fn tick(&mut self, bus: &mut Bus) {
+        bus.ram[0x4016] = self.0;
+    }
+
+    fn set_buttons(&mut self, port: usize, buttons: u8) {
+        if port == 0 {
+            self.0 = buttons;
+        }
+    }
+}
+
+fn system(program: &[u8]) -> System {
+    let mut system = System::new(Console::Nes);
+    system.bus.borrow_mut().ram[0x8000..0x8000 + program.len()].copy_from_slice(program);
+    system.bus.borrow_mut().ram[0xFFFD] = 0x80;
+    system.attach_ppu(Box::new(Pad(0)));
+    system.reset();
+    system.step_instruction();
+    system
+}
+
+// The loop a host runs: hooks around every instruction, frame hooks at frame ends
+fn run(system: &mut System, script: &mut Script, frames: u64) -> Result<(), ScriptError> {
+    let end = system.frame + frames;
+    while system.frame < end && !script.stopped() {
+        let hits = system.cpu.check_execute_breakpoints();
+        assert!(script.dispatch(system, hits)?.is_empty());
+        let frame = system.frame;
+        system.step_instruction();
+        let hits = system.cpu.take_break_hits();
+        assert!(script.dispatch(system, hits)?.is_empty());
+        if system.frame != frame {
+            script.frame_end(system)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn presses_buttons_and_watches_frames() {
+    let mut system = system(&asm6502!(
+        "loop:   lda $4016",
+        "        sta $0300",
+        "        jmp loop"
+    ));
+    let source = r#"
+        press("start", 2);
+        on_frame(|frame| {
+            clear_text();
+            text(8, 8, "pad " + peek(0x300));
+            if frame >= 3 && peek(0x300) == 0 {
+                print("released at frame " + frame);
+                stop();
+            }
+        });
+    "#;
+    let mut script = Script::new(source, &system).unwrap();
+    script.start(&mut system).unwrap();
+    assert_eq!(script.buttons(0), Some(0x08));
+    assert_eq!(script.buttons(2), None);
+
+    run(&mut system, &mut script, 1).unwrap();
+    assert_eq!(system.bus.borrow().ram[0x0300], 0x08);
+    assert_eq!(
+        script.text(),
+        vec![Text {
+            x: 8,
+            y: 8,
+            message: "pad 8".to_string()
+        }]
+    );
+    assert!(!script.stopped());
+
+    run(&mut system, &mut script, 10).unwrap();
+    assert!(script.stopped());
+    assert_eq!(system.frame, 3);
+    assert_eq!(script.buttons(0), Some(0));
+    assert_eq!(script.take_output(), vec!["released at frame 3"]);
+}
+
+#[test]
+fn hooks_breakpoints_and_memory() {
+    let mut system = system(&asm6502!(
+        "        lda #1",
+        "        sta $10",
+        "        lda $10",
+        "        sta $11",
+        "done:   jmp done"
+    ));
+    let source = r#"
+        on_break(0x8002, |addr| {
+            print("break at " + addr + " with a=" + reg("a"));
+            set_reg("a", 0x42);
+        });
+        on_write(0x10, 0x11, |addr, value| print("write " + addr + "=" + value));
+        // Overwritten by the sta $11 that follows
+        on_read(0x10, 0x10, |addr, value| poke(0x11, 0x99));
+        on_break(0x8008, |addr| { if peek16(0x10) != 0x4242 { throw "bad copy"; } stop(); });
+    "#;
+    let mut script = Script::new(source, &system).unwrap();
+    script.start(&mut system).unwrap();
+    assert_eq!(system.cpu.breakpoints.list.len(), 4);
+    run(&mut system, &mut script, 1).unwrap();
+    assert!(script.stopped());
+    assert_eq!(
+        script.take_output(),
+        vec!["break at 32770 with a=1", "write 16=66", "write 17=66"]
+    );
+    script.finish(&mut system);
+    assert!(system.cpu.breakpoints.is_empty());
+
+    // A throw fails the run
+    let source = "on_break(0x8008, |addr| { if peek(0x11) != 0x42 { throw \"bad copy\"; } });";
+    let mut system = self::system(&asm6502!(
+        "        lda #1",
+        "        sta $10",
+        "        lda #2",
+        "        sta $11",
+        "done:   jmp done"
+    ));
+    let mut script = Script::new(source, &system).unwrap();
+    script.start(&mut system).unwrap();
+    let err = run(&mut system, &mut script, 1).unwrap_err();
+    assert!(err.to_string().contains("bad copy"), "{}", err);
+
+    assert!(matches!(
+        Script::new("on_frame(", &system),
+        Err(ScriptError::Parse(_))
+    ));
+    let mut script = Script::new("press(\"turbo\")", &system).unwrap();
+    let err = script.start(&mut system).unwrap_err();
+    assert!(err.to_string().contains("unknown button turbo"), "{}", err);
+}